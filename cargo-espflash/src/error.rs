@@ -23,9 +23,12 @@ pub enum Error {
     #[error("Multiple build artifacts found")]
     #[diagnostic(
         code(cargo_espflash::multiple_artifacts),
-        help("Please specify which artifact to flash using --bin")
+        help("Please specify which artifact to flash using --bin or --package")
     )]
     MultipleArtifacts,
+    #[error("Package `{0}` not found in the workspace")]
+    #[diagnostic(code(cargo_espflash::unknown_package))]
+    UnknownPackage(String),
     #[error("Specified partition table is not a csv file")]
     #[diagnostic(code(cargo_espflash::partition_table_path))]
     InvalidPartitionTablePath,
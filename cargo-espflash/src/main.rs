@@ -1,25 +1,36 @@
 use cargo_config::has_build_std;
 use cargo_metadata::Message;
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use error::Error;
-use espflash::{Config, Flasher, PartitionTable};
+use espflash::{
+    app_desc::AppDescriptorOverrides,
+    monitor::{self, monitor, LogFormat, MonitorOptions, OutputFormat},
+    notify::Notifier,
+    port,
+    reset::ResetSequence,
+    sdkconfig::SdkConfig,
+    Config, FlashFrequency, FlashMode, FlashSettings, FlashSize, FlashTiming, Flasher,
+    FlasherBuilder, PartitionTable, ResetAfterOperation, ResetBeforeOperation,
+};
 use miette::{IntoDiagnostic, Result, WrapErr};
-use monitor::monitor;
 use package_metadata::CargoEspFlashMeta;
-use serial::{BaudRate, FlowControl, SerialPort};
+use serial::BaudRate;
 use std::{
-    fs,
-    path::PathBuf,
+    fs, io,
+    path::{Path, PathBuf},
     process::{exit, Command, ExitStatus, Stdio},
     string::ToString,
+    time::Duration,
 };
 
 mod cargo_config;
 mod error;
-mod line_endings;
-mod monitor;
 mod package_metadata;
 
+/// Default time to retry opening the serial port for, in seconds, retrying is
+/// opt-in so that a wrong port name fails right away
+const DEFAULT_RETRY_OPEN: f64 = 0.0;
+
 fn main() -> Result<()> {
     miette::set_panic_hook();
     let mut app = App::new(env!("CARGO_PKG_NAME"))
@@ -38,6 +49,32 @@ fn main() -> Result<()> {
                         .long("ram")
                         .help("Load the application to RAM instead of Flash"),
                 )
+                .arg(
+                    Arg::with_name("entry")
+                        .long("entry")
+                        .takes_value(true)
+                        .value_name("ADDRESS")
+                        .requires("ram")
+                        .help("Address to jump to after loading to RAM, instead of the entry point of the application"),
+                )
+                .arg(
+                    Arg::with_name("no_run")
+                        .long("no-run")
+                        .requires("ram")
+                        .help("Only load the application to RAM, without starting it"),
+                )
+                .arg(
+                    Arg::with_name("verbose")
+                        .short("v")
+                        .long("verbose")
+                        .multiple(true)
+                        .help("Show debug messages, or trace every command sent to the device when given twice"),
+                )
+                .arg(
+                    Arg::with_name("non_interactive")
+                        .long("non-interactive")
+                        .help("Never prompt, failing with an exit code telling what went wrong instead"),
+                )
                 .arg(
                     Arg::with_name("release")
                         .long("release")
@@ -50,6 +87,22 @@ fn main() -> Result<()> {
                         .value_name("PATH")
                         .help("Path to a binary (.bin) bootloader file"),
                 )
+                .arg(
+                    Arg::with_name("package")
+                        .long("package")
+                        .short("p")
+                        .takes_value(true)
+                        .value_name("SPEC")
+                        .help("Package of the workspace to build and flash"),
+                )
+                .arg(
+                    Arg::with_name("bin")
+                        .long("bin")
+                        .takes_value(true)
+                        .value_name("NAME")
+                        .conflicts_with("example")
+                        .help("Binary to build and flash"),
+                )
                 .arg(
                     Arg::with_name("example")
                         .long("example")
@@ -70,7 +123,137 @@ fn main() -> Result<()> {
                         .long("partition-table")
                         .takes_value(true)
                         .value_name("PATH")
-                        .help("Path to a CSV file containing partition table"),
+                        .help("Path to a CSV, JSON, YAML or binary file containing partition table"),
+                )
+                .arg(
+                    Arg::with_name("sdkconfig")
+                        .long("sdkconfig")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .help("Path to an ESP-IDF sdkconfig or sdkconfig.json to take the flash settings and partition table from"),
+                )
+                .arg(
+                    Arg::with_name("bootloader_offset")
+                        .long("bootloader-offset")
+                        .takes_value(true)
+                        .value_name("OFFSET")
+                        .help("Offset to write the bootloader at, instead of the chip's default"),
+                )
+                .arg(
+                    Arg::with_name("partition_table_offset")
+                        .long("partition-table-offset")
+                        .takes_value(true)
+                        .value_name("OFFSET")
+                        .help("Offset to write the partition table at, instead of the chip's default"),
+                )
+                .arg(
+                    Arg::with_name("app_offset")
+                        .long("app-offset")
+                        .takes_value(true)
+                        .value_name("OFFSET")
+                        .help("Offset to write the application at, instead of its partition"),
+                )
+                .arg(
+                    Arg::with_name("app_version")
+                        .long("app-version")
+                        .takes_value(true)
+                        .value_name("VERSION")
+                        .help("Override the version in the application descriptor"),
+                )
+                .arg(
+                    Arg::with_name("project_name")
+                        .long("project-name")
+                        .takes_value(true)
+                        .value_name("NAME")
+                        .help("Override the project name in the application descriptor"),
+                )
+                .arg(
+                    Arg::with_name("reproducible")
+                        .long("reproducible")
+                        .help("Clear the build date and time in the application descriptor"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Flash without asking for confirmation when downgrading the application"),
+                )
+                .arg(
+                    Arg::with_name("force_unprotect")
+                        .long("force-unprotect")
+                        .conflicts_with("ram")
+                        .help("Clear the write protection bits of the flash status register before flashing"),
+                )
+                .arg(
+                    Arg::with_name("safe_mode")
+                        .long("safe-mode")
+                        .conflicts_with("speed")
+                        .help("Only use conservative ROM loader commands, without compression or baud rate changes"),
+                )
+                .arg(
+                    Arg::with_name("pipeline")
+                        .long("pipeline")
+                        .conflicts_with("safe_mode")
+                        .help("Send each block while the previous one is being written to flash"),
+                )
+                .arg(
+                    Arg::with_name("spi_clock_div")
+                        .long("spi-clock-div")
+                        .takes_value(true)
+                        .value_name("DIVIDER")
+                        .help("Divider of the SPI clock used to access flash, for boards with slow flash"),
+                )
+                .arg(
+                    Arg::with_name("dummy_cycles")
+                        .long("dummy-cycles")
+                        .takes_value(true)
+                        .value_name("CYCLES")
+                        .help("Number of dummy cycles before reading from flash"),
+                )
+                .arg(
+                    Arg::with_name("flash_mode")
+                        .long("flash-mode")
+                        .takes_value(true)
+                        .value_name("MODE")
+                        .possible_values(&["qio", "qout", "dio", "dout"])
+                        .help("SPI mode used to access flash"),
+                )
+                .arg(
+                    Arg::with_name("flash_freq")
+                        .long("flash-freq")
+                        .takes_value(true)
+                        .value_name("FREQUENCY")
+                        .possible_values(&["20m", "26m", "40m", "80m"])
+                        .help("SPI clock frequency used to access flash"),
+                )
+                .arg(
+                    Arg::with_name("flash_size")
+                        .long("flash-size")
+                        .takes_value(true)
+                        .value_name("SIZE")
+                        .help("Size of the flash, e.g. 4MB"),
+                )
+                .arg(
+                    Arg::with_name("before")
+                        .long("before")
+                        .takes_value(true)
+                        .value_name("RESET")
+                        .possible_values(&["default_reset", "usb_reset", "no_reset"])
+                        .help("How to reset the chip into the bootloader before flashing [default: default_reset]"),
+                )
+                .arg(
+                    Arg::with_name("reset_sequence")
+                        .long("reset-sequence")
+                        .takes_value(true)
+                        .value_name("STEPS")
+                        .help("Custom steps resetting the chip into the bootloader, like \"dtr=0, rts=1, sleep 100ms, rts=0\""),
+                )
+                .arg(
+                    Arg::with_name("after")
+                        .long("after")
+                        .takes_value(true)
+                        .value_name("RESET")
+                        .possible_values(&["hard_reset", "soft_reset", "no_reset", "stay_in_bootloader"])
+                        .help("What to do with the chip after flashing [default: hard_reset]"),
                 )
                 .arg(
                     Arg::with_name("speed")
@@ -89,6 +272,149 @@ fn main() -> Result<()> {
                     Arg::with_name("monitor")
                         .long("monitor")
                         .help("Open a serial monitor after flashing"),
+                )
+                .arg(
+                    Arg::with_name("monitor_port")
+                        .long("monitor-port")
+                        .takes_value(true)
+                        .value_name("SERIAL")
+                        .requires("monitor")
+                        .help("Serial port to monitor, if the application logs on a different port than the one used for flashing"),
+                )
+                .arg(
+                    Arg::with_name("output_format")
+                        .long("output-format")
+                        .takes_value(true)
+                        .value_name("FORMAT")
+                        .possible_values(&["text", "jsonl"])
+                        .requires("monitor")
+                        .help("Format of the serial monitor output, jsonl writes each line as a JSON object"),
+                )
+                .arg(
+                    Arg::with_name("log_format")
+                        .long("log-format")
+                        .takes_value(true)
+                        .value_name("FORMAT")
+                        .possible_values(&["serial", "defmt"])
+                        .requires("monitor")
+                        .help("Encoding of the application logs, defmt frames are decoded with the table of the flashed ELF [default: serial]"),
+                )
+                .arg(
+                    Arg::with_name("log_filter")
+                        .long("log-filter")
+                        .takes_value(true)
+                        .value_name("TAG=LEVEL")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .requires("monitor")
+                        .help("Only show ESP-IDF log messages of a tag up to a level, e.g. wifi=warn or *=info, later filters take precedence"),
+                )
+                .arg(
+                    Arg::with_name("input_mode")
+                        .long("input-mode")
+                        .takes_value(true)
+                        .value_name("MODE")
+                        .possible_values(&["raw", "line"])
+                        .requires("monitor")
+                        .help("Send typed keys to the device right away, or edit whole lines locally and send them on Enter [default: raw]"),
+                )
+                .arg(
+                    Arg::with_name("eol")
+                        .long("eol")
+                        .takes_value(true)
+                        .value_name("EOL")
+                        .possible_values(&["cr", "lf", "crlf"])
+                        .requires("monitor")
+                        .help("Line ending sent for the Enter key [default: cr]"),
+                )
+                .arg(
+                    Arg::with_name("timestamps")
+                        .long("timestamps")
+                        .requires("monitor")
+                        .help("Prefix each line of the serial monitor with the time it was received"),
+                )
+                .arg(
+                    Arg::with_name("log_file")
+                        .long("log-file")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .requires("monitor")
+                        .help("Append the serial monitor output to this file as well"),
+                )
+                .arg(
+                    Arg::with_name("log_file_size")
+                        .long("log-file-size")
+                        .takes_value(true)
+                        .value_name("SIZE")
+                        .requires("log_file")
+                        .help("Rotate the log file once it reaches this size, e.g. 10M, keeping 5 old files"),
+                )
+                .arg(
+                    Arg::with_name("monitor_baud")
+                        .long("monitor-baud")
+                        .takes_value(true)
+                        .value_name("BAUD")
+                        .requires("monitor")
+                        .help("Baud rate of the application output [default: 115200]"),
+                )
+                .arg(
+                    Arg::with_name("boot_baud")
+                        .long("boot-baud")
+                        .takes_value(true)
+                        .value_name("BAUD")
+                        .requires("monitor")
+                        .help("Show the boot messages at this baud rate, like 74880 for the ESP8266 ROM, switching to the monitor baud rate once the application starts"),
+                )
+                .arg(
+                    Arg::with_name("exit_key")
+                        .long("exit-key")
+                        .takes_value(true)
+                        .value_name("KEY")
+                        .requires("monitor")
+                        .help("Letter quitting the serial monitor together with CTRL [default: c]"),
+                )
+                .arg(
+                    Arg::with_name("serve")
+                        .long("serve")
+                        .takes_value(true)
+                        .value_name("ADDRESS")
+                        .requires("monitor")
+                        .help("Share the serial port while monitoring, on host:port for raw TCP or rfc2217://host:port for RFC2217"),
+                )
+                .arg(
+                    Arg::with_name("retry_open")
+                        .long("retry-open")
+                        .takes_value(true)
+                        .value_name("SECONDS")
+                        .help("How long to retry opening the serial port while the device is being enumerated [default: 0]"),
+                )
+                .arg(
+                    Arg::with_name("gpio_en")
+                        .long("gpio-en")
+                        .takes_value(true)
+                        .value_name("LINE")
+                        .requires("gpio_io0")
+                        .help("GPIO line of the host wired to the EN pin of the chip, to reset it without DTR and RTS"),
+                )
+                .arg(
+                    Arg::with_name("gpio_io0")
+                        .long("gpio-io0")
+                        .takes_value(true)
+                        .value_name("LINE")
+                        .requires("gpio_en")
+                        .help("GPIO line of the host wired to the IO0 pin of the chip"),
+                )
+                .arg(
+                    Arg::with_name("notify")
+                        .long("notify")
+                        .help("Show a desktop notification when flashing completes or fails"),
+                )
+                .arg(
+                    Arg::with_name("notify_webhook")
+                        .long("notify-webhook")
+                        .takes_value(true)
+                        .value_name("URL")
+                        .help("POST a JSON notification to this URL when flashing completes or fails"),
                 ),
         );
 
@@ -100,30 +426,56 @@ fn main() -> Result<()> {
             exit(0);
         }
     };
+    espflash::logging::init(matches.occurrences_of("verbose"));
+
+    let notifier = if matches.is_present("notify") || matches.is_present("notify_webhook") {
+        Some(Notifier::new(
+            matches.is_present("notify"),
+            matches.value_of("notify_webhook").map(ToString::to_string),
+        ))
+    } else {
+        None
+    };
 
+    let result = flash(matches, notifier.as_ref());
+    if let (Some(notifier), Err(err)) = (&notifier, &result) {
+        send_notification(notifier.failure(&err.to_string()));
+    }
+
+    match result {
+        Err(err) if matches.is_present("non_interactive") => {
+            let code = err
+                .downcast_ref::<espflash::Error>()
+                .map_or(1, |err| err.probe_failure().exit_code());
+            eprintln!("{:?}", err);
+            exit(code)
+        }
+        result => result,
+    }
+}
+
+fn flash(matches: &ArgMatches, notifier: Option<&Notifier>) -> Result<()> {
     let config = Config::load();
-    let metadata = CargoEspFlashMeta::load("Cargo.toml")?;
+    let metadata = match matches.value_of("package") {
+        Some(package) => CargoEspFlashMeta::load(package_manifest(package)?)?,
+        None => CargoEspFlashMeta::load("Cargo.toml")?,
+    };
 
     // The serial port must be specified, either as a command-line argument or in
     // the cargo configuration file. In the case that both have been provided the
     // command-line argument will take precedence.
     let port = if let Some(serial) = matches.value_of("serial") {
         serial.to_string()
-    } else if let Some(serial) = config.connection.serial {
+    } else if let Some(serial) = config.connection.serial.clone() {
         serial
     } else {
-        app.print_help().into_diagnostic()?;
-        exit(0);
+        port::detect(&config, !matches.is_present("non_interactive"))?
     };
 
     // Only build the application if the '--board-info' flag has not been passed.
     let show_board_info = matches.is_present("board_info");
     let path = if !show_board_info {
-        let release = matches.is_present("release");
-        let example = matches.value_of("example");
-        let features = matches.value_of("features");
-
-        let path = build(release, example, features)?;
+        let path = build(matches)?;
 
         Some(path)
     } else {
@@ -133,38 +485,170 @@ fn main() -> Result<()> {
     // Attempt to open the serial port and set its initial baud rate.
     println!("Serial port: {}", port);
     println!("Connecting...\n");
-    let mut serial = serial::open(&port)
-        .map_err(espflash::Error::from)
+    let retry_window = match matches.value_of("retry_open") {
+        Some(seconds) => seconds.parse::<f64>().into_diagnostic()?,
+        None => config.connection.retry_open.unwrap_or(DEFAULT_RETRY_OPEN),
+    };
+    let retry_window = Duration::try_from_secs_f64(retry_window).into_diagnostic()?;
+    let mut serial = port::open(&port, retry_window)
         .wrap_err_with(|| format!("Failed to open serial port {}", port))?;
-    serial
-        .reconfigure(&|settings| {
-            settings.set_flow_control(FlowControl::FlowNone);
-            settings.set_baud_rate(BaudRate::Baud115200)?;
-            Ok(())
-        })
-        .into_diagnostic()?;
+    let gpio_en = parse_value::<u32>(matches.value_of("gpio_en"))?.or(config.connection.gpio_en);
+    let gpio_io0 = parse_value::<u32>(matches.value_of("gpio_io0"))?.or(config.connection.gpio_io0);
+    if let (Some(en), Some(io0)) = (gpio_en, gpio_io0) {
+        serial = Box::new(
+            port::GpioResetPort::new(serial, port::GpioPins { en, io0 })
+                .into_diagnostic()
+                .wrap_err("Failed to set up the GPIOs resetting the chip")?,
+        );
+    }
+
+    // Open the monitor port up front, so output printed right after the device
+    // boots isn't lost.
+    let monitor_serial = match matches.value_of("monitor_port") {
+        Some(monitor_port) => Some(
+            port::open(monitor_port, retry_window)
+                .wrap_err_with(|| format!("Failed to open serial port {}", monitor_port))?,
+        ),
+        None => None,
+    };
+    let monitor_options = MonitorOptions {
+        format: match matches.value_of("output_format") {
+            Some("jsonl") => OutputFormat::JsonLines,
+            _ => OutputFormat::Text,
+        },
+        log_format: match matches.value_of("log_format") {
+            Some("defmt") => LogFormat::Defmt,
+            _ => LogFormat::Serial,
+        },
+        baud: parse_value(matches.value_of("monitor_baud"))?.unwrap_or(115_200),
+        boot_baud: parse_value(matches.value_of("boot_baud"))?,
+        exit_key: match matches.value_of("exit_key") {
+            Some(key) => MonitorOptions::parse_exit_key(key)?,
+            None => 'c',
+        },
+        reset: false,
+        timestamps: matches.is_present("timestamps"),
+        log_file: matches.value_of("log_file").map(PathBuf::from),
+        log_file_size: matches
+            .value_of("log_file_size")
+            .map(|size| {
+                monitor::parse_size(size)
+                    .ok_or_else(|| miette::miette!("Invalid log file size `{}`", size))
+            })
+            .transpose()?,
+        log_filter: match matches.values_of("log_filter") {
+            Some(filters) => Some(filters.collect::<Vec<_>>().join(",").parse()?),
+            None => None,
+        },
+        input_mode: parse_value(matches.value_of("input_mode"))?.unwrap_or_default(),
+        line_ending: parse_value(matches.value_of("eol"))?.unwrap_or_default(),
+        serve: match matches.value_of("serve") {
+            Some(address) => Some(address.parse()?),
+            None => None,
+        },
+        // the USB CDC port of an application disappears while it resets
+        reopen: Some(matches.value_of("monitor_port").unwrap_or(&port))
+            .filter(|port| !port::NetworkPort::is_url(port))
+            .map(String::from),
+    };
 
-    // Parse the baud rate if provided as as a command-line argument.
+    // Parse the baud rate if provided as as a command-line argument, or use the
+    // one from the config file unless in safe mode.
     let speed = if let Some(speed) = matches.value_of("speed") {
         let speed = speed.parse::<usize>().into_diagnostic()?;
         Some(BaudRate::from_speed(speed))
-    } else {
+    } else if matches.is_present("safe_mode") {
         None
+    } else {
+        config.connection.baud.map(BaudRate::from_speed)
     };
 
     // Connect the Flasher to the target device. If the '--board-info' flag has been
     // provided, display the board info and terminate the application.
-    let mut flasher = Flasher::connect(serial, speed)?;
+    let before = parse_value::<ResetBeforeOperation>(matches.value_of("before"))?;
+    let after = parse_value::<ResetAfterOperation>(matches.value_of("after"))?;
+    let reset_sequence = parse_value::<ResetSequence>(matches.value_of("reset_sequence"))?
+        .or_else(|| config.connection.reset_sequence.clone());
+    let mut flasher = FlasherBuilder::new()
+        .transport(serial)
+        .usb_jtag_serial(port::is_usb_jtag_serial(&port))
+        .reopen(port.clone())
+        .speed(speed)
+        .before(before.unwrap_or_default())
+        .reset_sequence(reset_sequence)
+        .after(after.unwrap_or_default())
+        .connect()?;
+    flasher.set_safe_mode(matches.is_present("safe_mode"));
+    flasher.set_pipelined(matches.is_present("pipeline"));
+    flasher.set_flash_timing(FlashTiming {
+        clock_divider: match matches.value_of("spi_clock_div") {
+            Some(divider) => Some(divider.parse().into_diagnostic()?),
+            None => config.flash.spi_clock_div,
+        },
+        dummy_cycles: match matches.value_of("dummy_cycles") {
+            Some(cycles) => Some(cycles.parse().into_diagnostic()?),
+            None => config.flash.dummy_cycles,
+        },
+    })?;
+    flasher.set_app_descriptor_overrides(AppDescriptorOverrides {
+        version: matches.value_of("app_version").map(ToString::to_string),
+        project_name: matches.value_of("project_name").map(ToString::to_string),
+        reproducible: matches.is_present("reproducible"),
+        ..AppDescriptorOverrides::default()
+    });
+
+    // If the '--sdkconfig' option is provided, use the flash settings and
+    // partition table the firmware was configured for.
+    let sdkconfig = match matches.value_of("sdkconfig") {
+        Some(path) => {
+            let data = fs::read_to_string(path).into_diagnostic()?;
+            Some((
+                path,
+                SdkConfig::parse(&data).map_err(espflash::Error::from)?,
+            ))
+        }
+        None => None,
+    };
+    let mut settings = match &sdkconfig {
+        Some((_, config)) => config.flash_settings().map_err(espflash::Error::from)?,
+        None => FlashSettings::default(),
+    };
+
+    // Flash settings and offsets given on the command line take precedence over
+    // the sdkconfig, which takes precedence over the config file.
+    settings.mode = parse_value::<FlashMode>(matches.value_of("flash_mode"))?
+        .or(settings.mode)
+        .or(config.flash.mode);
+    settings.frequency = parse_value::<FlashFrequency>(matches.value_of("flash_freq"))?
+        .or(settings.frequency)
+        .or(config.flash.frequency);
+    settings.size = parse_value::<FlashSize>(matches.value_of("flash_size"))?
+        .or(settings.size)
+        .or(config.flash.size);
+    settings.bootloader_offset = parse_offset(matches.value_of("bootloader_offset"))?
+        .or(settings.bootloader_offset)
+        .or(config.flash.bootloader_offset);
+    settings.partition_table_offset = parse_offset(matches.value_of("partition_table_offset"))?
+        .or(settings.partition_table_offset)
+        .or(config.flash.partition_table_offset);
+    settings.app_offset = parse_offset(matches.value_of("app_offset"))?
+        .or(settings.app_offset)
+        .or(config.flash.app_offset);
+    flasher.set_flash_settings(settings);
+
     if show_board_info {
-        board_info(&flasher);
+        board_info(&mut flasher)?;
         return Ok(());
     }
 
     // If the '--bootloader' option is provided, load the binary file at the
-    // specified path.
+    // specified path, otherwise fall back to the one configured in the package
+    // metadata or the config file.
     let bootloader = if let Some(path) = matches
         .value_of("bootloader")
-        .or_else(|| metadata.bootloader.as_deref())
+        .map(PathBuf::from)
+        .or_else(|| metadata.bootloader.as_ref().map(PathBuf::from))
+        .or(config.flash.bootloader)
     {
         let path = fs::canonicalize(path).into_diagnostic()?;
         let data = fs::read(path).into_diagnostic()?;
@@ -174,14 +658,21 @@ fn main() -> Result<()> {
     };
 
     // If the '--partition-table' option is provided, load the partition table from
-    // the CSV at the specified path.
+    // the CSV at the specified path, otherwise fall back to the one configured
+    // in the package metadata, the sdkconfig or the config file.
+    let sdkconfig_partition_table = sdkconfig
+        .as_ref()
+        .and_then(|(path, config)| config.partition_table(Path::new(path)));
     let partition_table = if let Some(path) = matches
         .value_of("partition_table")
-        .or_else(|| metadata.partition_table.as_deref())
+        .map(PathBuf::from)
+        .or_else(|| metadata.partition_table.as_ref().map(PathBuf::from))
+        .or(sdkconfig_partition_table)
+        .or(config.flash.partition_table)
     {
         let path = fs::canonicalize(path).into_diagnostic()?;
-        let data = fs::read_to_string(path).into_diagnostic()?;
-        let table = PartitionTable::try_from_str(data)?;
+        let data = fs::read(path).into_diagnostic()?;
+        let table = PartitionTable::try_from_data(data)?;
         Some(table)
     } else {
         None
@@ -190,25 +681,69 @@ fn main() -> Result<()> {
     // Read the ELF data from the build path and load it to the target.
     let elf_data = fs::read(path.unwrap()).into_diagnostic()?;
     if matches.is_present("ram") {
-        flasher.load_elf_to_ram(&elf_data)?;
+        let entry = parse_offset(matches.value_of("entry"))?;
+        flasher.load_elf_to_ram_with(&elf_data, entry, !matches.is_present("no_run"))?;
     } else {
-        flasher.load_elf_to_flash(&elf_data, bootloader, partition_table)?;
+        if !matches.is_present("force") {
+            flasher.check_downgrade(
+                &elf_data,
+                partition_table.as_ref(),
+                !matches.is_present("non_interactive"),
+            )?;
+        }
+        if matches.is_present("force_unprotect") && flasher.unprotect_flash()? {
+            println!("Cleared the write protection of the flash");
+        }
+        let report = flasher.load_elf_to_flash(&elf_data, bootloader, partition_table)?;
+        print!("{}", report);
+    }
+
+    if let Some(notifier) = notifier {
+        send_notification(notifier.success("Flashing completed"));
     }
 
     if matches.is_present("monitor") {
-        monitor(flasher.into_serial()).into_diagnostic()?;
+        let serial = match monitor_serial {
+            Some(serial) => serial,
+            None => flasher.into_serial(),
+        };
+        monitor(serial, &monitor_options, Some(&elf_data)).into_diagnostic()?;
     }
 
     // We're all done!
     Ok(())
 }
 
-fn board_info(flasher: &Flasher) {
-    println!("Chip type:  {}", flasher.chip());
-    println!("Flash size: {}", flasher.flash_size());
+/// Parse an offset given in decimal or as hexadecimal number prefixed with `0x`
+fn parse_offset(value: Option<&str>) -> Result<Option<u32>> {
+    value
+        .map(|value| match value.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => value.parse(),
+        })
+        .transpose()
+        .into_diagnostic()
 }
 
-fn build(release: bool, example: Option<&str>, features: Option<&str>) -> Result<PathBuf> {
+fn parse_value<T: std::str::FromStr>(value: Option<&str>) -> Result<Option<T>>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    value.map(str::parse).transpose().into_diagnostic()
+}
+
+fn send_notification(result: io::Result<()>) {
+    if let Err(err) = result {
+        eprintln!("Failed to send notification: {}", err);
+    }
+}
+
+fn board_info(flasher: &mut Flasher) -> Result<()> {
+    print!("{}", flasher.board_info()?);
+    Ok(())
+}
+
+fn build(matches: &ArgMatches) -> Result<PathBuf> {
     // The 'build-std' unstable cargo feature is required to enable
     // cross-compilation. If it has not been set then we cannot build the
     // application.
@@ -219,25 +754,27 @@ fn build(release: bool, example: Option<&str>, features: Option<&str>) -> Result
     // Build the list of arguments to pass to 'cargo build'.
     let mut args = vec![];
 
-    if release {
-        args.push("--release");
+    if matches.is_present("release") {
+        args.push("--release".to_string());
     }
 
-    if let Some(example) = example {
-        args.push("--example");
-        args.push(example);
+    for name in ["package", "bin", "example"] {
+        if let Some(value) = matches.value_of(name) {
+            args.push(format!("--{}", name));
+            args.push(value.to_string());
+        }
     }
 
-    if let Some(features) = features {
-        args.push("--features");
-        args.push(features);
+    if let Some(features) = matches.values_of("features") {
+        args.push("--features".to_string());
+        args.push(features.collect::<Vec<_>>().join(","));
     }
 
     // Invoke the 'cargo build' command, passing our list of arguments.
     let output = Command::new("cargo")
         .arg("build")
         .args(args)
-        .args(&["--message-format", "json-diagnostic-rendered-ansi"])
+        .args(["--message-format", "json-diagnostic-rendered-ansi"])
         .stdout(Stdio::piped())
         .stderr(Stdio::inherit())
         .spawn()
@@ -253,13 +790,11 @@ fn build(release: bool, example: Option<&str>, features: Option<&str>) -> Result
 
     for message in messages {
         match message.into_diagnostic()? {
-            Message::CompilerArtifact(artifact) => {
-                if artifact.executable.is_some() {
-                    if target_artifact.is_some() {
-                        return Err(Error::MultipleArtifacts.into());
-                    } else {
-                        target_artifact = Some(artifact);
-                    }
+            Message::CompilerArtifact(artifact) if artifact.executable.is_some() => {
+                if target_artifact.is_some() {
+                    return Err(Error::MultipleArtifacts.into());
+                } else {
+                    target_artifact = Some(artifact);
                 }
             }
             Message::CompilerMessage(message) => {
@@ -287,6 +822,21 @@ fn build(release: bool, example: Option<&str>, features: Option<&str>) -> Result
     Ok(artifact_path)
 }
 
+/// Path of the manifest of the workspace member `package`
+fn package_manifest(package: &str) -> Result<PathBuf> {
+    let metadata = cargo_metadata::MetadataCommand::new()
+        .no_deps()
+        .exec()
+        .into_diagnostic()?;
+
+    metadata
+        .packages
+        .into_iter()
+        .find(|pkg| pkg.name == package)
+        .map(|pkg| pkg.manifest_path.into())
+        .ok_or_else(|| Error::UnknownPackage(package.to_string()).into())
+}
+
 #[cfg(unix)]
 fn exit_with_process_status(status: ExitStatus) -> ! {
     use std::os::unix::process::ExitStatusExt;
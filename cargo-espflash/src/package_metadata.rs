@@ -16,8 +16,11 @@ pub struct Meta {
 }
 
 impl CargoEspFlashMeta {
+    /// Load the metadata from the manifest at `path`, making the paths in it
+    /// relative to the package directory
     pub fn load<P: AsRef<Path>>(path: P) -> Result<CargoEspFlashMeta> {
-        let manifest = Manifest::<Meta>::from_path_with_metadata(path)
+        let dir = path.as_ref().parent().unwrap_or_else(|| Path::new(""));
+        let manifest = Manifest::<Meta>::from_path_with_metadata(path.as_ref())
             .into_diagnostic()
             .wrap_err("Failed to parse Cargo.toml")?;
         let meta = manifest
@@ -26,18 +29,23 @@ impl CargoEspFlashMeta {
             .unwrap_or_default()
             .espflash
             .unwrap_or_default();
-        match meta.partition_table {
+        match &meta.partition_table {
             Some(table) if !table.ends_with(".csv") => {
                 return Err(Error::InvalidPartitionTablePath.into())
             }
             _ => {}
         }
-        match meta.bootloader {
+        match &meta.bootloader {
             Some(table) if !table.ends_with(".bin") => {
                 return Err(Error::InvalidBootloaderPath.into())
             }
             _ => {}
         }
-        Ok(meta)
+
+        let resolve = |path: String| dir.join(path).to_string_lossy().into_owned();
+        Ok(CargoEspFlashMeta {
+            partition_table: meta.partition_table.map(resolve),
+            bootloader: meta.bootloader.map(resolve),
+        })
     }
 }
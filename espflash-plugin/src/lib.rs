@@ -0,0 +1,92 @@
+//! Primitives for writing external `espflash` subcommands
+//!
+//! `espflash <name>` runs the `espflash-<name>` executable found on `PATH`, so
+//! custom steps like provisioning devices can be shipped as separate binaries.
+//! This crate gives them the same config file, serial port handling and
+//! connection as the built-in subcommands.
+//!
+//! ```no_run
+//! fn main() -> miette::Result<()> {
+//!     let context = espflash_plugin::Context::load();
+//!     let port = std::env::args().nth(1);
+//!     let mut flasher = context.connect(port.as_deref())?;
+//!
+//!     println!("MAC address: {:02x?}", flasher.mac_address()?);
+//!     Ok(())
+//! }
+//! ```
+
+pub use espflash;
+
+use espflash::{plugin::ESPFLASH_ENV, port, Config, Flasher, Transport};
+use miette::{IntoDiagnostic, Result, WrapErr};
+
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::Duration;
+
+/// Default time to retry opening the serial port for, in seconds, retrying is
+/// opt-in so that a wrong port name fails right away
+pub const DEFAULT_RETRY_OPEN: f64 = 0.0;
+
+/// What an external subcommand is run with
+#[derive(Debug)]
+pub struct Context {
+    /// The `espflash` config file
+    pub config: Config,
+    /// The `espflash` executable running the subcommand, if it was run
+    /// through `espflash`
+    pub espflash: Option<PathBuf>,
+}
+
+impl Context {
+    pub fn load() -> Self {
+        Context {
+            config: Config::load(),
+            espflash: env::var_os(ESPFLASH_ENV).map(PathBuf::from),
+        }
+    }
+
+    /// The given serial port, or the one from the config file
+    pub fn serial(&self, port: Option<&str>) -> Option<String> {
+        port.map(ToString::to_string)
+            .or_else(|| self.config.connection.serial.clone())
+    }
+
+    /// How long to retry opening the serial port for
+    pub fn retry_window(&self) -> Result<Duration> {
+        let seconds = self
+            .config
+            .connection
+            .retry_open
+            .unwrap_or(DEFAULT_RETRY_OPEN);
+        Duration::try_from_secs_f64(seconds).into_diagnostic()
+    }
+
+    /// Open the given serial port, the one from the config file or the
+    /// detected one
+    pub fn open_serial(&self, port: Option<&str>) -> Result<Box<dyn Transport>> {
+        let serial = match self.serial(port) {
+            Some(serial) => serial,
+            None => port::detect(&self.config, true)?,
+        };
+
+        port::open(&serial, self.retry_window()?)
+            .wrap_err_with(|| format!("Failed to open serial port {}", serial))
+    }
+
+    /// Connect to the device on the given serial port, the one from the config
+    /// file or the detected one
+    pub fn connect(&self, port: Option<&str>) -> Result<Flasher> {
+        Ok(Flasher::connect(self.open_serial(port)?, None)?)
+    }
+
+    /// A command running `espflash`, to reuse the built-in subcommands
+    pub fn espflash_command(&self) -> Command {
+        match &self.espflash {
+            Some(path) => Command::new(path),
+            None => Command::new("espflash"),
+        }
+    }
+}
@@ -0,0 +1,250 @@
+//! The `esp_app_desc_t` application descriptor embedded in ESP-IDF images
+//!
+//! The descriptor is placed at the start of the first segment of the image and
+//! records the version and build information of the application.
+
+use bytemuck::{Pod, Zeroable};
+use serde::Serialize;
+
+use std::cmp::Ordering;
+use std::mem::size_of;
+
+/// Magic word at the start of the application descriptor
+pub const APP_DESC_MAGIC: u32 = 0xABCD5432;
+/// Offset of the application descriptor in an application image, right after
+/// the image header and the header of the first segment
+pub const APP_DESC_OFFSET: u32 = 0x20;
+/// Size of the application descriptor
+pub const APP_DESC_SIZE: u32 = size_of::<RawAppDescriptor>() as u32;
+
+#[derive(Copy, Clone, Zeroable, Pod)]
+#[repr(C)]
+struct RawAppDescriptor {
+    magic_word: u32,
+    secure_version: u32,
+    reserved1: [u32; 2],
+    version: [u8; 32],
+    project_name: [u8; 32],
+    time: [u8; 16],
+    date: [u8; 16],
+    idf_ver: [u8; 32],
+    app_elf_sha256: [u8; 32],
+    reserved2: [u32; 20],
+}
+
+/// Version and build information of an application
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct AppDescriptor {
+    /// Security version used for anti-rollback
+    pub secure_version: u32,
+    /// Application version
+    pub version: String,
+    /// Project name
+    pub project_name: String,
+    /// Compile time
+    pub time: String,
+    /// Compile date
+    pub date: String,
+    /// Version of ESP-IDF the application was built with
+    pub idf_version: String,
+    /// SHA-256 of the application ELF file
+    pub elf_sha256: [u8; 32],
+}
+
+impl AppDescriptor {
+    /// Parse an application descriptor, returning `None` if the data doesn't
+    /// start with a valid descriptor
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let raw: RawAppDescriptor =
+            bytemuck::pod_read_unaligned(data.get(..APP_DESC_SIZE as usize)?);
+        if raw.magic_word != APP_DESC_MAGIC {
+            return None;
+        }
+
+        Some(AppDescriptor {
+            secure_version: raw.secure_version,
+            version: c_string(&raw.version),
+            project_name: c_string(&raw.project_name),
+            time: c_string(&raw.time),
+            date: c_string(&raw.date),
+            idf_version: c_string(&raw.idf_ver),
+            elf_sha256: raw.app_elf_sha256,
+        })
+    }
+
+    /// Find the application descriptor in an application image
+    pub fn from_image(image: &[u8]) -> Option<Self> {
+        Self::parse(image.get(APP_DESC_OFFSET as usize..)?)
+    }
+
+    /// Check whether replacing `current` by this application would be a
+    /// downgrade, either of the security version or of the application
+    /// version
+    ///
+    /// Versions which can't be compared, such as git hashes, are never
+    /// considered a downgrade.
+    pub fn is_downgrade_from(&self, current: &AppDescriptor) -> bool {
+        self.secure_version < current.secure_version
+            || compare_versions(&self.version, &current.version) == Some(Ordering::Less)
+    }
+}
+
+/// Fields of the application descriptor to override while generating an image
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct AppDescriptorOverrides {
+    /// Replace the application version
+    pub version: Option<String>,
+    /// Replace the project name
+    pub project_name: Option<String>,
+    /// Replace the compile time
+    pub time: Option<String>,
+    /// Replace the compile date
+    pub date: Option<String>,
+    /// Clear the compile time and date, so that images built from the same
+    /// sources are identical
+    pub reproducible: bool,
+}
+
+impl AppDescriptorOverrides {
+    /// Patch the application descriptor in a generated image, if it has one
+    ///
+    /// The image is expected to end with its checksum byte, without an
+    /// appended digest, the checksum is updated to match the patched data.
+    pub(crate) fn apply(&self, image: &mut [u8]) {
+        let start = APP_DESC_OFFSET as usize;
+        let end = start + APP_DESC_SIZE as usize;
+        if *self == AppDescriptorOverrides::default() || image.len() <= end {
+            return;
+        }
+
+        let mut raw: RawAppDescriptor = bytemuck::pod_read_unaligned(&image[start..end]);
+        if raw.magic_word != APP_DESC_MAGIC {
+            return;
+        }
+
+        if let Some(version) = &self.version {
+            set_c_string(&mut raw.version, version);
+        }
+        if let Some(project_name) = &self.project_name {
+            set_c_string(&mut raw.project_name, project_name);
+        }
+        if self.reproducible {
+            raw.time = [0; 16];
+            raw.date = [0; 16];
+        }
+        if let Some(time) = &self.time {
+            set_c_string(&mut raw.time, time);
+        }
+        if let Some(date) = &self.date {
+            set_c_string(&mut raw.date, date);
+        }
+
+        // The checksum is the xor of all segment data, so the difference between
+        // the old and new descriptor can be applied to it directly
+        let patched = bytemuck::bytes_of(&raw);
+        let delta = image[start..end]
+            .iter()
+            .zip(patched)
+            .fold(0, |delta, (old, new)| delta ^ old ^ new);
+
+        image[start..end].copy_from_slice(patched);
+        if let Some(checksum) = image.last_mut() {
+            *checksum ^= delta;
+        }
+    }
+}
+
+/// Compare two version strings like `v1.2.3-rc1` by their numeric
+/// components, ignoring any pre-release or build suffix
+fn compare_versions(a: &str, b: &str) -> Option<Ordering> {
+    fn components(version: &str) -> Option<Vec<u32>> {
+        version
+            .trim_start_matches('v')
+            .split(['-', '+'])
+            .next()?
+            .split('.')
+            .map(|component| component.parse().ok())
+            .collect()
+    }
+
+    Some(components(a)?.cmp(&components(b)?))
+}
+
+/// Store a string in a fixed size, nul terminated field, truncating it if
+/// needed
+fn set_c_string(field: &mut [u8], value: &str) {
+    let len = value.len().min(field.len() - 1);
+    field.fill(0);
+    field[..len].copy_from_slice(&value.as_bytes()[..len]);
+}
+
+fn c_string(data: &[u8]) -> String {
+    let len = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..len]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn descriptor(secure_version: u32, version: &str) -> Vec<u8> {
+        let mut raw = RawAppDescriptor::zeroed();
+        raw.magic_word = APP_DESC_MAGIC;
+        raw.secure_version = secure_version;
+        raw.version[..version.len()].copy_from_slice(version.as_bytes());
+        raw.project_name[..5].copy_from_slice(b"blink");
+        raw.date[..11].copy_from_slice(b"Jan  1 2021");
+
+        bytemuck::bytes_of(&raw).to_vec()
+    }
+
+    #[test]
+    fn test_parse() {
+        let desc = AppDescriptor::parse(&descriptor(1, "v1.2.3")).unwrap();
+        assert_eq!(1, desc.secure_version);
+        assert_eq!("v1.2.3", desc.version);
+        assert_eq!("blink", desc.project_name);
+
+        assert_eq!(None, AppDescriptor::parse(&[0; 256]));
+        assert_eq!(None, AppDescriptor::parse(&descriptor(1, "v1.2.3")[..100]));
+    }
+
+    #[test]
+    fn test_apply_overrides() {
+        let mut image = vec![0xaa; APP_DESC_OFFSET as usize];
+        image.extend(descriptor(0, "v1.0.0"));
+        let checksum = image.iter().fold(0, |checksum, byte| checksum ^ byte);
+        image.push(checksum);
+
+        let overrides = AppDescriptorOverrides {
+            version: Some(String::from("v2.0.0")),
+            reproducible: true,
+            ..AppDescriptorOverrides::default()
+        };
+        overrides.apply(&mut image);
+
+        let desc = AppDescriptor::from_image(&image).unwrap();
+        assert_eq!("v2.0.0", desc.version);
+        assert_eq!("blink", desc.project_name);
+        assert_eq!("", desc.date);
+
+        let (checksum, data) = image.split_last().unwrap();
+        assert_eq!(
+            *checksum,
+            data.iter().fold(0, |checksum, byte| checksum ^ byte)
+        );
+    }
+
+    #[test]
+    fn test_is_downgrade_from() {
+        let parse = |secure_version, version| {
+            AppDescriptor::parse(&descriptor(secure_version, version)).unwrap()
+        };
+
+        assert!(parse(0, "v1.2.3").is_downgrade_from(&parse(0, "v1.10.0")));
+        assert!(parse(0, "1.2.3").is_downgrade_from(&parse(1, "1.2.3")));
+        assert!(!parse(0, "v1.10.0").is_downgrade_from(&parse(0, "v1.2.3")));
+        assert!(!parse(0, "v1.2.3-rc1").is_downgrade_from(&parse(0, "v1.2.3")));
+        assert!(!parse(0, "3f2a9c1").is_downgrade_from(&parse(0, "v1.2.3")));
+    }
+}
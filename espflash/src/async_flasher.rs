@@ -0,0 +1,217 @@
+//! Flashing from async code
+//!
+//! The serial protocol of the ROM bootloader is strictly request-response, so
+//! [`AsyncFlasher`] keeps the [`Flasher`] on a thread of its own, connected
+//! over a port opened with `tokio-serial`, and hands each operation to it.
+//! The returned futures don't block the runtime, and dropping one cancels the
+//! operation like a [`CancelToken`] would.
+
+use tokio::sync::oneshot;
+use tokio_serial::SerialPort;
+
+use std::io::{self, Read, Write};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use crate::{
+    board_info::BoardInfo, command::DEFAULT_TIMEOUT, report::FlashReport, CancelToken, Chip, Error,
+    Flasher, FlasherBuilder, PartitionTable, Transport,
+};
+
+/// An operation run on the flasher thread
+type Job = Box<dyn FnOnce(&mut Flasher) + Send>;
+
+/// A [`Flasher`] with async methods, for use on the tokio runtime
+///
+/// ```no_run
+/// # async fn flash(elf_data: Vec<u8>) -> Result<(), espflash::Error> {
+/// use espflash::AsyncFlasher;
+///
+/// let flasher = AsyncFlasher::connect("/dev/ttyUSB0", |builder| builder).await?;
+/// let report = flasher.load_elf_to_flash(elf_data, None, None).await?;
+/// print!("{}", report);
+/// # Ok(())
+/// # }
+/// ```
+pub struct AsyncFlasher {
+    jobs: mpsc::Sender<Job>,
+    chip: Chip,
+}
+
+impl AsyncFlasher {
+    /// Open the serial port `port` and connect to the device
+    ///
+    /// The [`FlasherBuilder`] connecting over the port is passed through
+    /// `configure`, to set the speed, reset sequence and so on.
+    pub async fn connect<F>(port: impl Into<String>, configure: F) -> Result<Self, Error>
+    where
+        F: FnOnce(FlasherBuilder) -> FlasherBuilder + Send + 'static,
+    {
+        let port = port.into();
+        let (connected, connection) = oneshot::channel();
+        let (jobs, receiver) = mpsc::channel::<Job>();
+
+        thread::Builder::new()
+            .name(format!("espflash {}", port))
+            .spawn(move || {
+                let builder =
+                    FlasherBuilder::new().usb_jtag_serial(crate::port::is_usb_jtag_serial(&port));
+                let flasher = SerialPortTransport::open(&port)
+                    .and_then(|serial| configure(builder.transport(serial)).connect());
+                let mut flasher = match flasher {
+                    Ok(flasher) => {
+                        let _ = connected.send(Ok(flasher.chip()));
+                        flasher
+                    }
+                    Err(err) => {
+                        let _ = connected.send(Err(err));
+                        return;
+                    }
+                };
+
+                // runs until the `AsyncFlasher` is dropped
+                for job in receiver {
+                    job(&mut flasher);
+                }
+            })?;
+
+        let chip = connection.await.map_err(|_| thread_exited())??;
+        Ok(AsyncFlasher { jobs, chip })
+    }
+
+    /// The chip connected to
+    pub fn chip(&self) -> Chip {
+        self.chip
+    }
+
+    /// Run `operation` on the flasher thread
+    ///
+    /// Dropping the returned future cancels the operation before the next
+    /// command is sent to the device.
+    pub async fn run<T, F>(&self, operation: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Flasher) -> Result<T, Error> + Send + 'static,
+    {
+        let cancel = CancelToken::new();
+        let _guard = CancelOnDrop(cancel.clone());
+
+        let (sender, result) = oneshot::channel();
+        let job: Job = Box::new(move |flasher| {
+            flasher.set_cancel_token(Some(cancel));
+            let _ = sender.send(operation(flasher));
+            flasher.set_cancel_token(None);
+        });
+        self.jobs.send(job).map_err(|_| thread_exited())?;
+
+        result.await.map_err(|_| thread_exited())?
+    }
+
+    /// See [`Flasher::board_info`]
+    pub async fn board_info(&self) -> Result<BoardInfo, Error> {
+        self.run(Flasher::board_info).await
+    }
+
+    /// See [`Flasher::read_flash`]
+    pub async fn read_flash(&self, offset: u32, length: u32) -> Result<Vec<u8>, Error> {
+        self.run(move |flasher| flasher.read_flash(offset, length))
+            .await
+    }
+
+    /// See [`Flasher::load_elf_to_flash`]
+    pub async fn load_elf_to_flash(
+        &self,
+        elf_data: Vec<u8>,
+        bootloader: Option<Vec<u8>>,
+        partition_table: Option<PartitionTable>,
+    ) -> Result<FlashReport, Error> {
+        self.run(move |flasher| flasher.load_elf_to_flash(&elf_data, bootloader, partition_table))
+            .await
+    }
+
+    /// See [`Flasher::write_bin_to_flash`]
+    pub async fn write_bin_to_flash(&self, addr: u32, data: Vec<u8>) -> Result<(), Error> {
+        self.run(move |flasher| flasher.write_bin_to_flash(addr, &data))
+            .await
+    }
+}
+
+/// Cancels the operation of a future which is dropped before it completes
+struct CancelOnDrop(CancelToken);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        // cancelling a finished operation does nothing, the token isn't used
+        // again
+        self.0.cancel();
+    }
+}
+
+fn thread_exited() -> Error {
+    io::Error::other("the flasher thread exited").into()
+}
+
+/// A port opened with `tokio-serial`, used from the flasher thread
+struct SerialPortTransport(Box<dyn SerialPort>);
+
+impl SerialPortTransport {
+    fn open(port: &str) -> Result<Self, Error> {
+        let serial = tokio_serial::new(port, 115_200)
+            .timeout(DEFAULT_TIMEOUT)
+            .open()
+            .map_err(io::Error::from)?;
+        Ok(SerialPortTransport(serial))
+    }
+}
+
+impl Read for SerialPortTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for SerialPortTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Transport for SerialPortTransport {
+    fn timeout(&self) -> Duration {
+        self.0.timeout()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        Ok(self.0.set_timeout(timeout)?)
+    }
+
+    fn set_baud(&mut self, baud: usize) -> io::Result<()> {
+        Ok(self.0.set_baud_rate(baud as u32)?)
+    }
+
+    fn set_dtr(&mut self, level: bool) -> io::Result<()> {
+        Ok(self.0.write_data_terminal_ready(level)?)
+    }
+
+    fn set_rts(&mut self, level: bool) -> io::Result<()> {
+        Ok(self.0.write_request_to_send(level)?)
+    }
+}
+
+#[test]
+fn test_connect_missing_port() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let result = runtime.block_on(AsyncFlasher::connect(
+        "/dev/espflash-missing-port",
+        |builder| builder,
+    ));
+
+    assert!(matches!(result, Err(Error::Connection(_))));
+}
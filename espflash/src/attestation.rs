@@ -0,0 +1,190 @@
+//! Signed records binding a device's identity to the firmware flashed onto it
+//!
+//! A record is collected from a connected device before flashing, completed
+//! with the digest of what was written, signed with an Ed25519 key held by the
+//! host and appended as a single line of JSON to a ledger file. Anyone with the
+//! public key of the host can later verify what each provisioned unit was
+//! flashed with, without being able to sign records themselves.
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::report::FlashReport;
+use crate::{Error, Flasher};
+
+/// Identity of a provisioned device and the firmware written to it
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct AttestationRecord {
+    /// Seconds since the unix epoch at which the record was collected
+    pub timestamp: u64,
+    /// The chip type of the device
+    pub chip: String,
+    /// Factory MAC address, formatted as colon separated hex
+    pub mac: String,
+    /// Hex encoded unique id of the attached flash chip
+    pub flash_unique_id: String,
+    /// Hex encoded SHA-256 of the contents of efuse block 0
+    pub efuse_sha256: String,
+    /// Hex encoded SHA-256 of the data written to flash, set by
+    /// [`set_firmware`]
+    ///
+    /// [`set_firmware`]: AttestationRecord::set_firmware
+    pub firmware_sha256: String,
+    /// Hex encoded Ed25519 public key of the host which signed the record, set
+    /// by [`sign`]
+    ///
+    /// This only names the key, verifiers check the record against the public
+    /// key they know the host by.
+    ///
+    /// [`sign`]: AttestationRecord::sign
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    /// Hex encoded Ed25519 signature of the other fields, set by [`sign`]
+    ///
+    /// [`sign`]: AttestationRecord::sign
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+impl AttestationRecord {
+    /// Collect the identity of the connected device
+    ///
+    /// This needs to happen before the firmware is flashed, as the device is
+    /// reset once flashing has finished.
+    pub fn collect(flasher: &mut Flasher) -> Result<Self, Error> {
+        let mac = flasher.mac_address()?;
+        let flash_unique_id = flasher.flash_unique_id()?;
+
+        let mut efuse = Sha256::new();
+        for word in 0..flasher.chip().efuse_block0_words() {
+            efuse.update(flasher.read_efuse(word)?.to_le_bytes());
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+
+        Ok(AttestationRecord {
+            timestamp,
+            chip: flasher.chip().to_string(),
+            mac: mac
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<Vec<_>>()
+                .join(":"),
+            flash_unique_id: format!("{:016x}", flash_unique_id),
+            efuse_sha256: hex(&efuse.finalize()),
+            firmware_sha256: String::new(),
+            public_key: None,
+            signature: None,
+        })
+    }
+
+    /// Record the digest of the regions written by a completed flash
+    /// operation, which is the image as the device stores it, after any
+    /// encryption on the host
+    pub fn set_firmware(&mut self, report: &FlashReport) {
+        self.firmware_sha256 = report.sha256.clone();
+    }
+
+    /// Sign the record with the secret key of the host
+    pub fn sign(&mut self, key: &SigningKey) {
+        self.public_key = Some(hex(key.verifying_key().as_bytes()));
+        let signature = key.sign(&self.payload());
+        self.signature = Some(hex(&signature.to_bytes()));
+    }
+
+    /// Check that the record carries a valid signature by the holder of the
+    /// secret key of `key`
+    pub fn verify(&self, key: &VerifyingKey) -> bool {
+        let signature = self
+            .signature
+            .as_deref()
+            .and_then(unhex)
+            .and_then(|signature| Signature::from_slice(&signature).ok());
+        match signature {
+            Some(signature) => key.verify(&self.payload(), &signature).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Append the record as a single line of JSON to the ledger at `path`,
+    /// creating it if needed
+    pub fn append_to(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut line = serde_json::to_vec(self)?;
+        line.push(b'\n');
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(&line)
+    }
+
+    /// The canonical encoding of the record that gets signed
+    fn payload(&self) -> Vec<u8> {
+        let unsigned = AttestationRecord {
+            signature: None,
+            ..self.clone()
+        };
+        serde_json::to_vec(&unsigned).expect("attestation records always serialize")
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let mut record = AttestationRecord {
+            timestamp: 1_600_000_000,
+            chip: "ESP32".into(),
+            mac: "24:0a:c4:00:00:01".into(),
+            flash_unique_id: "e4632c1234567890".into(),
+            efuse_sha256: hex(&Sha256::digest(b"efuse")),
+            firmware_sha256: String::new(),
+            public_key: None,
+            signature: None,
+        };
+        let report = FlashReport {
+            sha256: hex(&Sha256::digest(b"firmware")),
+            ..FlashReport::default()
+        };
+        record.set_firmware(&report);
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let other_key = SigningKey::from_bytes(&[2; 32]);
+        assert!(!record.verify(&key.verifying_key()));
+
+        record.sign(&key);
+        assert!(record.verify(&key.verifying_key()));
+        assert!(!record.verify(&other_key.verifying_key()));
+
+        record.mac = "24:0a:c4:00:00:02".into();
+        assert!(!record.verify(&key.verifying_key()));
+
+        record.signature = Some("not hex".into());
+        assert!(!record.verify(&key.verifying_key()));
+    }
+}
@@ -0,0 +1,248 @@
+//! Flashing several devices from a job file
+//!
+//! Each job of a [`BatchFile`] names the device to flash, the images to write
+//! and any further command line options. The jobs are run as separate
+//! `espflash` processes, either one after another or all at once.
+
+use serde::Deserialize;
+
+use std::ffi::OsString;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Directory in which udev creates links to serial ports, named after the
+/// USB device they belong to
+const SERIAL_BY_ID: &str = "/dev/serial/by-id";
+
+/// The contents of a job file
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchFile {
+    /// Run all jobs at the same time instead of one after another
+    #[serde(default)]
+    pub parallel: bool,
+    /// The jobs to run
+    #[serde(default, rename = "job")]
+    pub jobs: Vec<Job>,
+}
+
+/// A single device to flash
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Job {
+    /// Name shown in the summary, defaults to the port
+    pub name: Option<String>,
+    /// Serial port the device is connected to
+    pub port: Option<String>,
+    /// USB serial number of the device, used to look up its port
+    pub device_serial: Option<String>,
+    /// The elf image to flash
+    pub elf: PathBuf,
+    pub bootloader: Option<PathBuf>,
+    pub partition_table: Option<PathBuf>,
+    pub sdkconfig: Option<PathBuf>,
+    /// Further command line options, e.g. `["--safe-mode"]`
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+/// The outcome of a single job
+#[derive(Debug, Clone, PartialEq)]
+pub struct JobResult {
+    pub name: String,
+    /// Why the job failed, if it did
+    pub error: Option<String>,
+    pub duration: Duration,
+}
+
+/// The outcome of all jobs of a batch
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BatchSummary {
+    pub results: Vec<JobResult>,
+}
+
+impl BatchFile {
+    /// Load a job file
+    ///
+    /// Relative paths of the images are resolved against the directory of the
+    /// job file.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut batch: BatchFile = toml::from_str(&fs::read_to_string(path)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        for job in &mut batch.jobs {
+            job.resolve_paths(base);
+        }
+
+        Ok(batch)
+    }
+}
+
+impl Job {
+    /// The name the job is shown with
+    pub fn name(&self) -> String {
+        self.name
+            .clone()
+            .or_else(|| self.port.clone())
+            .or_else(|| self.device_serial.clone())
+            .unwrap_or_else(|| self.elf.display().to_string())
+    }
+
+    /// The serial port of the device, looking it up by its serial number if
+    /// no port is given
+    pub fn port(&self) -> io::Result<String> {
+        match (&self.port, &self.device_serial) {
+            (Some(port), _) => Ok(port.clone()),
+            (None, Some(serial)) => find_port(Path::new(SERIAL_BY_ID), serial),
+            (None, None) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "either `port` or `device_serial` needs to be set",
+            )),
+        }
+    }
+
+    /// The arguments to flash the job with, excluding the program name
+    pub fn args(&self, port: String) -> Vec<OsString> {
+        let mut args: Vec<OsString> = self.args.iter().map(OsString::from).collect();
+
+        for (flag, path) in [
+            ("--bootloader", &self.bootloader),
+            ("--partition-table", &self.partition_table),
+            ("--sdkconfig", &self.sdkconfig),
+        ] {
+            if let Some(path) = path {
+                args.push(flag.into());
+                args.push(path.into());
+            }
+        }
+
+        args.push(port.into());
+        args.push(self.elf.clone().into());
+        args
+    }
+
+    fn resolve_paths(&mut self, base: &Path) {
+        self.elf = base.join(&self.elf);
+        for path in [
+            &mut self.bootloader,
+            &mut self.partition_table,
+            &mut self.sdkconfig,
+        ]
+        .iter_mut()
+        .filter_map(|path| path.as_mut())
+        {
+            *path = base.join(&*path);
+        }
+    }
+}
+
+impl BatchSummary {
+    /// Whether every job succeeded
+    pub fn is_ok(&self) -> bool {
+        self.results.iter().all(|result| result.error.is_none())
+    }
+}
+
+impl Display for BatchSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let width = self
+            .results
+            .iter()
+            .map(|result| result.name.len())
+            .max()
+            .unwrap_or_default();
+
+        for result in &self.results {
+            let status = match &result.error {
+                None => "ok".to_string(),
+                Some(error) => format!("FAILED: {}", error),
+            };
+            writeln!(
+                f,
+                "{:width$}  {:>6.1}s  {}",
+                result.name,
+                result.duration.as_secs_f64(),
+                status,
+                width = width
+            )?;
+        }
+
+        let failed = self.results.iter().filter(|r| r.error.is_some()).count();
+        writeln!(
+            f,
+            "{} of {} jobs succeeded",
+            self.results.len() - failed,
+            self.results.len()
+        )
+    }
+}
+
+/// Find the serial port of the USB device with the given serial number in
+/// `dir`, whose entries are named like
+/// `usb-Silicon_Labs_CP2102_USB_to_UART_Bridge_Controller_0001-if00-port0`
+fn find_port(dir: &Path, serial: &str) -> io::Result<String> {
+    let suffix = format!("_{}-", serial);
+
+    // a missing directory means no USB serial devices are connected
+    for entry in fs::read_dir(dir).into_iter().flatten() {
+        let path = entry?.path();
+        let matches = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.contains(&suffix));
+        if matches {
+            return Ok(path.to_string_lossy().into_owned());
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no serial port found for device {}", serial),
+    ))
+}
+
+#[test]
+fn test_job_args() {
+    let batch: BatchFile = toml::from_str(
+        r#"
+        parallel = true
+
+        [[job]]
+        port = "/dev/ttyUSB0"
+        elf = "app"
+        bootloader = "boot.bin"
+        args = ["--safe-mode"]
+
+        [[job]]
+        name = "second"
+        device_serial = "0001"
+        elf = "/abs/app"
+        "#,
+    )
+    .unwrap();
+    assert!(batch.parallel);
+
+    let mut job = batch.jobs[0].clone();
+    job.resolve_paths(Path::new("jobs"));
+    assert_eq!("/dev/ttyUSB0", job.name());
+    assert_eq!(
+        vec![
+            "--safe-mode",
+            "--bootloader",
+            "jobs/boot.bin",
+            "/dev/ttyUSB0",
+            "jobs/app"
+        ],
+        job.args(job.port().unwrap())
+    );
+
+    let mut job = batch.jobs[1].clone();
+    job.resolve_paths(Path::new("jobs"));
+    assert_eq!("second", job.name());
+    assert_eq!(PathBuf::from("/abs/app"), job.elf);
+}
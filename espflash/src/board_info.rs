@@ -0,0 +1,114 @@
+//! Identification of a connected device
+
+use serde::Serialize;
+
+use std::fmt::{Display, Formatter};
+
+/// The chip, its features and the flash of a device
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct BoardInfo {
+    /// The chip type of the device
+    pub chip: String,
+    /// The package variant of the chip, e.g. `ESP32-D0WDQ6`
+    pub package: String,
+    /// Radios, cores and embedded flash or PSRAM
+    pub features: Vec<String>,
+    /// Crystal frequency in MHz
+    pub crystal_frequency: u32,
+    pub flash_size: String,
+    /// Vendor and device id of the flash chip
+    pub flash_id: String,
+    /// Factory MAC address, formatted as colon separated hex
+    pub mac: String,
+}
+
+impl Display for BoardInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Chip type:         {} ({})", self.chip, self.package)?;
+        writeln!(f, "Features:          {}", self.features.join(", "))?;
+        writeln!(f, "Crystal frequency: {} MHz", self.crystal_frequency)?;
+        writeln!(f, "Flash size:        {}", self.flash_size)?;
+        writeln!(f, "Flash chip:        {}", self.flash_id)?;
+        writeln!(f, "MAC address:       {}", self.mac)
+    }
+}
+
+/// JEDEC id of a SPI flash chip, as returned by the `RDID` command
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct FlashId {
+    pub manufacturer: u8,
+    pub memory_type: u8,
+    /// Binary logarithm of the size in bytes
+    pub capacity: u8,
+}
+
+impl FlashId {
+    /// Decode the 24 bits shifted out after `RDID`, in the order the device
+    /// sends them
+    pub fn from_rdid(id: u32) -> Self {
+        FlashId {
+            manufacturer: id as u8,
+            memory_type: (id >> 8) as u8,
+            capacity: (id >> 16) as u8,
+        }
+    }
+
+    /// Name of the manufacturer, for those found on ESP modules
+    pub fn vendor(&self) -> Option<&'static str> {
+        Some(match self.manufacturer {
+            0x01 => "Spansion",
+            0x0b => "XTX",
+            0x1c => "EON",
+            0x20 => "XMC",
+            0x5e => "Zbit",
+            0x68 => "Boya",
+            0x85 => "Puya",
+            0x9d => "ISSI",
+            0xa1 => "Fudan",
+            0xc2 => "Macronix",
+            0xc8 => "GigaDevice",
+            0xef => "Winbond",
+            _ => return None,
+        })
+    }
+
+    /// Size of the flash in bytes, if the capacity is a plausible one
+    pub fn size(&self) -> Option<u32> {
+        match self.capacity {
+            0x10..=0x1f => Some(1 << self.capacity),
+            _ => None,
+        }
+    }
+}
+
+impl Display for FlashId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.vendor() {
+            Some(vendor) => write!(f, "{}", vendor)?,
+            None => write!(f, "manufacturer {:#04x}", self.manufacturer)?,
+        }
+        write!(f, ", device {:#04x}{:02x}", self.memory_type, self.capacity)?;
+        match self.size() {
+            Some(size) if size >= 0x100000 => write!(f, ", {}MB", size / 0x100000),
+            Some(size) => write!(f, ", {}KB", size / 0x400),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flash_id() {
+        let id = FlashId::from_rdid(0x1940ef);
+        assert_eq!(id.vendor(), Some("Winbond"));
+        assert_eq!(id.size(), Some(0x2000000));
+        assert_eq!(id.to_string(), "Winbond, device 0x4019, 32MB");
+        assert_eq!(
+            FlashId::from_rdid(0xffffff).to_string(),
+            "manufacturer 0xff, device 0xffff"
+        );
+    }
+}
@@ -0,0 +1,654 @@
+//! Named efuse fields, as listed by `espefuse.py summary`
+//!
+//! Each chip describes the blocks of its efuses and where the fields are
+//! located in them, see [`Chip::efuse_blocks`](crate::Chip::efuse_blocks) and
+//! [`Chip::efuse_fields`](crate::Chip::efuse_fields). Fields of read
+//! protected blocks read as zero.
+//!
+//! Efuses can only be burned from 0 to 1. [`EfuseBlocks::burn`] sets the bits
+//! of a field in a copy of the blocks, which
+//! [`Flasher::burn_efuses`](crate::Flasher::burn_efuses) then burns through
+//! the [`EfuseController`] of the chip.
+
+use std::fmt::{self, Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::EfuseError;
+use crate::Chip;
+
+/// Value of the configuration register for burning
+#[cfg(feature = "serial")]
+pub(crate) const CONF_WRITE: u32 = 0x5a5a;
+/// Value of the configuration register for reading
+#[cfg(feature = "serial")]
+pub(crate) const CONF_READ: u32 = 0x5aa5;
+/// Command reloading the read registers from the efuses
+#[cfg(feature = "serial")]
+pub(crate) const CMD_READ: u32 = 0x1;
+/// Command burning the write registers, later chips add the block index
+/// shifted left by 2
+#[cfg(feature = "serial")]
+pub(crate) const CMD_PROGRAM: u32 = 0x2;
+
+/// A block of efuses, read through consecutive registers
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct EfuseBlock {
+    pub name: &'static str,
+    /// Address of the read register of the first word
+    pub reg: u32,
+    /// Number of 32 bit words
+    pub words: u32,
+}
+
+/// Masked write of a register, `(address, mask, value)`
+pub type RegUpdate = (u32, u32, u32);
+
+/// The registers of the efuse controller used to burn efuses
+#[derive(Debug, Clone, Copy)]
+pub struct EfuseController {
+    pub conf_reg: u32,
+    pub cmd_reg: u32,
+    pub write_regs: WriteRegs,
+    /// Register updates configuring the timing of burns, for each supported
+    /// crystal frequency in MHz
+    pub timing: &'static [(u32, &'static [RegUpdate])],
+}
+
+/// Where the words to burn are written to
+#[derive(Debug, Clone, Copy)]
+pub enum WriteRegs {
+    /// The first write register of each block, all blocks are burned by one
+    /// command like on the ESP32
+    PerBlock(&'static [u32]),
+    /// Registers shared by all blocks, which are burned one at a time. Blocks
+    /// other than block 0 are protected by Reed-Solomon check values
+    Shared { data: u32, check: u32 },
+}
+
+/// What a field is about, fields are summarized by category
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Category {
+    Identity,
+    Config,
+    Security,
+    Protection,
+    Keys,
+}
+
+impl Category {
+    pub const ALL: [Category; 5] = [
+        Category::Identity,
+        Category::Config,
+        Category::Security,
+        Category::Protection,
+        Category::Keys,
+    ];
+}
+
+impl Display for Category {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Category::Identity => write!(f, "Identity"),
+            Category::Config => write!(f, "Configuration"),
+            Category::Security => write!(f, "Security"),
+            Category::Protection => write!(f, "Read and write protection"),
+            Category::Keys => write!(f, "Keys and user data"),
+        }
+    }
+}
+
+/// How the bits of a field are interpreted
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FieldKind {
+    Bool,
+    Uint,
+    /// A bit mask, shown in hex
+    Mask,
+    /// A MAC address, stored with the last byte first
+    Mac,
+    /// Bytes in the order they are stored in, shown in hex
+    Bytes,
+    /// The purpose of a key block, see [`key_purpose`]
+    KeyPurpose,
+}
+
+/// A named field of the efuses
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct EfuseField {
+    pub name: &'static str,
+    pub category: Category,
+    /// Index of the block holding the field
+    pub block: usize,
+    /// Index of the word of the block the field starts in
+    pub word: usize,
+    /// Index of the bit of the word the field starts at
+    pub bit: u32,
+    /// Length in bits, fields can span words
+    pub bits: u32,
+    pub kind: FieldKind,
+    pub description: &'static str,
+}
+
+impl EfuseField {
+    /// A field at `(block, word, bit, bits)`
+    pub(crate) const fn new(
+        category: Category,
+        name: &'static str,
+        (block, word, bit, bits): (usize, usize, u32, u32),
+        kind: FieldKind,
+        description: &'static str,
+    ) -> Self {
+        EfuseField {
+            name,
+            category,
+            block,
+            word,
+            bit,
+            bits,
+            kind,
+            description,
+        }
+    }
+
+    /// Parse a value for the field from the command line
+    ///
+    /// Booleans are `true`, `false`, `1` or `0`, integers and masks are
+    /// decimal or hex prefixed with `0x`, MAC addresses are separated by
+    /// colons, bytes are hex and key purposes are named or numbered.
+    pub fn parse_value(&self, value: &str) -> Result<EfuseValue, EfuseError> {
+        let invalid = |reason: &str| EfuseError::InvalidValue {
+            field: self.name.to_string(),
+            value: value.to_string(),
+            reason: reason.to_string(),
+        };
+
+        match self.kind {
+            FieldKind::Bool => match value.to_ascii_lowercase().as_str() {
+                "1" | "true" => Ok(EfuseValue::Bool(true)),
+                "0" | "false" => Ok(EfuseValue::Bool(false)),
+                _ => Err(invalid("expected `true` or `false`")),
+            },
+            FieldKind::Uint => parse_u64(value)
+                .map(EfuseValue::Uint)
+                .ok_or_else(|| invalid("expected an integer")),
+            FieldKind::Mask => parse_u64(value)
+                .map(EfuseValue::Mask)
+                .ok_or_else(|| invalid("expected an integer")),
+            FieldKind::Mac => parse_mac(value)
+                .map(EfuseValue::Mac)
+                .ok_or_else(|| invalid("expected six hex bytes separated by colons")),
+            FieldKind::Bytes => {
+                let data = parse_hex(value).ok_or_else(|| invalid("expected hex bytes"))?;
+                if data.len() != self.bits as usize / 8 {
+                    return Err(invalid(&format!("expected {} bytes", self.bits / 8)));
+                }
+                Ok(EfuseValue::Bytes(data))
+            }
+            FieldKind::KeyPurpose => (0..16)
+                .find(|purpose| key_purpose(*purpose).eq_ignore_ascii_case(value))
+                .or_else(|| {
+                    parse_u64(value)
+                        .filter(|purpose| *purpose < 16)
+                        .map(|p| p as u8)
+                })
+                .map(EfuseValue::KeyPurpose)
+                .ok_or_else(|| invalid("expected a key purpose like `XTS_AES_128_KEY`")),
+        }
+    }
+}
+
+/// Parse a MAC address of six hex bytes separated by colons
+pub fn parse_mac(value: &str) -> Option<[u8; 6]> {
+    let bytes = value
+        .split(':')
+        .map(|byte| u8::from_str_radix(byte, 16).ok())
+        .collect::<Option<Vec<_>>>()
+        .filter(|bytes| bytes.len() == 6)?;
+    let mut mac = [0; 6];
+    mac.copy_from_slice(&bytes);
+    Some(mac)
+}
+
+fn parse_u64(value: &str) -> Option<u64> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+fn parse_hex(value: &str) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// The value of a field
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum EfuseValue {
+    Bool(bool),
+    Uint(u64),
+    Mask(u64),
+    Mac([u8; 6]),
+    Bytes(Vec<u8>),
+    KeyPurpose(u8),
+}
+
+impl Display for EfuseValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            EfuseValue::Bool(value) => write!(f, "{}", value),
+            EfuseValue::Uint(value) => write!(f, "{}", value),
+            EfuseValue::Mask(value) => write!(f, "{:#x}", value),
+            EfuseValue::Mac(mac) => {
+                let mac: Vec<String> = mac.iter().map(|byte| format!("{:02x}", byte)).collect();
+                write!(f, "{}", mac.join(":"))
+            }
+            EfuseValue::Bytes(data) => data.iter().try_for_each(|byte| write!(f, "{:02x}", byte)),
+            EfuseValue::KeyPurpose(purpose) => write!(f, "{} ({})", key_purpose(*purpose), purpose),
+        }
+    }
+}
+
+/// Name of a `KEY_PURPOSE_n` value of the chips with six key blocks
+pub fn key_purpose(purpose: u8) -> &'static str {
+    match purpose {
+        0 => "USER",
+        1 => "RESERVED",
+        2 => "XTS_AES_256_KEY_1",
+        3 => "XTS_AES_256_KEY_2",
+        4 => "XTS_AES_128_KEY",
+        5 => "HMAC_DOWN_ALL",
+        6 => "HMAC_DOWN_JTAG",
+        7 => "HMAC_DOWN_DIGITAL_SIGNATURE",
+        8 => "HMAC_UP",
+        9 => "SECURE_BOOT_DIGEST0",
+        10 => "SECURE_BOOT_DIGEST1",
+        11 => "SECURE_BOOT_DIGEST2",
+        _ => "unknown",
+    }
+}
+
+/// The words of all efuse blocks read from a device, in the order of
+/// [`Chip::efuse_blocks`](crate::Chip::efuse_blocks)
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct EfuseBlocks(Vec<Vec<u32>>);
+
+impl EfuseBlocks {
+    pub fn new(blocks: Vec<Vec<u32>>) -> Self {
+        EfuseBlocks(blocks)
+    }
+
+    /// Blocks of which no efuse is burned
+    pub fn blank(blocks: &[EfuseBlock]) -> Self {
+        EfuseBlocks(
+            blocks
+                .iter()
+                .map(|block| vec![0; block.words as usize])
+                .collect(),
+        )
+    }
+
+    /// The words of block `index`, empty if it wasn't read
+    pub fn block(&self, index: usize) -> &[u32] {
+        self.0.get(index).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// The value of `field`, missing words count as zero
+    pub fn value(&self, field: &EfuseField) -> EfuseValue {
+        match field.kind {
+            FieldKind::Bytes => EfuseValue::Bytes(self.bytes(field)),
+            kind => {
+                let value = self.bits(field);
+                match kind {
+                    FieldKind::Bool => EfuseValue::Bool(value != 0),
+                    FieldKind::Mask => EfuseValue::Mask(value),
+                    FieldKind::Mac => {
+                        let mut mac = [0; 6];
+                        mac.copy_from_slice(&value.to_be_bytes()[2..]);
+                        EfuseValue::Mac(mac)
+                    }
+                    FieldKind::KeyPurpose => EfuseValue::KeyPurpose(value as u8),
+                    _ => EfuseValue::Uint(value),
+                }
+            }
+        }
+    }
+
+    /// Set `field` to `value`, which has to keep all bits of the field which
+    /// are already burned
+    pub fn burn(&mut self, field: &EfuseField, value: &EfuseValue) -> Result<(), EfuseError> {
+        let invalid = |reason: String| EfuseError::InvalidValue {
+            field: field.name.to_string(),
+            value: value.to_string(),
+            reason,
+        };
+
+        let bytes = match (field.kind, value) {
+            (FieldKind::Bytes, EfuseValue::Bytes(data)) => {
+                if data.len() != field.bits as usize / 8 {
+                    return Err(invalid(format!("expected {} bytes", field.bits / 8)));
+                }
+                data.clone()
+            }
+            (FieldKind::Bytes, _) => return Err(invalid("expected bytes".to_string())),
+            (_, value) => {
+                let bits = match value {
+                    EfuseValue::Bool(value) => *value as u64,
+                    EfuseValue::Uint(value) | EfuseValue::Mask(value) => *value,
+                    EfuseValue::Mac(mac) => {
+                        mac.iter().fold(0, |bits, byte| bits << 8 | *byte as u64)
+                    }
+                    EfuseValue::KeyPurpose(purpose) => *purpose as u64,
+                    EfuseValue::Bytes(_) => return Err(invalid("expected an integer".to_string())),
+                };
+                if field.bits < 64 && bits >> field.bits != 0 {
+                    return Err(invalid(format!("doesn't fit in {} bits", field.bits)));
+                }
+                bits.to_le_bytes().to_vec()
+            }
+        };
+
+        let bit = |i: u32| (bytes.get(i as usize / 8).copied().unwrap_or_default() >> (i % 8)) & 1;
+        let mut words = self.block(field.block).to_vec();
+        for i in 0..field.bits {
+            let offset = field.bit + i;
+            let word = field.word + offset as usize / 32;
+            if words.len() <= word {
+                words.resize(word + 1, 0);
+            }
+            let burned = (words[word] >> (offset % 32)) & 1;
+            if burned == 1 && bit(i) == 0 {
+                return Err(EfuseError::ClearsBits(field.name.to_string()));
+            }
+            words[word] |= (bit(i) as u32) << (offset % 32);
+        }
+
+        if self.0.len() <= field.block {
+            self.0.resize(field.block + 1, Vec::new());
+        }
+        self.0[field.block] = words;
+        Ok(())
+    }
+
+    /// The bits of a field of at most 64 bits
+    fn bits(&self, field: &EfuseField) -> u64 {
+        let words = self.block(field.block);
+        (0..field.bits.min(64)).fold(0, |value, i| {
+            let bit = field.bit + i;
+            let word = words
+                .get(field.word + bit as usize / 32)
+                .copied()
+                .unwrap_or_default();
+            value | (((word >> (bit % 32)) & 1) as u64) << i
+        })
+    }
+
+    fn bytes(&self, field: &EfuseField) -> Vec<u8> {
+        let data: Vec<u8> = self
+            .block(field.block)
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        let start = field.word * 4 + field.bit as usize / 8;
+        (start..start + field.bits as usize / 8)
+            .map(|i| data.get(i).copied().unwrap_or_default())
+            .collect()
+    }
+}
+
+/// The custom MAC address burned to the efuses, `None` if there is none
+///
+/// On the ESP32 it is only used with version 1 and a matching CRC, as ESP-IDF
+/// does.
+pub fn custom_mac(chip: Chip, blocks: &EfuseBlocks) -> Option<[u8; 6]> {
+    let mac = match blocks.value(chip.efuse_field("CUSTOM_MAC")?) {
+        EfuseValue::Bytes(mac) if mac.iter().any(|byte| *byte != 0) => mac,
+        _ => return None,
+    };
+    let mut custom_mac = [0; 6];
+    custom_mac.copy_from_slice(&mac);
+
+    if let Some(crc) = chip.efuse_field("CUSTOM_MAC_CRC") {
+        let version = chip.efuse_field("MAC_VERSION")?;
+        if blocks.value(version) != EfuseValue::Uint(1)
+            || blocks.value(crc) != EfuseValue::Uint(mac_crc(&custom_mac) as u64)
+        {
+            return None;
+        }
+    }
+
+    Some(custom_mac)
+}
+
+/// Set a custom MAC address in `blocks`, along with its CRC and version on the
+/// ESP32
+pub fn burn_custom_mac(
+    chip: Chip,
+    blocks: &mut EfuseBlocks,
+    mac: [u8; 6],
+) -> Result<(), EfuseError> {
+    let field = chip
+        .efuse_field("CUSTOM_MAC")
+        .ok_or(EfuseError::Unsupported(chip))?;
+    if mac[0] & 1 != 0 || mac == [0; 6] {
+        return Err(EfuseError::InvalidValue {
+            field: field.name.to_string(),
+            value: EfuseValue::Mac(mac).to_string(),
+            reason: "a custom MAC address has to be a unicast address".to_string(),
+        });
+    }
+
+    blocks.burn(field, &EfuseValue::Bytes(mac.to_vec()))?;
+    if let (Some(crc), Some(version)) = (
+        chip.efuse_field("CUSTOM_MAC_CRC"),
+        chip.efuse_field("MAC_VERSION"),
+    ) {
+        blocks.burn(crc, &EfuseValue::Uint(mac_crc(&mac) as u64))?;
+        blocks.burn(version, &EfuseValue::Uint(1))?;
+    }
+
+    Ok(())
+}
+
+/// CRC-8 of a MAC address, `esp_crc8` of the ESP32 ROM
+fn mac_crc(mac: &[u8; 6]) -> u8 {
+    mac.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            (crc >> 1) ^ if crc & 1 != 0 { 0x8c } else { 0 }
+        })
+    })
+}
+
+/// The Reed-Solomon check values of the 32 data bytes of a block, as
+/// `reedsolo.RSCodec(12)` computes them for `espefuse.py`
+#[cfg(feature = "serial")]
+pub(crate) fn reed_solomon(data: &[u8; 32]) -> [u8; 12] {
+    let mut generator = vec![1];
+    let mut root = 1;
+    for _ in 0..12 {
+        let mut product = generator.clone();
+        product.push(0);
+        for (i, coefficient) in generator.iter().enumerate() {
+            product[i + 1] ^= gf_mul(*coefficient, root);
+        }
+        generator = product;
+        root = gf_mul(root, 2);
+    }
+
+    let mut remainder = data.to_vec();
+    remainder.resize(32 + 12, 0);
+    for i in 0..32 {
+        let coefficient = remainder[i];
+        if coefficient != 0 {
+            for (j, factor) in generator.iter().enumerate().skip(1) {
+                remainder[i + j] ^= gf_mul(*factor, coefficient);
+            }
+        }
+    }
+
+    let mut check = [0; 12];
+    check.copy_from_slice(&remainder[32..]);
+    check
+}
+
+/// Multiplication in GF(2^8) with the polynomial 0x11d
+#[cfg(feature = "serial")]
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        a = (a << 1) ^ if a & 0x80 != 0 { 0x1d } else { 0 };
+        b >>= 1;
+    }
+    product
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fields_fit_their_blocks() {
+        for chip in [Chip::Esp32, Chip::Esp32c3, Chip::Esp32s2, Chip::Esp8266] {
+            let blocks = chip.efuse_blocks();
+            for field in chip.efuse_fields() {
+                let end = field.word as u32 * 32 + field.bit + field.bits;
+                assert!(
+                    end <= blocks[field.block].words * 32,
+                    "{} of the {}",
+                    field.name,
+                    chip
+                );
+                assert!(field.bits <= 64 || field.kind == FieldKind::Bytes);
+            }
+        }
+    }
+
+    #[test]
+    fn test_burn() {
+        let mut blocks = EfuseBlocks::blank(Chip::Esp32c3.efuse_blocks());
+        let field = |name| Chip::Esp32c3.efuse_field(name).unwrap();
+
+        let purpose = field("KEY_PURPOSE_0")
+            .parse_value("xts_aes_128_key")
+            .unwrap();
+        blocks.burn(field("KEY_PURPOSE_0"), &purpose).unwrap();
+        assert_eq!(4 << 24, blocks.block(0)[2]);
+        assert_eq!(purpose, blocks.value(field("KEY_PURPOSE_0")));
+
+        // counters are burned one bit at a time, never back
+        let counter = field("SPI_BOOT_CRYPT_CNT");
+        blocks.burn(counter, &EfuseValue::Uint(0b001)).unwrap();
+        blocks.burn(counter, &EfuseValue::Uint(0b011)).unwrap();
+        assert!(matches!(
+            blocks.burn(counter, &EfuseValue::Uint(0b100)),
+            Err(EfuseError::ClearsBits(_))
+        ));
+        assert!(matches!(
+            blocks.burn(counter, &EfuseValue::Uint(0b1000)),
+            Err(EfuseError::InvalidValue { .. })
+        ));
+        assert_eq!(EfuseValue::Uint(0b011), blocks.value(counter));
+
+        let key = field("BLOCK_KEY0")
+            .parse_value(&"0123456789abcdef".repeat(4))
+            .unwrap();
+        blocks.burn(field("BLOCK_KEY0"), &key).unwrap();
+        assert_eq!(0x6745_2301, blocks.block(4)[0]);
+        assert_eq!(key, blocks.value(field("BLOCK_KEY0")));
+        assert!(field("BLOCK_KEY0").parse_value("0123").is_err());
+    }
+
+    #[test]
+    fn test_custom_mac() {
+        let mac = [0x24, 0x0a, 0xc4, 0x12, 0x34, 0x56];
+        for chip in [Chip::Esp32, Chip::Esp32c3] {
+            let mut blocks = EfuseBlocks::blank(chip.efuse_blocks());
+            assert_eq!(None, custom_mac(chip, &blocks));
+            burn_custom_mac(chip, &mut blocks, mac).unwrap();
+            assert_eq!(Some(mac), custom_mac(chip, &blocks));
+            // efuses can't be burned back
+            assert!(burn_custom_mac(chip, &mut blocks, [0x24, 0, 0, 0, 0, 0]).is_err());
+        }
+
+        let mut blocks = EfuseBlocks::blank(Chip::Esp32.efuse_blocks());
+        assert!(burn_custom_mac(Chip::Esp32, &mut blocks, [0x01, 0, 0, 0, 0, 1]).is_err());
+        // the ESP32 ignores a custom MAC address without the right CRC
+        let field = Chip::Esp32.efuse_field("CUSTOM_MAC").unwrap();
+        blocks
+            .burn(field, &EfuseValue::Bytes(mac.to_vec()))
+            .unwrap();
+        assert_eq!(None, custom_mac(Chip::Esp32, &blocks));
+        assert_eq!(0, mac_crc(&[0; 6]));
+    }
+
+    #[cfg(feature = "serial")]
+    #[test]
+    fn test_reed_solomon() {
+        let mut data = [0; 32];
+        data.iter_mut()
+            .enumerate()
+            .for_each(|(i, byte)| *byte = i as u8 * 7);
+        let check = reed_solomon(&data);
+
+        // the code word is a multiple of the generator, so it vanishes at its
+        // roots
+        let mut root = 1;
+        for _ in 0..12 {
+            let value = data
+                .iter()
+                .chain(check.iter())
+                .fold(0, |value, byte| gf_mul(value, root) ^ byte);
+            assert_eq!(0, value);
+            root = gf_mul(root, 2);
+        }
+        assert_eq!([0; 12], reed_solomon(&[0; 32]));
+    }
+
+    #[test]
+    fn test_values() {
+        let blocks = EfuseBlocks::new(vec![
+            vec![0x0090_000f, 0xcc3d_8aa4, 0x0000_7c9e, 0x0800_0000],
+            vec![0x0403_0201, 0x0807_0695],
+        ]);
+        let field =
+            |location, kind| EfuseField::new(Category::Identity, "FIELD", location, kind, "");
+
+        assert_eq!(
+            EfuseValue::Mask(0xf),
+            blocks.value(&field((0, 0, 0, 16), FieldKind::Mask))
+        );
+        assert_eq!(
+            EfuseValue::Uint(9),
+            blocks.value(&field((0, 0, 20, 7), FieldKind::Uint))
+        );
+        assert_eq!(
+            EfuseValue::Bool(true),
+            blocks.value(&field((0, 3, 27, 1), FieldKind::Bool))
+        );
+        let mac = blocks.value(&field((0, 1, 0, 48), FieldKind::Mac));
+        assert_eq!("7c:9e:cc:3d:8a:a4", mac.to_string());
+        assert_eq!(
+            EfuseValue::Bytes(vec![2, 3, 4, 0x95, 6]),
+            blocks.value(&field((1, 0, 8, 40), FieldKind::Bytes))
+        );
+        assert_eq!(
+            "SECURE_BOOT_DIGEST0 (9)",
+            blocks
+                .value(&field((1, 1, 4, 4), FieldKind::KeyPurpose))
+                .to_string()
+        );
+        // fields of blocks which weren't read are zero
+        assert_eq!(
+            EfuseValue::Uint(0),
+            blocks.value(&field((5, 0, 0, 8), FieldKind::Uint))
+        );
+    }
+}
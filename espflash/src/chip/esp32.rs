@@ -1,9 +1,13 @@
+#[cfg(feature = "serial")]
+use crate::chip::estimate_crystal_frequency;
 use crate::elf::merge_segments;
 use crate::{
     chip::{
-        encode_flash_size, get_segment_padding, save_flash_segment, save_segment, Chip, ChipType,
-        EspCommonHeader, ExtendedHeader, SegmentHeader, SpiRegisters, ESP_MAGIC, SEG_HEADER_LEN,
-        WP_PIN_DISABLED,
+        default_partition_table,
+        efuse::{Category, EfuseBlock, EfuseController, EfuseField, FieldKind, WriteRegs},
+        encode_flash_size, flash_offsets, get_segment_padding, save_flash_segment, save_segment,
+        validate_bootloader, Chip, ChipType, Efuses, EspCommonHeader, ExtendedHeader, Feature,
+        FlashOffsets, SegmentHeader, SpiRegisters, ESP_MAGIC, SEG_HEADER_LEN, WP_PIN_DISABLED,
     },
     elf::{FirmwareImage, RomSegment, ESP_CHECKSUM_MAGIC},
     Error, PartitionTable,
@@ -20,27 +24,377 @@ const IROM_MAP_END: u32 = 0x40400000;
 const DROM_MAP_START: u32 = 0x3F400000;
 const DROM_MAP_END: u32 = 0x3F800000;
 
-const BOOT_ADDR: u32 = 0x1000;
-const PARTION_ADDR: u32 = 0x8000;
-const NVS_ADDR: u32 = 0x9000;
-const PHY_INIT_DATA_ADDR: u32 = 0xf000;
-const APP_ADDR: u32 = 0x10000;
+#[cfg(feature = "serial")]
+const UART_CLKDIV_REG: u32 = 0x3ff40014;
+#[cfg(feature = "serial")]
+const UART_CLKDIV_MASK: u32 = 0xfffff;
 
-const NVS_SIZE: u32 = 0x6000;
-const PHY_INIT_DATA_SIZE: u32 = 0x1000;
-const APP_SIZE: u32 = 0x3f0000;
+/// Default offsets of the bootloader, partition table and application
+pub(crate) const DEFAULT_OFFSETS: FlashOffsets = FlashOffsets {
+    bootloader: 0x1000,
+    partition_table: 0x8000,
+    app: 0x10000,
+};
+
+/// Chip id stored in the extended image header
+pub(crate) const CHIP_ID: u16 = 0;
 
 impl ChipType for Esp32 {
     const CHIP_DETECT_MAGIC_VALUE: u32 = 0x00f01d83;
 
     const SPI_REGISTERS: SpiRegisters = SpiRegisters {
         base: 0x3ff42000,
+        clock_offset: 0x18,
         usr_offset: 0x1c,
         usr1_offset: 0x20,
         usr2_offset: 0x24,
         w0_offset: 0x80,
         mosi_length_offset: Some(0x28),
         miso_length_offset: Some(0x2c),
+        clkcnt_shifts: (12, 6),
+        dummy_cyclelen_mask: 0xff,
+    };
+
+    const UF2_FAMILY_ID: u32 = 0x1c5f21b0;
+
+    const EFUSE_REG_BASE: u32 = 0x3ff5a000;
+    const EFUSE_BLOCK0_WORDS: u32 = 7;
+    const MAC_EFUSE_REG: u32 = 0x3ff5a004;
+
+    const EFUSE_BLOCKS: &'static [EfuseBlock] = &[
+        EfuseBlock {
+            name: "BLOCK0",
+            reg: 0x3ff5a000,
+            words: 7,
+        },
+        EfuseBlock {
+            name: "BLOCK1",
+            reg: 0x3ff5a038,
+            words: 8,
+        },
+        EfuseBlock {
+            name: "BLOCK2",
+            reg: 0x3ff5a058,
+            words: 8,
+        },
+        EfuseBlock {
+            name: "BLOCK3",
+            reg: 0x3ff5a078,
+            words: 8,
+        },
+    ];
+    const EFUSE_CONTROLLER: Option<EfuseController> = Some(EfuseController {
+        conf_reg: 0x3ff5a0fc,
+        cmd_reg: 0x3ff5a104,
+        write_regs: WriteRegs::PerBlock(&[0x3ff5a01c, 0x3ff5a098, 0x3ff5a0b8, 0x3ff5a0d8]),
+        // EFUSE_CLK_SEL0, EFUSE_CLK_SEL1 and EFUSE_DAC_CLK_DIV
+        timing: &[
+            (
+                26,
+                &[
+                    (0x3ff5a0f8, 0x00ff, 250),
+                    (0x3ff5a0f8, 0xff00, 255 << 8),
+                    (0x3ff5a118, 0x00ff, 52),
+                ],
+            ),
+            (
+                40,
+                &[
+                    (0x3ff5a0f8, 0x00ff, 160),
+                    (0x3ff5a0f8, 0xff00, 255 << 8),
+                    (0x3ff5a118, 0x00ff, 80),
+                ],
+            ),
+        ],
+    });
+    const EFUSE_FIELDS: &'static [EfuseField] = {
+        use Category::*;
+        use FieldKind::*;
+
+        &[
+            EfuseField::new(Identity, "MAC", (0, 1, 0, 48), Mac, "Factory MAC address"),
+            EfuseField::new(
+                Identity,
+                "MAC_CRC",
+                (0, 2, 16, 8),
+                Uint,
+                "CRC-8 of the factory MAC address",
+            ),
+            EfuseField::new(
+                Identity,
+                "CUSTOM_MAC",
+                (3, 0, 8, 48),
+                Bytes,
+                "Custom MAC address",
+            ),
+            EfuseField::new(
+                Identity,
+                "CUSTOM_MAC_CRC",
+                (3, 0, 0, 8),
+                Uint,
+                "CRC-8 of the custom MAC address",
+            ),
+            EfuseField::new(
+                Identity,
+                "MAC_VERSION",
+                (3, 5, 24, 8),
+                Uint,
+                "Version of the custom MAC address, 1 if it is set",
+            ),
+            EfuseField::new(
+                Identity,
+                "CHIP_PACKAGE",
+                (0, 3, 9, 3),
+                Uint,
+                "Package version",
+            ),
+            EfuseField::new(
+                Identity,
+                "CHIP_PACKAGE_4BIT",
+                (0, 3, 2, 1),
+                Bool,
+                "Package version, fourth bit",
+            ),
+            EfuseField::new(
+                Identity,
+                "CHIP_VER_REV1",
+                (0, 3, 15, 1),
+                Bool,
+                "Revision 1 or later",
+            ),
+            EfuseField::new(
+                Identity,
+                "CHIP_VER_REV2",
+                (0, 5, 20, 1),
+                Bool,
+                "Revision 2 or later",
+            ),
+            EfuseField::new(
+                Identity,
+                "WAFER_VERSION_MINOR",
+                (0, 5, 24, 2),
+                Uint,
+                "Minor chip version",
+            ),
+            EfuseField::new(
+                Identity,
+                "CHIP_VER_DIS_APP_CPU",
+                (0, 3, 0, 1),
+                Bool,
+                "The app CPU is disabled",
+            ),
+            EfuseField::new(
+                Identity,
+                "CHIP_VER_DIS_BT",
+                (0, 3, 1, 1),
+                Bool,
+                "Bluetooth is disabled",
+            ),
+            EfuseField::new(
+                Identity,
+                "CHIP_CPU_FREQ_RATED",
+                (0, 3, 13, 1),
+                Bool,
+                "The CPU frequency is rated",
+            ),
+            EfuseField::new(
+                Identity,
+                "CHIP_CPU_FREQ_LOW",
+                (0, 3, 12, 1),
+                Bool,
+                "The CPU is rated for 160 MHz",
+            ),
+            EfuseField::new(
+                Config,
+                "CLK8M_FREQ",
+                (0, 4, 0, 8),
+                Uint,
+                "Calibration of the 8 MHz clock",
+            ),
+            EfuseField::new(
+                Config,
+                "ADC_VREF",
+                (0, 4, 8, 5),
+                Uint,
+                "Calibrated ADC reference voltage",
+            ),
+            EfuseField::new(
+                Config,
+                "XPD_SDIO_REG",
+                (0, 4, 14, 1),
+                Bool,
+                "The flash regulator is powered up at reset",
+            ),
+            EfuseField::new(
+                Config,
+                "XPD_SDIO_TIEH",
+                (0, 4, 15, 1),
+                Bool,
+                "The flash regulator outputs 3.3 V",
+            ),
+            EfuseField::new(
+                Config,
+                "XPD_SDIO_FORCE",
+                (0, 4, 16, 1),
+                Bool,
+                "GPIO12 doesn't select the flash voltage",
+            ),
+            EfuseField::new(
+                Config,
+                "SPI_PAD_CONFIG_CLK",
+                (0, 5, 0, 5),
+                Uint,
+                "Override of the flash CLK pad",
+            ),
+            EfuseField::new(
+                Config,
+                "SPI_PAD_CONFIG_Q",
+                (0, 5, 5, 5),
+                Uint,
+                "Override of the flash Q pad",
+            ),
+            EfuseField::new(
+                Config,
+                "SPI_PAD_CONFIG_D",
+                (0, 5, 10, 5),
+                Uint,
+                "Override of the flash D pad",
+            ),
+            EfuseField::new(
+                Config,
+                "SPI_PAD_CONFIG_CS0",
+                (0, 5, 15, 5),
+                Uint,
+                "Override of the flash CS pad",
+            ),
+            EfuseField::new(
+                Config,
+                "SPI_PAD_CONFIG_HD",
+                (0, 3, 4, 5),
+                Uint,
+                "Override of the flash HD pad",
+            ),
+            EfuseField::new(
+                Config,
+                "BLK3_PART_RESERVE",
+                (0, 3, 14, 1),
+                Bool,
+                "BLOCK3 holds ADC calibration",
+            ),
+            EfuseField::new(
+                Config,
+                "CODING_SCHEME",
+                (0, 6, 0, 2),
+                Uint,
+                "Coding scheme of BLOCK1 to BLOCK3",
+            ),
+            EfuseField::new(
+                Config,
+                "CONSOLE_DEBUG_DISABLE",
+                (0, 6, 2, 1),
+                Bool,
+                "The ROM BASIC console is disabled",
+            ),
+            EfuseField::new(
+                Config,
+                "DISABLE_SDIO_HOST",
+                (0, 6, 3, 1),
+                Bool,
+                "The SDIO host is disabled",
+            ),
+            EfuseField::new(
+                Security,
+                "FLASH_CRYPT_CNT",
+                (0, 0, 20, 7),
+                Uint,
+                "Flash encryption is enabled for an odd number of bits",
+            ),
+            EfuseField::new(
+                Security,
+                "FLASH_CRYPT_CONFIG",
+                (0, 5, 28, 4),
+                Mask,
+                "Flash encryption key tweak",
+            ),
+            EfuseField::new(
+                Security,
+                "UART_DOWNLOAD_DIS",
+                (0, 0, 27, 1),
+                Bool,
+                "UART download mode is disabled",
+            ),
+            EfuseField::new(
+                Security,
+                "ABS_DONE_0",
+                (0, 6, 4, 1),
+                Bool,
+                "Secure boot V1 is enabled",
+            ),
+            EfuseField::new(
+                Security,
+                "ABS_DONE_1",
+                (0, 6, 5, 1),
+                Bool,
+                "Secure boot V2 is enabled",
+            ),
+            EfuseField::new(
+                Security,
+                "JTAG_DISABLE",
+                (0, 6, 6, 1),
+                Bool,
+                "JTAG is disabled",
+            ),
+            EfuseField::new(
+                Security,
+                "DISABLE_DL_ENCRYPT",
+                (0, 6, 7, 1),
+                Bool,
+                "Flash encryption is disabled in download mode",
+            ),
+            EfuseField::new(
+                Security,
+                "DISABLE_DL_DECRYPT",
+                (0, 6, 8, 1),
+                Bool,
+                "Flash decryption is disabled in download mode",
+            ),
+            EfuseField::new(
+                Security,
+                "DISABLE_DL_CACHE",
+                (0, 6, 9, 1),
+                Bool,
+                "The flash cache is disabled in download mode",
+            ),
+            EfuseField::new(
+                Protection,
+                "WR_DIS",
+                (0, 0, 0, 16),
+                Mask,
+                "Efuses which are write protected",
+            ),
+            EfuseField::new(
+                Protection,
+                "RD_DIS",
+                (0, 0, 16, 4),
+                Mask,
+                "Efuses which are read protected",
+            ),
+            EfuseField::new(
+                Keys,
+                "BLOCK1",
+                (1, 0, 0, 256),
+                Bytes,
+                "Flash encryption key",
+            ),
+            EfuseField::new(Keys, "BLOCK2", (2, 0, 0, 256), Bytes, "Secure boot key"),
+            EfuseField::new(
+                Keys,
+                "BLOCK3",
+                (3, 0, 0, 256),
+                Bytes,
+                "User data or custom MAC address",
+            ),
+        ]
     };
 
     fn addr_is_flash(addr: u32) -> bool {
@@ -48,12 +402,84 @@ impl ChipType for Esp32 {
             || (DROM_MAP_START..DROM_MAP_END).contains(&addr)
     }
 
+    const FEATURE_EFUSE_REGS: &'static [u32] = &[
+        Self::EFUSE_REG_BASE + 3 * 4,
+        Self::EFUSE_REG_BASE + 4 * 4,
+        Self::EFUSE_REG_BASE + 6 * 4,
+    ];
+
+    fn features(efuses: &Efuses) -> Vec<Feature> {
+        let word3 = efuses.word(Self::EFUSE_REG_BASE + 3 * 4);
+        let word4 = efuses.word(Self::EFUSE_REG_BASE + 4 * 4);
+        let word6 = efuses.word(Self::EFUSE_REG_BASE + 6 * 4);
+
+        let package_version = (word3 >> 9) & 0x7 | ((word3 >> 2) & 0x1) << 3;
+        let package = match package_version {
+            0 => "ESP32-D0WDQ6",
+            1 => "ESP32-D0WD",
+            2 => "ESP32-D2WD",
+            4 => "ESP32-U4WDH",
+            5 => "ESP32-PICO-D4",
+            6 => "ESP32-PICO-V3-02",
+            _ => "unknown ESP32",
+        };
+
+        let mut features = vec![Feature::Package(package), Feature::WiFi];
+        if word3 & (1 << 1) == 0 {
+            features.push(Feature::Bt);
+        }
+        features.push(if word3 & 1 == 0 {
+            Feature::DualCore
+        } else {
+            Feature::SingleCore
+        });
+        if word3 & (1 << 13) != 0 {
+            features.push(Feature::MaxCpuFrequency(if word3 & (1 << 12) == 0 {
+                240
+            } else {
+                160
+            }));
+        }
+        if matches!(package_version, 2 | 4 | 5 | 6) {
+            features.push(Feature::EmbeddedFlash(None));
+        }
+        if package_version == 6 {
+            features.push(Feature::EmbeddedPsram(None));
+        }
+        if (word4 >> 8) & 0x1f != 0 {
+            features.push(Feature::VrefCalibration);
+        }
+        if (word3 >> 14) & 0x1 != 0 {
+            features.push(Feature::Blk3PartiallyReserved);
+        }
+        features.push(Feature::CodingScheme(match word6 & 0x3 {
+            0 => "None",
+            1 => "3/4",
+            2 => "Repeat (unsupported)",
+            _ => "Invalid",
+        }));
+
+        features
+    }
+
+    #[cfg(feature = "serial")]
+    fn crystal_frequency(
+        read_reg: &mut dyn FnMut(u32) -> Result<u32, Error>,
+        baud: u32,
+    ) -> Result<u32, Error> {
+        let uart_div = read_reg(UART_CLKDIV_REG)? & UART_CLKDIV_MASK;
+        Ok(estimate_crystal_frequency(uart_div, baud, 1))
+    }
+
     fn get_flash_segments<'a>(
         image: &'a FirmwareImage,
         bootloader: Option<Vec<u8>>,
         partition_table: Option<PartitionTable>,
     ) -> Box<dyn Iterator<Item = Result<RomSegment<'a>, Error>> + 'a> {
         let bootloader = if let Some(bytes) = bootloader {
+            if let Err(err) = validate_bootloader(&bytes, CHIP_ID) {
+                return Box::new(once(Err(err)));
+            }
             bytes
         } else {
             let bytes = include_bytes!("../../bootloader/esp32-bootloader.bin");
@@ -61,29 +487,33 @@ impl ChipType for Esp32 {
         };
 
         let partition_table = if let Some(table) = partition_table {
+            if let Err(err) = table.validate() {
+                return Box::new(once(Err(err.into())));
+            }
             table
         } else {
-            PartitionTable::basic(
-                NVS_ADDR,
-                NVS_SIZE,
-                PHY_INIT_DATA_ADDR,
-                PHY_INIT_DATA_SIZE,
-                APP_ADDR,
-                APP_SIZE,
-            )
+            default_partition_table(image, DEFAULT_OFFSETS)
         };
+        let offsets = match flash_offsets(image, DEFAULT_OFFSETS, &partition_table) {
+            Ok(offsets) => offsets,
+            Err(err) => return Box::new(once(Err(err))),
+        };
+        let app = get_data(image, offsets.app, CHIP_ID, Chip::Esp32).and_then(|app| {
+            partition_table.check_app_fits(app.data.len() as u32)?;
+            Ok(app)
+        });
         let partition_table = partition_table.to_bytes();
 
         Box::new(
             once(Ok(RomSegment {
-                addr: BOOT_ADDR,
+                addr: offsets.bootloader,
                 data: Cow::Owned(bootloader),
             }))
             .chain(once(Ok(RomSegment {
-                addr: PARTION_ADDR,
+                addr: offsets.partition_table,
                 data: Cow::Owned(partition_table),
             })))
-            .chain(once(get_data(image, 0, Chip::Esp32))),
+            .chain(once(app)),
         )
     }
 }
@@ -107,9 +537,95 @@ fn test_esp32_rom() {
     assert_eq!(&expected_bin.as_slice(), &buff);
 }
 
+#[test]
+fn test_default_partition_table() {
+    use crate::elf::FlashSize;
+    use std::fs::read;
+
+    let input_bytes = read("./tests/data/esp32").unwrap();
+    let mut image = FirmwareImage::from_data(&input_bytes).unwrap();
+
+    for (size, end) in [
+        (FlashSize::Flash4Mb, 0x400000),
+        (FlashSize::Flash16Mb, 0x1000000),
+    ] {
+        image.flash_size = size;
+        let segments = Esp32::get_flash_segments(&image, None, None)
+            .collect::<Result<Vec<_>, Error>>()
+            .unwrap();
+        let table = PartitionTable::try_from_bytes(&segments[1].data).unwrap();
+        assert_eq!(end, table.end());
+    }
+
+    // moving the partition table moves the partitions after it
+    image.partition_table_offset = Some(0xa000);
+    let segments = Esp32::get_flash_segments(&image, None, None)
+        .collect::<Result<Vec<_>, Error>>()
+        .unwrap();
+    let table = PartitionTable::try_from_bytes(&segments[1].data).unwrap();
+    assert_eq!(0xa000, segments[1].addr);
+    assert_eq!(0x20000, segments[2].addr);
+    assert_eq!(Some(0x20000), table.app_offset());
+
+    let table = PartitionTable::try_from_str(
+        "nvs,data,nvs,0x9000,0x6000,\nfactory,app,factory,0x10000,1M,",
+    )
+    .unwrap();
+    assert!(Esp32::get_flash_segments(&image, None, Some(table)).any(|segment| segment.is_err()));
+
+    // tables are only validated when flashed
+    let table = PartitionTable::try_from_data(b"nvs,data,nvs,0x9000,0x6000,".to_vec()).unwrap();
+    let mut segments = Esp32::get_flash_segments(&image, None, Some(table));
+    assert!(matches!(
+        segments.next(),
+        Some(Err(Error::InvalidPartitionTable(_)))
+    ));
+}
+
+#[test]
+fn test_appended_digest() {
+    use std::fs::read;
+
+    let input_bytes = read("./tests/data/esp32").unwrap();
+    let image = FirmwareImage::from_data(&input_bytes).unwrap();
+
+    let segment = get_data(&image, DEFAULT_OFFSETS.app, CHIP_ID, Chip::Esp32).unwrap();
+    let (data, digest) = segment.data.split_at(segment.data.len() - 32);
+
+    // `append_digest` field of the extended header
+    assert_eq!(1, data[23]);
+    assert_eq!(Sha256::digest(data)[..], *digest);
+}
+
+#[test]
+fn test_segment_layout() {
+    use crate::elf::SegmentLayout;
+    use std::fs::read;
+
+    let input_bytes = read("./tests/data/esp32").unwrap();
+    let mut image = FirmwareImage::from_data(&input_bytes).unwrap();
+
+    image.segment_layout = SegmentLayout {
+        segment_alignment: 16,
+        ..SegmentLayout::default()
+    };
+    let segment = get_data(&image, DEFAULT_OFFSETS.app, CHIP_ID, Chip::Esp32).unwrap();
+    let info = crate::ImageInfo::parse(&segment.data, Chip::Esp32).unwrap();
+    assert!(info.checksum_valid);
+    assert!(info
+        .segments
+        .iter()
+        .filter(|segment| segment.addr != 0)
+        .all(|segment| segment.length % 16 == 0));
+
+    image.segment_layout.segment_alignment = 3;
+    assert!(get_data(&image, DEFAULT_OFFSETS.app, CHIP_ID, Chip::Esp32).is_err());
+}
+
 // shared between all esp32 family chips
 pub(crate) fn get_data<'a>(
     image: &'a FirmwareImage,
+    addr: u32,
     chip_id: u16,
     chip: Chip,
 ) -> Result<RomSegment<'a>, Error> {
@@ -143,15 +659,25 @@ pub(crate) fn get_data<'a>(
 
     let mut segment_count = 0;
 
+    let layout = &image.segment_layout;
+    layout.validate()?;
+
     for segment in flash_segments {
         loop {
-            let pad_len = get_segment_padding(data.len(), &segment);
+            let pad_len = get_segment_padding(data.len(), &segment, layout.mmu_page_size);
             if pad_len > 0 {
-                if pad_len > SEG_HEADER_LEN {
+                // the ram segment can only fill the padding in whole aligned blocks
+                let fill_len = pad_len - pad_len % layout.segment_alignment;
+                if pad_len > SEG_HEADER_LEN && fill_len > 0 {
                     if let Some(ram_segment) = ram_segments.first_mut() {
                         // save up to `pad_len` from the ram segment, any remaining bits in the ram segments will be saved later
-                        let pad_segment = ram_segment.split_off(pad_len as usize);
-                        checksum = save_segment(&mut data, &pad_segment, checksum)?;
+                        let pad_segment = ram_segment.split_off(fill_len as usize);
+                        checksum = save_segment(
+                            &mut data,
+                            &pad_segment,
+                            checksum,
+                            layout.segment_alignment,
+                        )?;
                         if ram_segment.data().is_empty() {
                             ram_segments.remove(0);
                         }
@@ -161,7 +687,7 @@ pub(crate) fn get_data<'a>(
                 }
                 let pad_header = SegmentHeader {
                     addr: 0,
-                    length: pad_len as u32,
+                    length: pad_len,
                 };
                 data.write_all(bytes_of(&pad_header))?;
                 for _ in 0..pad_len {
@@ -172,17 +698,17 @@ pub(crate) fn get_data<'a>(
                 break;
             }
         }
-        checksum = save_flash_segment(&mut data, &segment, checksum)?;
+        checksum = save_flash_segment(&mut data, &segment, checksum, layout)?;
         segment_count += 1;
     }
 
     for segment in ram_segments {
-        checksum = save_segment(&mut data, &segment, checksum)?;
+        checksum = save_segment(&mut data, &segment, checksum, layout.segment_alignment)?;
         segment_count += 1;
     }
 
     let padding = 15 - (data.len() % 16);
-    let padding = &[0u8; 16][0..padding as usize];
+    let padding = &[0u8; 16][0..padding];
     data.write_all(padding)?;
 
     data.write_all(&[checksum])?;
@@ -190,13 +716,47 @@ pub(crate) fn get_data<'a>(
     // since we added some dummy segments, we need to patch the segment count
     data[1] = segment_count as u8;
 
+    image.app_desc.apply(&mut data);
+
     let mut hasher = Sha256::new();
     hasher.update(&data);
     let hash = hasher.finalize();
     data.write_all(&hash)?;
 
     Ok(RomSegment {
-        addr: APP_ADDR,
+        addr,
         data: Cow::Owned(data),
     })
 }
+
+#[test]
+fn test_features() {
+    let mut efuses = Efuses::new();
+    // ESP32-PICO-D4 with the app CPU disabled
+    efuses.insert(0x3ff5a00c, 5 << 9 | 1);
+    efuses.insert(0x3ff5a018, 1);
+    let features = Esp32::features(&efuses);
+
+    assert_eq!(
+        vec![
+            "ESP32-PICO-D4",
+            "WiFi",
+            "BT",
+            "Single Core",
+            "Embedded Flash",
+            "Coding Scheme 3/4"
+        ],
+        features.iter().map(ToString::to_string).collect::<Vec<_>>()
+    );
+    assert_eq!(Some("ESP32-PICO-D4"), features[0].package());
+
+    // divider for 115200 baud from a 40 MHz crystal
+    #[cfg(feature = "serial")]
+    {
+        let mut read_reg = |reg| {
+            assert_eq!(UART_CLKDIV_REG, reg);
+            Ok(347)
+        };
+        assert_eq!(40, Esp32::crystal_frequency(&mut read_reg, 115200).unwrap());
+    }
+}
@@ -1,6 +1,10 @@
 use crate::chip::esp32::get_data;
 use crate::{
-    chip::{ChipType, SpiRegisters},
+    chip::{
+        default_partition_table,
+        efuse::{Category, EfuseBlock, EfuseController, EfuseField, FieldKind},
+        flash_offsets, validate_bootloader, ChipType, Efuses, Feature, FlashOffsets, SpiRegisters,
+    },
     elf::{FirmwareImage, RomSegment},
     Chip, Error, PartitionTable,
 };
@@ -14,27 +18,447 @@ const IROM_MAP_END: u32 = 0x40b80000;
 const DROM_MAP_START: u32 = 0x3F000000;
 const DROM_MAP_END: u32 = 0x3F3F0000;
 
-const BOOT_ADDR: u32 = 0x1000;
-const PARTITION_ADDR: u32 = 0x8000;
-const NVS_ADDR: u32 = 0x9000;
-const PHY_INIT_DATA_ADDR: u32 = 0xf000;
-const APP_ADDR: u32 = 0x10000;
+/// Default offsets of the bootloader, partition table and application
+pub(crate) const DEFAULT_OFFSETS: FlashOffsets = FlashOffsets {
+    bootloader: 0x1000,
+    partition_table: 0x8000,
+    app: 0x10000,
+};
 
-const NVS_SIZE: u32 = 0x6000;
-const PHY_INIT_DATA_SIZE: u32 = 0x1000;
-const APP_SIZE: u32 = 0x100000;
+/// Chip id stored in the extended image header
+pub(crate) const CHIP_ID: u16 = 2;
 
 impl ChipType for Esp32s2 {
     const CHIP_DETECT_MAGIC_VALUE: u32 = 0x000007c6;
 
     const SPI_REGISTERS: SpiRegisters = SpiRegisters {
         base: 0x3f402000,
+        clock_offset: 0x14,
         usr_offset: 0x18,
         usr1_offset: 0x1C,
         usr2_offset: 0x20,
         w0_offset: 0x58,
         mosi_length_offset: Some(0x24),
         miso_length_offset: Some(0x28),
+        clkcnt_shifts: (16, 8),
+        dummy_cyclelen_mask: 0x3f,
+    };
+
+    const UF2_FAMILY_ID: u32 = 0xbfdd4eee;
+
+    const EFUSE_REG_BASE: u32 = 0x3f41a02c;
+    const EFUSE_BLOCK0_WORDS: u32 = 6;
+    const MAC_EFUSE_REG: u32 = 0x3f41a044;
+
+    const EFUSE_BLOCKS: &'static [EfuseBlock] = &[
+        EfuseBlock {
+            name: "BLOCK0",
+            reg: 0x3f41a02c,
+            words: 6,
+        },
+        EfuseBlock {
+            name: "MAC_SPI_8M_0",
+            reg: 0x3f41a044,
+            words: 6,
+        },
+        EfuseBlock {
+            name: "BLOCK_SYS_DATA",
+            reg: 0x3f41a05c,
+            words: 8,
+        },
+        EfuseBlock {
+            name: "BLOCK_USR_DATA",
+            reg: 0x3f41a07c,
+            words: 8,
+        },
+        EfuseBlock {
+            name: "BLOCK_KEY0",
+            reg: 0x3f41a09c,
+            words: 8,
+        },
+        EfuseBlock {
+            name: "BLOCK_KEY1",
+            reg: 0x3f41a0bc,
+            words: 8,
+        },
+        EfuseBlock {
+            name: "BLOCK_KEY2",
+            reg: 0x3f41a0dc,
+            words: 8,
+        },
+        EfuseBlock {
+            name: "BLOCK_KEY3",
+            reg: 0x3f41a0fc,
+            words: 8,
+        },
+        EfuseBlock {
+            name: "BLOCK_KEY4",
+            reg: 0x3f41a11c,
+            words: 8,
+        },
+        EfuseBlock {
+            name: "BLOCK_KEY5",
+            reg: 0x3f41a13c,
+            words: 8,
+        },
+        EfuseBlock {
+            name: "BLOCK_SYS_DATA2",
+            reg: 0x3f41a15c,
+            words: 8,
+        },
+    ];
+    // the burn timing of the ESP32-S2 isn't known
+    const EFUSE_CONTROLLER: Option<EfuseController> = None;
+    const EFUSE_FIELDS: &'static [EfuseField] = {
+        use Category::*;
+        use FieldKind::*;
+
+        &[
+            EfuseField::new(Identity, "MAC", (1, 0, 0, 48), Mac, "Factory MAC address"),
+            EfuseField::new(
+                Identity,
+                "CUSTOM_MAC",
+                (3, 6, 8, 48),
+                Bytes,
+                "Custom MAC address",
+            ),
+            EfuseField::new(
+                Identity,
+                "WAFER_VERSION_MAJOR",
+                (1, 3, 18, 2),
+                Uint,
+                "Major chip version",
+            ),
+            EfuseField::new(
+                Identity,
+                "WAFER_VERSION_MINOR_HI",
+                (1, 3, 20, 1),
+                Uint,
+                "Minor chip version, fourth bit",
+            ),
+            EfuseField::new(
+                Identity,
+                "WAFER_VERSION_MINOR_LO",
+                (1, 4, 4, 3),
+                Uint,
+                "Minor chip version, low bits",
+            ),
+            EfuseField::new(
+                Identity,
+                "PKG_VERSION",
+                (1, 4, 0, 4),
+                Uint,
+                "Package version",
+            ),
+            EfuseField::new(
+                Identity,
+                "FLASH_VERSION",
+                (1, 3, 21, 4),
+                Uint,
+                "Embedded flash",
+            ),
+            EfuseField::new(
+                Identity,
+                "PSRAM_VERSION",
+                (1, 3, 28, 4),
+                Uint,
+                "Embedded PSRAM",
+            ),
+            EfuseField::new(
+                Identity,
+                "OPTIONAL_UNIQUE_ID",
+                (2, 0, 0, 128),
+                Bytes,
+                "Unique id",
+            ),
+            EfuseField::new(
+                Config,
+                "DIS_RTC_RAM_BOOT",
+                (0, 1, 7, 1),
+                Bool,
+                "Booting from RTC RAM is disabled",
+            ),
+            EfuseField::new(
+                Config,
+                "DIS_ICACHE",
+                (0, 1, 8, 1),
+                Bool,
+                "The instruction cache is disabled",
+            ),
+            EfuseField::new(
+                Config,
+                "DIS_DCACHE",
+                (0, 1, 9, 1),
+                Bool,
+                "The data cache is disabled",
+            ),
+            EfuseField::new(
+                Config,
+                "DIS_USB",
+                (0, 1, 13, 1),
+                Bool,
+                "The USB controller is disabled",
+            ),
+            EfuseField::new(
+                Config,
+                "DIS_TWAI",
+                (0, 1, 14, 1),
+                Bool,
+                "The TWAI controller is disabled",
+            ),
+            EfuseField::new(
+                Config,
+                "DIS_BOOT_REMAP",
+                (0, 1, 15, 1),
+                Bool,
+                "Remapping the ROM to RAM is disabled",
+            ),
+            EfuseField::new(
+                Config,
+                "USB_EXCHG_PINS",
+                (0, 1, 24, 1),
+                Bool,
+                "The USB D+ and D- pins are swapped",
+            ),
+            EfuseField::new(
+                Config,
+                "USB_EXT_PHY_ENABLE",
+                (0, 1, 25, 1),
+                Bool,
+                "An external USB PHY is used",
+            ),
+            EfuseField::new(
+                Config,
+                "WDT_DELAY_SEL",
+                (0, 2, 16, 2),
+                Uint,
+                "Timeout of the RTC watchdog at boot",
+            ),
+            EfuseField::new(
+                Config,
+                "FLASH_TPUW",
+                (0, 3, 28, 4),
+                Uint,
+                "Flash power up delay in ms",
+            ),
+            EfuseField::new(
+                Config,
+                "DIS_LEGACY_SPI_BOOT",
+                (0, 4, 1, 1),
+                Bool,
+                "Booting without an image header is disabled",
+            ),
+            EfuseField::new(
+                Config,
+                "UART_PRINT_CHANNEL",
+                (0, 4, 2, 1),
+                Uint,
+                "UART the ROM prints boot messages on",
+            ),
+            EfuseField::new(
+                Config,
+                "DIS_USB_DOWNLOAD_MODE",
+                (0, 4, 4, 1),
+                Bool,
+                "Download mode over USB is disabled",
+            ),
+            EfuseField::new(
+                Config,
+                "UART_PRINT_CONTROL",
+                (0, 4, 6, 2),
+                Uint,
+                "When the ROM prints boot messages",
+            ),
+            EfuseField::new(
+                Config,
+                "PIN_POWER_SELECTION",
+                (0, 4, 8, 1),
+                Uint,
+                "Power supply of the flash pins",
+            ),
+            EfuseField::new(
+                Config,
+                "FLASH_TYPE",
+                (0, 4, 9, 1),
+                Uint,
+                "Maximum number of flash data lines",
+            ),
+            EfuseField::new(
+                Config,
+                "FORCE_SEND_RESUME",
+                (0, 4, 10, 1),
+                Bool,
+                "The flash is resumed at boot",
+            ),
+            EfuseField::new(
+                Security,
+                "SPI_BOOT_CRYPT_CNT",
+                (0, 2, 18, 3),
+                Uint,
+                "Flash encryption is enabled for an odd number of bits",
+            ),
+            EfuseField::new(
+                Security,
+                "SECURE_BOOT_EN",
+                (0, 3, 20, 1),
+                Bool,
+                "Secure boot is enabled",
+            ),
+            EfuseField::new(
+                Security,
+                "SECURE_BOOT_KEY_REVOKE0",
+                (0, 2, 21, 1),
+                Bool,
+                "The first secure boot key is revoked",
+            ),
+            EfuseField::new(
+                Security,
+                "SECURE_BOOT_KEY_REVOKE1",
+                (0, 2, 22, 1),
+                Bool,
+                "The second secure boot key is revoked",
+            ),
+            EfuseField::new(
+                Security,
+                "SECURE_BOOT_KEY_REVOKE2",
+                (0, 2, 23, 1),
+                Bool,
+                "The third secure boot key is revoked",
+            ),
+            EfuseField::new(
+                Security,
+                "SECURE_BOOT_AGGRESSIVE_REVOKE",
+                (0, 3, 21, 1),
+                Bool,
+                "Keys are revoked as soon as verification fails",
+            ),
+            EfuseField::new(
+                Security,
+                "DIS_DOWNLOAD_MODE",
+                (0, 4, 0, 1),
+                Bool,
+                "Download mode is disabled",
+            ),
+            EfuseField::new(
+                Security,
+                "ENABLE_SECURITY_DOWNLOAD",
+                (0, 4, 5, 1),
+                Bool,
+                "Download mode is restricted to flashing",
+            ),
+            EfuseField::new(
+                Security,
+                "DIS_FORCE_DOWNLOAD",
+                (0, 1, 12, 1),
+                Bool,
+                "Software can't force download mode",
+            ),
+            EfuseField::new(
+                Security,
+                "DIS_DOWNLOAD_ICACHE",
+                (0, 1, 10, 1),
+                Bool,
+                "The instruction cache is disabled in download mode",
+            ),
+            EfuseField::new(
+                Security,
+                "DIS_DOWNLOAD_MANUAL_ENCRYPT",
+                (0, 1, 19, 1),
+                Bool,
+                "Flash encryption is disabled in download mode",
+            ),
+            EfuseField::new(
+                Security,
+                "SOFT_DIS_JTAG",
+                (0, 1, 17, 1),
+                Uint,
+                "JTAG is disabled until re-enabled by HMAC",
+            ),
+            EfuseField::new(
+                Security,
+                "DIS_DOWNLOAD_DCACHE",
+                (0, 1, 11, 1),
+                Bool,
+                "The data cache is disabled in download mode",
+            ),
+            EfuseField::new(
+                Security,
+                "HARD_DIS_JTAG",
+                (0, 1, 18, 1),
+                Bool,
+                "JTAG is disabled",
+            ),
+            EfuseField::new(
+                Security,
+                "SECURE_VERSION",
+                (0, 4, 11, 16),
+                Uint,
+                "Anti-rollback version of the application",
+            ),
+            EfuseField::new(
+                Protection,
+                "WR_DIS",
+                (0, 0, 0, 32),
+                Mask,
+                "Efuses which are write protected",
+            ),
+            EfuseField::new(
+                Protection,
+                "RD_DIS",
+                (0, 1, 0, 7),
+                Mask,
+                "Key blocks which are read protected",
+            ),
+            EfuseField::new(
+                Keys,
+                "KEY_PURPOSE_0",
+                (0, 2, 24, 4),
+                KeyPurpose,
+                "Purpose of BLOCK_KEY0",
+            ),
+            EfuseField::new(
+                Keys,
+                "KEY_PURPOSE_1",
+                (0, 2, 28, 4),
+                KeyPurpose,
+                "Purpose of BLOCK_KEY1",
+            ),
+            EfuseField::new(
+                Keys,
+                "KEY_PURPOSE_2",
+                (0, 3, 0, 4),
+                KeyPurpose,
+                "Purpose of BLOCK_KEY2",
+            ),
+            EfuseField::new(
+                Keys,
+                "KEY_PURPOSE_3",
+                (0, 3, 4, 4),
+                KeyPurpose,
+                "Purpose of BLOCK_KEY3",
+            ),
+            EfuseField::new(
+                Keys,
+                "KEY_PURPOSE_4",
+                (0, 3, 8, 4),
+                KeyPurpose,
+                "Purpose of BLOCK_KEY4",
+            ),
+            EfuseField::new(
+                Keys,
+                "KEY_PURPOSE_5",
+                (0, 3, 12, 4),
+                KeyPurpose,
+                "Purpose of BLOCK_KEY5",
+            ),
+            EfuseField::new(Keys, "BLOCK_USR_DATA", (3, 0, 0, 256), Bytes, "User data"),
+            EfuseField::new(Keys, "BLOCK_KEY0", (4, 0, 0, 256), Bytes, "Key 0"),
+            EfuseField::new(Keys, "BLOCK_KEY1", (5, 0, 0, 256), Bytes, "Key 1"),
+            EfuseField::new(Keys, "BLOCK_KEY2", (6, 0, 0, 256), Bytes, "Key 2"),
+            EfuseField::new(Keys, "BLOCK_KEY3", (7, 0, 0, 256), Bytes, "Key 3"),
+            EfuseField::new(Keys, "BLOCK_KEY4", (8, 0, 0, 256), Bytes, "Key 4"),
+            EfuseField::new(Keys, "BLOCK_KEY5", (9, 0, 0, 256), Bytes, "Key 5"),
+        ]
     };
 
     fn addr_is_flash(addr: u32) -> bool {
@@ -42,12 +466,55 @@ impl ChipType for Esp32s2 {
             || (DROM_MAP_START..DROM_MAP_END).contains(&addr)
     }
 
+    // block 1 starts with the MAC address
+    const FEATURE_EFUSE_REGS: &'static [u32] = &[Self::MAC_EFUSE_REG + 3 * 4];
+
+    fn features(efuses: &Efuses) -> Vec<Feature> {
+        let word3 = efuses.word(Self::MAC_EFUSE_REG + 3 * 4);
+        let flash_version = (word3 >> 21) & 0xf;
+        let psram_version = (word3 >> 28) & 0xf;
+
+        let package = match (flash_version, psram_version) {
+            (0, 0) => "ESP32-S2",
+            (1, 0) => "ESP32-S2FH2",
+            (2, 0) => "ESP32-S2FH4",
+            (0, 1) => "ESP32-S2R2",
+            (2, 1) => "ESP32-S2FNR2",
+            _ => "unknown ESP32-S2",
+        };
+
+        let mut features = vec![Feature::Package(package), Feature::WiFi];
+        match flash_version {
+            1 => features.push(Feature::EmbeddedFlash(Some(2))),
+            2 => features.push(Feature::EmbeddedFlash(Some(4))),
+            _ => {}
+        }
+        match psram_version {
+            1 => features.push(Feature::EmbeddedPsram(Some(2))),
+            2 => features.push(Feature::EmbeddedPsram(Some(4))),
+            _ => {}
+        }
+
+        features
+    }
+
+    #[cfg(feature = "serial")]
+    fn crystal_frequency(
+        _read_reg: &mut dyn FnMut(u32) -> Result<u32, Error>,
+        _baud: u32,
+    ) -> Result<u32, Error> {
+        Ok(40)
+    }
+
     fn get_flash_segments<'a>(
         image: &'a FirmwareImage,
         bootloader: Option<Vec<u8>>,
         partition_table: Option<PartitionTable>,
     ) -> Box<dyn Iterator<Item = Result<RomSegment<'a>, Error>> + 'a> {
         let bootloader = if let Some(bytes) = bootloader {
+            if let Err(err) = validate_bootloader(&bytes, CHIP_ID) {
+                return Box::new(once(Err(err)));
+            }
             bytes
         } else {
             let bytes = include_bytes!("../../bootloader/esp32s2-bootloader.bin");
@@ -55,29 +522,33 @@ impl ChipType for Esp32s2 {
         };
 
         let partition_table = if let Some(table) = partition_table {
+            if let Err(err) = table.validate() {
+                return Box::new(once(Err(err.into())));
+            }
             table
         } else {
-            PartitionTable::basic(
-                NVS_ADDR,
-                NVS_SIZE,
-                PHY_INIT_DATA_ADDR,
-                PHY_INIT_DATA_SIZE,
-                APP_ADDR,
-                APP_SIZE,
-            )
+            default_partition_table(image, DEFAULT_OFFSETS)
+        };
+        let offsets = match flash_offsets(image, DEFAULT_OFFSETS, &partition_table) {
+            Ok(offsets) => offsets,
+            Err(err) => return Box::new(once(Err(err))),
         };
+        let app = get_data(image, offsets.app, CHIP_ID, Chip::Esp32s2).and_then(|app| {
+            partition_table.check_app_fits(app.data.len() as u32)?;
+            Ok(app)
+        });
         let partition_table = partition_table.to_bytes();
 
         Box::new(
             once(Ok(RomSegment {
-                addr: BOOT_ADDR,
+                addr: offsets.bootloader,
                 data: Cow::Owned(bootloader),
             }))
             .chain(once(Ok(RomSegment {
-                addr: PARTITION_ADDR,
+                addr: offsets.partition_table,
                 data: Cow::Owned(partition_table),
             })))
-            .chain(once(get_data(image, 2, Chip::Esp32s2))),
+            .chain(once(app)),
         )
     }
 }
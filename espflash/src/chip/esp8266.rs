@@ -1,11 +1,15 @@
 use bytemuck::bytes_of;
 
 use super::{ChipType, EspCommonHeader, SegmentHeader, ESP_MAGIC};
+#[cfg(feature = "serial")]
+use crate::chip::estimate_crystal_frequency;
 use crate::{
-    chip::{Chip, SpiRegisters},
-    elf::{update_checksum, CodeSegment, FirmwareImage, RomSegment, ESP_CHECKSUM_MAGIC},
+    chip::{
+        efuse::{EfuseBlock, EfuseController, EfuseField},
+        Chip, Efuses, Feature, SpiRegisters,
+    },
+    elf::{update_checksum, CodeSegment, FirmwareImage, FlashSize, RomSegment, ESP_CHECKSUM_MAGIC},
     error::FlashDetectError,
-    flasher::FlashSize,
     Error, PartitionTable,
 };
 
@@ -14,6 +18,11 @@ use std::{borrow::Cow, io::Write, iter::once, mem::size_of};
 pub const IROM_MAP_START: u32 = 0x40200000;
 const IROM_MAP_END: u32 = 0x40300000;
 
+#[cfg(feature = "serial")]
+const UART_CLKDIV_REG: u32 = 0x60000014;
+#[cfg(feature = "serial")]
+const UART_CLKDIV_MASK: u32 = 0xfffff;
+
 pub struct Esp8266;
 
 impl ChipType for Esp8266 {
@@ -21,18 +30,64 @@ impl ChipType for Esp8266 {
 
     const SPI_REGISTERS: SpiRegisters = SpiRegisters {
         base: 0x60000200,
+        clock_offset: 0x18,
         usr_offset: 0x1c,
         usr1_offset: 0x20,
         usr2_offset: 0x24,
         w0_offset: 0x40,
         mosi_length_offset: None,
         miso_length_offset: None,
+        clkcnt_shifts: (12, 6),
+        dummy_cyclelen_mask: 0xff,
     };
 
+    const UF2_FAMILY_ID: u32 = 0x7eab61ed;
+
+    const EFUSE_REG_BASE: u32 = 0x3ff00050;
+    const EFUSE_BLOCK0_WORDS: u32 = 4;
+    const MAC_EFUSE_REG: u32 = 0x3ff00050;
+
+    const EFUSE_BLOCKS: &'static [EfuseBlock] = &[EfuseBlock {
+        name: "BLOCK0",
+        reg: 0x3ff00050,
+        words: 4,
+    }];
+    // espefuse doesn't describe the efuses of the ESP8266
+    const EFUSE_CONTROLLER: Option<EfuseController> = None;
+    const EFUSE_FIELDS: &'static [EfuseField] = &[];
+
     fn addr_is_flash(addr: u32) -> bool {
         (IROM_MAP_START..IROM_MAP_END).contains(&addr)
     }
 
+    const FEATURE_EFUSE_REGS: &'static [u32] =
+        &[Self::EFUSE_REG_BASE, Self::EFUSE_REG_BASE + 2 * 4];
+
+    fn features(efuses: &Efuses) -> Vec<Feature> {
+        let word0 = efuses.word(Self::EFUSE_REG_BASE);
+        let word2 = efuses.word(Self::EFUSE_REG_BASE + 2 * 4);
+
+        // the ESP8285 has the flash embedded in the package
+        if word0 & (1 << 4) != 0 || word2 & (1 << 16) != 0 {
+            vec![
+                Feature::Package("ESP8285"),
+                Feature::WiFi,
+                Feature::EmbeddedFlash(None),
+            ]
+        } else {
+            vec![Feature::Package("ESP8266EX"), Feature::WiFi]
+        }
+    }
+
+    #[cfg(feature = "serial")]
+    fn crystal_frequency(
+        read_reg: &mut dyn FnMut(u32) -> Result<u32, Error>,
+        baud: u32,
+    ) -> Result<u32, Error> {
+        let uart_div = read_reg(UART_CLKDIV_REG)? & UART_CLKDIV_MASK;
+        Ok(estimate_crystal_frequency(uart_div, baud, 2))
+    }
+
     fn get_flash_segments<'a>(
         image: &'a FirmwareImage,
         _bootloader: Option<Vec<u8>>,
@@ -97,7 +152,7 @@ impl ChipType for Esp8266 {
     }
 }
 
-fn encode_flash_size(size: FlashSize) -> Result<u8, FlashDetectError> {
+pub(crate) fn encode_flash_size(size: FlashSize) -> Result<u8, FlashDetectError> {
     match size {
         FlashSize::Flash256Kb => Ok(0x10),
         FlashSize::Flash512Kb => Ok(0x00),
@@ -106,7 +161,23 @@ fn encode_flash_size(size: FlashSize) -> Result<u8, FlashDetectError> {
         FlashSize::Flash4Mb => Ok(0x40),
         FlashSize::Flash8Mb => Ok(0x80),
         FlashSize::Flash16Mb => Ok(0x90),
-        FlashSize::FlashRetry => Err(FlashDetectError::from(size as u8)),
+        FlashSize::Flash32Mb
+        | FlashSize::Flash64Mb
+        | FlashSize::Flash128Mb
+        | FlashSize::FlashRetry => Err(FlashDetectError::from(size as u8)),
+    }
+}
+
+pub(crate) fn decode_flash_size(value: u8) -> Option<FlashSize> {
+    match value {
+        0x10 => Some(FlashSize::Flash256Kb),
+        0x00 => Some(FlashSize::Flash512Kb),
+        0x20 => Some(FlashSize::Flash1Mb),
+        0x30 => Some(FlashSize::Flash2Mb),
+        0x40 => Some(FlashSize::Flash4Mb),
+        0x80 => Some(FlashSize::Flash8Mb),
+        0x90 => Some(FlashSize::Flash16Mb),
+        _ => None,
     }
 }
 
@@ -0,0 +1,83 @@
+//! Features and package of a chip, as programmed into its efuses
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+/// Efuse words read from a device, by the address of their read register
+///
+/// [`Chip::feature_efuse_regs`](crate::Chip::feature_efuse_regs) lists the
+/// registers needed to tell the features of a chip, words which weren't read
+/// count as zero.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Efuses(BTreeMap<u32, u32>);
+
+impl Efuses {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, reg: u32, value: u32) {
+        self.0.insert(reg, value);
+    }
+
+    /// The word read from the register at `reg`
+    pub fn word(&self, reg: u32) -> u32 {
+        self.0.get(&reg).copied().unwrap_or_default()
+    }
+}
+
+/// A feature of a chip
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Feature {
+    /// The package variant of the chip, e.g. `ESP32-D0WDQ6`
+    Package(&'static str),
+    WiFi,
+    Bt,
+    Ble,
+    SingleCore,
+    DualCore,
+    /// Highest CPU frequency in MHz
+    MaxCpuFrequency(u32),
+    /// Flash in the package, with its size in MB if known
+    EmbeddedFlash(Option<u32>),
+    /// PSRAM in the package, with its size in MB if known
+    EmbeddedPsram(Option<u32>),
+    /// The ADC reference voltage is calibrated in efuse
+    VrefCalibration,
+    /// Part of efuse block 3 is reserved for ADC calibration
+    Blk3PartiallyReserved,
+    /// Coding scheme of the efuse blocks
+    CodingScheme(&'static str),
+}
+
+impl Feature {
+    /// The package variant, if this is one
+    pub fn package(&self) -> Option<&'static str> {
+        match self {
+            Feature::Package(package) => Some(package),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Feature {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Feature::Package(package) => write!(f, "{}", package),
+            Feature::WiFi => write!(f, "WiFi"),
+            Feature::Bt => write!(f, "BT"),
+            Feature::Ble => write!(f, "BLE"),
+            Feature::SingleCore => write!(f, "Single Core"),
+            Feature::DualCore => write!(f, "Dual Core"),
+            Feature::MaxCpuFrequency(mhz) => write!(f, "{}MHz", mhz),
+            Feature::EmbeddedFlash(None) => write!(f, "Embedded Flash"),
+            Feature::EmbeddedFlash(Some(mb)) => write!(f, "Embedded Flash {}MB", mb),
+            Feature::EmbeddedPsram(None) => write!(f, "Embedded PSRAM"),
+            Feature::EmbeddedPsram(Some(mb)) => write!(f, "Embedded PSRAM {}MB", mb),
+            Feature::VrefCalibration => write!(f, "VRef calibration in efuse"),
+            Feature::Blk3PartiallyReserved => write!(f, "BLK3 partially reserved"),
+            Feature::CodingScheme(scheme) => write!(f, "Coding Scheme {}", scheme),
+        }
+    }
+}
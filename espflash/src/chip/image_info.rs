@@ -0,0 +1,259 @@
+use bytemuck::pod_read_unaligned;
+use sha2::{Digest, Sha256};
+
+use std::fmt::{Display, Formatter};
+use std::mem::size_of;
+
+use super::{
+    decode_flash_size, esp8266, Chip, EspCommonHeader, ExtendedHeader, SegmentHeader, ESP_MAGIC,
+};
+use crate::{
+    app_desc::AppDescriptor,
+    elf::{update_checksum, FlashFrequency, FlashMode, FlashSize, ESP_CHECKSUM_MAGIC},
+    error::ImageError,
+    Error,
+};
+
+/// A segment in the segment table of an image
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ImageSegment {
+    /// Address the segment is loaded or mapped to
+    pub addr: u32,
+    /// Length of the segment data
+    pub length: u32,
+    /// Offset of the segment data in the image
+    pub offset: usize,
+}
+
+/// Information from the headers of an application or bootloader image
+#[derive(Debug, Clone)]
+pub struct ImageInfo {
+    pub entry: u32,
+    pub flash_mode: Option<FlashMode>,
+    pub flash_size: Option<FlashSize>,
+    pub flash_frequency: Option<FlashFrequency>,
+    /// Chip id from the extended header, ESP8266 images don't have one
+    pub chip_id: Option<u16>,
+    /// Minimum chip revision from the extended header
+    pub min_rev: Option<u8>,
+    pub segments: Vec<ImageSegment>,
+    pub checksum: u8,
+    pub checksum_valid: bool,
+    /// Length of the image up to the checksum, without the appended digest
+    pub length: usize,
+    /// Whether the appended SHA-256 digest matches, `None` if the image
+    /// doesn't have one
+    pub digest_valid: Option<bool>,
+    pub app_desc: Option<AppDescriptor>,
+}
+
+impl ImageInfo {
+    /// Parse the headers of an image built for `chip`
+    ///
+    /// All chips but the ESP8266 share the same format, the chip the image was
+    /// actually built for is identified by [`chip_id`].
+    ///
+    /// [`chip_id`]: ImageInfo::chip_id
+    pub fn parse(data: &[u8], chip: Chip) -> Result<Self, Error> {
+        let mut offset = size_of::<EspCommonHeader>();
+        let header: EspCommonHeader =
+            pod_read_unaligned(data.get(..offset).ok_or(ImageError::TooShort)?);
+        if header.magic != ESP_MAGIC {
+            return Err(ImageError::InvalidMagic(header.magic).into());
+        }
+
+        let extended_header = if chip == Chip::Esp8266 {
+            None
+        } else {
+            let end = offset + size_of::<ExtendedHeader>();
+            let extended_header: ExtendedHeader =
+                pod_read_unaligned(data.get(offset..end).ok_or(ImageError::TooShort)?);
+            offset = end;
+            Some(extended_header)
+        };
+
+        let mut segments = Vec::with_capacity(header.segment_count as usize);
+        let mut checksum = ESP_CHECKSUM_MAGIC;
+        for index in 0..header.segment_count as usize {
+            let data_offset = offset + size_of::<SegmentHeader>();
+            let segment: SegmentHeader = pod_read_unaligned(
+                data.get(offset..data_offset)
+                    .ok_or(ImageError::TruncatedSegment(index))?,
+            );
+            let end = data_offset + segment.length as usize;
+            let segment_data = data
+                .get(data_offset..end)
+                .ok_or(ImageError::TruncatedSegment(index))?;
+
+            checksum = update_checksum(segment_data, checksum);
+            segments.push(ImageSegment {
+                addr: segment.addr,
+                length: segment.length,
+                offset: data_offset,
+            });
+            offset = end;
+        }
+
+        // The checksum is stored in the last byte of the 16 byte block
+        // following the segments
+        let checksum_offset = offset + 15 - offset % 16;
+        let stored_checksum = *data.get(checksum_offset).ok_or(ImageError::TooShort)?;
+        let image_end = checksum_offset + 1;
+
+        let digest_valid = match extended_header {
+            Some(extended_header) if extended_header.append_digest == 1 => Some(
+                data.get(image_end..image_end + 32)
+                    .is_some_and(|digest| Sha256::digest(&data[..image_end])[..] == *digest),
+            ),
+            _ => None,
+        };
+
+        let flash_size = match chip {
+            Chip::Esp8266 => esp8266::decode_flash_size(header.flash_config & 0xf0),
+            _ => decode_flash_size(header.flash_config & 0xf0),
+        };
+
+        Ok(ImageInfo {
+            entry: header.entry,
+            flash_mode: decode_flash_mode(header.flash_mode),
+            flash_size,
+            flash_frequency: decode_flash_frequency(header.flash_config & 0x0f),
+            chip_id: extended_header.map(|header| header.chip_id),
+            min_rev: extended_header.map(|header| header.min_rev),
+            segments,
+            checksum: stored_checksum,
+            checksum_valid: stored_checksum == checksum,
+            length: image_end,
+            digest_valid,
+            app_desc: AppDescriptor::from_image(data),
+        })
+    }
+}
+
+impl Display for ImageInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(chip_id) = self.chip_id {
+            match Chip::from_chip_id(chip_id) {
+                Some(chip) => writeln!(f, "Chip id:          {} ({})", chip_id, chip)?,
+                None => writeln!(f, "Chip id:          {} (unknown)", chip_id)?,
+            }
+        }
+        if let Some(min_rev) = self.min_rev {
+            writeln!(f, "Minimum revision: {}", min_rev)?;
+        }
+        writeln!(f, "Entry point:      {:#010x}", self.entry)?;
+        writeln!(
+            f,
+            "Flash mode:       {}",
+            display_or_unknown(self.flash_mode)
+        )?;
+        writeln!(
+            f,
+            "Flash size:       {}",
+            display_or_unknown(self.flash_size)
+        )?;
+        writeln!(
+            f,
+            "Flash frequency:  {}",
+            display_or_unknown(self.flash_frequency)
+        )?;
+
+        writeln!(f, "Segments:         {}", self.segments.len())?;
+        for (index, segment) in self.segments.iter().enumerate() {
+            writeln!(
+                f,
+                "  {:>2}: address {:#010x}, length {:#08x}, file offset {:#08x}",
+                index, segment.addr, segment.length, segment.offset
+            )?;
+        }
+
+        writeln!(
+            f,
+            "Checksum:         {:#04x} ({})",
+            self.checksum,
+            if self.checksum_valid {
+                "valid"
+            } else {
+                "invalid"
+            }
+        )?;
+        let digest = match self.digest_valid {
+            Some(true) => "valid",
+            Some(false) => "invalid",
+            None => "not appended",
+        };
+        writeln!(f, "SHA-256 digest:   {}", digest)?;
+
+        if let Some(app_desc) = &self.app_desc {
+            writeln!(f, "Project name:     {}", app_desc.project_name)?;
+            writeln!(f, "App version:      {}", app_desc.version)?;
+            writeln!(f, "Secure version:   {}", app_desc.secure_version)?;
+            writeln!(f, "Compiled:         {} {}", app_desc.date, app_desc.time)?;
+            writeln!(f, "ESP-IDF version:  {}", app_desc.idf_version)?;
+            let elf_sha256: String = app_desc
+                .elf_sha256
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            writeln!(f, "ELF SHA-256:      {}", elf_sha256)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn decode_flash_mode(value: u8) -> Option<FlashMode> {
+    match value {
+        0 => Some(FlashMode::Qio),
+        1 => Some(FlashMode::Qout),
+        2 => Some(FlashMode::Dio),
+        3 => Some(FlashMode::Dout),
+        _ => None,
+    }
+}
+
+fn decode_flash_frequency(value: u8) -> Option<FlashFrequency> {
+    match value {
+        0x0 => Some(FlashFrequency::Flash40M),
+        0x1 => Some(FlashFrequency::Flash26M),
+        0x2 => Some(FlashFrequency::Flash20M),
+        0xf => Some(FlashFrequency::Flash80M),
+        _ => None,
+    }
+}
+
+fn display_or_unknown<T: Display>(value: Option<T>) -> String {
+    value.map_or_else(|| String::from("unknown"), |value| value.to_string())
+}
+
+#[test]
+fn test_parse_app_image() {
+    use crate::elf::FirmwareImage;
+    use std::fs::read;
+
+    let input_bytes = read("./tests/data/esp32").unwrap();
+    let image = FirmwareImage::from_data(&input_bytes).unwrap();
+    let segment = super::esp32::get_data(
+        &image,
+        super::esp32::DEFAULT_OFFSETS.app,
+        super::esp32::CHIP_ID,
+        Chip::Esp32,
+    )
+    .unwrap();
+
+    let info = ImageInfo::parse(&segment.data, Chip::Esp32).unwrap();
+    assert_eq!(Some(0), info.chip_id);
+    assert_eq!(image.entry, info.entry);
+    assert_eq!(Some(FlashMode::Dio), info.flash_mode);
+    assert_eq!(Some(FlashSize::Flash4Mb), info.flash_size);
+    assert_eq!(segment.data[1] as usize, info.segments.len());
+    assert!(info.checksum_valid);
+    assert_eq!(Some(true), info.digest_valid);
+
+    let mut corrupted = segment.data.into_owned();
+    let last_segment = info.segments.last().unwrap();
+    corrupted[last_segment.offset] ^= 0xff;
+    let info = ImageInfo::parse(&corrupted, Chip::Esp32).unwrap();
+    assert!(!info.checksum_valid);
+    assert_eq!(Some(false), info.digest_valid);
+}
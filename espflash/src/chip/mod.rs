@@ -1,35 +1,205 @@
 use bytemuck::{bytes_of, Pod, Zeroable};
-use strum_macros::Display;
+use sha2::{Digest, Sha256};
+use strum_macros::{Display, EnumString};
 
+use self::efuse::{EfuseBlock, EfuseController, EfuseField};
 use crate::{
-    elf::{update_checksum, CodeSegment, FirmwareImage, RomSegment},
-    error::{ChipDetectError, FlashDetectError},
-    flash_target::{Esp32Target, Esp8266Target, FlashTarget, RamTarget},
-    flasher::{FlashSize, SpiAttachParams},
+    elf::{
+        update_checksum, CodeSegment, FirmwareImage, FlashSettings, FlashSize, RomSegment,
+        SegmentLayout,
+    },
+    error::{BootloaderError, ChipDetectError, FlashDetectError},
     Error, PartitionTable,
 };
+#[cfg(feature = "serial")]
+use crate::{
+    flash_target::{Esp32Target, Esp8266Target, FlashTarget, RamTarget},
+    flasher::SpiAttachParams,
+};
 
 use std::io::Write;
+use std::mem::size_of;
 
 pub use esp32::Esp32;
 pub use esp32c3::Esp32c3;
 pub use esp32s2::Esp32s2;
 pub use esp8266::Esp8266;
 
+pub use features::{Efuses, Feature};
+pub use image_info::{ImageInfo, ImageSegment};
+
+pub(crate) use esp32::CHIP_ID as ESP32_CHIP_ID;
+
+pub mod efuse;
 mod esp32;
 mod esp32c3;
 mod esp32s2;
 mod esp8266;
+mod features;
+mod image_info;
 
 const ESP_MAGIC: u8 = 0xE9;
 const WP_PIN_DISABLED: u8 = 0xEE;
 
+const FLASH_SECTOR_SIZE: u32 = 0x1000;
+const APP_ALIGNMENT: u32 = 0x10000;
+
+const NVS_SIZE: u32 = 0x6000;
+const PHY_INIT_DATA_SIZE: u32 = 0x1000;
+
+/// Offsets in flash of the bootloader, partition table and application
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct FlashOffsets {
+    pub bootloader: u32,
+    pub partition_table: u32,
+    pub app: u32,
+}
+
+impl FlashOffsets {
+    /// Check the offsets against the constraints of the boot process, given
+    /// the default offsets of the chip
+    fn validate(&self, defaults: &FlashOffsets) -> Result<(), Error> {
+        let invalid = |name, offset, reason| {
+            Err(Error::InvalidFlashOffset {
+                name,
+                offset,
+                reason,
+            })
+        };
+
+        if self.bootloader < defaults.bootloader {
+            return invalid(
+                "bootloader",
+                self.bootloader,
+                "the flash before the default offset is reserved on this chip",
+            );
+        }
+        if !self.bootloader.is_multiple_of(FLASH_SECTOR_SIZE) {
+            return invalid(
+                "bootloader",
+                self.bootloader,
+                "it must be aligned to the 4 KiB flash sectors",
+            );
+        }
+        if !self.partition_table.is_multiple_of(FLASH_SECTOR_SIZE) {
+            return invalid(
+                "partition table",
+                self.partition_table,
+                "it must be aligned to the 4 KiB flash sectors",
+            );
+        }
+        if self.partition_table <= self.bootloader {
+            return invalid(
+                "partition table",
+                self.partition_table,
+                "it must be placed after the bootloader",
+            );
+        }
+        if !self.app.is_multiple_of(APP_ALIGNMENT) {
+            return invalid(
+                "application",
+                self.app,
+                "it must be aligned to the 64 KiB pages of the flash MMU",
+            );
+        }
+        if self.app <= self.partition_table {
+            return invalid(
+                "application",
+                self.app,
+                "it must be placed after the partition table",
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// The partition table used if none is given, laid out like the single app
+/// table of ESP-IDF directly after the partition table
+fn default_partition_table(image: &FirmwareImage, defaults: FlashOffsets) -> PartitionTable {
+    let partition_table_offset = image
+        .partition_table_offset
+        .unwrap_or(defaults.partition_table);
+    let nvs_offset = partition_table_offset + FLASH_SECTOR_SIZE;
+    let phy_init_data_offset = nvs_offset + NVS_SIZE;
+    let app_offset = (phy_init_data_offset + PHY_INIT_DATA_SIZE).next_multiple_of(APP_ALIGNMENT);
+
+    PartitionTable::basic(
+        nvs_offset,
+        NVS_SIZE,
+        phy_init_data_offset,
+        PHY_INIT_DATA_SIZE,
+        app_offset,
+        default_app_size(image, app_offset),
+    )
+}
+
+/// Size of the app partition in the default partition table, which fills the
+/// rest of the flash
+fn default_app_size(image: &FirmwareImage, app_offset: u32) -> u32 {
+    let flash_size = match image.flash_size {
+        // not detected, assume the most common size
+        FlashSize::FlashRetry => FlashSize::Flash4Mb,
+        size => size,
+    };
+    flash_size.size().saturating_sub(app_offset)
+}
+
+/// Resolve the offsets the bootloader, partition table and application are
+/// written at
+///
+/// Offsets overridden in the image take precedence, the application is
+/// otherwise written to the partition it is booted from.
+fn flash_offsets(
+    image: &FirmwareImage,
+    defaults: FlashOffsets,
+    partition_table: &PartitionTable,
+) -> Result<FlashOffsets, Error> {
+    let offsets = FlashOffsets {
+        bootloader: image.bootloader_offset.unwrap_or(defaults.bootloader),
+        partition_table: image
+            .partition_table_offset
+            .unwrap_or(defaults.partition_table),
+        app: image
+            .app_offset
+            .or_else(|| partition_table.app_offset())
+            .unwrap_or(defaults.app),
+    };
+    offsets.validate(&defaults)?;
+
+    if partition_table.overlaps(offsets.partition_table, FLASH_SECTOR_SIZE) {
+        return Err(Error::InvalidFlashOffset {
+            name: "partition table",
+            offset: offsets.partition_table,
+            reason: "it overlaps one of the partitions in the table",
+        });
+    }
+
+    Ok(offsets)
+}
+
 pub trait ChipType {
     const CHIP_DETECT_MAGIC_VALUE: u32;
     const CHIP_DETECT_MAGIC_VALUE2: u32 = 0x0; // give default value, as most chips don't only have one
 
     const SPI_REGISTERS: SpiRegisters;
 
+    /// Family id identifying the chip in UF2 files
+    const UF2_FAMILY_ID: u32;
+
+    /// Address of the first read register of efuse block 0
+    const EFUSE_REG_BASE: u32;
+    /// Number of 32 bit words in efuse block 0
+    const EFUSE_BLOCK0_WORDS: u32;
+    /// Efuse read register holding the low word of the factory MAC address
+    const MAC_EFUSE_REG: u32;
+    /// Blocks of the efuses, starting with block 0
+    const EFUSE_BLOCKS: &'static [EfuseBlock];
+    /// The controller burning the efuses, if burning is supported
+    const EFUSE_CONTROLLER: Option<EfuseController>;
+    /// Named fields of the efuses
+    const EFUSE_FIELDS: &'static [EfuseField];
+
     /// Get the firmware segments for writing an image to flash
     fn get_flash_segments<'a>(
         image: &'a FirmwareImage,
@@ -38,23 +208,87 @@ pub trait ChipType {
     ) -> Box<dyn Iterator<Item = Result<RomSegment<'a>, Error>> + 'a>;
 
     fn addr_is_flash(addr: u32) -> bool;
+
+    /// Efuse read registers holding the package and features of the chip
+    const FEATURE_EFUSE_REGS: &'static [u32];
+
+    /// The package and features of the chip, starting with the package
+    fn features(efuses: &Efuses) -> Vec<Feature>;
+
+    /// Read the crystal frequency in MHz, `baud` is the current baud rate of
+    /// the connection
+    #[cfg(feature = "serial")]
+    fn crystal_frequency(
+        read_reg: &mut dyn FnMut(u32) -> Result<u32, Error>,
+        baud: u32,
+    ) -> Result<u32, Error>;
+}
+
+/// Estimate the crystal frequency from the divider the ROM configured for the
+/// UART, which is either 26 or 40 MHz
+#[cfg(feature = "serial")]
+pub(crate) fn estimate_crystal_frequency(uart_div: u32, baud: u32, xtal_divider: u32) -> u32 {
+    let estimate = uart_div as u64 * baud as u64 / 1_000_000 / xtal_divider as u64;
+    if estimate > 33 {
+        40
+    } else {
+        26
+    }
 }
 
 pub struct SpiRegisters {
     base: u32,
+    clock_offset: u32,
     usr_offset: u32,
     usr1_offset: u32,
     usr2_offset: u32,
     w0_offset: u32,
     mosi_length_offset: Option<u32>,
     miso_length_offset: Option<u32>,
+    /// Position of the `clkcnt_n` and `clkcnt_h` fields in the clock register
+    clkcnt_shifts: (u32, u32),
+    /// Mask of the dummy cycle length in the `usr1` register
+    dummy_cyclelen_mask: u32,
 }
 
+/// Enables the dummy phase in the `usr` register
+#[cfg(feature = "serial")]
+pub(crate) const SPI_USR_DUMMY: u32 = 1 << 29;
+/// Enables the command, data out and data in phases in the `usr` register
+#[cfg(feature = "serial")]
+const SPI_USR_COMMAND: u32 = 1 << 31;
+#[cfg(feature = "serial")]
+const SPI_USR_MOSI: u32 = 1 << 27;
+#[cfg(feature = "serial")]
+const SPI_USR_MISO: u32 = 1 << 28;
+
+/// Sets the SPI clock to the APB clock in the clock register
+const SPI_CLK_EQU_SYSCLK: u32 = 1 << 31;
+
 impl SpiRegisters {
     pub fn cmd(&self) -> u32 {
         self.base
     }
 
+    pub fn clock(&self) -> u32 {
+        self.base + self.clock_offset
+    }
+
+    /// The clock register value dividing the APB clock by `divider`
+    pub fn clock_value(&self, divider: u8) -> u32 {
+        let divider = divider as u32;
+        if divider <= 1 {
+            return SPI_CLK_EQU_SYSCLK;
+        }
+
+        let (n_shift, h_shift) = self.clkcnt_shifts;
+        (divider - 1) << n_shift | (divider / 2 - 1) << h_shift | (divider - 1)
+    }
+
+    pub fn dummy_cyclelen_mask(&self) -> u32 {
+        self.dummy_cyclelen_mask
+    }
+
     pub fn usr(&self) -> u32 {
         self.base + self.usr_offset
     }
@@ -78,6 +312,60 @@ impl SpiRegisters {
     pub fn miso_length(&self) -> Option<u32> {
         self.miso_length_offset.map(|offset| self.base + offset)
     }
+
+    /// The register writes, as `(address, value, mask)`, setting up a user
+    /// command which sends `data_len` bytes after the opcode and reads
+    /// `read_bits` bits after `dummy_cycles` dummy cycles
+    ///
+    /// The `usr` register is written as a whole, so the dummy phase is only
+    /// enabled for commands which have one.
+    #[cfg(feature = "serial")]
+    pub(crate) fn user_command(
+        &self,
+        data_len: usize,
+        read_bits: u32,
+        dummy_cycles: u8,
+    ) -> Vec<(u32, u32, Option<u32>)> {
+        let mut usr = SPI_USR_COMMAND;
+        if data_len > 0 {
+            usr |= SPI_USR_MOSI;
+        }
+        if read_bits > 0 {
+            usr |= SPI_USR_MISO;
+        }
+        if dummy_cycles > 0 {
+            usr |= SPI_USR_DUMMY;
+        }
+
+        let mut writes = vec![(self.usr(), usr, None)];
+        if dummy_cycles > 0 {
+            writes.push((
+                self.usr1(),
+                dummy_cycles as u32 - 1,
+                Some(self.dummy_cyclelen_mask),
+            ));
+        }
+
+        let mosi_bits = (data_len as u32 * 8).saturating_sub(1);
+        let miso_bits = read_bits.saturating_sub(1);
+        match (self.mosi_length(), self.miso_length()) {
+            (Some(mosi_length), Some(miso_length)) => {
+                if data_len > 0 {
+                    writes.push((mosi_length, mosi_bits, None));
+                }
+                if read_bits > 0 {
+                    writes.push((miso_length, miso_bits, None));
+                }
+            }
+            _ => writes.push((
+                self.usr1(),
+                miso_bits << 8 | mosi_bits << 17,
+                Some(!self.dummy_cyclelen_mask),
+            )),
+        }
+
+        writes
+    }
 }
 
 #[derive(Copy, Clone, Zeroable, Pod)]
@@ -93,19 +381,81 @@ struct ExtendedHeader {
     append_digest: u8,
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Display)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Display, EnumString)]
+#[strum(ascii_case_insensitive)]
 pub enum Chip {
-    #[strum(serialize = "ESP32")]
+    #[strum(to_string = "ESP32", serialize = "esp32")]
     Esp32,
-    #[strum(serialize = "ESP32-C3")]
+    #[strum(to_string = "ESP32-C3", serialize = "esp32c3")]
     Esp32c3,
-    #[strum(serialize = "ESP32-S2")]
+    #[strum(to_string = "ESP32-S2", serialize = "esp32s2")]
     Esp32s2,
-    #[strum(serialize = "ESP8266")]
+    #[strum(to_string = "ESP8266", serialize = "esp8266")]
     Esp8266,
 }
 
 impl Chip {
+    /// The chip with the given id from the extended image header, the ESP8266
+    /// doesn't use the extended header
+    pub fn from_chip_id(chip_id: u16) -> Option<Self> {
+        match chip_id {
+            esp32::CHIP_ID => Some(Chip::Esp32),
+            esp32c3::CHIP_ID => Some(Chip::Esp32c3),
+            esp32s2::CHIP_ID => Some(Chip::Esp32s2),
+            _ => None,
+        }
+    }
+
+    /// The default offsets of the bootloader, partition table and
+    /// application, the ESP8266 doesn't use a bootloader or partition table
+    pub fn default_offsets(&self) -> Option<FlashOffsets> {
+        match self {
+            Chip::Esp32 => Some(esp32::DEFAULT_OFFSETS),
+            Chip::Esp32c3 => Some(esp32c3::DEFAULT_OFFSETS),
+            Chip::Esp32s2 => Some(esp32s2::DEFAULT_OFFSETS),
+            Chip::Esp8266 => None,
+        }
+    }
+
+    /// Set the flash mode, frequency and size in the header of the image
+    /// `data`, as esptool does for the bootloader it writes
+    ///
+    /// The appended SHA-256 digest is updated along with the header. Returns
+    /// `false` and leaves `data` alone if it isn't an image.
+    pub fn update_image_header(
+        &self,
+        data: &mut [u8],
+        settings: &FlashSettings,
+    ) -> Result<bool, Error> {
+        let info = match ImageInfo::parse(data, *self) {
+            Ok(info) => info,
+            Err(_) => return Ok(false),
+        };
+
+        if let Some(mode) = settings.mode {
+            data[2] = mode as u8;
+        }
+        if let Some(size) = settings.size {
+            let size = match self {
+                Chip::Esp8266 => esp8266::encode_flash_size(size)?,
+                _ => encode_flash_size(size)?,
+            };
+            data[3] = size | (data[3] & 0x0f);
+        }
+        if let Some(frequency) = settings.frequency {
+            data[3] = (data[3] & 0xf0) | frequency as u8;
+        }
+
+        if info.digest_valid.is_some() {
+            let digest = Sha256::digest(&data[..info.length]);
+            if let Some(stored) = data.get_mut(info.length..info.length + digest.len()) {
+                stored.copy_from_slice(&digest);
+            }
+        }
+
+        Ok(true)
+    }
+
     pub fn from_magic(magic: u32) -> Result<Self, ChipDetectError> {
         match magic {
             Esp32::CHIP_DETECT_MAGIC_VALUE => Ok(Chip::Esp32),
@@ -150,18 +500,201 @@ impl Chip {
         }
     }
 
-    pub fn ram_target(&self) -> Box<dyn FlashTarget> {
-        Box::new(RamTarget::new())
+    pub fn uf2_family_id(&self) -> u32 {
+        match self {
+            Chip::Esp32 => Esp32::UF2_FAMILY_ID,
+            Chip::Esp32c3 => Esp32c3::UF2_FAMILY_ID,
+            Chip::Esp32s2 => Esp32s2::UF2_FAMILY_ID,
+            Chip::Esp8266 => Esp8266::UF2_FAMILY_ID,
+        }
+    }
+
+    pub fn efuse_reg_base(&self) -> u32 {
+        match self {
+            Chip::Esp32 => Esp32::EFUSE_REG_BASE,
+            Chip::Esp32c3 => Esp32c3::EFUSE_REG_BASE,
+            Chip::Esp32s2 => Esp32s2::EFUSE_REG_BASE,
+            Chip::Esp8266 => Esp8266::EFUSE_REG_BASE,
+        }
     }
 
-    pub fn flash_target(&self, spi_params: SpiAttachParams) -> Box<dyn FlashTarget> {
+    pub fn efuse_block0_words(&self) -> u32 {
+        match self {
+            Chip::Esp32 => Esp32::EFUSE_BLOCK0_WORDS,
+            Chip::Esp32c3 => Esp32c3::EFUSE_BLOCK0_WORDS,
+            Chip::Esp32s2 => Esp32s2::EFUSE_BLOCK0_WORDS,
+            Chip::Esp8266 => Esp8266::EFUSE_BLOCK0_WORDS,
+        }
+    }
+
+    pub fn mac_efuse_reg(&self) -> u32 {
+        match self {
+            Chip::Esp32 => Esp32::MAC_EFUSE_REG,
+            Chip::Esp32c3 => Esp32c3::MAC_EFUSE_REG,
+            Chip::Esp32s2 => Esp32s2::MAC_EFUSE_REG,
+            Chip::Esp8266 => Esp8266::MAC_EFUSE_REG,
+        }
+    }
+
+    /// Blocks of the efuses, in the order of
+    /// [`EfuseBlocks`](efuse::EfuseBlocks)
+    pub fn efuse_blocks(&self) -> &'static [EfuseBlock] {
+        match self {
+            Chip::Esp32 => Esp32::EFUSE_BLOCKS,
+            Chip::Esp32c3 => Esp32c3::EFUSE_BLOCKS,
+            Chip::Esp32s2 => Esp32s2::EFUSE_BLOCKS,
+            Chip::Esp8266 => Esp8266::EFUSE_BLOCKS,
+        }
+    }
+
+    /// Named fields of the efuses, empty if they aren't known
+    pub fn efuse_fields(&self) -> &'static [EfuseField] {
+        match self {
+            Chip::Esp32 => Esp32::EFUSE_FIELDS,
+            Chip::Esp32c3 => Esp32c3::EFUSE_FIELDS,
+            Chip::Esp32s2 => Esp32s2::EFUSE_FIELDS,
+            Chip::Esp8266 => Esp8266::EFUSE_FIELDS,
+        }
+    }
+
+    /// The MAC addresses of the network interfaces, derived from the base MAC
+    /// address like ESP-IDF does with its default number of universally
+    /// administered addresses
+    pub fn derived_macs(&self, base: [u8; 6]) -> Vec<(&'static str, [u8; 6])> {
+        let offset = |offset: u8| {
+            let mut mac = base;
+            mac[5] = mac[5].wrapping_add(offset);
+            mac
+        };
+
+        match self {
+            Chip::Esp32 => vec![
+                ("Wi-Fi station", base),
+                ("Wi-Fi access point", offset(1)),
+                ("Bluetooth", offset(2)),
+                ("Ethernet", offset(3)),
+            ],
+            Chip::Esp32c3 => vec![
+                ("Wi-Fi station", base),
+                ("Wi-Fi access point", offset(1)),
+                ("Bluetooth", offset(2)),
+            ],
+            Chip::Esp32s2 => vec![("Wi-Fi station", base), ("Wi-Fi access point", offset(1))],
+            // the access point uses the locally administered variant
+            Chip::Esp8266 => {
+                let mut access_point = base;
+                access_point[0] |= 0x02;
+                vec![
+                    ("Wi-Fi station", base),
+                    ("Wi-Fi access point", access_point),
+                ]
+            }
+        }
+    }
+
+    /// The efuse field named `name`, ignoring case
+    pub fn efuse_field(&self, name: &str) -> Option<&'static EfuseField> {
+        self.efuse_fields()
+            .iter()
+            .find(|field| field.name.eq_ignore_ascii_case(name))
+    }
+
+    /// The controller burning the efuses, `None` if burning isn't supported
+    pub fn efuse_controller(&self) -> Option<EfuseController> {
+        match self {
+            Chip::Esp32 => Esp32::EFUSE_CONTROLLER,
+            Chip::Esp32c3 => Esp32c3::EFUSE_CONTROLLER,
+            Chip::Esp32s2 => Esp32s2::EFUSE_CONTROLLER,
+            Chip::Esp8266 => Esp8266::EFUSE_CONTROLLER,
+        }
+    }
+
+    /// Efuse read registers to read for [`Chip::features`]
+    pub fn feature_efuse_regs(&self) -> &'static [u32] {
+        match self {
+            Chip::Esp32 => Esp32::FEATURE_EFUSE_REGS,
+            Chip::Esp32c3 => Esp32c3::FEATURE_EFUSE_REGS,
+            Chip::Esp32s2 => Esp32s2::FEATURE_EFUSE_REGS,
+            Chip::Esp8266 => Esp8266::FEATURE_EFUSE_REGS,
+        }
+    }
+
+    /// The package and features of the chip, starting with the package
+    pub fn features(&self, efuses: &Efuses) -> Vec<Feature> {
+        match self {
+            Chip::Esp32 => Esp32::features(efuses),
+            Chip::Esp32c3 => Esp32c3::features(efuses),
+            Chip::Esp32s2 => Esp32s2::features(efuses),
+            Chip::Esp8266 => Esp8266::features(efuses),
+        }
+    }
+
+    #[cfg(feature = "serial")]
+    pub(crate) fn crystal_frequency(
+        &self,
+        read_reg: &mut dyn FnMut(u32) -> Result<u32, Error>,
+        baud: u32,
+    ) -> Result<u32, Error> {
+        match self {
+            Chip::Esp32 => Esp32::crystal_frequency(read_reg, baud),
+            Chip::Esp32c3 => Esp32c3::crystal_frequency(read_reg, baud),
+            Chip::Esp32s2 => Esp32s2::crystal_frequency(read_reg, baud),
+            Chip::Esp8266 => Esp8266::crystal_frequency(read_reg, baud),
+        }
+    }
+
+    #[cfg(feature = "serial")]
+    pub fn ram_target(&self, entry: Option<u32>) -> Box<dyn FlashTarget> {
+        Box::new(RamTarget::new(entry))
+    }
+
+    #[cfg(feature = "serial")]
+    pub fn flash_target(
+        &self,
+        spi_params: SpiAttachParams,
+        compress: bool,
+    ) -> Box<dyn FlashTarget> {
         match self {
             Chip::Esp8266 => Box::new(Esp8266Target::new()),
-            _ => Box::new(Esp32Target::new(*self, spi_params)),
+            _ => Box::new(Esp32Target::new(*self, spi_params, compress)),
         }
     }
 }
 
+/// Check that a user supplied bootloader is an image for the chip with the
+/// given id
+pub(crate) fn validate_bootloader(bootloader: &[u8], chip_id: u16) -> Result<(), Error> {
+    let header_len = size_of::<EspCommonHeader>() + size_of::<ExtendedHeader>();
+    if bootloader.len() < header_len {
+        return Err(BootloaderError::TooShort.into());
+    }
+    if bootloader[0] != ESP_MAGIC {
+        return Err(BootloaderError::InvalidMagic(bootloader[0]).into());
+    }
+
+    let extended_header: ExtendedHeader =
+        bytemuck::pod_read_unaligned(&bootloader[size_of::<EspCommonHeader>()..header_len]);
+    if extended_header.chip_id != chip_id {
+        return Err(BootloaderError::WrongChip {
+            expected: chip_id,
+            found: extended_header.chip_id,
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Check whether a bootloader, which has already been validated, has a
+/// SHA-256 digest appended to it
+pub(crate) fn appends_digest(bootloader: &[u8]) -> bool {
+    let header_len = size_of::<EspCommonHeader>() + size_of::<ExtendedHeader>();
+    let extended_header: ExtendedHeader =
+        bytemuck::pod_read_unaligned(&bootloader[size_of::<EspCommonHeader>()..header_len]);
+
+    extended_header.append_digest == 1
+}
+
 #[derive(Copy, Clone, Zeroable, Pod, Debug)]
 #[repr(C)]
 struct EspCommonHeader {
@@ -190,28 +723,44 @@ fn encode_flash_size(size: FlashSize) -> Result<u8, FlashDetectError> {
         FlashSize::Flash4Mb => Ok(0x20),
         FlashSize::Flash8Mb => Ok(0x30),
         FlashSize::Flash16Mb => Ok(0x40),
+        FlashSize::Flash32Mb => Ok(0x50),
+        FlashSize::Flash64Mb => Ok(0x60),
+        FlashSize::Flash128Mb => Ok(0x70),
         FlashSize::FlashRetry => Err(FlashDetectError::from(size as u8)),
     }
 }
 
-const IROM_ALIGN: u32 = 65536;
+fn decode_flash_size(value: u8) -> Option<FlashSize> {
+    match value {
+        0x00 => Some(FlashSize::Flash1Mb),
+        0x10 => Some(FlashSize::Flash2Mb),
+        0x20 => Some(FlashSize::Flash4Mb),
+        0x30 => Some(FlashSize::Flash8Mb),
+        0x40 => Some(FlashSize::Flash16Mb),
+        0x50 => Some(FlashSize::Flash32Mb),
+        0x60 => Some(FlashSize::Flash64Mb),
+        0x70 => Some(FlashSize::Flash128Mb),
+        _ => None,
+    }
+}
+
 const SEG_HEADER_LEN: u32 = 8;
 
 /// Actual alignment (in data bytes) required for a segment header: positioned
-/// so that after we write the next 8 byte header, file_offs % IROM_ALIGN ==
-/// segment.addr % IROM_ALIGN
+/// so that after we write the next 8 byte header, file_offs % page_size ==
+/// segment.addr % page_size
 ///
-/// (this is because the segment's vaddr may not be IROM_ALIGNed, more likely is
-/// aligned IROM_ALIGN+0x18 to account for the binary file header
-fn get_segment_padding(offset: usize, segment: &CodeSegment) -> u32 {
-    let align_past = (segment.addr - SEG_HEADER_LEN) % IROM_ALIGN;
-    let pad_len = ((IROM_ALIGN - ((offset as u32) % IROM_ALIGN)) + align_past) % IROM_ALIGN;
-    if pad_len == 0 || pad_len == IROM_ALIGN {
+/// (this is because the segment's vaddr may not be page aligned, more likely
+/// is aligned to the page size + 0x18 to account for the binary file header
+fn get_segment_padding(offset: usize, segment: &CodeSegment, page_size: u32) -> u32 {
+    let align_past = segment.addr.wrapping_sub(SEG_HEADER_LEN) % page_size;
+    let pad_len = ((page_size - ((offset as u32) % page_size)) + align_past) % page_size;
+    if pad_len == 0 || pad_len == page_size {
         0
     } else if pad_len > SEG_HEADER_LEN {
         pad_len - SEG_HEADER_LEN
     } else {
-        pad_len + IROM_ALIGN - SEG_HEADER_LEN
+        pad_len + page_size - SEG_HEADER_LEN
     }
 }
 
@@ -219,13 +768,14 @@ fn save_flash_segment(
     data: &mut Vec<u8>,
     segment: &CodeSegment,
     checksum: u8,
+    layout: &SegmentLayout,
 ) -> Result<u8, Error> {
     let end_pos = (data.len() + segment.data().len()) as u32 + SEG_HEADER_LEN;
-    let segment_reminder = end_pos % IROM_ALIGN;
+    let segment_reminder = end_pos % layout.mmu_page_size;
 
-    let checksum = save_segment(data, segment, checksum)?;
+    let checksum = save_segment(data, segment, checksum, layout.segment_alignment)?;
 
-    if segment_reminder < 0x24 {
+    if layout.pad_page_tail && segment_reminder < 0x24 {
         // Work around a bug in ESP-IDF 2nd stage bootloader, that it didn't map the
         // last MMU page, if an IROM/DROM segment was < 0x24 bytes over the page
         // boundary.
@@ -234,8 +784,13 @@ fn save_flash_segment(
     Ok(checksum)
 }
 
-fn save_segment(data: &mut Vec<u8>, segment: &CodeSegment, checksum: u8) -> Result<u8, Error> {
-    let padding = (4 - segment.size() % 4) % 4;
+fn save_segment(
+    data: &mut Vec<u8>,
+    segment: &CodeSegment,
+    checksum: u8,
+    alignment: u32,
+) -> Result<u8, Error> {
+    let padding = (alignment - segment.size() % alignment) % alignment;
 
     let header = SegmentHeader {
         addr: segment.addr,
@@ -243,9 +798,179 @@ fn save_segment(data: &mut Vec<u8>, segment: &CodeSegment, checksum: u8) -> Resu
     };
     data.write_all(bytes_of(&header))?;
     data.write_all(segment.data())?;
-
-    let padding = &[0u8; 4][0..padding as usize];
-    data.write_all(padding)?;
+    data.resize(data.len() + padding as usize, 0);
 
     Ok(update_checksum(segment.data(), checksum))
 }
+
+#[test]
+fn test_validate_bootloader() {
+    let bootloader = include_bytes!("../../bootloader/esp32c3-bootloader.bin");
+
+    assert!(validate_bootloader(bootloader, 5).is_ok());
+    assert!(matches!(
+        validate_bootloader(bootloader, 0),
+        Err(Error::InvalidBootloader(BootloaderError::WrongChip {
+            expected: 0,
+            found: 5
+        }))
+    ));
+    assert!(matches!(
+        validate_bootloader(&[0; 4], 5),
+        Err(Error::InvalidBootloader(BootloaderError::TooShort))
+    ));
+    assert!(matches!(
+        validate_bootloader(&[0; 32], 5),
+        Err(Error::InvalidBootloader(BootloaderError::InvalidMagic(0)))
+    ));
+}
+
+#[test]
+fn test_validate_flash_offsets() {
+    let defaults = esp32::DEFAULT_OFFSETS;
+    assert!(defaults.validate(&defaults).is_ok());
+
+    let valid = FlashOffsets {
+        partition_table: 0x9000,
+        app: 0x20000,
+        ..defaults
+    };
+    assert!(valid.validate(&defaults).is_ok());
+
+    for invalid in [
+        FlashOffsets {
+            bootloader: 0x0,
+            ..defaults
+        },
+        FlashOffsets {
+            partition_table: 0x8800,
+            ..defaults
+        },
+        FlashOffsets {
+            app: 0x18000,
+            ..defaults
+        },
+        FlashOffsets {
+            partition_table: 0x10000,
+            ..defaults
+        },
+    ] {
+        assert!(matches!(
+            invalid.validate(&defaults),
+            Err(Error::InvalidFlashOffset { .. })
+        ));
+    }
+}
+
+#[test]
+fn test_spi_clock_value() {
+    assert_eq!(1 << 31, Chip::Esp32.spi_registers().clock_value(1));
+    // 20MHz from the 80MHz APB clock
+    assert_eq!(0x3043, Chip::Esp32.spi_registers().clock_value(4));
+    assert_eq!(0x30103, Chip::Esp32c3.spi_registers().clock_value(4));
+}
+
+#[test]
+fn test_derived_macs() {
+    let base = [0x24, 0x0a, 0xc4, 0x12, 0x34, 0xff];
+    let macs = Chip::Esp32.derived_macs(base);
+    assert_eq!(4, macs.len());
+    assert_eq!(("Ethernet", [0x24, 0x0a, 0xc4, 0x12, 0x34, 0x02]), macs[3]);
+    assert_eq!(2, Chip::Esp32s2.derived_macs(base).len());
+    assert_eq!(
+        [0x26, 0x0a, 0xc4, 0x12, 0x34, 0xff],
+        Chip::Esp8266.derived_macs(base)[1].1
+    );
+}
+
+#[cfg(feature = "serial")]
+#[test]
+fn test_user_command() {
+    let registers = Chip::Esp8266.spi_registers();
+    // READ has no dummy phase, which clears the one set up for the ROM
+    assert_eq!(
+        vec![
+            (registers.usr(), 1 << 31 | 1 << 28 | 1 << 27, None),
+            (registers.usr1(), 255 << 8 | 23 << 17, Some(!0xff)),
+        ],
+        registers.user_command(3, 256, 0)
+    );
+    // FAST_READ with the configured dummy cycles
+    assert_eq!(
+        vec![
+            (registers.usr(), 1 << 31 | 1 << 29 | 1 << 28 | 1 << 27, None),
+            (registers.usr1(), 9, Some(0xff)),
+            (registers.usr1(), 255 << 8 | 23 << 17, Some(!0xff)),
+        ],
+        registers.user_command(3, 256, 10)
+    );
+
+    let registers = Chip::Esp32c3.spi_registers();
+    assert_eq!(
+        vec![
+            (registers.usr(), 1 << 31 | 1 << 29 | 1 << 28 | 1 << 27, None),
+            (registers.usr1(), 7, Some(0x3f)),
+            (registers.mosi_length().unwrap(), 23, None),
+            (registers.miso_length().unwrap(), 255, None),
+        ],
+        registers.user_command(3, 256, 8)
+    );
+    assert_eq!(
+        vec![(registers.usr(), 1 << 31, None)],
+        registers.user_command(0, 0, 0)
+    );
+}
+
+#[test]
+fn test_flash_size_encoding() {
+    for size in [
+        FlashSize::Flash1Mb,
+        FlashSize::Flash16Mb,
+        FlashSize::Flash32Mb,
+        FlashSize::Flash64Mb,
+        FlashSize::Flash128Mb,
+    ] {
+        assert_eq!(
+            Some(size),
+            decode_flash_size(encode_flash_size(size).unwrap())
+        );
+    }
+    assert!(esp8266::encode_flash_size(FlashSize::Flash32Mb).is_err());
+
+    #[cfg(feature = "serial")]
+    {
+        assert_eq!(FlashSize::Flash32Mb, FlashSize::from(0x19).unwrap());
+        assert_eq!(FlashSize::Flash64Mb, FlashSize::from(0x20).unwrap());
+        assert_eq!(FlashSize::Flash16Mb, FlashSize::from(0x38).unwrap());
+    }
+    assert_eq!("128MB".parse(), Ok(FlashSize::Flash128Mb));
+}
+
+#[test]
+fn test_update_image_header() {
+    use crate::elf::{FlashFrequency, FlashMode};
+
+    let mut bootloader = include_bytes!("../../bootloader/esp32-bootloader.bin").to_vec();
+    let settings = FlashSettings {
+        mode: Some(FlashMode::Qio),
+        frequency: Some(FlashFrequency::Flash80M),
+        size: Some(FlashSize::Flash16Mb),
+        ..FlashSettings::default()
+    };
+    assert!(Chip::Esp32
+        .update_image_header(&mut bootloader, &settings)
+        .unwrap());
+
+    let info = ImageInfo::parse(&bootloader, Chip::Esp32).unwrap();
+    assert_eq!(Some(FlashMode::Qio), info.flash_mode);
+    assert_eq!(Some(FlashFrequency::Flash80M), info.flash_frequency);
+    assert_eq!(Some(FlashSize::Flash16Mb), info.flash_size);
+    assert!(info.checksum_valid);
+    assert_eq!(Some(true), info.digest_valid);
+
+    let mut data = vec![0xff; 64];
+    assert!(!Chip::Esp32
+        .update_image_header(&mut data, &settings)
+        .unwrap());
+    assert_eq!(vec![0xff; 64], data);
+}
@@ -0,0 +1,206 @@
+//! Commands understood by the ROM serial bootloader
+//!
+//! See the [serial protocol] documentation from Espressif for the details of
+//! each command and the layout of its payload.
+//!
+//! [serial protocol]: https://docs.espressif.com/projects/esptool/en/latest/esp32/advanced-topics/serial-protocol.html
+
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString};
+
+use std::convert::{TryFrom, TryInto};
+use std::time::Duration;
+
+/// Timeout used for commands which don't have a more specific one
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+/// Timeout per megabyte of flash to erase when beginning a flash operation
+pub const ERASE_REGION_TIMEOUT_PER_MB: Duration = Duration::from_secs(30);
+/// Timeout per megabyte of (decompressed) data written to flash
+pub const ERASE_WRITE_TIMEOUT_PER_MB: Duration = Duration::from_secs(40);
+/// Timeout per megabyte of flash to calculate the MD5 digest of
+pub const MD5_TIMEOUT_PER_MB: Duration = Duration::from_secs(8);
+/// Timeout for the `MemEnd` command, the device might not respond at all if
+/// it jumps to the loaded code
+pub const MEM_END_TIMEOUT: Duration = Duration::from_millis(50);
+/// Timeout for a single `Sync` attempt
+pub const SYNC_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// A command opcode as sent to the ROM bootloader
+///
+/// Commands are named in kebab-case, e.g. `flash-begin`, both when displayed
+/// and when serialized.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Display, EnumString, Serialize, Deserialize)]
+#[repr(u8)]
+#[non_exhaustive]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case", ascii_case_insensitive)]
+pub enum Command {
+    /// Placeholder for an unknown opcode
+    Unknown = 0,
+    /// Begin a flash download, erasing the target region
+    FlashBegin = 0x02,
+    /// Write a block of data to flash
+    FlashData = 0x03,
+    /// Finish a flash download, optionally rebooting the device
+    FlashEnd = 0x04,
+    /// Begin a download to RAM
+    MemBegin = 0x05,
+    /// Finish a download to RAM, optionally jumping to an entry point
+    MemEnd = 0x06,
+    /// Write a block of data to RAM
+    MemData = 0x07,
+    /// Synchronize with the bootloader and detect the baud rate
+    Sync = 0x08,
+    /// Write a 32 bit memory address
+    WriteReg = 0x09,
+    /// Read a 32 bit memory address
+    ReadReg = 0x0a,
+    /// Configure the geometry of the attached SPI flash
+    SpiSetParams = 0x0B,
+    /// Attach the SPI flash, selecting the pins it is connected to
+    SpiAttach = 0x0D,
+    /// Change the baud rate used by the bootloader
+    ChangeBaud = 0x0F,
+    /// Begin a compressed flash download
+    FlashDeflateBegin = 0x10,
+    /// Write a block of compressed data to flash
+    FlashDeflateData = 0x11,
+    /// Finish a compressed flash download
+    FlashDeflateEnd = 0x12,
+    /// Calculate the MD5 of a flash region
+    FlashMd5 = 0x13,
+    /// SPI flash `RDID` opcode, sent to the flash chip itself rather than the
+    /// bootloader
+    FlashDetect = 0x9f,
+}
+
+impl Command {
+    /// The timeout to use when waiting for the response to this command
+    pub fn timeout(&self) -> Duration {
+        match self {
+            Command::MemEnd => MEM_END_TIMEOUT,
+            Command::Sync => SYNC_TIMEOUT,
+            _ => DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// The timeout to use for this command when it operates on `size` bytes
+    pub fn timeout_for_size(&self, size: u32) -> Duration {
+        fn calc_timeout(timeout_per_mb: Duration, size: u32) -> Duration {
+            let mb = size as f64 / 1_000_000.0;
+            std::cmp::max(
+                DEFAULT_TIMEOUT,
+                Duration::from_millis((timeout_per_mb.as_millis() as f64 * mb) as u64),
+            )
+        }
+        match self {
+            Command::FlashBegin | Command::FlashDeflateBegin => {
+                calc_timeout(ERASE_REGION_TIMEOUT_PER_MB, size)
+            }
+            Command::FlashData | Command::FlashDeflateData => {
+                calc_timeout(ERASE_WRITE_TIMEOUT_PER_MB, size)
+            }
+            Command::FlashMd5 => calc_timeout(MD5_TIMEOUT_PER_MB, size),
+            _ => self.timeout(),
+        }
+    }
+
+    /// The timeout to use for this command sent with `payload`
+    ///
+    /// Scales with the size of the region erased by the begin commands, the
+    /// data block written by the data commands and the region hashed by
+    /// `FlashMd5`, and includes the delay requested with `WriteReg`.
+    pub fn timeout_for_payload(&self, payload: &[u8]) -> Duration {
+        let word = |index: usize| {
+            payload
+                .get(index * 4..index * 4 + 4)
+                .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+                .unwrap_or_default()
+        };
+        match self {
+            Command::FlashBegin | Command::FlashDeflateBegin => self.timeout_for_size(word(0)),
+            Command::FlashData | Command::FlashDeflateData => {
+                self.timeout_for_size(payload.len().saturating_sub(16) as u32)
+            }
+            Command::FlashMd5 => self.timeout_for_size(word(1)),
+            Command::WriteReg => self.timeout() + Duration::from_micros(word(3) as u64),
+            _ => self.timeout(),
+        }
+    }
+}
+
+impl TryFrom<u8> for Command {
+    type Error = u8;
+
+    fn try_from(opcode: u8) -> Result<Self, Self::Error> {
+        match opcode {
+            0x02 => Ok(Command::FlashBegin),
+            0x03 => Ok(Command::FlashData),
+            0x04 => Ok(Command::FlashEnd),
+            0x05 => Ok(Command::MemBegin),
+            0x06 => Ok(Command::MemEnd),
+            0x07 => Ok(Command::MemData),
+            0x08 => Ok(Command::Sync),
+            0x09 => Ok(Command::WriteReg),
+            0x0a => Ok(Command::ReadReg),
+            0x0b => Ok(Command::SpiSetParams),
+            0x0d => Ok(Command::SpiAttach),
+            0x0f => Ok(Command::ChangeBaud),
+            0x10 => Ok(Command::FlashDeflateBegin),
+            0x11 => Ok(Command::FlashDeflateData),
+            0x12 => Ok(Command::FlashDeflateEnd),
+            0x13 => Ok(Command::FlashMd5),
+            0x9f => Ok(Command::FlashDetect),
+            _ => Err(opcode),
+        }
+    }
+}
+
+#[test]
+fn test_command_opcode_round_trip() {
+    for command in [
+        Command::FlashBegin,
+        Command::SpiAttach,
+        Command::FlashMd5,
+        Command::FlashDetect,
+    ] {
+        assert_eq!(Ok(command), Command::try_from(command as u8));
+    }
+    assert_eq!(Err(0x01), Command::try_from(0x01));
+}
+
+#[test]
+fn test_command_names() {
+    for command in [
+        Command::FlashBegin,
+        Command::SpiSetParams,
+        Command::FlashMd5,
+    ] {
+        let name = command.to_string();
+        assert_eq!(
+            format!("\"{}\"", name),
+            serde_json::to_string(&command).unwrap()
+        );
+        assert_eq!(Ok(command), name.parse());
+    }
+    assert_eq!("flash-deflate-data", Command::FlashDeflateData.to_string());
+}
+
+#[test]
+fn test_payload_timeouts() {
+    let erase_16mb = [16 * 1024 * 1024u32, 4096, 0x400, 0];
+    let payload: Vec<u8> = erase_16mb.iter().flat_map(|w| w.to_le_bytes()).collect();
+    assert!(Command::FlashBegin.timeout_for_payload(&payload) > Duration::from_secs(480));
+    assert_eq!(
+        Command::FlashBegin.timeout_for_payload(&[]),
+        DEFAULT_TIMEOUT
+    );
+    assert_eq!(Command::Sync.timeout_for_payload(&payload), SYNC_TIMEOUT);
+
+    let delayed = [0u32, 0, 0xffff_ffff, 2_000_000];
+    let payload: Vec<u8> = delayed.iter().flat_map(|w| w.to_le_bytes()).collect();
+    assert_eq!(
+        Command::WriteReg.timeout_for_payload(&payload),
+        DEFAULT_TIMEOUT + Duration::from_secs(2)
+    );
+}
@@ -1,27 +1,238 @@
+use crate::reset::ResetSequence;
+use crate::{FlashFrequency, FlashMode, FlashSize};
 use directories_next::ProjectDirs;
-use serde::Deserialize;
-use std::fs::read;
+use serde::{de, Deserialize, Deserializer};
+use std::env::current_dir;
+use std::fmt::Display;
+use std::fs::{create_dir_all, read, write};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Name of the config file, both in the user config dir and in projects
+const FILE_NAME: &str = "espflash.toml";
 
 #[derive(Debug, Deserialize, Default)]
 pub struct Config {
     #[serde(default)]
     pub connection: Connection,
+    #[serde(default)]
+    pub flash: Flash,
 }
 
 #[derive(Debug, Deserialize, Default)]
 pub struct Connection {
     pub serial: Option<String>,
+    /// Baud rate to flash with
+    pub baud: Option<usize>,
+    /// How long to retry opening the serial port for, in seconds
+    pub retry_open: Option<f64>,
+    /// USB serial number of the device to use when several serial ports are
+    /// detected
+    pub usb_serial: Option<String>,
+    /// Steps resetting the chip into the bootloader, for boards the default
+    /// sequence doesn't work with
+    #[serde(default, deserialize_with = "from_str")]
+    pub reset_sequence: Option<ResetSequence>,
+    /// GPIO line of the host wired to the `EN` pin of the chip
+    pub gpio_en: Option<u32>,
+    /// GPIO line of the host wired to the `IO0` pin of the chip
+    pub gpio_io0: Option<u32>,
+}
+
+/// Flash settings and offsets overriding the defaults of the chip
+#[derive(Debug, Deserialize, Default)]
+pub struct Flash {
+    #[serde(default, deserialize_with = "from_str")]
+    pub mode: Option<FlashMode>,
+    #[serde(default, deserialize_with = "from_str")]
+    pub frequency: Option<FlashFrequency>,
+    #[serde(default, deserialize_with = "from_str")]
+    pub size: Option<FlashSize>,
+    /// Bootloader image to flash
+    pub bootloader: Option<PathBuf>,
+    /// Partition table CSV or binary to flash
+    pub partition_table: Option<PathBuf>,
+    pub bootloader_offset: Option<u32>,
+    pub partition_table_offset: Option<u32>,
+    pub app_offset: Option<u32>,
+    /// SPI clock divider for boards with slow flash
+    pub spi_clock_div: Option<u8>,
+    /// Dummy cycles before reading from flash
+    pub dummy_cycles: Option<u8>,
 }
 
 impl Config {
-    /// Load the config from config file
+    /// Load the config from the user config file, overridden by the
+    /// `espflash.toml` of the project the current directory is in
+    ///
+    /// Relative paths in the project config are relative to its directory.
     pub fn load() -> Self {
+        let mut config = read_value(&Self::path()).unwrap_or_else(empty_table);
+
+        if let Some(path) = Self::project_path() {
+            if let Some(mut project) = read_value(&path) {
+                resolve_paths(&mut project, path.parent().unwrap());
+                merge(&mut config, project);
+            }
+        }
+
+        config.try_into().unwrap()
+    }
+
+    /// Remember the USB serial number of the device to use in the config file,
+    /// keeping the other settings
+    pub fn save_usb_serial(usb_serial: &str) -> io::Result<()> {
+        let path = Self::path();
+        let mut config: toml::Value = match read(&path) {
+            Ok(data) => toml::from_slice(&data)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => empty_table(),
+            Err(err) => return Err(err),
+        };
+        set_usb_serial(&mut config, usb_serial)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid config file"))?;
+
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir)?;
+        }
+        write(&path, config.to_string())
+    }
+
+    fn path() -> PathBuf {
         let dirs = ProjectDirs::from("rs", "esp", "espflash").unwrap();
-        let file = dirs.config_dir().join("espflash.toml");
-        if let Ok(data) = read(&file) {
-            toml::from_slice(&data).unwrap()
-        } else {
-            Self::default()
+        dirs.config_dir().join(FILE_NAME)
+    }
+
+    /// The `espflash.toml` in the current directory or the closest of its
+    /// ancestors
+    fn project_path() -> Option<PathBuf> {
+        current_dir()
+            .ok()?
+            .ancestors()
+            .map(|dir| dir.join(FILE_NAME))
+            .find(|path| path.is_file())
+    }
+}
+
+fn read_value(path: &Path) -> Option<toml::Value> {
+    let data = read(path).ok()?;
+    Some(toml::from_slice(&data).unwrap())
+}
+
+fn empty_table() -> toml::Value {
+    toml::Value::Table(Default::default())
+}
+
+/// Merge the tables of `other` into `config`, the values of `other` taking
+/// precedence
+fn merge(config: &mut toml::Value, other: toml::Value) {
+    match (config, other) {
+        (toml::Value::Table(config), toml::Value::Table(other)) => {
+            for (key, value) in other {
+                match config.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        config.insert(key, value);
+                    }
+                }
+            }
+        }
+        (config, other) => *config = other,
+    }
+}
+
+/// Make the paths of the `[flash]` section relative to `dir`
+fn resolve_paths(config: &mut toml::Value, dir: &Path) {
+    let flash = match config.get_mut("flash").and_then(toml::Value::as_table_mut) {
+        Some(flash) => flash,
+        None => return,
+    };
+
+    for key in ["bootloader", "partition_table"] {
+        if let Some(toml::Value::String(path)) = flash.get_mut(key) {
+            *path = dir.join(&*path).to_string_lossy().into_owned();
         }
     }
 }
+
+fn from_str<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| value.parse().map_err(de::Error::custom))
+        .transpose()
+}
+
+fn set_usb_serial(config: &mut toml::Value, usb_serial: &str) -> Option<()> {
+    let connection = config
+        .as_table_mut()?
+        .entry("connection")
+        .or_insert_with(|| toml::Value::Table(Default::default()));
+    connection
+        .as_table_mut()?
+        .insert("usb_serial".into(), usb_serial.into());
+    Some(())
+}
+
+#[test]
+fn test_set_usb_serial() {
+    let mut config: toml::Value = toml::from_str(
+        r#"
+        [flash]
+        app_offset = 0x20000
+        "#,
+    )
+    .unwrap();
+    set_usb_serial(&mut config, "0001").unwrap();
+
+    let config: Config = toml::from_str(&config.to_string()).unwrap();
+    assert_eq!(Some("0001"), config.connection.usb_serial.as_deref());
+    assert_eq!(Some(0x20000), config.flash.app_offset);
+}
+
+#[test]
+fn test_merge() {
+    let mut config: toml::Value = toml::from_str(
+        r#"
+        [connection]
+        serial = "/dev/ttyUSB0"
+        baud = 460800
+
+        [flash]
+        bootloader = "/opt/bootloader.bin"
+        "#,
+    )
+    .unwrap();
+    let mut project: toml::Value = toml::from_str(
+        r#"
+        [connection]
+        baud = 921600
+
+        [flash]
+        mode = "dio"
+        size = "4MB"
+        partition_table = "partitions.csv"
+        "#,
+    )
+    .unwrap();
+    resolve_paths(&mut project, Path::new("project"));
+    merge(&mut config, project);
+
+    let config: Config = config.try_into().unwrap();
+    assert_eq!(Some("/dev/ttyUSB0"), config.connection.serial.as_deref());
+    assert_eq!(Some(921600), config.connection.baud);
+    assert_eq!(Some(FlashMode::Dio), config.flash.mode);
+    assert_eq!(Some(FlashSize::Flash4Mb), config.flash.size);
+    assert_eq!(
+        Some(Path::new("/opt/bootloader.bin")),
+        config.flash.bootloader.as_deref()
+    );
+    assert_eq!(
+        Some(Path::new("project/partitions.csv")),
+        config.flash.partition_table.as_deref()
+    );
+}
@@ -1,18 +1,147 @@
-use std::io::Write;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use crate::command::Command;
 use crate::encoder::SlipEncoder;
 use crate::error::{ConnectionError, Error, ResultExt, RomError};
-use crate::flasher::Command;
+use crate::flasher::{checksum, CHECKSUM_INIT};
+use crate::journal::Journal;
+use crate::port;
+use crate::reset::ResetSequence;
 use binread::io::Cursor;
 use binread::{BinRead, BinReaderExt};
-use serial::{BaudRate, SerialPort, SerialPortSettings, SystemPort};
+use serial::{BaudRate, SerialPort, SystemPort};
 use slip_codec::Decoder;
 
+/// Byte stream to the chip, with the control lines used to reset it
+///
+/// Implemented for local serial ports and the `tcp://` and `rfc2217://` ports
+/// of network serial servers, see [`port::open`]. Other transports, like USB
+/// CDC devices, PTYs or mocks, only need reads which time out. Without control
+/// lines the chip has to be in the bootloader already, use
+/// [`ResetBeforeOperation::NoReset`](crate::ResetBeforeOperation::NoReset).
+pub trait Transport: Read + Write {
+    /// How long reads wait for data before failing with
+    /// [`io::ErrorKind::TimedOut`]
+    fn timeout(&self) -> Duration;
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()>;
+
+    /// Fails with [`io::ErrorKind::Unsupported`] unless implemented
+    fn set_baud(&mut self, _baud: usize) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "The transport doesn't support changing the baud rate",
+        ))
+    }
+
+    /// Does nothing unless implemented
+    fn set_dtr(&mut self, _level: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Does nothing unless implemented
+    fn set_rts(&mut self, _level: bool) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn timeout(&self) -> Duration {
+        (**self).timeout()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        (**self).set_timeout(timeout)
+    }
+
+    fn set_baud(&mut self, baud: usize) -> io::Result<()> {
+        (**self).set_baud(baud)
+    }
+
+    fn set_dtr(&mut self, level: bool) -> io::Result<()> {
+        (**self).set_dtr(level)
+    }
+
+    fn set_rts(&mut self, level: bool) -> io::Result<()> {
+        (**self).set_rts(level)
+    }
+}
+
+impl Transport for SystemPort {
+    fn timeout(&self) -> Duration {
+        SerialPort::timeout(self)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        Ok(SerialPort::set_timeout(self, timeout)?)
+    }
+
+    fn set_baud(&mut self, baud: usize) -> io::Result<()> {
+        Ok(port::set_baud(self, baud)?)
+    }
+
+    fn set_dtr(&mut self, level: bool) -> io::Result<()> {
+        Ok(SerialPort::set_dtr(self, level)?)
+    }
+
+    fn set_rts(&mut self, level: bool) -> io::Result<()> {
+        Ok(SerialPort::set_rts(self, level)?)
+    }
+}
+
+/// Flag for cancelling flashing and reading from another thread
+///
+/// The operation stops with [`Error::Cancelled`] before the next block is
+/// sent, leaving the loader ready for further commands. A command already
+/// sent runs to completion, like the erase done by the begin command of a
+/// segment.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Connection to the ROM bootloader or the flasher stub
+///
+/// [`Connection::command`] gives access to the loader commands espflash has no
+/// higher level methods for, see [`Flasher::connection`](crate::Flasher::connection).
 pub struct Connection {
-    serial: SystemPort,
+    serial: Box<dyn Transport>,
     decoder: Decoder,
+    journal: Option<Journal>,
+    cancel: Option<CancelToken>,
+    pipelined: bool,
+    pending: VecDeque<PendingBlock>,
+    usb_jtag_serial: bool,
+}
+
+/// Length of the header in front of the data of a response
+const HEADER_LENGTH: usize = 8;
+/// Number of data blocks in flight at most when pipelining
+const PIPELINE_WINDOW: usize = 2;
+
+/// A data block whose response wasn't read yet
+struct PendingBlock {
+    command: Command,
+    size: u16,
+    sent: Instant,
+    timeout: Duration,
 }
 
 #[derive(Debug, Copy, Clone, BinRead)]
@@ -26,38 +155,119 @@ pub struct CommandResponse {
 }
 
 impl Connection {
-    pub fn new(serial: SystemPort) -> Self {
+    pub fn new(serial: Box<dyn Transport>) -> Self {
         Connection {
             serial,
             decoder: Decoder::new(),
+            journal: None,
+            cancel: None,
+            pipelined: false,
+            pending: VecDeque::new(),
+            usb_jtag_serial: false,
+        }
+    }
+
+    pub fn set_journal(&mut self, journal: Option<Journal>) {
+        self.journal = journal;
+    }
+
+    pub fn set_cancel_token(&mut self, cancel: Option<CancelToken>) {
+        self.cancel = cancel;
+    }
+
+    /// Whether the port is the USB-JTAG-serial peripheral of the chip, which
+    /// is reset differently and ignores baud rate changes
+    pub fn set_usb_jtag_serial(&mut self, usb_jtag_serial: bool) {
+        self.usb_jtag_serial = usb_jtag_serial;
+    }
+
+    pub fn is_usb_jtag_serial(&self) -> bool {
+        self.usb_jtag_serial
+    }
+
+    /// Send the next data block while waiting for the response to the
+    /// previous one
+    ///
+    /// This keeps the line busy while the loader writes to flash, which speeds
+    /// up flashing at high baud rates. The loader has to buffer the block
+    /// meanwhile, so this is off by default.
+    pub fn set_pipelined(&mut self, pipelined: bool) {
+        self.pipelined = pipelined;
+    }
+
+    /// Fail with [`Error::Cancelled`] if the operation was cancelled
+    pub(crate) fn check_cancelled(&self) -> Result<(), Error> {
+        match &self.cancel {
+            Some(cancel) if cancel.is_cancelled() => Err(Error::Cancelled),
+            _ => Ok(()),
+        }
+    }
+
+    /// Record a command which was sent at `sent` in the journal, if enabled,
+    /// and trace it
+    pub fn record<T>(
+        &mut self,
+        command: Command,
+        size: u16,
+        sent: Instant,
+        result: &Result<T, Error>,
+    ) {
+        match result {
+            Ok(_) => log::trace!("{} with {} bytes: ok", command, size),
+            Err(err) => log::trace!("{} with {} bytes: {}", command, size, err),
+        }
+        if let Some(journal) = &mut self.journal {
+            let error = result.as_ref().err().map(ToString::to_string);
+            journal.record(command, size as u32, sent, error);
         }
     }
 
     pub fn reset(&mut self) -> Result<(), Error> {
         sleep(Duration::from_millis(100));
 
+        port::hard_reset(self.serial.as_mut(), self.usb_jtag_serial)
+    }
+
+    pub fn reset_to_flash(&mut self) -> Result<(), Error> {
         self.serial.set_dtr(false)?;
         self.serial.set_rts(true)?;
 
         sleep(Duration::from_millis(100));
 
+        self.serial.set_dtr(true)?;
         self.serial.set_rts(false)?;
 
+        sleep(Duration::from_millis(50));
+
+        self.serial.set_dtr(false)?;
+
         Ok(())
     }
 
-    pub fn reset_to_flash(&mut self) -> Result<(), Error> {
-        self.serial.set_dtr(false)?;
-        self.serial.set_rts(true)?;
+    /// Reset into the bootloader with the steps of a custom `sequence`
+    pub fn run_reset_sequence(&mut self, sequence: &ResetSequence) -> Result<(), Error> {
+        sequence.run(self.serial.as_mut())?;
+        Ok(())
+    }
 
+    /// Reset into the bootloader through the USB-JTAG-serial peripheral, which
+    /// maps `DTR` and `RTS` to the boot and reset signals of the chip
+    pub fn usb_reset_to_flash(&mut self) -> Result<(), Error> {
+        self.serial.set_rts(false)?;
+        self.serial.set_dtr(false)?;
         sleep(Duration::from_millis(100));
 
         self.serial.set_dtr(true)?;
         self.serial.set_rts(false)?;
+        sleep(Duration::from_millis(100));
 
-        sleep(Duration::from_millis(50));
+        self.serial.set_rts(true)?;
+        self.serial.set_dtr(false)?;
+        self.serial.set_rts(true)?;
+        sleep(Duration::from_millis(100));
 
         self.serial.set_dtr(false)?;
+        self.serial.set_rts(false)?;
 
         Ok(())
     }
@@ -68,8 +278,7 @@ impl Connection {
     }
 
     pub fn set_baud(&mut self, speed: BaudRate) -> Result<(), Error> {
-        self.serial
-            .reconfigure(&|setup: &mut dyn SerialPortSettings| setup.set_baud_rate(speed))?;
+        self.serial.set_baud(speed.speed())?;
         Ok(())
     }
 
@@ -97,10 +306,10 @@ impl Connection {
         Ok(Some(header))
     }
 
-    pub fn write_command(
+    pub(crate) fn write_command(
         &mut self,
         command: u8,
-        data: impl LazyBytes<SystemPort>,
+        data: impl LazyBytes<Box<dyn Transport>>,
         check: u32,
     ) -> Result<(), Error> {
         let mut encoder = SlipEncoder::new(&mut self.serial)?;
@@ -113,28 +322,161 @@ impl Connection {
         Ok(())
     }
 
-    pub fn command<Data: LazyBytes<SystemPort>>(
+    /// Send a command to the loader and return the value field of its
+    /// response
+    ///
+    /// The payload is SLIP framed and the data block following the 16 byte
+    /// header of the `*Data` commands is checksummed. Errors reported by the
+    /// loader are returned as [`Error::RomError`].
+    ///
+    /// The response is waited for as long as [`Command::timeout_for_payload`],
+    /// or the current timeout of the port if that is longer.
+    pub fn command(&mut self, command: Command, payload: &[u8]) -> Result<u32, Error> {
+        let check = data_checksum(command, payload);
+        self.with_timeout(self.command_timeout(command, payload), |connection| {
+            connection.command_with_check(command, payload, check)
+        })
+    }
+
+    fn command_timeout(&self, command: Command, payload: &[u8]) -> Duration {
+        command
+            .timeout_for_payload(payload)
+            .max(self.serial.timeout())
+    }
+
+    pub(crate) fn command_with_check<Data: LazyBytes<Box<dyn Transport>>>(
         &mut self,
         command: Command,
         data: Data,
         check: u32,
     ) -> Result<u32, Error> {
+        let size = data.length();
+        let sent = Instant::now();
+        let result = self
+            .send_command(command, data, check, 0)
+            .map(|(value, _)| value);
+        self.record(command, size, sent, &result);
+        result
+    }
+
+    /// Send a data block and wait up to `timeout` for its response, or only
+    /// once the window of blocks in flight is full when pipelining
+    pub(crate) fn block_command<Data: LazyBytes<Box<dyn Transport>>>(
+        &mut self,
+        command: Command,
+        data: Data,
+        check: u32,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        if !self.pipelined {
+            let old_timeout = self.serial.timeout();
+            self.serial.set_timeout(timeout)?;
+            let result = self.command_with_check(command, data, check);
+            self.serial.set_timeout(old_timeout)?;
+            return result.map(drop);
+        }
+
+        let size = data.length();
+        let sent = Instant::now();
+        if let Err(err) = self.write_command(command as u8, data, check) {
+            let result = Err(err).for_command(command);
+            self.record(command, size, sent, &result);
+            self.pending.clear();
+            return result;
+        }
+        self.pending.push_back(PendingBlock {
+            command,
+            size,
+            sent,
+            timeout,
+        });
+
+        while self.pending.len() >= PIPELINE_WINDOW {
+            self.acknowledge_block()?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the responses to all data blocks in flight
+    pub(crate) fn finish_blocks(&mut self) -> Result<(), Error> {
+        while !self.pending.is_empty() {
+            self.acknowledge_block()?;
+        }
+        Ok(())
+    }
+
+    fn acknowledge_block(&mut self) -> Result<(), Error> {
+        let block = match self.pending.pop_front() {
+            Some(block) => block,
+            None => return Ok(()),
+        };
+        let result = self
+            .with_timeout(block.timeout, |connection| {
+                connection.read_command_response(block.command, 0)
+            })
+            .map(drop);
+        self.record(block.command, block.size, block.sent, &result);
+        if result.is_err() {
+            self.pending.clear();
+        }
+        result
+    }
+
+    /// Send a command whose response carries `response_length` bytes of data
+    /// in front of the status bytes, and return that data
+    pub fn command_with_data(
+        &mut self,
+        command: Command,
+        payload: &[u8],
+        response_length: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let size = payload.len() as u16;
+        let sent = Instant::now();
+        let check = data_checksum(command, payload);
+        let result = self
+            .with_timeout(self.command_timeout(command, payload), |connection| {
+                connection.send_command(command, payload, check, response_length)
+            })
+            .map(|(_, data)| data);
+        self.record(command, size, sent, &result);
+        result
+    }
+
+    fn send_command<Data: LazyBytes<Box<dyn Transport>>>(
+        &mut self,
+        command: Command,
+        data: Data,
+        check: u32,
+        response_length: usize,
+    ) -> Result<(u32, Vec<u8>), Error> {
+        self.finish_blocks()?;
         self.write_command(command as u8, data, check)
             .for_command(command)?;
+        self.read_command_response(command, response_length)
+    }
 
+    fn read_command_response(
+        &mut self,
+        command: Command,
+        response_length: usize,
+    ) -> Result<(u32, Vec<u8>), Error> {
         for _ in 0..100 {
-            match self.read_response().for_command(command)? {
-                Some(response) if response.return_op == command as u8 => {
-                    if response.status == 1 {
-                        let _error = self.flush();
-                        return Err(Error::RomError(RomError::from(response.error)));
-                    } else {
-                        return Ok(response.value);
-                    }
-                }
-                _ => {
-                    continue;
-                }
+            let response = self.read().for_command(command)?;
+            if response.len() < HEADER_LENGTH + response_length + 2 {
+                continue;
+            }
+
+            let header: CommandResponse = Cursor::new(&response).read_le()?;
+            if header.return_op != command as u8 {
+                continue;
+            }
+
+            let (data, status) = response[HEADER_LENGTH..].split_at(response_length);
+            if status[0] == 1 {
+                let _error = self.flush();
+                return Err(Error::RomError(RomError::from(status[1])));
+            } else {
+                return Ok((header.value, data.to_vec()));
             }
         }
         Err(Error::Connection(ConnectionError::ConnectionFailed))
@@ -151,9 +493,26 @@ impl Connection {
         Ok(())
     }
 
-    pub fn into_serial(self) -> SystemPort {
+    pub fn into_serial(self) -> Box<dyn Transport> {
         self.serial
     }
+
+    /// Continue over `serial`, after the port was reopened
+    pub(crate) fn set_serial(&mut self, serial: Box<dyn Transport>) {
+        self.serial = serial;
+        self.decoder = Decoder::new();
+        self.pending.clear();
+    }
+}
+
+/// Checksum of the data block of the commands carrying one
+fn data_checksum(command: Command, payload: &[u8]) -> u32 {
+    match (command, payload.get(16..)) {
+        (Command::FlashData | Command::MemData | Command::FlashDeflateData, Some(data)) => {
+            checksum(data, CHECKSUM_INIT) as u32
+        }
+        _ => 0,
+    }
 }
 
 pub trait LazyBytes<W: Write> {
@@ -182,3 +541,106 @@ impl<W: Write, F: Fn(&mut SlipEncoder<W>) -> Result<(), Error>> LazyBytes<W> for
         self.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers with the bytes given, then times out
+    struct MockTransport(Cursor<Vec<u8>>);
+
+    impl Read for MockTransport {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.read(buf)? {
+                0 => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")),
+                count => Ok(count),
+            }
+        }
+    }
+
+    impl Write for MockTransport {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for MockTransport {
+        fn timeout(&self) -> Duration {
+            Duration::from_millis(10)
+        }
+
+        fn set_timeout(&mut self, _timeout: Duration) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn response(status: [u8; 2]) -> Box<dyn Transport> {
+        let mut frame = vec![0xc0, 0x01, Command::ReadReg as u8, 0x02, 0x00];
+        frame.extend_from_slice(&0x1234_5678u32.to_le_bytes());
+        frame.extend_from_slice(&status);
+        frame.push(0xc0);
+        Box::new(MockTransport(Cursor::new(frame)))
+    }
+
+    #[test]
+    fn mock_transport() {
+        let mut connection = Connection::new(response([0, 0]));
+        let value = connection
+            .command(Command::ReadReg, &0x6000_0000u32.to_le_bytes())
+            .unwrap();
+        assert_eq!(value, 0x1234_5678);
+
+        let mut connection = Connection::new(response([1, 0x05]));
+        assert!(matches!(
+            connection.command(Command::ReadReg, &0x6000_0000u32.to_le_bytes()),
+            Err(Error::RomError(RomError::InvalidMessage))
+        ));
+        assert!(connection.set_baud(BaudRate::from_speed(921_600)).is_err());
+
+        let cancel = CancelToken::new();
+        connection.set_cancel_token(Some(cancel.clone()));
+        assert!(connection.check_cancelled().is_ok());
+        cancel.cancel();
+        assert!(matches!(
+            connection.check_cancelled(),
+            Err(Error::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn pipelined_blocks() {
+        let mut frames = Vec::new();
+        for _ in 0..3 {
+            frames.extend_from_slice(&[0xc0, 0x01, Command::FlashData as u8, 0x02, 0x00]);
+            frames.extend_from_slice(&[0; 4]);
+            frames.extend_from_slice(&[0, 0, 0xc0]);
+        }
+        let mut connection = Connection::new(Box::new(MockTransport(Cursor::new(frames))));
+        connection.set_pipelined(true);
+
+        for _ in 0..3 {
+            connection
+                .block_command(Command::FlashData, &[0; 20][..], 0, Duration::from_secs(1))
+                .unwrap();
+        }
+        assert_eq!(connection.pending.len(), PIPELINE_WINDOW - 1);
+        connection.finish_blocks().unwrap();
+        assert!(connection.pending.is_empty());
+    }
+
+    #[test]
+    fn data_checksums() {
+        let mut payload = vec![0; 16];
+        payload.extend_from_slice(&[0x01, 0x02, 0xff]);
+        assert_eq!(
+            data_checksum(Command::MemData, &payload),
+            0xef ^ 0x01 ^ 0x02 ^ 0xff
+        );
+        assert_eq!(data_checksum(Command::ReadReg, &payload), 0);
+        assert_eq!(data_checksum(Command::FlashData, &[0; 4]), 0);
+    }
+}
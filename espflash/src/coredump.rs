@@ -0,0 +1,209 @@
+//! ESP-IDF core dumps, printed on the UART or saved to the coredump partition
+//!
+//! A core dump starts with a header giving its length and format version. In
+//! the ELF formats the header is followed by an ELF core file and a checksum,
+//! the ELF is extracted so it can be loaded into GDB or `espcoredump.py`
+//! directly. Dumps in the legacy binary format are kept as they are, for
+//! `espcoredump.py --core-format raw`.
+
+use crate::error::CoreDumpError;
+#[cfg(feature = "serial")]
+use crate::{flasher::Flasher, partition_table::PartitionTable, Error};
+use sha2::{Digest, Sha256};
+use std::convert::TryInto;
+use std::fs::write;
+use std::io;
+use std::path::Path;
+
+/// Line printed before the base64 encoded core dump on the UART
+pub const UART_START: &str = "CORE DUMP START";
+/// Line printed after the base64 encoded core dump on the UART
+pub const UART_END: &str = "CORE DUMP END";
+
+/// Size of the header, the length, version, number of tasks, size of a task
+/// control block and number of memory segments
+const HEADER_SIZE: usize = 20;
+
+/// Major version of the ELF formats
+const VERSION_ELF: u32 = 1;
+/// Minor version of the ELF format with a SHA256 checksum instead of CRC32
+const VERSION_ELF_SHA256: u32 = 1;
+
+#[derive(Debug)]
+pub struct CoreDump {
+    version: u32,
+    data: Vec<u8>,
+}
+
+impl CoreDump {
+    /// Parse a core dump, checking the checksum of the ELF formats
+    pub fn from_bytes(data: &[u8]) -> Result<Self, CoreDumpError> {
+        if data.len() < HEADER_SIZE {
+            return Err(CoreDumpError::Truncated);
+        }
+        let len = u32::from_le_bytes(data[0..4].try_into().unwrap());
+        if len == u32::MAX {
+            return Err(CoreDumpError::Empty);
+        }
+        let len = len as usize;
+        if len < HEADER_SIZE || len > data.len() {
+            return Err(CoreDumpError::Truncated);
+        }
+
+        let dump = CoreDump {
+            version: u32::from_le_bytes(data[4..8].try_into().unwrap()),
+            data: data[..len].to_vec(),
+        };
+        if dump.is_elf() {
+            if len < HEADER_SIZE + dump.checksum_len() {
+                return Err(CoreDumpError::Truncated);
+            }
+            let (content, checksum) = dump.data.split_at(len - dump.checksum_len());
+            let valid = if dump.minor_version() == VERSION_ELF_SHA256 {
+                Sha256::digest(content)[..] == *checksum
+            } else {
+                crc32fast::hash(content).to_le_bytes() == checksum
+            };
+            if !valid {
+                return Err(CoreDumpError::ChecksumMismatch);
+            }
+        }
+
+        Ok(dump)
+    }
+
+    /// Parse a core dump printed on the UART, the base64 encoded lines between
+    /// [`UART_START`] and [`UART_END`]
+    pub fn from_base64(text: &str) -> Result<Self, CoreDumpError> {
+        Self::from_bytes(&decode_base64(text).ok_or(CoreDumpError::InvalidBase64)?)
+    }
+
+    fn major_version(&self) -> u32 {
+        (self.version >> 8) & 0xff
+    }
+
+    fn minor_version(&self) -> u32 {
+        self.version & 0xff
+    }
+
+    fn checksum_len(&self) -> usize {
+        if self.minor_version() == VERSION_ELF_SHA256 {
+            32
+        } else {
+            4
+        }
+    }
+
+    /// Whether the dump contains an ELF core file
+    pub fn is_elf(&self) -> bool {
+        self.major_version() == VERSION_ELF
+    }
+
+    /// The ELF core file, for dumps in the ELF formats
+    pub fn elf(&self) -> Option<&[u8]> {
+        if !self.is_elf() || self.data.len() < HEADER_SIZE + self.checksum_len() {
+            return None;
+        }
+        Some(&self.data[HEADER_SIZE..self.data.len() - self.checksum_len()])
+    }
+
+    /// The extension of the file [`CoreDump::save`] writes
+    pub fn extension(&self) -> &'static str {
+        if self.is_elf() {
+            "elf"
+        } else {
+            "bin"
+        }
+    }
+
+    /// Save the ELF core file, or the raw dump if it is in the binary format
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        write(path, self.elf().unwrap_or(&self.data))
+    }
+}
+
+/// Read the core dump saved to the coredump partition of the device
+#[cfg(feature = "serial")]
+pub fn read_from_flash(flasher: &mut Flasher, table: &PartitionTable) -> Result<CoreDump, Error> {
+    let (offset, size) = table.coredump().ok_or(CoreDumpError::NoPartition)?;
+
+    let header = flasher.read_flash(offset, HEADER_SIZE as u32)?;
+    let len = match u32::from_le_bytes(header[0..4].try_into().unwrap()) {
+        u32::MAX => return Err(CoreDumpError::Empty.into()),
+        len if len > size || (len as usize) < HEADER_SIZE => {
+            return Err(CoreDumpError::Truncated.into())
+        }
+        len => len,
+    };
+
+    let data = flasher.read_flash(offset, len)?;
+    Ok(CoreDump::from_bytes(&data)?)
+}
+
+/// Decode standard base64, ignoring whitespace
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::with_capacity(text.len() * 3 / 4);
+    let mut bits = 0u32;
+    let mut count = 0;
+
+    for c in text.bytes().filter(|c| !c.is_ascii_whitespace()) {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => return None,
+        };
+        bits = (bits << 6) | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            data.push((bits >> count) as u8);
+        }
+    }
+
+    Some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn elf_dump(checksum: Option<[u8; 4]>) -> Vec<u8> {
+        let elf = b"\x7fELF core";
+        let len = (HEADER_SIZE + elf.len() + 4) as u32;
+        let mut data = Vec::new();
+        data.extend_from_slice(&len.to_le_bytes());
+        data.extend_from_slice(&0x0000_0100u32.to_le_bytes());
+        data.extend_from_slice(&[0; 12]);
+        data.extend_from_slice(elf);
+        let crc = crc32fast::hash(&data).to_le_bytes();
+        data.extend_from_slice(&checksum.unwrap_or(crc));
+        data
+    }
+
+    #[test]
+    fn elf_format() {
+        let dump = CoreDump::from_bytes(&elf_dump(None)).unwrap();
+        assert!(dump.is_elf());
+        assert_eq!(dump.elf().unwrap(), b"\x7fELF core");
+
+        assert!(matches!(
+            CoreDump::from_bytes(&elf_dump(Some([0; 4]))),
+            Err(CoreDumpError::ChecksumMismatch)
+        ));
+        assert!(matches!(
+            CoreDump::from_bytes(&[0xff; 32]),
+            Err(CoreDumpError::Empty)
+        ));
+    }
+
+    #[test]
+    fn base64() {
+        assert_eq!(decode_base64("aGVs\nbG8=").unwrap(), b"hello");
+        assert_eq!(decode_base64("AAEC/w==").unwrap(), vec![0, 1, 2, 255]);
+        assert_eq!(decode_base64("a*b"), None);
+    }
+}
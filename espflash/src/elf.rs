@@ -1,17 +1,22 @@
 use std::borrow::Cow;
 use std::cmp::Ordering;
 
+use crate::app_desc::AppDescriptorOverrides;
 use crate::chip::Chip;
-use crate::flasher::FlashSize;
+#[cfg(feature = "serial")]
+use crate::error::FlashDetectError;
+use crate::Error;
 use std::fmt::{Debug, Formatter};
 use std::mem::take;
+use strum_macros::{Display, EnumString};
 use xmas_elf::sections::{SectionData, ShType};
 use xmas_elf::ElfFile;
 
 pub const ESP_CHECKSUM_MAGIC: u8 = 0xef;
 
-#[derive(Copy, Clone)]
-#[allow(dead_code)]
+/// SPI mode used to access the flash, as set in the image header
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Display, EnumString)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
 pub enum FlashMode {
     Qio,
     Qout,
@@ -19,22 +24,177 @@ pub enum FlashMode {
     Dout,
 }
 
-#[derive(Copy, Clone)]
+/// SPI clock frequency used to access the flash, as set in the image header
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Display, EnumString)]
 #[repr(u8)]
-#[allow(dead_code)]
+#[strum(ascii_case_insensitive)]
 pub enum FlashFrequency {
+    #[strum(serialize = "40m")]
     Flash40M = 0,
+    #[strum(serialize = "26m")]
     Flash26M = 1,
+    #[strum(serialize = "20m")]
     Flash20M = 2,
+    #[strum(serialize = "80m")]
     Flash80M = 0xf,
 }
 
+/// Options controlling the layout of the segments in generated images
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SegmentLayout {
+    /// Alignment of the length of each segment
+    pub segment_alignment: u32,
+    /// Size of the MMU pages flash segments are mapped with, flash segments are
+    /// placed so that their offset in flash matches their address modulo the
+    /// page size
+    pub mmu_page_size: u32,
+    /// Pad flash segments ending less than 0x24 bytes past a page boundary,
+    /// working around older ESP-IDF bootloaders not mapping the last page
+    pub pad_page_tail: bool,
+}
+
+impl Default for SegmentLayout {
+    fn default() -> Self {
+        SegmentLayout {
+            segment_alignment: 4,
+            mmu_page_size: 0x10000,
+            pad_page_tail: true,
+        }
+    }
+}
+
+impl SegmentLayout {
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        if self.segment_alignment < 4 || !self.segment_alignment.is_power_of_two() {
+            return Err(Error::InvalidSegmentLayout(
+                "segment alignment must be a power of two of at least 4 bytes",
+            ));
+        }
+        if self.mmu_page_size < 0x100 || !self.mmu_page_size.is_power_of_two() {
+            return Err(Error::InvalidSegmentLayout(
+                "MMU page size must be a power of two of at least 256 bytes",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Display, EnumString)]
+#[allow(dead_code)]
+#[repr(u8)]
+#[strum(ascii_case_insensitive)]
+pub enum FlashSize {
+    #[strum(serialize = "256KB")]
+    Flash256Kb = 0x12,
+    #[strum(serialize = "512KB")]
+    Flash512Kb = 0x13,
+    #[strum(serialize = "1MB")]
+    Flash1Mb = 0x14,
+    #[strum(serialize = "2MB")]
+    Flash2Mb = 0x15,
+    #[strum(serialize = "4MB")]
+    Flash4Mb = 0x16,
+    #[strum(serialize = "8MB")]
+    Flash8Mb = 0x17,
+    #[strum(serialize = "16MB")]
+    Flash16Mb = 0x18,
+    #[strum(serialize = "32MB")]
+    Flash32Mb = 0x19,
+    #[strum(serialize = "64MB")]
+    Flash64Mb = 0x1A,
+    #[strum(serialize = "128MB")]
+    Flash128Mb = 0x1B,
+    #[strum(disabled)]
+    FlashRetry = 0xFF, // used to hint that alternate detection should be tried
+}
+
+impl FlashSize {
+    /// Map the capacity byte of the JEDEC flash id to the flash size
+    ///
+    /// Besides the usual `log2(size)` encoding, some vendors use `0x20` and up
+    /// for their larger parts and `0x32` and up for their low voltage parts.
+    #[cfg(feature = "serial")]
+    pub(crate) fn from(value: u8) -> Result<FlashSize, FlashDetectError> {
+        match value {
+            0x12 | 0x32 => Ok(FlashSize::Flash256Kb),
+            0x13 | 0x33 => Ok(FlashSize::Flash512Kb),
+            0x14 | 0x34 => Ok(FlashSize::Flash1Mb),
+            0x15 | 0x35 => Ok(FlashSize::Flash2Mb),
+            0x16 | 0x36 => Ok(FlashSize::Flash4Mb),
+            0x17 | 0x37 => Ok(FlashSize::Flash8Mb),
+            0x18 | 0x38 => Ok(FlashSize::Flash16Mb),
+            0x19 | 0x39 => Ok(FlashSize::Flash32Mb),
+            0x1A | 0x20 | 0x3A => Ok(FlashSize::Flash64Mb),
+            0x1B | 0x21 => Ok(FlashSize::Flash128Mb),
+            0xFF => Ok(FlashSize::FlashRetry),
+            _ => Err(FlashDetectError::from(value)),
+        }
+    }
+
+    /// The size of the flash in bytes
+    pub fn size(self) -> u32 {
+        match self {
+            FlashSize::Flash256Kb => 0x40000,
+            FlashSize::Flash512Kb => 0x80000,
+            FlashSize::Flash1Mb => 0x100000,
+            FlashSize::Flash2Mb => 0x200000,
+            FlashSize::Flash4Mb => 0x400000,
+            FlashSize::Flash8Mb => 0x800000,
+            FlashSize::Flash16Mb => 0x1000000,
+            FlashSize::Flash32Mb => 0x2000000,
+            FlashSize::Flash64Mb => 0x4000000,
+            FlashSize::Flash128Mb => 0x8000000,
+            FlashSize::FlashRetry => 0,
+        }
+    }
+}
+
+/// Flash parameters written to the image header and offsets of the
+/// bootloader, partition table and application, overriding the detected or
+/// default values
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct FlashSettings {
+    pub mode: Option<FlashMode>,
+    pub frequency: Option<FlashFrequency>,
+    pub size: Option<FlashSize>,
+    pub bootloader_offset: Option<u32>,
+    pub partition_table_offset: Option<u32>,
+    pub app_offset: Option<u32>,
+}
+
+impl FlashSettings {
+    pub(crate) fn apply(&self, image: &mut FirmwareImage) {
+        if let Some(mode) = self.mode {
+            image.flash_mode = mode;
+        }
+        if let Some(frequency) = self.frequency {
+            image.flash_frequency = frequency;
+        }
+        if let Some(size) = self.size {
+            image.flash_size = size;
+        }
+        image.bootloader_offset = self.bootloader_offset;
+        image.partition_table_offset = self.partition_table_offset;
+        image.app_offset = self.app_offset;
+    }
+}
+
 pub struct FirmwareImage<'a> {
     pub entry: u32,
     pub elf: ElfFile<'a>,
     pub flash_mode: FlashMode,
     pub flash_size: FlashSize,
     pub flash_frequency: FlashFrequency,
+    pub app_desc: AppDescriptorOverrides,
+    /// Offset of the bootloader, if it differs from the chip's default
+    pub bootloader_offset: Option<u32>,
+    /// Offset of the partition table, if it differs from the chip's default
+    pub partition_table_offset: Option<u32>,
+    /// Offset of the application, if it differs from the boot partition or the
+    /// chip's default
+    pub app_offset: Option<u32>,
+    pub segment_layout: SegmentLayout,
 }
 
 impl<'a> FirmwareImage<'a> {
@@ -49,6 +209,11 @@ impl<'a> FirmwareImage<'a> {
             flash_mode: FlashMode::Dio,
             flash_size: FlashSize::Flash4Mb,
             flash_frequency: FlashFrequency::Flash40M,
+            app_desc: AppDescriptorOverrides::default(),
+            bootloader_offset: None,
+            partition_table_offset: None,
+            app_offset: None,
+            segment_layout: SegmentLayout::default(),
         }
     }
 
@@ -172,7 +337,7 @@ impl PartialEq for CodeSegment<'_> {
 
 impl PartialOrd for CodeSegment<'_> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.addr.partial_cmp(&other.addr)
+        Some(self.cmp(other))
     }
 }
 
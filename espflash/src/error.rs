@@ -1,9 +1,13 @@
-use crate::flasher::Command;
+use crate::board_info::FlashId;
+use crate::command::Command;
+use crate::Chip;
 use csv::Position;
-use miette::{Diagnostic, SourceOffset, SourceSpan};
+use miette::{Diagnostic, LabeledSpan, SourceOffset, SourceSpan};
+#[cfg(feature = "serial")]
 use slip_codec::Error as SlipError;
 use std::fmt::{Display, Formatter};
 use std::io;
+use strum_macros::Display;
 use thiserror::Error;
 
 #[derive(Error, Debug, Diagnostic)]
@@ -36,15 +40,272 @@ pub enum Error {
         help("If your chip is supported, try hard-resetting the device and try again")
     )]
     UnrecognizedChip(#[from] ChipDetectError),
-    #[error("Flash chip not supported, flash sizes from 1 to 16MB are supported")]
+    #[error("Flash chip not supported, flash sizes from 256KB to 128MB are supported")]
     #[diagnostic(code(espflash::unrecognized_flash))]
     UnsupportedFlash(#[from] FlashDetectError),
+    #[error("No serial port of a known USB-serial bridge found")]
+    #[diagnostic(
+        code(espflash::no_serial_port),
+        help("Pass the serial port or set it in the config file, `espflash list-ports` shows the available ports")
+    )]
+    NoSerialPort,
+    #[error("No serial port of a known USB-serial bridge found, the serial ports are: {0}")]
+    #[diagnostic(
+        code(espflash::unknown_serial_ports),
+        help("Pass the serial port of the board or set it in the config file")
+    )]
+    UnknownSerialPorts(String),
+    #[error("Failed to list the serial ports: {0}")]
+    #[diagnostic(
+        code(espflash::list_serial_ports),
+        help("Pass the serial port or set it in the config file")
+    )]
+    ListSerialPorts(String),
+    #[error("Several serial ports found: {0}")]
+    #[diagnostic(
+        code(espflash::ambiguous_serial_port),
+        help("Pass the serial port or set it in the config file")
+    )]
+    AmbiguousSerialPort(String),
+    #[error("Refusing to downgrade {project_name} {current} to {new}")]
+    #[diagnostic(
+        code(espflash::downgrade_aborted),
+        help("Use `--force` to downgrade without confirmation")
+    )]
+    DowngradeAborted {
+        project_name: String,
+        current: String,
+        new: String,
+    },
     #[error("Failed to connect to on-device flash")]
     #[diagnostic(code(espflash::flash_connect))]
     FlashConnect,
     #[error(transparent)]
     #[diagnostic(transparent)]
     MalformedPartitionTable(#[from] PartitionTableError),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    InvalidPartitionTable(#[from] InvalidPartitionTableError),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    AppTooLarge(#[from] AppTooLargeError),
+    #[error("Malformed binary partition table")]
+    #[diagnostic(
+        code(espflash::malformed_binary_partition_table),
+        help("Make sure the file was generated by gen_esp32part.py or espflash")
+    )]
+    MalformedBinaryPartitionTable(#[from] BinaryPartitionTableError),
+    #[error("Malformed JSON or YAML partition table")]
+    #[diagnostic(
+        code(espflash::malformed_structured_partition_table),
+        help("Each partition needs a name, type, subtype, offset and size")
+    )]
+    MalformedStructuredPartitionTable(#[from] StructuredPartitionTableError),
+    #[error("Supplied bootloader image is not valid")]
+    #[diagnostic(
+        code(espflash::invalid_bootloader),
+        help("Make sure the bootloader was built for the chip being flashed")
+    )]
+    InvalidBootloader(#[from] BootloaderError),
+    #[error("Supplied image is not valid")]
+    #[diagnostic(
+        code(espflash::invalid_image),
+        help("Make sure the file is an application or bootloader image, not a merged image")
+    )]
+    InvalidImage(#[from] ImageError),
+    #[error("Invalid {name} offset {offset:#x}, {reason}")]
+    #[diagnostic(
+        code(espflash::invalid_flash_offset),
+        help(
+            "Check the offsets passed on the command line, in the sdkconfig or in the config file"
+        )
+    )]
+    InvalidFlashOffset {
+        name: &'static str,
+        offset: u32,
+        reason: &'static str,
+    },
+    #[error("Invalid flash timing: {0}")]
+    #[diagnostic(code(espflash::invalid_flash_timing))]
+    InvalidFlashTiming(&'static str),
+    #[error("Invalid segment layout: {0}")]
+    #[diagnostic(code(espflash::invalid_segment_layout))]
+    InvalidSegmentLayout(&'static str),
+    #[error("Secure boot key must be 24 or 32 bytes long, found {0} bytes")]
+    #[diagnostic(
+        code(espflash::invalid_secure_boot_key),
+        help("Use the raw 256 bit key, or the 192 bit key when using the 3/4 coding scheme")
+    )]
+    InvalidSecureBootKey(usize),
+    #[error("Failed to read the sdkconfig")]
+    #[diagnostic(
+        code(espflash::invalid_sdkconfig),
+        help("Make sure the file is an sdkconfig or sdkconfig.json generated by ESP-IDF")
+    )]
+    InvalidSdkConfig(#[from] SdkConfigError),
+    #[error("Failed to read the flasher arguments")]
+    #[diagnostic(
+        code(espflash::invalid_flasher_args),
+        help("Make sure the file is the flasher_args.json generated by ESP-IDF in the build directory")
+    )]
+    InvalidFlasherArgs(#[from] FlasherArgsError),
+    #[error("Failed to generate the NVS partition")]
+    #[diagnostic(
+        code(espflash::invalid_nvs),
+        help("Check the NVS CSV file, it uses the same format as ESP-IDF's nvs_partition_gen.py")
+    )]
+    InvalidNvs(#[from] NvsError),
+    #[error("Failed to generate the filesystem image")]
+    #[diagnostic(
+        code(espflash::invalid_fs_image),
+        help("Check that the files fit into the partition and that their paths are short enough")
+    )]
+    InvalidFsImage(#[from] FsImageError),
+    #[error("Failed to select the boot partition")]
+    #[diagnostic(
+        code(espflash::invalid_ota),
+        help("Check that the partition table matches the one on the device")
+    )]
+    InvalidOta(#[from] OtaError),
+    #[error("Failed to update the device over the network")]
+    #[diagnostic(
+        code(espflash::espota),
+        help("Make sure the device runs ArduinoOTA or another espota server, and that incoming connections aren't blocked by a firewall")
+    )]
+    Espota(#[from] EspotaError),
+    #[error("Failed to read the core dump")]
+    #[diagnostic(
+        code(espflash::invalid_coredump),
+        help("Core dumps are saved by ESP-IDF when `CONFIG_ESP_COREDUMP_ENABLE` is set, to flash or the UART")
+    )]
+    InvalidCoreDump(#[from] CoreDumpError),
+    #[error("Failed to burn the efuses")]
+    #[diagnostic(
+        code(espflash::efuse),
+        help("Efuses can only be burned from 0 to 1, check the current values with `espflash efuse summary`")
+    )]
+    Efuse(#[from] EfuseError),
+    #[error("Failed to set up flash encryption")]
+    #[diagnostic(
+        code(espflash::flash_encryption),
+        help("Keys are 256 bit, or 512 bit for XTS-AES-256 on the ESP32-S2, check the efuses with `espflash efuse summary`")
+    )]
+    FlashEncryption(#[from] FlashEncryptionError),
+    #[error("Segment at {0:#x} overlaps with the previous segment")]
+    #[diagnostic(
+        code(espflash::overlapping_segments),
+        help("Ensure that the bootloader, partition table and application don't overlap")
+    )]
+    OverlappingSegments(u32),
+    #[error("Image requires {required} bytes but only {size} bytes are available")]
+    #[diagnostic(
+        code(espflash::image_too_large),
+        help("Select a larger size for the image")
+    )]
+    ImageTooLarge { required: u32, size: u32 },
+    #[error("Invalid monitor exit key '{0}'")]
+    #[diagnostic(
+        code(espflash::invalid_exit_key),
+        help("Use a single letter other than 'r', which resets the chip")
+    )]
+    InvalidExitKey(String),
+    #[error("Invalid log filter `{0}`")]
+    #[diagnostic(
+        code(espflash::invalid_log_filter),
+        help("Filters are written as `tag=level`, like `wifi=warn,*=info`, with the levels none, error, warn, info, debug and verbose")
+    )]
+    InvalidLogFilter(String),
+    #[error("Invalid address to serve the serial port on `{0}`")]
+    #[diagnostic(
+        code(espflash::invalid_serve_address),
+        help("Use `host:port` for raw TCP or `rfc2217://host:port` for RFC2217")
+    )]
+    InvalidServeAddress(String),
+    #[error("The flash stays write protected, its status register is {0:#06x}")]
+    #[diagnostic(
+        code(espflash::flash_protected),
+        help("The status register is likely locked by its protection bit and the `WP` pin of the flash being held low")
+    )]
+    FlashProtected(u16),
+    #[error("Invalid reset step `{0}`")]
+    #[diagnostic(
+        code(espflash::invalid_reset_step),
+        help("Steps are `dtr=0`, `dtr=1`, `rts=0`, `rts=1` or `sleep` with a duration like `100ms`, separated by commas")
+    )]
+    InvalidResetStep(String),
+    #[error("Expected an {expected}, but an {found} is connected")]
+    #[diagnostic(
+        code(espflash::chip_mismatch),
+        help("Make sure the right device is connected to the serial port")
+    )]
+    ChipMismatch { expected: Chip, found: Chip },
+    #[error("Invalid esptool arguments: {0}")]
+    #[diagnostic(
+        code(espflash::invalid_esptool_args),
+        help("The esptool commands write_flash, read_flash, read_mac, chip_id, flash_id, run and image_info are supported")
+    )]
+    InvalidEsptoolArgs(String),
+    #[error("The operation was cancelled")]
+    #[diagnostic(code(espflash::cancelled))]
+    Cancelled,
+}
+
+impl Error {
+    /// Classify why connecting to or probing a device failed
+    pub fn probe_failure(&self) -> ProbeFailure {
+        match self {
+            Error::Connection(err) | Error::Flashing(err) => match err {
+                ConnectionError::DeviceNotFound => ProbeFailure::PortNotFound,
+                ConnectionError::Serial(_) => ProbeFailure::SerialError,
+                ConnectionError::ConnectionFailed | ConnectionError::Timeout(_) => {
+                    ProbeFailure::NoResponse
+                }
+                ConnectionError::FramingError
+                | ConnectionError::OverSizedPacket
+                | ConnectionError::InvalidResponse => ProbeFailure::CommunicationError,
+            },
+            Error::RomError(_) => ProbeFailure::CommunicationError,
+            Error::UnrecognizedChip(_) => ProbeFailure::UnrecognizedChip,
+            Error::NoSerialPort | Error::UnknownSerialPorts(_) | Error::AmbiguousSerialPort(_) => {
+                ProbeFailure::PortNotFound
+            }
+            _ => ProbeFailure::Other,
+        }
+    }
+}
+
+/// Classification of a failure to probe a device
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Display)]
+#[strum(serialize_all = "kebab-case")]
+#[non_exhaustive]
+pub enum ProbeFailure {
+    /// The serial port doesn't exist
+    PortNotFound,
+    /// The serial port couldn't be opened or configured
+    SerialError,
+    /// The device didn't respond, it might not be in download mode
+    NoResponse,
+    /// The device responded with invalid or error responses
+    CommunicationError,
+    /// The device responded, but its chip type isn't supported
+    UnrecognizedChip,
+    /// Any other failure
+    Other,
+}
+
+impl ProbeFailure {
+    /// A distinct exit code for each kind of failure, so scripts can react
+    /// without parsing the output
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ProbeFailure::PortNotFound => 2,
+            ProbeFailure::SerialError => 3,
+            ProbeFailure::NoResponse => 4,
+            ProbeFailure::CommunicationError => 5,
+            ProbeFailure::UnrecognizedChip => 6,
+            ProbeFailure::Other => 1,
+        }
+    }
 }
 
 #[derive(Error, Debug, Diagnostic)]
@@ -52,7 +313,7 @@ pub enum Error {
 pub enum ConnectionError {
     #[error("IO error while using serial port: {0}")]
     #[diagnostic(code(espflash::serial_error))]
-    Serial(#[source] serial::core::Error),
+    Serial(#[source] io::Error),
     #[error("Failed to connect to the device")]
     #[diagnostic(
         code(espflash::connection_failed),
@@ -80,38 +341,62 @@ pub enum ConnectionError {
         help("Try hard-resetting the device and try again, if the error persists your rom might be corrupted")
     )]
     OverSizedPacket,
+    #[error("Received a malformed response from the device")]
+    #[diagnostic(
+        code(espflash::invalid_response),
+        help("Try hard-resetting the device and try again")
+    )]
+    InvalidResponse,
 }
 
 #[derive(Debug, Default, Clone)]
 pub struct TimedOutCommand {
     command: Option<Command>,
+    spi_opcode: Option<u8>,
+}
+
+impl TimedOutCommand {
+    /// A timeout of the SPI flash command with `opcode`, sent through the
+    /// SPI peripheral rather than to the loader
+    pub fn spi(opcode: u8) -> Self {
+        TimedOutCommand {
+            command: None,
+            spi_opcode: Some(opcode),
+        }
+    }
 }
 
 impl From<Command> for TimedOutCommand {
     fn from(c: Command) -> Self {
-        TimedOutCommand { command: Some(c) }
+        TimedOutCommand {
+            command: Some(c),
+            spi_opcode: None,
+        }
     }
 }
 
 impl Display for TimedOutCommand {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match &self.command {
-            Some(command) => write!(f, "{} ", command),
-            None => Ok(()),
+        match (&self.command, self.spi_opcode) {
+            (Some(command), _) => write!(f, "{} ", command),
+            (None, Some(opcode)) => write!(f, "SPI flash {:#04x} ", opcode),
+            (None, None) => Ok(()),
         }
     }
 }
 
+#[cfg(feature = "serial")]
 impl From<serial::Error> for ConnectionError {
     fn from(err: serial::Error) -> Self {
         match err.kind() {
-            serial::ErrorKind::Io(kind) => from_error_kind(kind, err),
+            serial::ErrorKind::Io(kind) => from_error_kind(kind, err.into()),
             serial::ErrorKind::NoDevice => ConnectionError::DeviceNotFound,
-            _ => ConnectionError::Serial(err),
+            _ => ConnectionError::Serial(err.into()),
         }
     }
 }
 
+#[cfg(feature = "serial")]
 impl From<serial::Error> for Error {
     fn from(err: serial::Error) -> Self {
         Self::Connection(err.into())
@@ -130,14 +415,15 @@ impl From<io::Error> for Error {
     }
 }
 
-fn from_error_kind<E: Into<serial::Error>>(kind: io::ErrorKind, err: E) -> ConnectionError {
+fn from_error_kind(kind: io::ErrorKind, err: io::Error) -> ConnectionError {
     match kind {
         io::ErrorKind::TimedOut => ConnectionError::Timeout(TimedOutCommand::default()),
         io::ErrorKind::NotFound => ConnectionError::DeviceNotFound,
-        _ => ConnectionError::Serial(err.into()),
+        _ => ConnectionError::Serial(err),
     }
 }
 
+#[cfg(feature = "serial")]
 impl From<SlipError> for ConnectionError {
     fn from(err: SlipError) -> Self {
         match err {
@@ -149,6 +435,7 @@ impl From<SlipError> for ConnectionError {
     }
 }
 
+#[cfg(feature = "serial")]
 impl From<SlipError> for Error {
     fn from(err: SlipError) -> Self {
         Self::Connection(err.into())
@@ -188,7 +475,10 @@ pub enum RomError {
     #[diagnostic(code(espflash::rom::flash_write))]
     FlashWriteError = 0x08,
     #[error("Bootloader failed to read from flash")]
-    #[diagnostic(code(espflash::rom::flash_read))]
+    #[diagnostic(
+        code(espflash::rom::flash_read),
+        help("Slow flash may need a larger `--spi-clock-div` or more `--dummy-cycles`")
+    )]
     FlashReadError = 0x09,
     #[error("Invalid length for flash read")]
     #[diagnostic(code(espflash::rom::flash_read_length))]
@@ -216,6 +506,7 @@ impl From<u8> for RomError {
     }
 }
 
+#[cfg(feature = "serial")]
 pub(crate) trait ResultExt {
     /// mark an error as having occurred during the flashing stage
     fn flashing(self) -> Self;
@@ -223,6 +514,7 @@ pub(crate) trait ResultExt {
     fn for_command(self, command: Command) -> Self;
 }
 
+#[cfg(feature = "serial")]
 impl<T> ResultExt for Result<T, Error> {
     fn flashing(self) -> Self {
         match self {
@@ -298,10 +590,259 @@ impl PartitionTableError {
     }
 }
 
+/// Partitions which are misplaced or conflict with each other
+#[derive(Debug, Error)]
+#[error("Invalid partition table")]
+pub struct InvalidPartitionTableError {
+    pub(crate) source_code: String,
+    pub(crate) problems: Vec<(SourceSpan, String)>,
+    pub(crate) missing_app: bool,
+}
+
+impl Diagnostic for InvalidPartitionTableError {
+    fn code<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        Some(Box::new("espflash::invalid_partition_table"))
+    }
+
+    fn help<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        if self.missing_app {
+            Some(Box::new(
+                "The partition table needs at least one app partition",
+            ))
+        } else {
+            Some(Box::new(
+                "App partitions need to be aligned to 64 KiB, data partitions to 4 KiB",
+            ))
+        }
+    }
+
+    fn source_code(&self) -> Option<&dyn miette::SourceCode> {
+        Some(&self.source_code)
+    }
+
+    fn labels(&self) -> Option<Box<dyn Iterator<Item = LabeledSpan> + '_>> {
+        Some(Box::new(self.problems.iter().map(|(span, message)| {
+            LabeledSpan::new_with_span(Some(message.clone()), span.clone())
+        })))
+    }
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("The application is {app_size} bytes, but the `{name}` partition is only {size} bytes")]
+#[diagnostic(
+    code(espflash::app_too_large),
+    help("Increase the size of the `{}` partition to at least {:#x}", self.name, self.suggested_size)
+)]
+pub struct AppTooLargeError {
+    #[source_code]
+    pub(crate) source_code: String,
+    #[label("this partition is too small")]
+    pub(crate) span: SourceSpan,
+    pub(crate) name: String,
+    pub(crate) size: u32,
+    pub(crate) app_size: u32,
+    pub(crate) suggested_size: u32,
+}
+
 fn pos_to_offset(pos: Position) -> SourceOffset {
     (pos.byte() as usize).into()
 }
 
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum BinaryPartitionTableError {
+    #[error("Unexpected magic bytes {0:#06x} in entry {1}")]
+    InvalidMagic(u16, usize),
+    #[error("Unknown partition type {0:#04x} in entry {1}")]
+    UnknownType(u8, usize),
+    #[error("Unknown partition subtype {0:#04x} in entry {1}")]
+    UnknownSubType(u8, usize),
+    #[error("MD5 checksum of the partition table does not match")]
+    ChecksumMismatch,
+    #[error("The partition table holds at most {0} partitions")]
+    TooManyEntries(usize),
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum StructuredPartitionTableError {
+    #[error(transparent)]
+    Deserialize(#[from] serde_json::Error),
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum SdkConfigError {
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("Expected `CONFIG_<name>=<value>` on line {0}")]
+    InvalidLine(usize),
+    #[error("Unsupported value `{value}` for `{key}`")]
+    InvalidValue { key: String, value: String },
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum FlasherArgsError {
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid offset `{0}`")]
+    InvalidOffset(String),
+    #[error("Unsupported value `{value}` for `{key}`")]
+    InvalidValue { key: String, value: String },
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum NvsError {
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+    #[error("Invalid entry on line {line}: {reason}")]
+    InvalidEntry { line: usize, reason: String },
+    #[error("Failed to read \"{path}\"")]
+    Io {
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("NVS partitions need to be a multiple of 4 KiB and at least 12 KiB large, found {0:#x} bytes")]
+    InvalidSize(u32),
+    #[error("The entries don't fit into the partition")]
+    TooLarge,
+    #[error("NVS partitions can hold at most 254 namespaces")]
+    TooManyNamespaces,
+    #[error("NVS keys need to be 64 bytes long, found {0} bytes")]
+    InvalidKeys(usize),
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum FsImageError {
+    #[error("Failed to read \"{path}\"")]
+    Io {
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Filesystem images need to be a multiple of the block size of {block_size:#x} bytes, found {size:#x} bytes")]
+    InvalidSize { size: u32, block_size: u32 },
+    #[error("The path `{0}` is too long for the filesystem")]
+    NameTooLong(String),
+    #[error("The files don't fit into the partition")]
+    TooLarge,
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum EspotaError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("The device didn't answer the invitation")]
+    NoAnswer,
+    #[error("Unexpected answer `{0}` to the invitation")]
+    UnexpectedAnswer(String),
+    #[error("The device requires a password")]
+    PasswordRequired,
+    #[error("The device rejected the password")]
+    AuthenticationFailed,
+    #[error("The device didn't connect to fetch the image")]
+    NoConnection,
+    #[error("The device didn't confirm the update")]
+    NotConfirmed,
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum OtaError {
+    #[error("The partition table has no `otadata` partition")]
+    NoOtadata,
+    #[error("The `otadata` partition needs to be 8 KiB large, found {0:#x} bytes")]
+    InvalidOtadataSize(u32),
+    #[error("No app partition named `{0}` in the partition table")]
+    UnknownPartition(String),
+    #[error("The `{0}` partition can't be selected through `otadata`")]
+    NotSelectable(String),
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum EfuseError {
+    #[error("Invalid value `{value}` for `{field}`: {reason}")]
+    InvalidValue {
+        field: String,
+        value: String,
+        reason: String,
+    },
+    #[error("Burning `{0}` would clear bits which are already burned")]
+    ClearsBits(String),
+    #[error("`{0}` is already written and its check values can't be updated")]
+    BlockAlreadyWritten(String),
+    #[error("`{0}` can only be burned with the `NONE` coding scheme")]
+    CodingScheme(String),
+    #[error("Burning efuses isn't supported for the {0}")]
+    Unsupported(Chip),
+    #[error("Burning efuses isn't supported with a {0} MHz crystal")]
+    UnsupportedCrystal(u32),
+    #[error("The efuse controller didn't finish its command")]
+    Busy,
+    #[error("`{0}` doesn't read back as burned, it may be read or write protected")]
+    BurnFailed(String),
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum FlashEncryptionError {
+    #[error("Encrypting flash contents on the host isn't supported for the {0}")]
+    UnsupportedChip(Chip),
+    #[error("The {chip} doesn't support flash encryption keys of {len} bytes")]
+    InvalidKeyLength { chip: Chip, len: usize },
+    #[error("Encrypted data has to be written at a multiple of 16 bytes, not at {0:#x}")]
+    Unaligned(u32),
+    #[error("Flash encryption is already enabled")]
+    AlreadyEnabled,
+    #[error("No free key blocks are left")]
+    NoFreeKeyBlock,
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum CoreDumpError {
+    #[error("The partition table has no `coredump` partition")]
+    NoPartition,
+    #[error("No core dump was saved")]
+    Empty,
+    #[error("The core dump is truncated")]
+    Truncated,
+    #[error("The checksum of the core dump doesn't match")]
+    ChecksumMismatch,
+    #[error("The core dump isn't valid base64")]
+    InvalidBase64,
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ImageError {
+    #[error("Image is too short to contain an image header")]
+    TooShort,
+    #[error("Invalid magic byte {0:#04x}, expected 0xe9")]
+    InvalidMagic(u8),
+    #[error("Segment {0} extends past the end of the image")]
+    TruncatedSegment(usize),
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum BootloaderError {
+    #[error("Image is too short to contain an image header")]
+    TooShort,
+    #[error("Invalid magic byte {0:#04x}, expected 0xe9")]
+    InvalidMagic(u8),
+    #[error("Image is built for chip id {found}, expected {expected}")]
+    WrongChip { expected: u16, found: u16 },
+}
+
 #[derive(Debug, Error)]
 #[error("{0}")]
 pub struct ElfError(&'static str);
@@ -323,11 +864,36 @@ impl From<u32> for ChipDetectError {
 }
 
 #[derive(Debug, Error)]
-#[error("Unrecognized flash id {0:#x}")]
-pub struct FlashDetectError(u8);
+pub enum FlashDetectError {
+    #[error("Unrecognized flash id {0:#x}")]
+    SizeId(u8),
+    #[error("Unrecognized flash chip ({0})")]
+    FlashId(FlashId),
+}
 
 impl From<u8> for FlashDetectError {
     fn from(err: u8) -> Self {
-        FlashDetectError(err)
+        FlashDetectError::SizeId(err)
+    }
+}
+
+impl From<FlashId> for FlashDetectError {
+    fn from(id: FlashId) -> Self {
+        FlashDetectError::FlashId(id)
     }
 }
+
+#[test]
+fn test_timed_out_command() {
+    let error = ConnectionError::Timeout(TimedOutCommand::spi(0x03));
+    assert_eq!(
+        "Timeout while running SPI flash 0x03 command",
+        error.to_string()
+    );
+
+    let error = ConnectionError::Timeout(Command::FlashBegin.into());
+    assert_eq!(
+        "Timeout while running flash-begin command",
+        error.to_string()
+    );
+}
@@ -0,0 +1,292 @@
+//! Updating devices over the network with the espota protocol
+//!
+//! This is the protocol of ArduinoOTA and `espota.py`. The host invites the
+//! device over UDP, telling it the size and MD5 digest of the image and the
+//! TCP port to fetch it from, authenticates with a password if the device asks
+//! for one, and then sends the image once the device connects back. The device
+//! checks the digest before switching to the new image.
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::error::EspotaError;
+use crate::progress::ProgressCallbacks;
+
+/// Port ArduinoOTA listens on for invitations on the ESP32
+pub const ESP32_PORT: u16 = 3232;
+/// Port ArduinoOTA listens on for invitations on the ESP8266
+pub const ESP8266_PORT: u16 = 8266;
+
+/// Size of the chunks the image is sent in, one TCP segment each
+const CHUNK_SIZE: usize = 1460;
+
+/// Command of the invitation to authenticate
+const AUTH_COMMAND: u32 = 200;
+
+/// What the image replaces on the device
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum EspotaTarget {
+    /// The application
+    #[default]
+    Flash,
+    /// The SPIFFS or LittleFS filesystem partition
+    Filesystem,
+}
+
+impl EspotaTarget {
+    fn command(self) -> u32 {
+        match self {
+            EspotaTarget::Flash => 0,
+            EspotaTarget::Filesystem => 100,
+        }
+    }
+}
+
+/// Where and how to send an image
+#[derive(Clone, Debug)]
+pub struct EspotaOptions {
+    /// Address the device listens for invitations on
+    pub device: SocketAddr,
+    /// Local port the device fetches the image from, any free one for 0
+    pub host_port: u16,
+    pub password: Option<String>,
+    pub target: EspotaTarget,
+    /// How long to wait for each answer of the device
+    pub timeout: Duration,
+}
+
+/// Send `image` to the device, `name` is the file name the authentication is
+/// salted with
+pub fn upload(
+    image: &[u8],
+    name: &str,
+    options: &EspotaOptions,
+    progress: &mut dyn ProgressCallbacks,
+) -> Result<(), EspotaError> {
+    let listener = TcpListener::bind(("0.0.0.0", options.host_port))?;
+    let host_port = listener.local_addr()?.port();
+    let digest = format!("{:x}", md5::compute(image));
+
+    invite(image.len(), &digest, host_port, name, options)?;
+    log::debug!("{} accepted the invitation", options.device);
+
+    let mut stream = accept(&listener, options.timeout)?;
+    stream.set_read_timeout(Some(options.timeout))?;
+    stream.set_nodelay(true)?;
+
+    let chunks = image.chunks(CHUNK_SIZE);
+    progress.init(0, chunks.len());
+    let mut confirmed = false;
+    let mut response = [0; 32];
+    for (index, chunk) in chunks.enumerate() {
+        stream.write_all(chunk)?;
+        // the device answers each chunk with the number of bytes it received,
+        // and with `OK` once the last one was written and verified
+        let len = stream.read(&mut response)?;
+        confirmed = contains_ok(&response[..len]);
+        progress.update(index + 1);
+    }
+    progress.finish();
+
+    // erasing and verifying the image can take a while after the last chunk
+    let deadline = Instant::now() + options.timeout;
+    while !confirmed {
+        let len = match stream.read(&mut response) {
+            Ok(0) => return Err(EspotaError::NotConfirmed),
+            Ok(len) => len,
+            Err(err) if is_timeout(&err) && Instant::now() < deadline => continue,
+            Err(err) if is_timeout(&err) => return Err(EspotaError::NotConfirmed),
+            Err(err) => return Err(err.into()),
+        };
+        confirmed = contains_ok(&response[..len]);
+    }
+
+    Ok(())
+}
+
+/// Invite the device to fetch the image, authenticating if it asks to
+fn invite(
+    size: usize,
+    digest: &str,
+    host_port: u16,
+    name: &str,
+    options: &EspotaOptions,
+) -> Result<(), EspotaError> {
+    let bind = match options.device {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let socket = UdpSocket::bind(bind)?;
+    socket.set_read_timeout(Some(options.timeout))?;
+    socket.connect(options.device)?;
+
+    let invitation = format!(
+        "{} {} {} {}\n",
+        options.target.command(),
+        host_port,
+        size,
+        digest
+    );
+    let answer = request(&socket, &invitation)?;
+    if answer == "OK" {
+        return Ok(());
+    }
+
+    let nonce = match answer.strip_prefix("AUTH ") {
+        Some(nonce) => nonce.trim(),
+        None => return Err(EspotaError::UnexpectedAnswer(answer)),
+    };
+    let password = options
+        .password
+        .as_deref()
+        .ok_or(EspotaError::PasswordRequired)?;
+
+    let cnonce = format!(
+        "{:x}",
+        md5::compute(format!("{}{}{}{}", name, size, digest, options.device.ip()))
+    );
+    let response = auth_response(password, nonce, &cnonce);
+    let answer = request(
+        &socket,
+        &format!("{} {} {}\n", AUTH_COMMAND, cnonce, response),
+    )?;
+    match answer.as_str() {
+        "OK" => Ok(()),
+        _ => Err(EspotaError::AuthenticationFailed),
+    }
+}
+
+/// `md5(md5(password):nonce:cnonce)`, as in HTTP digest authentication
+fn auth_response(password: &str, nonce: &str, cnonce: &str) -> String {
+    let password = format!("{:x}", md5::compute(password));
+    format!(
+        "{:x}",
+        md5::compute(format!("{}:{}:{}", password, nonce, cnonce))
+    )
+}
+
+/// Send a datagram and wait for the answer
+fn request(socket: &UdpSocket, message: &str) -> Result<String, EspotaError> {
+    socket.send(message.as_bytes())?;
+
+    let mut answer = [0; 64];
+    let len = match socket.recv(&mut answer) {
+        Ok(len) => len,
+        Err(err) if is_timeout(&err) => return Err(EspotaError::NoAnswer),
+        Err(err) => return Err(err.into()),
+    };
+    Ok(String::from_utf8_lossy(&answer[..len]).trim().to_string())
+}
+
+/// Wait for the device to connect back, the listener has no timeout of its own
+fn accept(listener: &TcpListener, timeout: Duration) -> Result<TcpStream, EspotaError> {
+    listener.set_nonblocking(true)?;
+    let deadline = Instant::now() + timeout;
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                return Ok(stream);
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                if Instant::now() >= deadline {
+                    return Err(EspotaError::NoConnection);
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+fn contains_ok(response: &[u8]) -> bool {
+    response.windows(2).any(|window| window == b"OK")
+}
+
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NoProgress;
+
+    impl ProgressCallbacks for NoProgress {
+        fn init(&mut self, _addr: u32, _total: usize) {}
+        fn update(&mut self, _current: usize) {}
+        fn finish(&mut self) {}
+    }
+
+    /// A device asking for the password `secret`, which answers each read
+    /// with the number of bytes received so far
+    fn fake_device(socket: UdpSocket) -> Vec<u8> {
+        let mut buffer = [0; 128];
+        let (len, host) = socket.recv_from(&mut buffer).unwrap();
+        let invitation = String::from_utf8_lossy(&buffer[..len]).to_string();
+        let fields: Vec<&str> = invitation.split_whitespace().collect();
+        assert_eq!("0", fields[0]);
+        let port: u16 = fields[1].parse().unwrap();
+        let size: usize = fields[2].parse().unwrap();
+        let digest = fields[3].to_string();
+
+        socket.send_to(b"AUTH 0123456789abcdef", host).unwrap();
+        let len = socket.recv(&mut buffer).unwrap();
+        let auth = String::from_utf8_lossy(&buffer[..len]).to_string();
+        let fields: Vec<&str> = auth.split_whitespace().collect();
+        assert_eq!("200", fields[0]);
+        assert_eq!(
+            auth_response("secret", "0123456789abcdef", fields[1]),
+            fields[2]
+        );
+        socket.send_to(b"OK", host).unwrap();
+
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut image = Vec::new();
+        while image.len() < size {
+            let len = stream.read(&mut buffer).unwrap();
+            image.extend_from_slice(&buffer[..len]);
+            stream
+                .write_all(image.len().to_string().as_bytes())
+                .unwrap();
+        }
+        assert_eq!(digest, format!("{:x}", md5::compute(&image)));
+        stream.write_all(b"OK").unwrap();
+
+        image
+    }
+
+    #[test]
+    fn upload_with_password() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let options = EspotaOptions {
+            device: socket.local_addr().unwrap(),
+            host_port: 0,
+            password: Some("secret".to_string()),
+            target: EspotaTarget::Flash,
+            timeout: Duration::from_secs(5),
+        };
+        let device = std::thread::spawn(move || fake_device(socket));
+
+        let image: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+        upload(&image, "app.bin", &options, &mut NoProgress).unwrap();
+        assert_eq!(image, device.join().unwrap());
+    }
+
+    #[test]
+    fn auth() {
+        // the password hash is `md5("secret")`
+        assert_eq!(
+            format!(
+                "{:x}",
+                md5::compute("5ebe2294ecd0e0f08eab7690d2a6ee69:nonce:cnonce")
+            ),
+            auth_response("secret", "nonce", "cnonce")
+        );
+    }
+}
@@ -0,0 +1,364 @@
+//! Compatibility with esptool command lines
+//!
+//! Scripts and Makefiles written for esptool.py can call espflash with the
+//! same arguments, e.g.
+//! `espflash -p /dev/ttyUSB0 -b 460800 write_flash 0x1000 boot.bin 0x10000 app.bin`.
+//! The most common commands are mapped to espflash operations, options without
+//! an effect on espflash, like `--compress`, are accepted and ignored.
+
+use crate::{
+    Chip, Error, FlashFrequency, FlashMode, FlashSettings, FlashSize, ResetAfterOperation,
+    ResetBeforeOperation,
+};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// The esptool commands espflash understands
+pub const COMMANDS: &[&str] = &[
+    "write_flash",
+    "read_flash",
+    "read_mac",
+    "chip_id",
+    "flash_id",
+    "run",
+    "image_info",
+];
+
+/// esptool commands which need the stub loader or have an espflash
+/// subcommand of their own
+const UNSUPPORTED_COMMANDS: &[(&str, &str)] = &[
+    (
+        "erase_flash",
+        "erasing the whole flash needs the stub loader",
+    ),
+    ("erase_region", "erasing needs the stub loader"),
+    ("merge_bin", "use `espflash merge-bin` instead"),
+    ("elf2image", "use `espflash merge-bin` instead"),
+    ("load_ram", "use `espflash --ram` instead"),
+    ("dump_mem", "use `espflash dump-mem` instead"),
+    ("read_mem", "use `espflash read-reg` instead"),
+    ("write_mem", "use `espflash write-reg` instead"),
+];
+
+/// Global options taking a value which are accepted and ignored
+const IGNORED_GLOBAL_VALUES: &[&str] = &["--connect-attempts"];
+
+/// Global flags which are accepted and ignored, espflash only talks to the
+/// ROM loader
+const IGNORED_GLOBAL_FLAGS: &[&str] = &["--no-stub", "--trace", "-t"];
+
+/// `write_flash` flags which are accepted and ignored
+const IGNORED_WRITE_FLAGS: &[&str] = &[
+    "--compress",
+    "-z",
+    "--no-compress",
+    "-u",
+    "--verify",
+    "--no-progress",
+    "-p",
+    "--force",
+];
+
+/// An esptool command line
+#[derive(Clone, Debug, PartialEq)]
+pub struct EsptoolArgs {
+    pub port: Option<String>,
+    pub baud: Option<usize>,
+    /// The chip given by `--chip`, `None` for `auto`
+    pub chip: Option<Chip>,
+    pub before: ResetBeforeOperation,
+    pub after: ResetAfterOperation,
+    pub command: EsptoolCommand,
+}
+
+/// An esptool command with its arguments
+#[derive(Clone, Debug, PartialEq)]
+pub enum EsptoolCommand {
+    /// Write files to flash, the flash mode, frequency and size are set in
+    /// the header of the bootloader
+    WriteFlash {
+        settings: FlashSettings,
+        files: Vec<(u32, PathBuf)>,
+    },
+    ReadFlash {
+        addr: u32,
+        size: u32,
+        output: PathBuf,
+    },
+    ReadMac,
+    ChipId,
+    FlashId,
+    /// Reset the chip to run the application
+    Run,
+    ImageInfo {
+        path: PathBuf,
+    },
+}
+
+impl EsptoolArgs {
+    /// Whether `args` is an esptool command line, that is the first argument
+    /// which isn't a global esptool option is an esptool command
+    pub fn is_esptool(args: &[String]) -> bool {
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let arg = normalize_option(arg);
+            if takes_global_value(&arg) {
+                args.next();
+            } else if !IGNORED_GLOBAL_FLAGS.contains(&arg.as_str()) {
+                return COMMANDS.contains(&arg.as_str())
+                    || UNSUPPORTED_COMMANDS.iter().any(|(name, _)| *name == arg);
+            }
+        }
+        false
+    }
+
+    /// Parse an esptool command line, without the program name
+    pub fn parse(args: &[String]) -> Result<Self, Error> {
+        let mut port = None;
+        let mut baud = None;
+        let mut chip = None;
+        let mut before = ResetBeforeOperation::default();
+        let mut after = ResetAfterOperation::default();
+        let mut args = args.iter();
+
+        let command = loop {
+            let arg = args
+                .next()
+                .ok_or_else(|| invalid("no esptool command given"))?;
+            let option = normalize_option(arg);
+            let mut value = || {
+                args.next()
+                    .cloned()
+                    .ok_or_else(|| invalid(format!("`{}` needs a value", arg)))
+            };
+
+            match option.as_str() {
+                "--port" | "-p" => port = Some(value()?),
+                "--baud" | "-b" => baud = Some(parse(&value()?, "baud rate")?),
+                "--chip" | "-c" => {
+                    chip = match value()?.as_str() {
+                        "auto" => None,
+                        chip => Some(parse(chip, "chip")?),
+                    }
+                }
+                "--before" => before = parse(&value()?.replace('-', "_"), "reset")?,
+                "--after" => after = parse(&value()?.replace('-', "_"), "reset")?,
+                option if IGNORED_GLOBAL_VALUES.contains(&option) => {
+                    value()?;
+                }
+                option if IGNORED_GLOBAL_FLAGS.contains(&option) => {}
+                _ => break arg.as_str(),
+            }
+        };
+
+        let args: Vec<&String> = args.collect();
+        let command = match command {
+            "write_flash" => parse_write_flash(&args)?,
+            "read_flash" => match args.as_slice() {
+                [addr, size, output] => EsptoolCommand::ReadFlash {
+                    addr: parse_u32(addr)?,
+                    size: parse_u32(size)?,
+                    output: PathBuf::from(output),
+                },
+                _ => return Err(invalid("`read_flash` needs an address, size and file")),
+            },
+            "image_info" => match args.as_slice() {
+                [path] => EsptoolCommand::ImageInfo {
+                    path: PathBuf::from(path),
+                },
+                _ => return Err(invalid("`image_info` needs a file")),
+            },
+            "read_mac" | "chip_id" | "flash_id" | "run" if !args.is_empty() => {
+                return Err(invalid(format!("`{}` takes no arguments", command)))
+            }
+            "read_mac" => EsptoolCommand::ReadMac,
+            "chip_id" => EsptoolCommand::ChipId,
+            "flash_id" => EsptoolCommand::FlashId,
+            "run" => EsptoolCommand::Run,
+            command => {
+                return Err(
+                    match UNSUPPORTED_COMMANDS
+                        .iter()
+                        .find(|(name, _)| *name == command)
+                    {
+                        Some((_, reason)) => {
+                            invalid(format!("`{}` isn't supported, {}", command, reason))
+                        }
+                        None => invalid(format!("unknown command `{}`", command)),
+                    },
+                )
+            }
+        };
+
+        Ok(EsptoolArgs {
+            port,
+            baud,
+            chip,
+            before,
+            after,
+            command,
+        })
+    }
+}
+
+fn parse_write_flash(args: &[&String]) -> Result<EsptoolCommand, Error> {
+    let mut settings = FlashSettings::default();
+    let mut files = Vec::new();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let option = normalize_option(arg);
+        let mut value = || {
+            args.next()
+                .map(|value| value.as_str())
+                .ok_or_else(|| invalid(format!("`{}` needs a value", arg)))
+        };
+
+        match option.as_str() {
+            "--flash-mode" | "-fm" => settings.mode = parse_keep::<FlashMode>(value()?)?,
+            "--flash-freq" | "-ff" => settings.frequency = parse_keep::<FlashFrequency>(value()?)?,
+            "--flash-size" | "-fs" => {
+                settings.size = match value()? {
+                    "detect" => None,
+                    size => parse_keep::<FlashSize>(size)?,
+                }
+            }
+            "--erase-all" | "-e" => {
+                return Err(invalid(
+                    "`--erase-all` isn't supported, erasing the whole flash needs the stub loader",
+                ))
+            }
+            "--encrypt" | "--encrypt-files" => {
+                return Err(invalid("writing encrypted files isn't supported"))
+            }
+            option if IGNORED_WRITE_FLAGS.contains(&option) => {}
+            option if option.starts_with('-') => {
+                return Err(invalid(format!("unknown `write_flash` option `{}`", arg)))
+            }
+            _ => {
+                let addr = parse_u32(arg)?;
+                let path = args
+                    .next()
+                    .ok_or_else(|| invalid(format!("no file given for address {}", arg)))?;
+                files.push((addr, PathBuf::from(path)));
+            }
+        }
+    }
+
+    if files.is_empty() {
+        return Err(invalid("`write_flash` needs at least one address and file"));
+    }
+
+    Ok(EsptoolCommand::WriteFlash { settings, files })
+}
+
+/// esptool accepts options with both dashes and underscores, e.g.
+/// `--flash_mode` and `--flash-mode`
+fn normalize_option(arg: &str) -> String {
+    match arg.strip_prefix("--") {
+        Some(option) => format!("--{}", option.replace('_', "-")),
+        None => arg.to_string(),
+    }
+}
+
+fn takes_global_value(option: &str) -> bool {
+    matches!(
+        option,
+        "--port" | "-p" | "--baud" | "-b" | "--chip" | "-c" | "--before" | "--after"
+    ) || IGNORED_GLOBAL_VALUES.contains(&option)
+}
+
+/// Parse a flash setting, `keep` keeps the one in the header
+fn parse_keep<T: FromStr>(value: &str) -> Result<Option<T>, Error> {
+    match value {
+        "keep" => Ok(None),
+        value => parse(value, "flash setting").map(Some),
+    }
+}
+
+fn parse<T: FromStr>(value: &str, name: &str) -> Result<T, Error> {
+    value
+        .parse()
+        .map_err(|_| invalid(format!("invalid {} `{}`", name, value)))
+}
+
+fn parse_u32(value: &str) -> Result<u32, Error> {
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|_| invalid(format!("invalid number `{}`", value)))
+}
+
+fn invalid(reason: impl Into<String>) -> Error {
+    Error::InvalidEsptoolArgs(reason.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn write_flash() {
+        let line =
+            "--chip esp32 -p /dev/ttyUSB0 -b 460800 --before default_reset --after no_reset \
+            write_flash -z --flash_mode dio --flash-freq 80m --flash_size detect \
+            0x1000 boot.bin 0x8000 part.bin 0x10000 app.bin";
+        assert!(EsptoolArgs::is_esptool(&args(line)));
+
+        let parsed = EsptoolArgs::parse(&args(line)).unwrap();
+        assert_eq!(Some(Chip::Esp32), parsed.chip);
+        assert_eq!(Some("/dev/ttyUSB0".to_string()), parsed.port);
+        assert_eq!(Some(460800), parsed.baud);
+        assert_eq!(ResetAfterOperation::NoReset, parsed.after);
+        assert_eq!(
+            EsptoolCommand::WriteFlash {
+                settings: FlashSettings {
+                    mode: Some(FlashMode::Dio),
+                    frequency: Some(FlashFrequency::Flash80M),
+                    ..FlashSettings::default()
+                },
+                files: vec![
+                    (0x1000, PathBuf::from("boot.bin")),
+                    (0x8000, PathBuf::from("part.bin")),
+                    (0x10000, PathBuf::from("app.bin")),
+                ],
+            },
+            parsed.command
+        );
+    }
+
+    #[test]
+    fn other_commands() {
+        let parsed = EsptoolArgs::parse(&args("read_flash 0 0x400000 flash.bin")).unwrap();
+        assert_eq!(
+            EsptoolCommand::ReadFlash {
+                addr: 0,
+                size: 0x400000,
+                output: PathBuf::from("flash.bin"),
+            },
+            parsed.command
+        );
+
+        let parsed = EsptoolArgs::parse(&args("--chip auto --port COM3 read_mac")).unwrap();
+        assert_eq!(None, parsed.chip);
+        assert_eq!(EsptoolCommand::ReadMac, parsed.command);
+    }
+
+    #[test]
+    fn not_esptool() {
+        assert!(!EsptoolArgs::is_esptool(&args("/dev/ttyUSB0 app.elf")));
+        assert!(!EsptoolArgs::is_esptool(&args("merge-bin --chip esp32")));
+        assert!(!EsptoolArgs::is_esptool(&args("-p /dev/ttyUSB0")));
+        assert!(EsptoolArgs::is_esptool(&args("erase_flash")));
+
+        assert!(EsptoolArgs::parse(&args("erase_flash")).is_err());
+        assert!(EsptoolArgs::parse(&args("write_flash 0x1000")).is_err());
+        assert!(EsptoolArgs::parse(&args("write_flash -e 0x0 all.bin")).is_err());
+        assert!(EsptoolArgs::parse(&args("read_mac now")).is_err());
+    }
+}
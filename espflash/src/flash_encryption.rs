@@ -0,0 +1,364 @@
+//! Encrypting flash contents on the host for flash encryption
+//!
+//! The ESP32-C3 and ESP32-S2 encrypt the flash with XTS-AES in units of 128
+//! bytes, each using its flash address as tweak and with the bytes of the unit
+//! reversed, like `espsecure.py encrypt_flash_data` does. Data written
+//! encrypted this way reads back as plaintext once flash encryption is enabled
+//! with the same key. The ESP32 uses its own scheme, which isn't supported.
+
+use aes::cipher::KeyInit;
+use aes::{Aes128, Aes256};
+use xts_mode::Xts128;
+
+use crate::chip::efuse::{key_purpose, EfuseBlocks, EfuseValue};
+use crate::error::FlashEncryptionError;
+use crate::{Chip, Error};
+
+/// Size of the units encrypted with the same tweak
+pub const DATA_UNIT_LEN: usize = 128;
+/// Size of each key block of the efuses
+const KEY_BLOCK_LEN: usize = 32;
+/// Number of key blocks of the efuses
+const KEY_BLOCKS: usize = 6;
+/// The efuse counting how often flash encryption was toggled
+const COUNTER: &str = "SPI_BOOT_CRYPT_CNT";
+/// Bit of `WR_DIS` protecting `KEY_PURPOSE_0`, the other purposes follow it
+const KEY_PURPOSE_WR_DIS: u32 = 8;
+/// Bit of `WR_DIS` protecting `BLOCK_KEY0`, the other key blocks follow it.
+/// Bit `n` of `RD_DIS` protects `BLOCK_KEYn`
+const KEY_BLOCK_WR_DIS: u32 = 23;
+
+/// A flash encryption key of a chip
+pub struct FlashEncryptionKey {
+    key: Vec<u8>,
+    xts: Cipher,
+}
+
+/// XTS-AES with the data key followed by the tweak key
+enum Cipher {
+    Aes128(Box<Xts128<Aes128>>),
+    Aes256(Box<Xts128<Aes256>>),
+}
+
+impl Cipher {
+    fn new(key: &[u8]) -> Self {
+        let (key, tweak_key) = key.split_at(key.len() / 2);
+        match key.len() {
+            16 => Cipher::Aes128(Box::new(Xts128::new(
+                Aes128::new_from_slice(key).unwrap(),
+                Aes128::new_from_slice(tweak_key).unwrap(),
+            ))),
+            _ => Cipher::Aes256(Box::new(Xts128::new(
+                Aes256::new_from_slice(key).unwrap(),
+                Aes256::new_from_slice(tweak_key).unwrap(),
+            ))),
+        }
+    }
+
+    fn encrypt(&self, tweak: [u8; 16], unit: &mut [u8]) {
+        match self {
+            Cipher::Aes128(xts) => xts.encrypt_sector(unit, tweak),
+            Cipher::Aes256(xts) => xts.encrypt_sector(unit, tweak),
+        }
+    }
+
+    fn decrypt(&self, tweak: [u8; 16], unit: &mut [u8]) {
+        match self {
+            Cipher::Aes128(xts) => xts.decrypt_sector(unit, tweak),
+            Cipher::Aes256(xts) => xts.decrypt_sector(unit, tweak),
+        }
+    }
+}
+
+impl FlashEncryptionKey {
+    /// A 256 bit key for XTS-AES-128, or a 512 bit key for XTS-AES-256 on the
+    /// ESP32-S2
+    pub fn new(chip: Chip, key: &[u8]) -> Result<Self, FlashEncryptionError> {
+        let key_lens: &[usize] = match chip {
+            Chip::Esp32c3 => &[32],
+            Chip::Esp32s2 => &[32, 64],
+            chip => return Err(FlashEncryptionError::UnsupportedChip(chip)),
+        };
+        if !key_lens.contains(&key.len()) {
+            return Err(FlashEncryptionError::InvalidKeyLength {
+                chip,
+                len: key.len(),
+            });
+        }
+
+        Ok(FlashEncryptionKey {
+            key: key.to_vec(),
+            xts: Cipher::new(key),
+        })
+    }
+
+    /// The contents of the key blocks to burn the key to, with the value of
+    /// their `KEY_PURPOSE_n` field
+    ///
+    /// The AES-XTS peripheral reads the key blocks in reverse, so each half of
+    /// a 512 bit key goes into its own block and the bytes are reversed, as
+    /// `espefuse.py burn_key` does.
+    pub fn key_blocks(&self) -> Vec<(u8, Vec<u8>)> {
+        let purposes: &[&str] = match self.key.len() {
+            KEY_BLOCK_LEN => &["XTS_AES_128_KEY"],
+            _ => &["XTS_AES_256_KEY_1", "XTS_AES_256_KEY_2"],
+        };
+
+        purposes
+            .iter()
+            .zip(self.key.chunks(KEY_BLOCK_LEN))
+            .map(|(name, key)| {
+                let purpose = (0..16).find(|purpose| key_purpose(*purpose) == *name);
+                (purpose.unwrap(), key.iter().rev().copied().collect())
+            })
+            .collect()
+    }
+
+    /// Encrypt `data` to be written at `address`, which has to be 16 byte
+    /// aligned. The data is padded to the next multiple of 16 bytes with 0xff
+    pub fn encrypt(&self, address: u32, data: &[u8]) -> Result<Vec<u8>, FlashEncryptionError> {
+        self.process(address, data, |tweak, unit| self.xts.encrypt(tweak, unit))
+    }
+
+    /// Decrypt `data` read from `address`, the reverse of
+    /// [`FlashEncryptionKey::encrypt`]
+    pub fn decrypt(&self, address: u32, data: &[u8]) -> Result<Vec<u8>, FlashEncryptionError> {
+        self.process(address, data, |tweak, unit| self.xts.decrypt(tweak, unit))
+    }
+
+    fn process(
+        &self,
+        address: u32,
+        data: &[u8],
+        cipher: impl Fn([u8; 16], &mut [u8]),
+    ) -> Result<Vec<u8>, FlashEncryptionError> {
+        if !address.is_multiple_of(16) {
+            return Err(FlashEncryptionError::Unaligned(address));
+        }
+
+        // pad to whole units, starting at the beginning of the first one. Each
+        // 16 byte block is encrypted on its own, so the padding doesn't
+        // change the result
+        let start = address as usize % DATA_UNIT_LEN;
+        let len = data.len().div_ceil(16) * 16;
+        let mut padded = vec![0xff; (start + len).div_ceil(DATA_UNIT_LEN) * DATA_UNIT_LEN];
+        padded[start..][..data.len()].copy_from_slice(data);
+
+        let first_unit = address & !(DATA_UNIT_LEN as u32 - 1);
+        for (i, unit) in padded.chunks_exact_mut(DATA_UNIT_LEN).enumerate() {
+            let mut tweak = [0; 16];
+            tweak[..4].copy_from_slice(&(first_unit + (i * DATA_UNIT_LEN) as u32).to_le_bytes());
+            unit.reverse();
+            cipher(tweak, unit);
+            unit.reverse();
+        }
+
+        Ok(padded[start..][..len].to_vec())
+    }
+}
+
+/// Whether flash encryption is enabled, which it is while an odd number of
+/// bits of `SPI_BOOT_CRYPT_CNT` is burned
+pub fn is_enabled(chip: Chip, blocks: &EfuseBlocks) -> bool {
+    match chip.efuse_field(COUNTER).map(|field| blocks.value(field)) {
+        Some(EfuseValue::Uint(count)) => count.count_ones() % 2 == 1,
+        _ => false,
+    }
+}
+
+/// Set the efuses enabling flash encryption in development mode with `key`
+/// in `blocks`, for [`Flasher::burn_efuses`](crate::Flasher::burn_efuses)
+///
+/// The key goes to the first key blocks which are unused and have no purpose
+/// yet, and one more bit of `SPI_BOOT_CRYPT_CNT` is burned. Unless
+/// `protect_key` is false, which is only meant for development, the key
+/// blocks are read protected and they and their purposes write protected, as
+/// `espefuse.py burn_key` does. Returns the indices of the key blocks used.
+pub fn enable(
+    chip: Chip,
+    blocks: &mut EfuseBlocks,
+    key: &FlashEncryptionKey,
+    protect_key: bool,
+) -> Result<Vec<usize>, Error> {
+    let counter = chip
+        .efuse_field(COUNTER)
+        .ok_or(FlashEncryptionError::UnsupportedChip(chip))?;
+    if is_enabled(chip, blocks) {
+        return Err(FlashEncryptionError::AlreadyEnabled.into());
+    }
+
+    let key_fields = |i: usize| {
+        let purpose = chip.efuse_field(&format!("KEY_PURPOSE_{}", i));
+        let block = chip.efuse_field(&format!("BLOCK_KEY{}", i));
+        purpose.zip(block)
+    };
+    let free: Vec<usize> = (0..KEY_BLOCKS)
+        .filter(|i| match key_fields(*i) {
+            Some((purpose, block)) => {
+                blocks.value(purpose) == EfuseValue::KeyPurpose(0)
+                    && blocks.block(block.block).iter().all(|word| *word == 0)
+            }
+            None => false,
+        })
+        .collect();
+    let key_blocks = key.key_blocks();
+    if free.len() < key_blocks.len() {
+        return Err(FlashEncryptionError::NoFreeKeyBlock.into());
+    }
+
+    let used = free[..key_blocks.len()].to_vec();
+    for (i, (purpose, data)) in used.iter().zip(key_blocks) {
+        let (purpose_field, block_field) = key_fields(*i).unwrap();
+        blocks.burn(block_field, &EfuseValue::Bytes(data))?;
+        blocks.burn(purpose_field, &EfuseValue::KeyPurpose(purpose))?;
+    }
+
+    if protect_key {
+        let read_protect = used.iter().fold(0, |mask, i| mask | 1 << i);
+        let write_protect = used.iter().fold(0, |mask, i| {
+            mask | 1 << (KEY_BLOCK_WR_DIS as usize + i) | 1 << (KEY_PURPOSE_WR_DIS as usize + i)
+        });
+        for (name, protect) in [("RD_DIS", read_protect), ("WR_DIS", write_protect)] {
+            let field = chip
+                .efuse_field(name)
+                .ok_or(FlashEncryptionError::UnsupportedChip(chip))?;
+            let protected = match blocks.value(field) {
+                EfuseValue::Mask(protected) => protected,
+                _ => 0,
+            };
+            blocks.burn(field, &EfuseValue::Mask(protected | protect))?;
+        }
+    }
+
+    let count = match blocks.value(counter) {
+        EfuseValue::Uint(count) => count,
+        _ => 0,
+    };
+    // burn the lowest bit which isn't burned yet
+    blocks.burn(counter, &EfuseValue::Uint(count | (count + 1)))?;
+
+    Ok(used)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> FlashEncryptionKey {
+        let key: Vec<u8> = (0..32).collect();
+        FlashEncryptionKey::new(Chip::Esp32c3, &key).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+        let encrypted = key().encrypt(0x10010, &data).unwrap();
+        assert_eq!(1008, encrypted.len());
+        assert_ne!(data[..16], encrypted[..16]);
+
+        let decrypted = key().decrypt(0x10010, &encrypted).unwrap();
+        assert_eq!(data, decrypted[..1000]);
+        assert!(decrypted[1000..].iter().all(|byte| *byte == 0xff));
+
+        // the tweak depends on the address of the unit
+        let moved = key().encrypt(0x10090, &data).unwrap();
+        assert_ne!(encrypted[..16], moved[..16]);
+        // but blocks are encrypted independently of the rest of the unit
+        let partial = key().encrypt(0x10020, &data[16..32]).unwrap();
+        assert_eq!(encrypted[16..32], partial[..]);
+    }
+
+    #[test]
+    fn test_xts_aes_128() {
+        // IEEE 1619, vector 1
+        let mut data = [0; 32];
+        Cipher::new(&[0; 32]).encrypt([0; 16], &mut data);
+        let hex: String = data.iter().map(|byte| format!("{:02x}", byte)).collect();
+        assert_eq!(
+            "917cf69ebd68b2ec9b9fe9a3eadda692cd43d2f59598ed858c02c2652fbf922e",
+            hex
+        );
+    }
+
+    #[test]
+    fn test_keys() {
+        let key: Vec<u8> = (0..64).collect();
+        assert!(matches!(
+            FlashEncryptionKey::new(Chip::Esp32c3, &key),
+            Err(FlashEncryptionError::InvalidKeyLength { len: 64, .. })
+        ));
+        assert!(matches!(
+            FlashEncryptionKey::new(Chip::Esp32, &key[..32]),
+            Err(FlashEncryptionError::UnsupportedChip(Chip::Esp32))
+        ));
+        assert!(matches!(
+            self::key().encrypt(0x1008, &[0; 16]),
+            Err(FlashEncryptionError::Unaligned(0x1008))
+        ));
+
+        let blocks = FlashEncryptionKey::new(Chip::Esp32s2, &key)
+            .unwrap()
+            .key_blocks();
+        assert_eq!(2, blocks.len());
+        assert_eq!(2, blocks[0].0);
+        assert_eq!(3, blocks[1].0);
+        assert_eq!(31, blocks[0].1[0]);
+        assert_eq!(32, blocks[1].1[31]);
+        assert_eq!(vec![(4, (0..32).rev().collect())], self::key().key_blocks());
+    }
+
+    #[test]
+    fn test_enable() {
+        let mut blocks = EfuseBlocks::blank(Chip::Esp32c3.efuse_blocks());
+        assert!(!is_enabled(Chip::Esp32c3, &blocks));
+        assert_eq!(
+            vec![0],
+            enable(Chip::Esp32c3, &mut blocks, &key(), true).unwrap()
+        );
+        assert!(is_enabled(Chip::Esp32c3, &blocks));
+        // the key block and its purpose are write protected and the key block
+        // read protected
+        assert_eq!(1 << 23 | 1 << 8, blocks.block(0)[0]);
+        assert_eq!(1, blocks.block(0)[1] & 0x7f);
+        // the key purpose and the first bit of the counter share a word
+        assert_eq!(4 << 24 | 1 << 18, blocks.block(0)[2]);
+        assert_eq!(0x1c1d_1e1f, blocks.block(4)[0]);
+        assert!(matches!(
+            enable(Chip::Esp32c3, &mut blocks, &key(), true),
+            Err(Error::FlashEncryption(FlashEncryptionError::AlreadyEnabled))
+        ));
+
+        // used key blocks are skipped, and the counter moves on to the next bit
+        let chip = Chip::Esp32s2;
+        let mut blocks = EfuseBlocks::blank(chip.efuse_blocks());
+        let field = |name| chip.efuse_field(name).unwrap();
+        blocks
+            .burn(field("KEY_PURPOSE_0"), &EfuseValue::KeyPurpose(9))
+            .unwrap();
+        blocks
+            .burn(field("SPI_BOOT_CRYPT_CNT"), &EfuseValue::Uint(0b011))
+            .unwrap();
+        let key: Vec<u8> = (0..64).collect();
+        let key = FlashEncryptionKey::new(chip, &key).unwrap();
+        assert_eq!(vec![1, 2], enable(chip, &mut blocks, &key, true).unwrap());
+        assert_eq!(
+            EfuseValue::Uint(0b111),
+            blocks.value(field("SPI_BOOT_CRYPT_CNT"))
+        );
+        assert_eq!(
+            EfuseValue::KeyPurpose(3),
+            blocks.value(field("KEY_PURPOSE_2"))
+        );
+        assert_eq!(EfuseValue::Mask(0b110), blocks.value(field("RD_DIS")));
+        assert_eq!(
+            EfuseValue::Mask(0b11 << 24 | 0b11 << 9),
+            blocks.value(field("WR_DIS"))
+        );
+
+        // without protection only the key, its purpose and the counter change
+        let mut blocks = EfuseBlocks::blank(Chip::Esp32c3.efuse_blocks());
+        enable(Chip::Esp32c3, &mut blocks, &self::key(), false).unwrap();
+        assert_eq!(0, blocks.block(0)[0]);
+        assert_eq!(0, blocks.block(0)[1]);
+    }
+}
@@ -1,34 +1,81 @@
+use crate::command::Command;
 use crate::connection::Connection;
-use crate::elf::{FirmwareImage, RomSegment};
+use crate::elf::RomSegment;
 use crate::error::Error;
-use crate::flash_target::{begin_command, block_command_with_timeout, FlashTarget};
-use crate::flasher::{Command, SpiAttachParams, FLASH_SECTOR_SIZE, FLASH_WRITE_SIZE};
+use crate::flash_target::{begin_command, block_command, block_command_with_timeout, FlashTarget};
+use crate::flasher::{SpiAttachParams, FLASH_SECTOR_SIZE, FLASH_WRITE_SIZE};
+use crate::progress::ProgressCallbacks;
 use crate::Chip;
 use flate2::write::{ZlibDecoder, ZlibEncoder};
 use flate2::Compression;
-use indicatif::{ProgressBar, ProgressStyle};
 use std::io::Write;
 
 pub struct Esp32Target {
     chip: Chip,
     spi_attach_params: SpiAttachParams,
+    compress: bool,
 }
 
 impl Esp32Target {
-    pub fn new(chip: Chip, spi_attach_params: SpiAttachParams) -> Self {
+    pub fn new(chip: Chip, spi_attach_params: SpiAttachParams, compress: bool) -> Self {
         Esp32Target {
             chip,
             spi_attach_params,
+            compress,
         }
     }
+
+    /// Write a segment using the plain `FlashBegin`/`FlashData` commands,
+    /// without compressing the data first
+    fn write_uncompressed(
+        &mut self,
+        connection: &mut Connection,
+        segment: RomSegment,
+        progress: &mut dyn ProgressCallbacks,
+    ) -> Result<u32, Error> {
+        let addr = segment.addr;
+        let block_count = segment.data.len().div_ceil(FLASH_WRITE_SIZE);
+        let erase_count = segment.data.len().div_ceil(FLASH_SECTOR_SIZE);
+
+        // round up to sector size
+        let erase_size = (erase_count * FLASH_SECTOR_SIZE) as u32;
+
+        begin_command(
+            connection,
+            Command::FlashBegin,
+            erase_size,
+            block_count as u32,
+            FLASH_WRITE_SIZE as u32,
+            addr,
+            self.chip != Chip::Esp32,
+        )?;
+
+        progress.init(addr, block_count);
+
+        for (i, block) in segment.data.chunks(FLASH_WRITE_SIZE).enumerate() {
+            let block_padding = FLASH_WRITE_SIZE - block.len();
+            block_command(
+                connection,
+                Command::FlashData,
+                block,
+                block_padding,
+                0xff,
+                i as u32,
+            )?;
+            progress.update(i + 1);
+        }
+        connection.finish_blocks()?;
+
+        progress.finish();
+
+        Ok(segment.data.len() as u32)
+    }
 }
 
 impl FlashTarget for Esp32Target {
-    fn begin(&mut self, connection: &mut Connection, _image: &FirmwareImage) -> Result<(), Error> {
+    fn begin(&mut self, connection: &mut Connection) -> Result<(), Error> {
         let spi_params = self.spi_attach_params.encode();
-        connection.with_timeout(Command::SpiAttach.timeout(), |connection| {
-            connection.command(Command::SpiAttach, spi_params.as_slice(), 0)
-        })?;
+        connection.command(Command::SpiAttach, spi_params.as_slice())?;
         Ok(())
     }
 
@@ -36,13 +83,18 @@ impl FlashTarget for Esp32Target {
         &mut self,
         connection: &mut Connection,
         segment: RomSegment,
-    ) -> Result<(), Error> {
+        progress: &mut dyn ProgressCallbacks,
+    ) -> Result<u32, Error> {
+        if !self.compress {
+            return self.write_uncompressed(connection, segment, progress);
+        }
+
         let addr = segment.addr;
         let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
         encoder.write_all(&segment.data)?;
         let compressed = encoder.finish()?;
-        let block_count = (compressed.len() + FLASH_WRITE_SIZE - 1) / FLASH_WRITE_SIZE;
-        let erase_count = (segment.data.len() + FLASH_SECTOR_SIZE - 1) / FLASH_SECTOR_SIZE;
+        let block_count = compressed.len().div_ceil(FLASH_WRITE_SIZE);
+        let erase_count = segment.data.len().div_ceil(FLASH_SECTOR_SIZE);
 
         // round up to sector size
         let erase_size = (erase_count * FLASH_SECTOR_SIZE) as u32;
@@ -58,15 +110,7 @@ impl FlashTarget for Esp32Target {
         )?;
 
         let chunks = compressed.chunks(FLASH_WRITE_SIZE);
-
-        let (_, chunk_size) = chunks.size_hint();
-        let chunk_size = chunk_size.unwrap_or(0) as u64;
-        let pb_chunk = ProgressBar::new(chunk_size);
-        pb_chunk.set_style(
-            ProgressStyle::default_bar()
-                .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}")
-                .progress_chars("#>-"),
-        );
+        progress.init(addr, block_count);
 
         // decode the chunks to see how much data the device will have to save
         let mut decoder = ZlibDecoder::new(Vec::new());
@@ -78,7 +122,6 @@ impl FlashTarget for Esp32Target {
             let size = decoder.get_ref().len() - decoded_size;
             decoded_size = decoder.get_ref().len();
 
-            pb_chunk.set_message(format!("segment 0x{:X} writing chunks", addr));
             block_command_with_timeout(
                 connection,
                 Command::FlashDeflateData,
@@ -88,17 +131,23 @@ impl FlashTarget for Esp32Target {
                 i as u32,
                 Command::FlashDeflateData.timeout_for_size(size as u32),
             )?;
-            pb_chunk.inc(1);
+            progress.update(i + 1);
         }
+        connection.finish_blocks()?;
 
-        pb_chunk.finish_with_message(format!("segment 0x{:X}", addr));
+        progress.finish();
 
-        Ok(())
+        Ok(compressed.len() as u32)
     }
 
     fn finish(&mut self, connection: &mut Connection, reboot: bool) -> Result<(), Error> {
-        connection.with_timeout(Command::FlashDeflateEnd.timeout(), |connection| {
-            connection.write_command(Command::FlashDeflateEnd as u8, &[1][..], 0)
+        let command = if self.compress {
+            Command::FlashDeflateEnd
+        } else {
+            Command::FlashEnd
+        };
+        connection.with_timeout(command.timeout(), |connection| {
+            connection.write_command(command as u8, &[1][..], 0)
         })?;
         if reboot {
             connection.reset()
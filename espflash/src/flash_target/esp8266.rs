@@ -1,9 +1,10 @@
+use crate::command::Command;
 use crate::connection::Connection;
-use crate::elf::{FirmwareImage, RomSegment};
+use crate::elf::RomSegment;
 use crate::error::Error;
 use crate::flash_target::{begin_command, block_command, FlashTarget};
-use crate::flasher::{get_erase_size, Command, FLASH_WRITE_SIZE};
-use indicatif::{ProgressBar, ProgressStyle};
+use crate::flasher::{get_erase_size, FLASH_WRITE_SIZE};
+use crate::progress::ProgressCallbacks;
 
 pub struct Esp8266Target;
 
@@ -14,7 +15,7 @@ impl Esp8266Target {
 }
 
 impl FlashTarget for Esp8266Target {
-    fn begin(&mut self, connection: &mut Connection, _image: &FirmwareImage) -> Result<(), Error> {
+    fn begin(&mut self, connection: &mut Connection) -> Result<(), Error> {
         begin_command(
             connection,
             Command::FlashBegin,
@@ -30,9 +31,10 @@ impl FlashTarget for Esp8266Target {
         &mut self,
         connection: &mut Connection,
         segment: RomSegment,
-    ) -> Result<(), Error> {
+        progress: &mut dyn ProgressCallbacks,
+    ) -> Result<u32, Error> {
         let addr = segment.addr;
-        let block_count = (segment.data.len() + FLASH_WRITE_SIZE - 1) / FLASH_WRITE_SIZE;
+        let block_count = segment.data.len().div_ceil(FLASH_WRITE_SIZE);
 
         let erase_size = get_erase_size(addr as usize, segment.data.len()) as u32;
 
@@ -46,19 +48,9 @@ impl FlashTarget for Esp8266Target {
             false,
         )?;
 
-        let chunks = segment.data.chunks(FLASH_WRITE_SIZE);
+        progress.init(addr, block_count);
 
-        let (_, chunk_size) = chunks.size_hint();
-        let chunk_size = chunk_size.unwrap_or(0) as u64;
-        let pb_chunk = ProgressBar::new(chunk_size);
-        pb_chunk.set_style(
-            ProgressStyle::default_bar()
-                .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}")
-                .progress_chars("#>-"),
-        );
-
-        for (i, block) in chunks.enumerate() {
-            pb_chunk.set_message(format!("segment 0x{:X} writing chunks", addr));
+        for (i, block) in segment.data.chunks(FLASH_WRITE_SIZE).enumerate() {
             let block_padding = FLASH_WRITE_SIZE - block.len();
             block_command(
                 connection,
@@ -68,12 +60,13 @@ impl FlashTarget for Esp8266Target {
                 0xff,
                 i as u32,
             )?;
-            pb_chunk.inc(1);
+            progress.update(i + 1);
         }
+        connection.finish_blocks()?;
 
-        pb_chunk.finish_with_message(format!("segment 0x{:X}", addr));
+        progress.finish();
 
-        Ok(())
+        Ok(segment.data.len() as u32)
     }
 
     fn finish(&mut self, connection: &mut Connection, reboot: bool) -> Result<(), Error> {
@@ -2,10 +2,12 @@ mod esp32;
 mod esp8266;
 mod ram;
 
+use crate::command::Command;
 use crate::connection::Connection;
-use crate::elf::{FirmwareImage, RomSegment};
+use crate::elf::RomSegment;
 use crate::error::Error;
-use crate::flasher::{checksum, Command, Encoder, CHECKSUM_INIT, FLASH_WRITE_SIZE};
+use crate::flasher::{checksum, Encoder, CHECKSUM_INIT, FLASH_WRITE_SIZE};
+use crate::progress::ProgressCallbacks;
 use bytemuck::{bytes_of, Pod, Zeroable};
 pub use esp32::Esp32Target;
 pub use esp8266::Esp8266Target;
@@ -14,12 +16,15 @@ use std::mem::size_of;
 use std::time::Duration;
 
 pub trait FlashTarget {
-    fn begin(&mut self, connection: &mut Connection, image: &FirmwareImage) -> Result<(), Error>;
+    fn begin(&mut self, connection: &mut Connection) -> Result<(), Error>;
+    /// Write a segment to the target, returning the number of bytes sent to
+    /// the device
     fn write_segment(
         &mut self,
         connection: &mut Connection,
         segment: RomSegment,
-    ) -> Result<(), Error>;
+        progress: &mut dyn ProgressCallbacks,
+    ) -> Result<u32, Error>;
     fn finish(&mut self, connection: &mut Connection, reboot: bool) -> Result<(), Error>;
 }
 
@@ -42,6 +47,7 @@ fn begin_command(
     offset: u32,
     supports_encrypted: bool,
 ) -> Result<(), Error> {
+    connection.check_cancelled()?;
     let params = BeginParams {
         size,
         blocks,
@@ -60,10 +66,8 @@ fn begin_command(
         bytes
     };
 
-    connection.with_timeout(command.timeout_for_size(size), |connection| {
-        connection.command(command, data, 0)?;
-        Ok(())
-    })
+    connection.command(command, data)?;
+    Ok(())
 }
 
 #[derive(Zeroable, Pod, Copy, Clone, Debug)]
@@ -103,6 +107,7 @@ fn block_command_with_timeout(
     sequence: u32,
     timout: Duration,
 ) -> Result<(), Error> {
+    connection.check_cancelled()?;
     let params = BlockParams {
         size: (data.len() + padding) as u32,
         sequence,
@@ -118,18 +123,16 @@ fn block_command_with_timeout(
         check = checksum(&[padding_byte], check);
     }
 
-    connection.with_timeout(timout, |connection| {
-        connection.command(
-            command,
-            (length as u16, |encoder: &mut Encoder| {
-                encoder.write(bytes_of(&params))?;
-                encoder.write(data)?;
-                let padding = &[padding_byte; FLASH_WRITE_SIZE][0..padding];
-                encoder.write(padding)?;
-                Ok(())
-            }),
-            check as u32,
-        )?;
-        Ok(())
-    })
+    connection.block_command(
+        command,
+        (length as u16, |encoder: &mut Encoder| {
+            encoder.write(bytes_of(&params))?;
+            encoder.write(data)?;
+            let padding = &[padding_byte; FLASH_WRITE_SIZE][0..padding];
+            encoder.write(padding)?;
+            Ok(())
+        }),
+        check as u32,
+        timout,
+    )
 }
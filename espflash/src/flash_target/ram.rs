@@ -1,8 +1,9 @@
+use crate::command::Command;
 use crate::connection::Connection;
-use crate::elf::{FirmwareImage, RomSegment};
+use crate::elf::RomSegment;
 use crate::error::Error;
 use crate::flash_target::{begin_command, block_command, FlashTarget};
-use crate::flasher::Command;
+use crate::progress::ProgressCallbacks;
 use bytemuck::{bytes_of, Pod, Zeroable};
 
 #[derive(Zeroable, Pod, Copy, Clone)]
@@ -13,18 +14,19 @@ struct EntryParams {
 }
 
 pub struct RamTarget {
+    /// Address jumped to when finishing with `reboot`, the loader keeps
+    /// running without one
     entry: Option<u32>,
 }
 
 impl RamTarget {
-    pub fn new() -> Self {
-        RamTarget { entry: None }
+    pub fn new(entry: Option<u32>) -> Self {
+        RamTarget { entry }
     }
 }
 
 impl FlashTarget for RamTarget {
-    fn begin(&mut self, _connection: &mut Connection, image: &FirmwareImage) -> Result<(), Error> {
-        self.entry = Some(image.entry());
+    fn begin(&mut self, _connection: &mut Connection) -> Result<(), Error> {
         Ok(())
     }
 
@@ -32,12 +34,12 @@ impl FlashTarget for RamTarget {
         &mut self,
         connection: &mut Connection,
         segment: RomSegment,
-    ) -> Result<(), Error> {
+        progress: &mut dyn ProgressCallbacks,
+    ) -> Result<u32, Error> {
         const MAX_RAM_BLOCK_SIZE: usize = 0x1800;
 
-        let padding = 4 - segment.data.len() % 4;
-        let block_count =
-            (segment.data.len() + padding + MAX_RAM_BLOCK_SIZE - 1) / MAX_RAM_BLOCK_SIZE;
+        let padding = (4 - segment.data.len() % 4) % 4;
+        let block_count = (segment.data.len() + padding).div_ceil(MAX_RAM_BLOCK_SIZE);
 
         begin_command(
             connection,
@@ -49,6 +51,8 @@ impl FlashTarget for RamTarget {
             false,
         )?;
 
+        progress.init(segment.addr, block_count);
+
         for (i, block) in segment.data.chunks(MAX_RAM_BLOCK_SIZE).enumerate() {
             let block_padding = if i == block_count - 1 { padding } else { 0 };
             block_command(
@@ -59,24 +63,31 @@ impl FlashTarget for RamTarget {
                 0,
                 i as u32,
             )?;
+            progress.update(i + 1);
         }
-        Ok(())
+
+        progress.finish();
+
+        Ok(segment.data.len() as u32)
     }
 
     fn finish(&mut self, connection: &mut Connection, reboot: bool) -> Result<(), Error> {
-        if reboot {
-            let params = match self.entry {
-                Some(entry) if entry > 0 => EntryParams { no_entry: 0, entry },
-                _ => EntryParams {
+        match self.entry {
+            Some(entry) if reboot && entry > 0 => {
+                let params = EntryParams { no_entry: 0, entry };
+                // there is no response once the loader jumped to the entry point
+                connection.with_timeout(Command::MemEnd.timeout(), |connection| {
+                    connection.write_command(Command::MemEnd as u8, bytes_of(&params), 0)
+                })
+            }
+            _ => {
+                let params = EntryParams {
                     no_entry: 1,
                     entry: 0,
-                },
-            };
-            connection.with_timeout(Command::MemEnd.timeout(), |connection| {
-                connection.write_command(Command::MemEnd as u8, bytes_of(&params), 0)
-            })
-        } else {
-            Ok(())
+                };
+                connection.command(Command::MemEnd, bytes_of(&params))?;
+                Ok(())
+            }
         }
     }
 }
@@ -1,122 +1,139 @@
 use bytemuck::{__core::time::Duration, bytes_of, Pod, Zeroable};
-use serial::{BaudRate, SystemPort};
-use strum_macros::Display;
+use serial::BaudRate;
+use sha2::{Digest, Sha256};
+use strum_macros::{Display, EnumString};
 
+use std::io::{stdin, stdout, IsTerminal, Write};
 use std::thread::sleep;
+use std::time::Instant;
 
+use crate::command::{Command, DEFAULT_TIMEOUT};
 use crate::elf::RomSegment;
-use crate::error::{ConnectionError, ElfError, FlashDetectError, ResultExt};
+use crate::error::{
+    ConnectionError, EfuseError, ElfError, FlashDetectError, ProbeFailure, ResultExt,
+    TimedOutCommand,
+};
 use crate::{
-    chip::Chip, connection::Connection, elf::FirmwareImage, encoder::SlipEncoder, error::RomError,
+    app_desc::{AppDescriptor, AppDescriptorOverrides, APP_DESC_OFFSET, APP_DESC_SIZE},
+    board_info::{BoardInfo, FlashId},
+    chip::{
+        efuse::{self, EfuseBlocks, EfuseController, EfuseValue, WriteRegs},
+        Chip, Efuses, Feature, SPI_USR_DUMMY,
+    },
+    connection::{CancelToken, Connection, Transport},
+    elf::{FirmwareImage, FlashSettings, FlashSize, SegmentLayout},
+    encoder::SlipEncoder,
+    error::RomError,
+    flash_encryption::FlashEncryptionKey,
+    journal::Journal,
+    manifest::format_mac,
+    output::app_image,
+    partition_table::MAX_PARTITION_LENGTH,
+    progress::{default_progress, ProgressCallbacks},
+    report::{FlashReport, RegionReport},
+    reset::ResetSequence,
     Error, PartitionTable,
 };
 use std::borrow::Cow;
 
-pub(crate) type Encoder<'a> = SlipEncoder<'a, SystemPort>;
+pub(crate) type Encoder<'a> = SlipEncoder<'a, Box<dyn Transport>>;
 
 pub(crate) const FLASH_SECTOR_SIZE: usize = 0x1000;
 const FLASH_BLOCK_SIZE: usize = 0x100;
 const FLASH_SECTORS_PER_BLOCK: usize = FLASH_SECTOR_SIZE / FLASH_BLOCK_SIZE;
 pub(crate) const FLASH_WRITE_SIZE: usize = 0x400;
 
+/// Maximum number of bits read in a single SPI flash command, limited by the
+/// size of the SPI data buffer
+const SPI_MAX_READ_BITS: u32 = 512;
+/// SPI flash `READ` opcode
+const SPI_FLASH_READ: u8 = 0x03;
+/// SPI flash `FAST_READ` opcode, which has dummy cycles before the data
+const SPI_FLASH_FAST_READ: u8 = 0x0b;
+/// SPI flash `READ4B` and `FAST_READ4B` opcodes, taking a 4 byte address for
+/// reading above 16 MB
+const SPI_FLASH_READ4B: u8 = 0x13;
+const SPI_FLASH_FAST_READ4B: u8 = 0x0c;
+/// Dummy cycles of `FAST_READ` unless configured otherwise
+const SPI_FLASH_FAST_READ_DUMMY_CYCLES: u8 = 8;
+/// SPI flash `WREN` opcode, enabling writes to the status register
+const SPI_FLASH_WREN: u8 = 0x06;
+/// SPI flash `RDSR` and `RDSR2` opcodes, reading the low and high byte of the
+/// status register
+const SPI_FLASH_RDSR: u8 = 0x05;
+const SPI_FLASH_RDSR2: u8 = 0x35;
+/// SPI flash `RDID` opcode, reading the JEDEC id of the flash chip
+const SPI_FLASH_RDID: u8 = 0x9f;
+/// SPI flash `RDUID` opcode, reading the unique id of the flash chip
+const SPI_FLASH_RDUID: u8 = 0x4b;
+/// SPI flash `WRSR` opcode, writing both bytes of the status register
+const SPI_FLASH_WRSR: u8 = 0x01;
+/// Status register bit set while the flash is busy writing
+const FLASH_STATUS_WIP: u16 = 1 << 0;
+/// Status register bits protecting flash regions from writes: the block
+/// protection bits, the status register protection bit and the complement
+/// protect bit
+pub const FLASH_STATUS_PROTECTION: u16 = 0x7c | 1 << 7 | 1 << 14;
+/// How long to wait for a port to reappear, after the device dropped off the
+/// bus while resetting
+const REOPEN_WINDOW: Duration = Duration::from_secs(5);
+
+/// Largest SPI clock divider supported by every chip
+const MAX_CLOCK_DIVIDER: u8 = 64;
+
+/// Payload of the `Sync` command
+const SYNC_FRAME: [u8; 36] = [
+    0x07, 0x07, 0x12, 0x20, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55,
+    0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55,
+    0x55, 0x55, 0x55, 0x55,
+];
+
 // register used for chip detect
 const CHIP_DETECT_MAGIC_REG_ADDR: u32 = 0x40001000;
 
-const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
-const ERASE_REGION_TIMEOUT_PER_MB: Duration = Duration::from_secs(30);
-const ERASE_WRITE_TIMEOUT_PER_MB: Duration = Duration::from_secs(40);
-const MEM_END_TIMEOUT: Duration = Duration::from_millis(50);
-const SYNC_TIMEOUT: Duration = Duration::from_millis(100);
-
-#[derive(Copy, Clone, Debug, Display)]
-#[allow(dead_code)]
-#[repr(u8)]
-#[non_exhaustive]
-pub enum Command {
-    Unknown = 0,
-    FlashBegin = 0x02,
-    FlashData = 0x03,
-    FlashEnd = 0x04,
-    MemBegin = 0x05,
-    MemEnd = 0x06,
-    MemData = 0x07,
-    Sync = 0x08,
-    WriteReg = 0x09,
-    ReadReg = 0x0a,
-    SpiSetParams = 0x0B,
-    SpiAttach = 0x0D,
-    ChangeBaud = 0x0F,
-    FlashDeflateBegin = 0x10,
-    FlashDeflateData = 0x11,
-    FlashDeflateEnd = 0x12,
-    FlashMd5 = 0x13,
-    FlashDetect = 0x9f,
-}
-
-impl Command {
-    pub fn timeout(&self) -> Duration {
-        match self {
-            Command::MemEnd => MEM_END_TIMEOUT,
-            Command::Sync => SYNC_TIMEOUT,
-            _ => DEFAULT_TIMEOUT,
-        }
-    }
-
-    pub fn timeout_for_size(&self, size: u32) -> Duration {
-        fn calc_timeout(timeout_per_mb: Duration, size: u32) -> Duration {
-            let mb = size as f64 / 1_000_000.0;
-            std::cmp::max(
-                DEFAULT_TIMEOUT,
-                Duration::from_millis((timeout_per_mb.as_millis() as f64 * mb) as u64),
-            )
-        }
-        match self {
-            Command::FlashBegin | Command::FlashDeflateBegin => {
-                calc_timeout(ERASE_REGION_TIMEOUT_PER_MB, size)
-            }
-            Command::FlashData | Command::FlashDeflateData => {
-                calc_timeout(ERASE_WRITE_TIMEOUT_PER_MB, size)
-            }
-            _ => self.timeout(),
-        }
-    }
+/// Timing of the SPI flash accesses made by the loader, overriding the values
+/// set up by the ROM
+///
+/// Slow external flash may need a lower clock or additional dummy cycles
+/// before data is read, otherwise reading or verifying it fails.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct FlashTiming {
+    /// Divider of the APB clock used as SPI clock, from 1 to 64
+    pub clock_divider: Option<u8>,
+    /// Number of dummy cycles inserted before data is read
+    pub dummy_cycles: Option<u8>,
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq, Display)]
-#[allow(dead_code)]
-#[repr(u8)]
-pub enum FlashSize {
-    #[strum(serialize = "256KB")]
-    Flash256Kb = 0x12,
-    #[strum(serialize = "512KB")]
-    Flash512Kb = 0x13,
-    #[strum(serialize = "1MB")]
-    Flash1Mb = 0x14,
-    #[strum(serialize = "2MB")]
-    Flash2Mb = 0x15,
-    #[strum(serialize = "4MB")]
-    Flash4Mb = 0x16,
-    #[strum(serialize = "8MB")]
-    Flash8Mb = 0x17,
-    #[strum(serialize = "16MB")]
-    Flash16Mb = 0x18,
-    FlashRetry = 0xFF, // used to hint that alternate detection should be tried
+/// How to reset the chip into the ROM bootloader before connecting, as in
+/// esptool's `--before`
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Display, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum ResetBeforeOperation {
+    /// Toggle `DTR` and `RTS` through the usual auto-reset circuit, or use
+    /// the sequence of the USB-JTAG-serial peripheral when connected to it
+    #[default]
+    DefaultReset,
+    /// The sequence of the USB-JTAG-serial peripheral of the chip
+    UsbReset,
+    /// Don't reset, the chip is already in the bootloader
+    NoReset,
 }
 
-impl FlashSize {
-    fn from(value: u8) -> Result<FlashSize, Error> {
-        match value {
-            0x12 => Ok(FlashSize::Flash256Kb),
-            0x13 => Ok(FlashSize::Flash512Kb),
-            0x14 => Ok(FlashSize::Flash1Mb),
-            0x15 => Ok(FlashSize::Flash2Mb),
-            0x16 => Ok(FlashSize::Flash4Mb),
-            0x17 => Ok(FlashSize::Flash8Mb),
-            0x18 => Ok(FlashSize::Flash16Mb),
-            0xFF => Ok(FlashSize::FlashRetry),
-            _ => Err(Error::UnsupportedFlash(FlashDetectError::from(value))),
-        }
-    }
+/// What to do with the chip after flashing, as in esptool's `--after`
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Display, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum ResetAfterOperation {
+    /// Reset the chip through `RTS`
+    #[default]
+    HardReset,
+    /// Ask the ROM bootloader to start the application, for boards without
+    /// an auto-reset circuit
+    SoftReset,
+    /// Leave the chip in the bootloader
+    NoReset,
+    /// Leave the chip in the bootloader, like `no_reset` as there is no stub
+    /// loader to exit
+    StayInBootloader,
 }
 
 #[derive(Copy, Clone)]
@@ -208,27 +225,224 @@ pub struct Flasher {
     chip: Chip,
     flash_size: FlashSize,
     spi_params: SpiAttachParams,
+    safe_mode: bool,
+    app_desc: AppDescriptorOverrides,
+    flash_settings: FlashSettings,
+    segment_layout: SegmentLayout,
+    flash_timing: FlashTiming,
+    speed: BaudRate,
+    progress: Box<dyn ProgressCallbacks>,
+    reset_after: ResetAfterOperation,
+    encryption_key: Option<FlashEncryptionKey>,
 }
 
-impl Flasher {
-    pub fn connect(serial: SystemPort, speed: Option<BaudRate>) -> Result<Self, Error> {
-        let mut flasher = Flasher {
-            connection: Connection::new(serial), // default baud is always 115200
-            chip: Chip::Esp8266,                 // dummy, set properly later
-            flash_size: FlashSize::Flash4Mb,
-            spi_params: SpiAttachParams::default(), // may be set when trying to attach to flash
+/// Settings to connect a [`Flasher`] with
+///
+/// ```no_run
+/// use espflash::{FlasherBuilder, ResetBeforeOperation};
+/// use serial::BaudRate;
+///
+/// let flasher = FlasherBuilder::new()
+///     .port("/dev/ttyUSB0")
+///     .speed(BaudRate::from_speed(921_600))
+///     .before(ResetBeforeOperation::UsbReset)
+///     .connect()?;
+/// # Ok::<(), espflash::Error>(())
+/// ```
+pub struct FlasherBuilder {
+    transport: Option<Box<dyn Transport>>,
+    port: Option<String>,
+    retry_open: Duration,
+    baud: usize,
+    speed: Option<BaudRate>,
+    chip: Option<Chip>,
+    timeout: Duration,
+    before: ResetBeforeOperation,
+    after: ResetAfterOperation,
+    journal: Option<Journal>,
+    usb_jtag_serial: Option<bool>,
+    reopen: Option<String>,
+    reset_sequence: Option<ResetSequence>,
+}
+
+impl Default for FlasherBuilder {
+    fn default() -> Self {
+        FlasherBuilder {
+            transport: None,
+            port: None,
+            retry_open: Duration::ZERO,
+            baud: 115_200,
+            speed: None,
+            chip: None,
+            timeout: DEFAULT_TIMEOUT,
+            before: ResetBeforeOperation::default(),
+            after: ResetAfterOperation::default(),
+            journal: None,
+            usb_jtag_serial: None,
+            reopen: None,
+            reset_sequence: None,
+        }
+    }
+}
+
+impl FlasherBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Open the serial port or `tcp://` and `rfc2217://` URL `port`, see
+    /// [`port::open`](crate::port::open)
+    pub fn port(mut self, port: impl Into<String>) -> Self {
+        self.port = Some(port.into());
+        self
+    }
+
+    /// Connect over `transport` instead of opening a port
+    pub fn transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.transport = Some(Box::new(transport));
+        self
+    }
+
+    /// How long to retry opening the port while the device is being
+    /// enumerated, not retrying by default
+    pub fn retry_open(mut self, retry_open: Duration) -> Self {
+        self.retry_open = retry_open;
+        self
+    }
+
+    /// Baud rate to synchronize with the bootloader at, 115200 by default
+    pub fn baud(mut self, baud: usize) -> Self {
+        self.baud = baud;
+        self
+    }
+
+    /// Baud rate to switch to once connected, not supported by the ESP8266
+    pub fn speed(mut self, speed: impl Into<Option<BaudRate>>) -> Self {
+        self.speed = speed.into();
+        self
+    }
+
+    /// Fail unless the connected chip is `chip`
+    pub fn chip(mut self, chip: impl Into<Option<Chip>>) -> Self {
+        self.chip = chip.into();
+        self
+    }
+
+    /// How long to wait for the response to a command without a timeout of
+    /// its own, 3 seconds by default
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How to reset the chip into the bootloader
+    pub fn before(mut self, before: ResetBeforeOperation) -> Self {
+        self.before = before;
+        self
+    }
+
+    /// What to do with the chip after an image was written to flash
+    pub fn after(mut self, after: ResetAfterOperation) -> Self {
+        self.after = after;
+        self
+    }
+
+    /// Reset the chip into the bootloader with `sequence` rather than the
+    /// sequence selected with [`FlasherBuilder::before`], unless that is
+    /// [`ResetBeforeOperation::NoReset`]
+    pub fn reset_sequence(mut self, sequence: impl Into<Option<ResetSequence>>) -> Self {
+        self.reset_sequence = sequence.into();
+        self
+    }
+
+    /// Whether the port is the USB-JTAG-serial peripheral of the chip
+    ///
+    /// Its `DTR` and `RTS` lines don't drive an auto-reset circuit, so it's
+    /// reset into the bootloader with its own sequence, and it runs at the same
+    /// speed whatever the baud rate, so the baud rate isn't changed. This is
+    /// detected from the USB ids of the port given to [`FlasherBuilder::port`].
+    pub fn usb_jtag_serial(mut self, usb_jtag_serial: bool) -> Self {
+        self.usb_jtag_serial = Some(usb_jtag_serial);
+        self
+    }
+
+    /// Reopen the serial port `port` if the device drops off the bus while it
+    /// is reset into the bootloader
+    ///
+    /// This happens with the USB CDC port of an application, e.g. through
+    /// TinyUSB, which is replaced by the one of the ROM bootloader. Ports given
+    /// to [`FlasherBuilder::port`] are reopened by default.
+    pub fn reopen(mut self, port: impl Into<String>) -> Self {
+        self.reopen = Some(port.into());
+        self
+    }
+
+    /// Record every command sent in `journal`, including the attempts to
+    /// synchronize with the bootloader
+    pub fn journal(mut self, journal: impl Into<Option<Journal>>) -> Self {
+        self.journal = journal.into();
+        self
+    }
+
+    /// Connect to the device and attach its flash
+    pub fn connect(self) -> Result<Flasher, Error> {
+        let usb_jtag_serial = self.usb_jtag_serial.unwrap_or_else(|| {
+            self.transport.is_none()
+                && self
+                    .port
+                    .as_deref()
+                    .is_some_and(crate::port::is_usb_jtag_serial)
+        });
+        let reopen = match (&self.reopen, &self.transport) {
+            (Some(port), _) => Some(port.clone()),
+            (None, None) => self.port.clone(),
+            (None, Some(_)) => None,
         };
-        flasher.start_connection()?;
-        flasher.connection.set_timeout(DEFAULT_TIMEOUT)?;
+        let mut serial = match (self.transport, self.port) {
+            (Some(transport), _) => transport,
+            (None, Some(port)) => crate::port::open(&port, self.retry_open)?,
+            (None, None) => return Err(Error::NoSerialPort),
+        };
+        if self.baud != 115_200 {
+            serial.set_baud(self.baud)?;
+        }
+
+        let mut flasher = Flasher::new(serial, self.journal);
+        flasher.connection.set_usb_jtag_serial(usb_jtag_serial);
+        flasher.speed = BaudRate::from_speed(self.baud);
+        flasher.reset_after = self.after;
+        let started = flasher.start_connection(self.before, self.reset_sequence.as_ref());
+        match (started, reopen) {
+            (Err(err), Some(port)) if is_disconnect(&err) => {
+                log::info!("{} disappeared while resetting, reopening it", port);
+                let mut serial = crate::port::open(&port, self.retry_open.max(REOPEN_WINDOW))?;
+                if self.baud != 115_200 {
+                    serial.set_baud(self.baud)?;
+                }
+                flasher.connection.set_serial(serial);
+                flasher.start_connection(ResetBeforeOperation::NoReset, None)?;
+            }
+            (result, _) => result?,
+        }
+        flasher.connection.set_timeout(self.timeout)?;
         flasher.chip_detect()?;
+        match self.chip {
+            Some(expected) if expected != flasher.chip => {
+                return Err(Error::ChipMismatch {
+                    expected,
+                    found: flasher.chip,
+                })
+            }
+            _ => {}
+        }
         flasher.spi_autodetect()?;
 
-        if let Some(b) = speed {
+        if let Some(b) = self.speed {
             match flasher.chip {
                 Chip::Esp8266 => (), /* Not available */
                 _ => {
-                    if b.speed() > BaudRate::Baud115200.speed() {
-                        println!("WARN setting baud rate higher than 115200 can cause issues.");
+                    if b.speed() > BaudRate::Baud115200.speed() && !usb_jtag_serial {
+                        log::warn!("Setting baud rate higher than 115200 can cause issues");
                         flasher.change_baud(b)?;
                     }
                 }
@@ -237,6 +451,59 @@ impl Flasher {
 
         Ok(flasher)
     }
+}
+
+impl Flasher {
+    /// Connect to the device on `serial`, a port from [`port::open`] or any
+    /// other [`Transport`], switching to `speed` once connected
+    ///
+    /// Use [`FlasherBuilder`] for more control over connecting.
+    ///
+    /// [`port::open`]: crate::port::open
+    pub fn connect(
+        serial: impl Transport + 'static,
+        speed: impl Into<Option<BaudRate>>,
+    ) -> Result<Self, Error> {
+        FlasherBuilder::new()
+            .transport(serial)
+            .speed(speed)
+            .connect()
+    }
+
+    /// Check that a device responds and detect its chip type, without
+    /// attaching the flash or changing the baud rate
+    ///
+    /// This is much cheaper than connecting, for example to health-check a
+    /// fixture before starting a long flash job. Use
+    /// [`Error::probe_failure`] to classify why probing failed.
+    pub fn probe(serial: impl Transport + 'static) -> Result<Chip, Error> {
+        let mut flasher = Flasher::new(Box::new(serial), None);
+        flasher.start_connection(ResetBeforeOperation::default(), None)?;
+        flasher.connection.set_timeout(DEFAULT_TIMEOUT)?;
+        flasher.chip_detect()?;
+
+        Ok(flasher.chip)
+    }
+
+    fn new(serial: Box<dyn Transport>, journal: Option<Journal>) -> Self {
+        let mut connection = Connection::new(serial); // default baud is always 115200
+        connection.set_journal(journal);
+        Flasher {
+            connection,
+            chip: Chip::Esp8266, // dummy, set properly later
+            flash_size: FlashSize::Flash4Mb,
+            spi_params: SpiAttachParams::default(), // may be set when trying to attach to flash
+            safe_mode: false,
+            app_desc: AppDescriptorOverrides::default(),
+            flash_settings: FlashSettings::default(),
+            segment_layout: SegmentLayout::default(),
+            flash_timing: FlashTiming::default(),
+            speed: BaudRate::Baud115200,
+            progress: default_progress(),
+            reset_after: ResetAfterOperation::default(),
+            encryption_key: None,
+        }
+    }
 
     fn spi_autodetect(&mut self) -> Result<(), Error> {
         // loop over all available spi params until we find one that successfully reads
@@ -258,28 +525,33 @@ impl Flasher {
         let magic = self.read_reg(CHIP_DETECT_MAGIC_REG_ADDR)?;
         let chip = Chip::from_magic(magic)?;
 
+        log::debug!("Detected chip {}", chip);
         self.chip = chip;
         Ok(())
     }
 
     fn flash_detect(&mut self) -> Result<bool, Error> {
-        let flash_id = self.spi_command(Command::FlashDetect, &[], 24)?;
-        let size_id = flash_id >> 16;
+        let flash_id = self.flash_id()?;
+        log::debug!("Flash chip: {}", flash_id);
 
-        self.flash_size = FlashSize::from(size_id as u8)?;
+        self.flash_size =
+            FlashSize::from(flash_id.capacity).map_err(|_| FlashDetectError::from(flash_id))?;
         Ok(self.flash_size != FlashSize::FlashRetry)
     }
 
-    fn sync(&mut self) -> Result<(), Error> {
+    /// Synchronize with the ROM bootloader
+    pub fn sync(&mut self) -> Result<(), Error> {
+        let sent = Instant::now();
+        let result = self.sync_inner();
         self.connection
-            .with_timeout(Command::Sync.timeout(), |connection| {
-                let data = &[
-                    0x07, 0x07, 0x12, 0x20, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55,
-                    0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55,
-                    0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55,
-                ][..];
+            .record(Command::Sync, SYNC_FRAME.len() as u16, sent, &result);
+        result
+    }
 
-                connection.write_command(Command::Sync as u8, data, 0)?;
+    fn sync_inner(&mut self) -> Result<(), Error> {
+        self.connection
+            .with_timeout(Command::Sync.timeout(), |connection| {
+                connection.write_command(Command::Sync as u8, &SYNC_FRAME[..], 0)?;
 
                 for _ in 0..100 {
                     match connection.read_response()? {
@@ -306,12 +578,26 @@ impl Flasher {
         Ok(())
     }
 
-    fn start_connection(&mut self) -> Result<(), Error> {
-        self.connection.reset_to_flash()?;
+    fn start_connection(
+        &mut self,
+        before: ResetBeforeOperation,
+        sequence: Option<&ResetSequence>,
+    ) -> Result<(), Error> {
+        match (before, sequence) {
+            (ResetBeforeOperation::NoReset, _) => {}
+            (_, Some(sequence)) => self.connection.run_reset_sequence(sequence)?,
+            (ResetBeforeOperation::DefaultReset, _) if self.connection.is_usb_jtag_serial() => {
+                self.connection.usb_reset_to_flash()?
+            }
+            (ResetBeforeOperation::DefaultReset, _) => self.connection.reset_to_flash()?,
+            (ResetBeforeOperation::UsbReset, _) => self.connection.usb_reset_to_flash()?,
+        }
         for _ in 0..10 {
             self.connection.flush()?;
-            if self.sync().is_ok() {
-                return Ok(());
+            match self.sync() {
+                Ok(()) => return Ok(()),
+                Err(err) if is_disconnect(&err) => return Err(err),
+                Err(_) => {}
             }
         }
         Err(Error::Connection(ConnectionError::ConnectionFailed))
@@ -343,11 +629,8 @@ impl Flasher {
             bytes
         };
 
-        self.connection
-            .with_timeout(command.timeout_for_size(size), |connection| {
-                connection.command(command, data, 0)?;
-                Ok(())
-            })
+        self.connection.command(command, data)?;
+        Ok(())
     }
 
     fn enable_flash(&mut self, spi_attach_params: SpiAttachParams) -> Result<(), Error> {
@@ -358,52 +641,69 @@ impl Flasher {
             _ => {
                 let spi_params = spi_attach_params.encode();
                 self.connection
-                    .with_timeout(Command::SpiAttach.timeout(), |connection| {
-                        connection.command(Command::SpiAttach, spi_params.as_slice(), 0)
-                    })?;
+                    .command(Command::SpiAttach, spi_params.as_slice())?;
             }
         }
+        // attaching the flash resets the timing set up by the ROM
+        self.apply_flash_timing()
+    }
+
+    fn apply_flash_timing(&mut self) -> Result<(), Error> {
+        let spi_registers = self.chip.spi_registers();
+
+        if let Some(divider) = self.flash_timing.clock_divider {
+            self.write_reg(
+                spi_registers.clock(),
+                spi_registers.clock_value(divider),
+                None,
+            )?;
+        }
+        if let Some(cycles) = self.flash_timing.dummy_cycles {
+            let mask = spi_registers.dummy_cyclelen_mask();
+            self.write_reg(
+                spi_registers.usr1(),
+                cycles.saturating_sub(1) as u32,
+                Some(mask),
+            )?;
+            let usr = if cycles > 0 { SPI_USR_DUMMY } else { 0 };
+            self.write_reg(spi_registers.usr(), usr, Some(SPI_USR_DUMMY))?;
+        }
+
         Ok(())
     }
 
-    fn spi_command(&mut self, command: Command, data: &[u8], read_bits: u32) -> Result<u32, Error> {
-        assert!(read_bits < 32);
+    fn spi_command(&mut self, opcode: u8, data: &[u8], read_bits: u32) -> Result<u64, Error> {
+        assert!(read_bits <= 64);
+
+        let words = self.spi_transfer(opcode, data, read_bits)?;
+        let low = words.first().copied().unwrap_or_default() as u64;
+        let high = words.get(1).copied().unwrap_or_default() as u64;
+
+        Ok(low | high << 32)
+    }
+
+    /// Run a command on the SPI flash, returning the words read back
+    fn spi_transfer(&mut self, opcode: u8, data: &[u8], read_bits: u32) -> Result<Vec<u32>, Error> {
+        assert!(read_bits <= SPI_MAX_READ_BITS);
         assert!(data.len() < 64);
 
         let spi_registers = self.chip.spi_registers();
 
         let old_spi_usr = self.read_reg(spi_registers.usr())?;
+        let old_spi_usr1 = self.read_reg(spi_registers.usr1())?;
         let old_spi_usr2 = self.read_reg(spi_registers.usr2())?;
 
-        let mut flags = 1 << 31;
-        if !data.is_empty() {
-            flags |= 1 << 27;
-        }
-        if read_bits > 0 {
-            flags |= 1 << 28;
-        }
-
-        self.write_reg(spi_registers.usr(), flags, None)?;
-        self.write_reg(spi_registers.usr2(), 7 << 28 | command as u32, None)?;
-
-        if let (Some(mosi_data_length), Some(miso_data_length)) =
-            (spi_registers.mosi_length(), spi_registers.miso_length())
-        {
-            if !data.is_empty() {
-                self.write_reg(mosi_data_length, data.len() as u32 * 8 - 1, None)?;
-            }
-            if read_bits > 0 {
-                self.write_reg(miso_data_length, read_bits - 1, None)?;
-            }
-        } else {
-            let mosi_mask = if data.is_empty() {
-                0
-            } else {
-                data.len() as u32 * 8 - 1
-            };
-            let miso_mask = if read_bits == 0 { 0 } else { read_bits - 1 };
-            self.write_reg(spi_registers.usr1(), miso_mask << 8 | mosi_mask << 17, None)?;
+        let dummy_cycles = match opcode {
+            SPI_FLASH_FAST_READ | SPI_FLASH_FAST_READ4B => self
+                .flash_timing
+                .dummy_cycles
+                .unwrap_or(SPI_FLASH_FAST_READ_DUMMY_CYCLES),
+            _ => 0,
+        };
+        for (reg, value, mask) in spi_registers.user_command(data.len(), read_bits, dummy_cycles) {
+            self.write_reg(reg, value, mask)?;
         }
+        self.write_reg(spi_registers.usr2(), 7 << 28 | opcode as u32, None)?;
 
         if data.is_empty() {
             self.write_reg(spi_registers.w0(), 0, None)?;
@@ -412,7 +712,7 @@ impl Flasher {
                 let mut data_bytes = [0; 4];
                 data_bytes[0..bytes.len()].copy_from_slice(bytes);
                 let data = u32::from_le_bytes(data_bytes);
-                self.write_reg(spi_registers.w0() + i as u32, data, None)?;
+                self.write_reg(spi_registers.w0() + i as u32 * 4, data, None)?;
             }
         }
 
@@ -426,38 +726,171 @@ impl Flasher {
             }
             i += 1;
             if i > 10 {
-                return Err(Error::Connection(ConnectionError::Timeout(command.into())));
+                return Err(Error::Connection(ConnectionError::Timeout(
+                    TimedOutCommand::spi(opcode),
+                )));
             }
         }
 
-        let result = self.read_reg(spi_registers.w0())?;
+        let mut result = Vec::with_capacity(read_bits.div_ceil(32) as usize);
+        for i in 0..read_bits.div_ceil(32).max(1) {
+            result.push(self.read_reg(spi_registers.w0() + i * 4)?);
+        }
         self.write_reg(spi_registers.usr(), old_spi_usr, None)?;
+        self.write_reg(spi_registers.usr1(), old_spi_usr1, None)?;
         self.write_reg(spi_registers.usr2(), old_spi_usr2, None)?;
 
         Ok(result)
     }
 
-    fn read_reg(&mut self, reg: u32) -> Result<u32, Error> {
+    /// Read a 32 bit register or memory address on the device
+    pub fn read_reg(&mut self, reg: u32) -> Result<u32, Error> {
         self.connection
-            .with_timeout(Command::ReadReg.timeout(), |connection| {
-                connection.command(Command::ReadReg, &reg.to_le_bytes()[..], 0)
-            })
+            .command(Command::ReadReg, &reg.to_le_bytes())
+    }
+
+    /// Read `length` bytes of memory starting at `addr`
+    ///
+    /// The memory is read a word at a time with `ReadReg`, so reading
+    /// registers with side effects, like FIFOs, triggers them.
+    pub fn read_mem(&mut self, addr: u32, length: u32) -> Result<Vec<u8>, Error> {
+        let start = addr & !3;
+        let end = (addr as u64 + length as u64 + 3) & !3;
+
+        let mut data = Vec::with_capacity((end - start as u64) as usize);
+        for word in (start as u64..end).step_by(4) {
+            self.connection.check_cancelled()?;
+            data.extend_from_slice(&self.read_reg(word as u32)?.to_le_bytes());
+        }
+
+        let offset = (addr - start) as usize;
+        Ok(data[offset..offset + length as usize].to_vec())
+    }
+
+    /// Write a 32 bit register or memory address on the device, only
+    /// changing the bits set in `mask` if given
+    pub fn write_reg(&mut self, addr: u32, value: u32, mask: Option<u32>) -> Result<(), Error> {
+        self.write_reg_delayed(addr, value, mask, 0)
     }
 
-    fn write_reg(&mut self, addr: u32, value: u32, mask: Option<u32>) -> Result<(), Error> {
+    /// Write a 32 bit register like [`Flasher::write_reg`], then have the
+    /// loader wait `delay_us` microseconds before answering
+    pub fn write_reg_delayed(
+        &mut self,
+        addr: u32,
+        value: u32,
+        mask: Option<u32>,
+        delay_us: u32,
+    ) -> Result<(), Error> {
         let params = WriteRegParams {
             addr,
             value,
             mask: mask.unwrap_or(0xFFFFFFFF),
-            delay_us: 0,
+            delay_us,
         };
         self.connection
-            .with_timeout(Command::WriteReg.timeout(), |connection| {
-                connection.command(Command::WriteReg, bytes_of(&params), 0)
-            })?;
+            .command(Command::WriteReg, bytes_of(&params))?;
         Ok(())
     }
 
+    /// Send a command to the ROM bootloader with the given payload, returning
+    /// the value field of its response
+    ///
+    /// This is intended for debugging the loader protocol, the higher level
+    /// methods should be preferred for everything else.
+    pub fn command(&mut self, command: Command, data: &[u8]) -> Result<u32, Error> {
+        self.connection.command(command, data)
+    }
+
+    /// The connection to the loader, for sending commands without a higher
+    /// level method
+    pub fn connection(&mut self) -> &mut Connection {
+        &mut self.connection
+    }
+
+    /// Attach the SPI flash using the pins detected while connecting
+    pub fn spi_attach(&mut self) -> Result<(), Error> {
+        self.enable_flash(self.spi_params)
+    }
+
+    /// Only use the most conservative ROM commands while flashing
+    ///
+    /// In safe mode data is written uncompressed using the plain `FlashBegin`
+    /// and `FlashData` commands, which every ROM loader supports. This is a
+    /// slow but reliable fallback for unusual hardware. Baud rate changes
+    /// should be avoided as well, by not passing a speed to [`connect`].
+    ///
+    /// [`connect`]: Flasher::connect
+    pub fn set_safe_mode(&mut self, safe_mode: bool) {
+        self.safe_mode = safe_mode;
+    }
+
+    /// Send each block of data written to flash while waiting for the response
+    /// to the previous one, see [`Connection::set_pipelined`]
+    pub fn set_pipelined(&mut self, pipelined: bool) {
+        self.connection.set_pipelined(pipelined);
+    }
+
+    /// Override fields of the application descriptor in images written to
+    /// flash
+    pub fn set_app_descriptor_overrides(&mut self, overrides: AppDescriptorOverrides) {
+        self.app_desc = overrides;
+    }
+
+    /// Override the flash parameters and layout of images written to flash
+    pub fn set_flash_settings(&mut self, settings: FlashSettings) {
+        self.flash_settings = settings;
+    }
+
+    /// Override the layout of the segments in images written to flash
+    pub fn set_segment_layout(&mut self, layout: SegmentLayout) {
+        self.segment_layout = layout;
+    }
+
+    /// Encrypt everything written to flash with `key` on the host, for
+    /// devices with flash encryption enabled
+    pub fn set_flash_encryption_key(&mut self, key: Option<FlashEncryptionKey>) {
+        self.encryption_key = key;
+    }
+
+    /// What to do with the chip after an image was written to flash
+    pub fn set_reset_after(&mut self, reset_after: ResetAfterOperation) {
+        self.reset_after = reset_after;
+    }
+
+    /// Stop writing to and reading from flash once `cancel` is cancelled
+    pub fn set_cancel_token(&mut self, cancel: Option<CancelToken>) {
+        self.connection.set_cancel_token(cancel);
+    }
+
+    /// Report the progress of writing to flash with `progress` instead of
+    /// progress bars
+    pub fn set_progress_callbacks(&mut self, progress: Box<dyn ProgressCallbacks>) {
+        self.progress = progress;
+    }
+
+    /// Override the timing of flash accesses, applying it to the attached
+    /// flash right away
+    pub fn set_flash_timing(&mut self, timing: FlashTiming) -> Result<(), Error> {
+        if let Some(divider) = timing.clock_divider {
+            if divider == 0 || divider > MAX_CLOCK_DIVIDER {
+                return Err(Error::InvalidFlashTiming(
+                    "the clock divider must be between 1 and 64",
+                ));
+            }
+        }
+        if let Some(cycles) = timing.dummy_cycles {
+            if cycles as u32 > self.chip.spi_registers().dummy_cyclelen_mask() + 1 {
+                return Err(Error::InvalidFlashTiming(
+                    "too many dummy cycles for the chip",
+                ));
+            }
+        }
+
+        self.flash_timing = timing;
+        self.apply_flash_timing()
+    }
+
     /// The chip type that the flasher is connected to
     pub fn chip(&self) -> Chip {
         self.chip
@@ -468,14 +901,490 @@ impl Flasher {
         self.flash_size
     }
 
+    /// Read a word from the efuse read registers, starting at block 0
+    pub fn read_efuse(&mut self, word: u32) -> Result<u32, Error> {
+        self.read_reg(self.chip.efuse_reg_base() + word * 4)
+    }
+
+    /// Read the efuse words holding the package and features of the chip, see
+    /// [`Chip::features`]
+    pub fn read_efuses(&mut self) -> Result<Efuses, Error> {
+        let mut efuses = Efuses::new();
+        for &reg in self.chip.feature_efuse_regs() {
+            efuses.insert(reg, self.read_reg(reg)?);
+        }
+        Ok(efuses)
+    }
+
+    /// Read all words of the efuse blocks of the chip
+    pub fn read_efuse_blocks(&mut self) -> Result<EfuseBlocks, Error> {
+        let mut blocks = Vec::new();
+        for block in self.chip.efuse_blocks() {
+            let words = (0..block.words)
+                .map(|word| self.read_reg(block.reg + word * 4))
+                .collect::<Result<_, _>>()?;
+            blocks.push(words);
+        }
+        Ok(EfuseBlocks::new(blocks))
+    }
+
+    /// Burn the efuses to `blocks`, which is what [`Flasher::read_efuse_blocks`]
+    /// returned with bits set by [`EfuseBlocks::burn`]
+    ///
+    /// Burning is irreversible. The blocks are read back afterwards, bits which
+    /// didn't get burned are reported as an error. Block 0 is burned last, so
+    /// the blocks it read protects are still verified.
+    pub fn burn_efuses(&mut self, blocks: &EfuseBlocks) -> Result<(), Error> {
+        let chip = self.chip;
+        let controller = chip
+            .efuse_controller()
+            .ok_or(EfuseError::Unsupported(chip))?;
+        let current = self.read_efuse_blocks()?;
+        let changed: Vec<usize> = (0..chip.efuse_blocks().len())
+            .filter(|block| blocks.block(*block) != current.block(*block))
+            .collect();
+        if changed.is_empty() {
+            return Ok(());
+        }
+
+        let baud = self.speed.speed() as u32;
+        let crystal_frequency = chip.crystal_frequency(&mut |reg| self.read_reg(reg), baud)?;
+        let (_, timing) = controller
+            .timing
+            .iter()
+            .find(|(frequency, _)| *frequency == crystal_frequency)
+            .ok_or(EfuseError::UnsupportedCrystal(crystal_frequency))?;
+        for (reg, mask, value) in timing.iter() {
+            self.write_reg(*reg, *value, Some(*mask))?;
+        }
+
+        match controller.write_regs {
+            WriteRegs::PerBlock(_) => {
+                if let Some(block) = changed.iter().find(|block| **block > 0) {
+                    let coding_scheme = chip
+                        .efuse_field("CODING_SCHEME")
+                        .map(|field| current.value(field));
+                    if coding_scheme.is_some_and(|scheme| scheme != EfuseValue::Uint(0)) {
+                        let name = chip.efuse_blocks()[*block].name;
+                        return Err(EfuseError::CodingScheme(name.to_string()).into());
+                    }
+                }
+            }
+            WriteRegs::Shared { .. } => {
+                // blocks protected by Reed-Solomon check values can't be
+                // burned again
+                if let Some(block) = changed.iter().find(|block| {
+                    **block > 0 && current.block(**block).iter().any(|word| *word != 0)
+                }) {
+                    let name = chip.efuse_blocks()[*block].name;
+                    return Err(EfuseError::BlockAlreadyWritten(name.to_string()).into());
+                }
+            }
+        }
+
+        // like `espefuse.py`, block 0 with the read and write protection goes
+        // last, so the other blocks are verified while they can still be read
+        let (block0, others): (Vec<usize>, Vec<usize>) =
+            changed.into_iter().partition(|block| *block == 0);
+        for group in [others, block0] {
+            if group.is_empty() {
+                continue;
+            }
+            self.program_efuses(&controller, &current, blocks, &group)?;
+
+            let burned = self.read_efuse_blocks()?;
+            for block in group {
+                let missing = blocks
+                    .block(block)
+                    .iter()
+                    .zip(burned.block(block))
+                    .any(|(wanted, burned)| wanted & !burned != 0);
+                if missing {
+                    let name = chip.efuse_blocks()[block].name;
+                    return Err(EfuseError::BurnFailed(name.to_string()).into());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Program the bits of `blocks` which aren't burned in `current` yet, for
+    /// the blocks with the indices in `group`
+    fn program_efuses(
+        &mut self,
+        controller: &EfuseController,
+        current: &EfuseBlocks,
+        blocks: &EfuseBlocks,
+        group: &[usize],
+    ) -> Result<(), Error> {
+        let new_bits = |block: usize| -> Vec<u32> {
+            let burned = current.block(block);
+            blocks
+                .block(block)
+                .iter()
+                .enumerate()
+                .map(|(i, word)| word & !burned.get(i).copied().unwrap_or_default())
+                .collect()
+        };
+
+        match controller.write_regs {
+            WriteRegs::PerBlock(regs) => {
+                for block in group {
+                    for (i, word) in new_bits(*block).into_iter().enumerate() {
+                        self.write_reg(regs[*block] + i as u32 * 4, word, None)?;
+                    }
+                }
+                self.efuse_command(controller, efuse::CONF_WRITE, efuse::CMD_PROGRAM)?;
+                for block in group {
+                    for i in 0..blocks.block(*block).len() as u32 {
+                        self.write_reg(regs[*block] + i * 4, 0, None)?;
+                    }
+                }
+                self.efuse_command(controller, efuse::CONF_READ, efuse::CMD_READ)?;
+            }
+            WriteRegs::Shared { data, check } => {
+                for block in group.iter().rev() {
+                    let mut words = if *block == 0 {
+                        new_bits(0)
+                    } else {
+                        blocks.block(*block).to_vec()
+                    };
+
+                    if *block > 0 {
+                        words.resize(8, 0);
+                        let mut bytes = [0; 32];
+                        for (i, word) in words.iter().enumerate() {
+                            bytes[i * 4..][..4].copy_from_slice(&word.to_le_bytes());
+                        }
+                        let check_bytes = efuse::reed_solomon(&bytes);
+                        for (i, word) in check_bytes.chunks(4).enumerate() {
+                            let word = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+                            self.write_reg(check + i as u32 * 4, word, None)?;
+                        }
+                    }
+                    for (i, word) in words.iter().enumerate() {
+                        self.write_reg(data + i as u32 * 4, *word, None)?;
+                    }
+
+                    let command = efuse::CMD_PROGRAM | (*block as u32) << 2;
+                    self.efuse_command(controller, efuse::CONF_WRITE, command)?;
+                    for i in 0..8 {
+                        self.write_reg(data + i * 4, 0, None)?;
+                    }
+                    for i in 0..3 {
+                        self.write_reg(check + i * 4, 0, None)?;
+                    }
+                    self.efuse_command(controller, efuse::CONF_READ, efuse::CMD_READ)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run a command of the efuse controller and wait for it to finish
+    fn efuse_command(
+        &mut self,
+        controller: &EfuseController,
+        conf: u32,
+        command: u32,
+    ) -> Result<(), Error> {
+        self.write_reg(controller.conf_reg, conf, None)?;
+        self.write_reg(controller.cmd_reg, command, None)?;
+        for _ in 0..100 {
+            if self.read_reg(controller.cmd_reg)? & (efuse::CMD_READ | efuse::CMD_PROGRAM) == 0 {
+                return Ok(());
+            }
+        }
+        Err(EfuseError::Busy.into())
+    }
+
+    /// Read the factory programmed MAC address from efuse
+    pub fn mac_address(&mut self) -> Result<[u8; 6], Error> {
+        let mac_reg = self.chip.mac_efuse_reg();
+        let mac0 = self.read_reg(mac_reg)?;
+        let mac1 = self.read_reg(mac_reg + 4)?;
+
+        let mac = match self.chip {
+            Chip::Esp8266 => {
+                let mac3 = self.read_reg(mac_reg + 12)?;
+                let oui = if mac3 != 0 {
+                    [(mac3 >> 16) as u8, (mac3 >> 8) as u8, mac3 as u8]
+                } else if (mac1 >> 16) & 0xff == 0 {
+                    [0x18, 0xfe, 0x34]
+                } else {
+                    [0xac, 0xd0, 0x74]
+                };
+                [
+                    oui[0],
+                    oui[1],
+                    oui[2],
+                    (mac1 >> 8) as u8,
+                    mac1 as u8,
+                    (mac0 >> 24) as u8,
+                ]
+            }
+            _ => [
+                (mac1 >> 8) as u8,
+                mac1 as u8,
+                (mac0 >> 24) as u8,
+                (mac0 >> 16) as u8,
+                (mac0 >> 8) as u8,
+                mac0 as u8,
+            ],
+        };
+
+        Ok(mac)
+    }
+
+    /// Read the identification of the device, its MAC address, features and
+    /// crystal frequency
+    pub fn board_info(&mut self) -> Result<BoardInfo, Error> {
+        let chip = self.chip;
+        let baud = self.speed.speed() as u32;
+        let features = chip.features(&self.read_efuses()?);
+        let crystal_frequency = chip.crystal_frequency(&mut |reg| self.read_reg(reg), baud)?;
+
+        Ok(BoardInfo {
+            chip: chip.to_string(),
+            package: features
+                .iter()
+                .find_map(Feature::package)
+                .unwrap_or_default()
+                .to_string(),
+            features: features
+                .iter()
+                .filter(|feature| feature.package().is_none())
+                .map(ToString::to_string)
+                .collect(),
+            crystal_frequency,
+            flash_size: self.flash_size.to_string(),
+            flash_id: self.flash_id()?.to_string(),
+            mac: format_mac(self.mac_address()?),
+        })
+    }
+
+    /// Read the JEDEC id of the attached flash chip
+    pub fn flash_id(&mut self) -> Result<FlashId, Error> {
+        let id = self.spi_command(SPI_FLASH_RDID, &[], 24)?;
+        Ok(FlashId::from_rdid(id as u32))
+    }
+
+    /// Read the 64 bit unique id of the attached flash chip
+    pub fn flash_unique_id(&mut self) -> Result<u64, Error> {
+        // RDUID is followed by 4 dummy bytes before the id is shifted out
+        self.spi_command(SPI_FLASH_RDUID, &[0; 4], 64)
+    }
+
+    /// Read the status register of the attached flash chip, with status
+    /// register 2 in the high byte
+    pub fn flash_status(&mut self) -> Result<u16, Error> {
+        let low = self.spi_transfer(SPI_FLASH_RDSR, &[], 8)?[0] as u16 & 0xff;
+        let high = self.spi_transfer(SPI_FLASH_RDSR2, &[], 8)?[0] as u16 & 0xff;
+        Ok(low | high << 8)
+    }
+
+    /// Write both bytes of the status register of the attached flash chip
+    pub fn write_flash_status(&mut self, status: u16) -> Result<(), Error> {
+        self.spi_transfer(SPI_FLASH_WREN, &[], 0)?;
+        self.spi_transfer(SPI_FLASH_WRSR, &status.to_le_bytes(), 0)?;
+
+        // the status register is written like any other flash cell
+        for _ in 0..100 {
+            if self.flash_status()? & FLASH_STATUS_WIP == 0 {
+                return Ok(());
+            }
+            sleep(Duration::from_millis(10));
+        }
+        Err(Error::Connection(ConnectionError::Timeout(
+            TimedOutCommand::default(),
+        )))
+    }
+
+    /// Clear the bits of the flash status register protecting regions from
+    /// writes, returning whether any were set
+    ///
+    /// Some boards ship with parts of the flash write-protected, which makes
+    /// writing to them silently fail.
+    pub fn unprotect_flash(&mut self) -> Result<bool, Error> {
+        let status = self.flash_status()?;
+        if status & FLASH_STATUS_PROTECTION == 0 {
+            return Ok(false);
+        }
+
+        log::debug!(
+            "Clearing the flash status register protection {:#06x}",
+            status
+        );
+        self.write_flash_status(status & !FLASH_STATUS_PROTECTION)?;
+        if self.flash_status()? & FLASH_STATUS_PROTECTION != 0 {
+            return Err(Error::FlashProtected(status));
+        }
+        Ok(true)
+    }
+
+    /// Read `length` bytes from flash starting at `offset`
+    ///
+    /// The data is read using the SPI flash `READ` command directly, which is
+    /// supported by the ROM of every chip but is rather slow. With dummy
+    /// cycles set by [`Flasher::set_flash_timing`] `FAST_READ` is used instead,
+    /// with that many dummy cycles. Above 16 MB their variants taking a 4 byte
+    /// address are used.
+    pub fn read_flash(&mut self, offset: u32, length: u32) -> Result<Vec<u8>, Error> {
+        let block_size = SPI_MAX_READ_BITS / 8;
+        let fast_read = self
+            .flash_timing
+            .dummy_cycles
+            .is_some_and(|cycles| cycles > 0);
+
+        let mut data = Vec::with_capacity(length as usize);
+        while (data.len() as u32) < length {
+            self.connection.check_cancelled()?;
+            let addr = offset + data.len() as u32;
+            let size = block_size.min(length - data.len() as u32);
+
+            let (opcode, addr) = read_command(fast_read, addr);
+            let words = self.spi_transfer(opcode, &addr, size * 8)?;
+            let bytes = words.iter().flat_map(|word| word.to_le_bytes());
+            data.extend(bytes.take(size as usize));
+        }
+
+        Ok(data)
+    }
+
+    /// Calculate the MD5 digest of `length` bytes of flash starting at `offset`
+    ///
+    /// The digest is calculated on the device where the ROM supports it, the
+    /// ESP8266 ROM doesn't so the region is read back instead.
+    pub fn flash_md5(&mut self, offset: u32, length: u32) -> Result<[u8; 16], Error> {
+        if self.chip == Chip::Esp8266 {
+            return Ok(md5::compute(self.read_flash(offset, length)?).0);
+        }
+
+        let params = [offset, length, 0, 0];
+        // the ROM responds with the digest as 32 hex characters
+        let response =
+            self.connection
+                .command_with_data(Command::FlashMd5, bytes_of(&params), 32)?;
+
+        let mut digest = [0; 16];
+        for (byte, hex) in digest.iter_mut().zip(response.chunks(2)) {
+            *byte = std::str::from_utf8(hex)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or(Error::Connection(ConnectionError::InvalidResponse))?;
+        }
+
+        Ok(digest)
+    }
+
+    /// Read the application descriptor of the application currently in
+    /// flash, if there is one
+    ///
+    /// The application is looked up in the boot partition, the factory or first
+    /// OTA partition, of `partition_table`, or of the partition table in flash
+    /// if none is given.
+    pub fn read_app_descriptor(
+        &mut self,
+        partition_table: Option<&PartitionTable>,
+    ) -> Result<Option<AppDescriptor>, Error> {
+        let defaults = match self.chip.default_offsets() {
+            Some(defaults) => defaults,
+            None => return Ok(None),
+        };
+
+        let app_offset = match partition_table {
+            Some(table) => table.app_offset(),
+            None => {
+                let data =
+                    self.read_flash(defaults.partition_table, MAX_PARTITION_LENGTH as u32)?;
+                PartitionTable::try_from_bytes(&data)
+                    .ok()
+                    .and_then(|table| table.app_offset())
+            }
+        };
+
+        let app_offset = app_offset.unwrap_or(defaults.app);
+        let data = self.read_flash(app_offset + APP_DESC_OFFSET, APP_DESC_SIZE)?;
+        Ok(AppDescriptor::parse(&data))
+    }
+
+    /// Check that flashing `elf_data` doesn't replace the application on the
+    /// device by an older version
+    ///
+    /// On a downgrade the user is asked for confirmation if `interactive` is
+    /// set, otherwise it is refused.
+    pub fn check_downgrade(
+        &mut self,
+        elf_data: &[u8],
+        partition_table: Option<&PartitionTable>,
+        interactive: bool,
+    ) -> Result<(), Error> {
+        let image = app_image(self.chip, elf_data)?;
+        let new = match AppDescriptor::from_image(&image) {
+            Some(new) => new,
+            None => return Ok(()),
+        };
+        let current = match self.read_app_descriptor(partition_table)? {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+
+        if !new.is_downgrade_from(&current) {
+            return Ok(());
+        }
+
+        if interactive && stdin().is_terminal() {
+            println!(
+                "The device runs {} {} (secure version {}), which is newer than {} (secure version {})",
+                current.project_name,
+                current.version,
+                current.secure_version,
+                new.version,
+                new.secure_version
+            );
+            print!("Downgrade anyway? [y/N] ");
+            stdout().flush()?;
+
+            let mut answer = String::new();
+            stdin().read_line(&mut answer)?;
+            if answer.trim().eq_ignore_ascii_case("y") {
+                return Ok(());
+            }
+        }
+
+        Err(Error::DowngradeAborted {
+            project_name: current.project_name,
+            current: current.version,
+            new: new.version,
+        })
+    }
+
     /// Load an elf image to ram and execute it
     ///
     /// Note that this will not touch the flash on the device
     pub fn load_elf_to_ram(&mut self, elf_data: &[u8]) -> Result<(), Error> {
+        self.load_elf_to_ram_with(elf_data, None, true)
+    }
+
+    /// Load an elf image to ram, then jump to `entry`, or the entry point of
+    /// the image if not given, if `run` is set
+    ///
+    /// Without `run` the loader keeps running, so the image can be inspected
+    /// with the debugger or further commands before it is started.
+    pub fn load_elf_to_ram_with(
+        &mut self,
+        elf_data: &[u8],
+        entry: Option<u32>,
+        run: bool,
+    ) -> Result<(), Error> {
         let image = FirmwareImage::from_data(elf_data).map_err(ElfError::from)?;
 
-        let mut target = self.chip.ram_target();
-        target.begin(&mut self.connection, &image).flashing()?;
+        let entry = entry.unwrap_or_else(|| image.entry());
+        log::debug!("Loading to RAM with entry point {:#x}", entry);
+        let mut target = self.chip.ram_target(Some(entry));
+        target.begin(&mut self.connection).flashing()?;
 
         if image.rom_segments(self.chip).next().is_some() {
             return Err(Error::ElfNotRamLoadable);
@@ -489,61 +1398,150 @@ impl Flasher {
                         addr: segment.addr,
                         data: Cow::Borrowed(segment.data()),
                     },
+                    self.progress.as_mut(),
                 )
                 .flashing()?;
         }
 
-        target.finish(&mut self.connection, true).flashing()
+        target.finish(&mut self.connection, run).flashing()
     }
 
     /// Load an elf image to flash and execute it
+    ///
+    /// Returns a report with statistics for each of the regions written
     pub fn load_elf_to_flash(
         &mut self,
         elf_data: &[u8],
         bootloader: Option<Vec<u8>>,
         partition_table: Option<PartitionTable>,
-    ) -> Result<(), Error> {
+    ) -> Result<FlashReport, Error> {
         let mut image = FirmwareImage::from_data(elf_data).map_err(ElfError::from)?;
         image.flash_size = self.flash_size();
+        image.app_desc = self.app_desc.clone();
+        self.flash_settings.apply(&mut image);
+        image.segment_layout = self.segment_layout;
 
-        let mut target = self.chip.flash_target(self.spi_params);
-        target.begin(&mut self.connection, &image).flashing()?;
+        let started = Instant::now();
+        let mut report = FlashReport::default();
+        let mut digest = Sha256::new();
+
+        let mut target = self.chip.flash_target(self.spi_params, !self.safe_mode);
+        target.begin(&mut self.connection).flashing()?;
 
         for segment in self
             .chip
             .get_flash_segments(&image, bootloader, partition_table)
         {
-            target
-                .write_segment(&mut self.connection, segment?)
+            let segment = self.encrypt_segment(segment?)?;
+            log::debug!(
+                "Writing {} bytes at {:#x}",
+                segment.data.len(),
+                segment.addr
+            );
+            let mut region = RegionReport::new(segment.addr, &segment.data);
+            digest.update(&segment.data);
+
+            let segment_started = Instant::now();
+            region.transferred = target
+                .write_segment(&mut self.connection, segment, self.progress.as_mut())
                 .flashing()?;
+            region.duration = segment_started.elapsed();
+
+            report.regions.push(region);
         }
 
-        target.finish(&mut self.connection, true).flashing()?;
+        target.finish(&mut self.connection, false).flashing()?;
+        self.reset_after_flashing().flashing()?;
+        report.duration = started.elapsed();
+        report.sha256 = format!("{:x}", digest.finalize());
 
-        Ok(())
+        Ok(report)
+    }
+
+    /// Write raw data to flash at `addr`, without rebooting the device
+    pub fn write_bin_to_flash(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+        let segment = self.encrypt_segment(RomSegment {
+            addr,
+            data: Cow::Borrowed(data),
+        })?;
+
+        let mut target = self.chip.flash_target(self.spi_params, !self.safe_mode);
+        target.begin(&mut self.connection).flashing()?;
+        target
+            .write_segment(&mut self.connection, segment, self.progress.as_mut())
+            .flashing()?;
+        target.finish(&mut self.connection, false).flashing()
+    }
+
+    /// Encrypt a segment with the key set by
+    /// [`Flasher::set_flash_encryption_key`], if any
+    fn encrypt_segment<'a>(&self, segment: RomSegment<'a>) -> Result<RomSegment<'a>, Error> {
+        match &self.encryption_key {
+            Some(key) => Ok(RomSegment {
+                addr: segment.addr,
+                data: Cow::Owned(key.encrypt(segment.addr, &segment.data)?),
+            }),
+            None => Ok(segment),
+        }
+    }
+
+    /// Reset the chip as selected with [`FlasherBuilder::after`], as is done
+    /// after flashing an elf image
+    pub fn reset_after(&mut self) -> Result<(), Error> {
+        self.reset_after_flashing()
     }
 
+    fn reset_after_flashing(&mut self) -> Result<(), Error> {
+        match self.reset_after {
+            ResetAfterOperation::HardReset => self.connection.reset(),
+            ResetAfterOperation::SoftReset => {
+                // an empty flash operation which ends by running the application
+                self.begin_command(Command::FlashBegin, 0, 0, FLASH_WRITE_SIZE as u32, 0)?;
+                self.connection
+                    .with_timeout(Command::FlashEnd.timeout(), |connection| {
+                        connection.write_command(Command::FlashEnd as u8, &[0][..], 0)
+                    })
+            }
+            ResetAfterOperation::NoReset | ResetAfterOperation::StayInBootloader => Ok(()),
+        }
+    }
+
+    /// Switch to the baud rate `speed`, unless connected through the
+    /// USB-JTAG-serial peripheral, which ignores it
     pub fn change_baud(&mut self, speed: BaudRate) -> Result<(), Error> {
+        if self.connection.is_usb_jtag_serial() {
+            log::debug!("Keeping the baud rate of the USB-JTAG-serial peripheral");
+            return Ok(());
+        }
+        log::debug!("Changing baud rate to {}", speed.speed());
         let new_speed = (speed.speed() as u32).to_le_bytes();
         let old_speed = 0u32.to_le_bytes();
 
         self.connection
-            .with_timeout(Command::ChangeBaud.timeout(), |connection| {
-                connection.command(Command::ChangeBaud, &[new_speed, old_speed].concat()[..], 0)
-            })?;
+            .command(Command::ChangeBaud, &[new_speed, old_speed].concat())?;
         self.connection.set_baud(speed)?;
+        self.speed = speed;
         std::thread::sleep(Duration::from_secs_f32(0.05));
         self.connection.flush()?;
         Ok(())
     }
 
-    pub fn into_serial(self) -> SystemPort {
+    pub fn into_serial(self) -> Box<dyn Transport> {
         self.connection.into_serial()
     }
 }
 
+/// Whether the port failed rather than the device not responding, as when it
+/// dropped off the bus
+fn is_disconnect(err: &Error) -> bool {
+    matches!(
+        err.probe_failure(),
+        ProbeFailure::PortNotFound | ProbeFailure::SerialError
+    )
+}
+
 pub(crate) fn get_erase_size(offset: usize, size: usize) -> usize {
-    let sector_count = (size + FLASH_SECTOR_SIZE - 1) / FLASH_SECTOR_SIZE;
+    let sector_count = size.div_ceil(FLASH_SECTOR_SIZE);
     let start_sector = offset / FLASH_SECTOR_SIZE;
 
     let head_sectors = usize::min(
@@ -552,7 +1550,7 @@ pub(crate) fn get_erase_size(offset: usize, size: usize) -> usize {
     );
 
     if sector_count < 2 * head_sectors {
-        (sector_count + 1) / 2 * FLASH_SECTOR_SIZE
+        sector_count.div_ceil(2) * FLASH_SECTOR_SIZE
     } else {
         (sector_count - head_sectors) * FLASH_SECTOR_SIZE
     }
@@ -567,3 +1565,35 @@ pub fn checksum(data: &[u8], mut checksum: u8) -> u8 {
 
     checksum
 }
+
+/// The SPI flash opcode and address bytes reading from `addr`, with a 4 byte
+/// address above the 16 MB reachable with 3 bytes
+fn read_command(fast_read: bool, addr: u32) -> (u8, Vec<u8>) {
+    let addr_bytes = addr.to_be_bytes();
+    match (addr > 0xFF_FFFF, fast_read) {
+        (false, false) => (SPI_FLASH_READ, addr_bytes[1..].to_vec()),
+        (false, true) => (SPI_FLASH_FAST_READ, addr_bytes[1..].to_vec()),
+        (true, false) => (SPI_FLASH_READ4B, addr_bytes.to_vec()),
+        (true, true) => (SPI_FLASH_FAST_READ4B, addr_bytes.to_vec()),
+    }
+}
+
+#[test]
+fn test_read_command() {
+    assert_eq!(
+        (SPI_FLASH_READ, vec![0xFF, 0xF0, 0x00]),
+        read_command(false, 0xFF_F000)
+    );
+    assert_eq!(
+        (SPI_FLASH_FAST_READ, vec![0x01, 0x00, 0x00]),
+        read_command(true, 0x1_0000)
+    );
+    assert_eq!(
+        (SPI_FLASH_READ4B, vec![0x01, 0x00, 0x00, 0x00]),
+        read_command(false, 0x100_0000)
+    );
+    assert_eq!(
+        (SPI_FLASH_FAST_READ4B, vec![0x01, 0x23, 0x45, 0x67]),
+        read_command(true, 0x123_4567)
+    );
+}
@@ -0,0 +1,281 @@
+//! The `flasher_args.json` of an ESP-IDF build directory
+//!
+//! ESP-IDF lists the binaries of a project with their offsets in
+//! `build/flasher_args.json`, along with the flash parameters they were built
+//! for and the chip, so that a build can be flashed without knowing its layout.
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::error::FlasherArgsError;
+use crate::{Chip, FlashFrequency, FlashMode, FlashSettings, FlashSize};
+
+/// Name of the file in the build directory
+pub const FLASHER_ARGS_FILE: &str = "flasher_args.json";
+
+/// The binaries and settings of an ESP-IDF build
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct FlasherArgs {
+    /// The flash parameters as esptool arguments
+    #[serde(default)]
+    pub write_flash_args: Vec<String>,
+    #[serde(default)]
+    pub flash_settings: FlasherArgsSettings,
+    /// The binaries to write keyed by their hex offset, relative to the build
+    /// directory
+    pub flash_files: BTreeMap<String, String>,
+    #[serde(default)]
+    pub extra_esptool_args: ExtraEsptoolArgs,
+}
+
+/// The flash parameters, `keep` or `detect` leave the ones in the bootloader
+/// header alone
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct FlasherArgsSettings {
+    pub flash_mode: String,
+    pub flash_size: String,
+    pub flash_freq: String,
+}
+
+impl Default for FlasherArgsSettings {
+    fn default() -> Self {
+        FlasherArgsSettings {
+            flash_mode: "keep".to_string(),
+            flash_size: "keep".to_string(),
+            flash_freq: "keep".to_string(),
+        }
+    }
+}
+
+/// How esptool connects to the chip
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ExtraEsptoolArgs {
+    pub before: String,
+    pub after: String,
+    pub stub: bool,
+    pub chip: String,
+}
+
+impl Default for ExtraEsptoolArgs {
+    fn default() -> Self {
+        ExtraEsptoolArgs {
+            before: "default_reset".to_string(),
+            after: "hard_reset".to_string(),
+            stub: true,
+            chip: "auto".to_string(),
+        }
+    }
+}
+
+impl FlasherArgs {
+    /// Describe the binaries saved for `chip` with their offsets, the file
+    /// names are relative to the directory of the `flasher_args.json`
+    ///
+    /// Flash parameters which aren't set are written as `keep`.
+    pub fn new(chip: Chip, settings: &FlashSettings, files: &[(u32, String)]) -> Self {
+        let flash_settings = FlasherArgsSettings {
+            flash_mode: keep_or(settings.mode),
+            flash_size: keep_or(settings.size),
+            flash_freq: keep_or(settings.frequency),
+        };
+        let write_flash_args = vec![
+            "--flash_mode".to_string(),
+            flash_settings.flash_mode.clone(),
+            "--flash_size".to_string(),
+            flash_settings.flash_size.clone(),
+            "--flash_freq".to_string(),
+            flash_settings.flash_freq.clone(),
+        ];
+        let chip = match chip {
+            Chip::Esp32 => "esp32",
+            Chip::Esp32c3 => "esp32c3",
+            Chip::Esp32s2 => "esp32s2",
+            Chip::Esp8266 => "esp8266",
+        };
+
+        FlasherArgs {
+            write_flash_args,
+            flash_settings,
+            flash_files: files
+                .iter()
+                .map(|(offset, file)| (format!("{:#x}", offset), file.clone()))
+                .collect(),
+            extra_esptool_args: ExtraEsptoolArgs {
+                chip: chip.to_string(),
+                ..ExtraEsptoolArgs::default()
+            },
+        }
+    }
+
+    pub fn parse(data: &str) -> Result<Self, FlasherArgsError> {
+        Ok(serde_json::from_str(data)?)
+    }
+
+    /// Save as JSON, laid out like the file written by ESP-IDF
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut data = serde_json::to_vec_pretty(self)?;
+        data.push(b'\n');
+        fs::write(path, data)
+    }
+
+    /// The flash parameters the binaries were built for
+    pub fn flash_settings(&self) -> Result<FlashSettings, FlasherArgsError> {
+        let settings = &self.flash_settings;
+        Ok(FlashSettings {
+            mode: parse_setting::<FlashMode>("flash_mode", &settings.flash_mode)?,
+            frequency: parse_setting::<FlashFrequency>("flash_freq", &settings.flash_freq)?,
+            size: parse_setting::<FlashSize>("flash_size", &settings.flash_size)?,
+            ..FlashSettings::default()
+        })
+    }
+
+    /// The chip the project was built for, `None` if it's detected
+    pub fn chip(&self) -> Result<Option<Chip>, FlasherArgsError> {
+        match self.extra_esptool_args.chip.as_str() {
+            "auto" => Ok(None),
+            chip => parse_setting("chip", chip),
+        }
+    }
+
+    /// The binaries with their offsets, sorted by offset, with their paths
+    /// resolved against the build directory `build_dir`
+    pub fn files(&self, build_dir: &Path) -> Result<Vec<(u32, PathBuf)>, FlasherArgsError> {
+        let mut files = self
+            .flash_files
+            .iter()
+            .map(|(offset, file)| {
+                let offset = parse_u32(offset)
+                    .map_err(|_| FlasherArgsError::InvalidOffset(offset.clone()))?;
+                Ok((offset, build_dir.join(file)))
+            })
+            .collect::<Result<Vec<_>, FlasherArgsError>>()?;
+        files.sort_by_key(|(offset, _)| *offset);
+
+        Ok(files)
+    }
+}
+
+fn keep_or<T: Display>(value: Option<T>) -> String {
+    value.map_or_else(|| "keep".to_string(), |value| value.to_string())
+}
+
+fn parse_setting<T: FromStr>(key: &str, value: &str) -> Result<Option<T>, FlasherArgsError> {
+    match value {
+        "keep" | "detect" => Ok(None),
+        value => value
+            .parse()
+            .map(Some)
+            .map_err(|_| FlasherArgsError::InvalidValue {
+                key: key.to_string(),
+                value: value.to_string(),
+            }),
+    }
+}
+
+fn parse_u32(value: &str) -> Result<u32, std::num::ParseIntError> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flasher_args() {
+        let json = r#"{
+    "write_flash_args" : [ "--flash_mode", "dio",
+                           "--flash_size", "2MB",
+                           "--flash_freq", "80m" ],
+    "flash_settings" : {
+        "flash_mode": "dio",
+        "flash_size": "2MB",
+        "flash_freq": "80m"
+    },
+    "flash_files" : {
+        "0x0" : "bootloader/bootloader.bin",
+        "0x10000" : "hello_world.bin",
+        "0x8000" : "partition_table/partition-table.bin"
+    },
+    "bootloader" : { "offset" : "0x0", "file" : "bootloader/bootloader.bin", "encrypted" : "false" },
+    "app" : { "offset" : "0x10000", "file" : "hello_world.bin", "encrypted" : "false" },
+    "extra_esptool_args" : {
+        "after"  : "hard_reset",
+        "before" : "default_reset",
+        "stub"   : true,
+        "chip"   : "esp32c3"
+    }
+}"#;
+
+        let args = FlasherArgs::parse(json).unwrap();
+        assert_eq!(Some(Chip::Esp32c3), args.chip().unwrap());
+        assert_eq!(
+            FlashSettings {
+                mode: Some(FlashMode::Dio),
+                frequency: Some(FlashFrequency::Flash80M),
+                size: Some(FlashSize::Flash2Mb),
+                ..FlashSettings::default()
+            },
+            args.flash_settings().unwrap()
+        );
+        assert_eq!(
+            vec![
+                (0x0, PathBuf::from("build/bootloader/bootloader.bin")),
+                (
+                    0x8000,
+                    PathBuf::from("build/partition_table/partition-table.bin")
+                ),
+                (0x10000, PathBuf::from("build/hello_world.bin")),
+            ],
+            args.files(Path::new("build")).unwrap()
+        );
+
+        let mut args = args;
+        args.flash_settings.flash_size = "3MB".to_string();
+        assert!(matches!(
+            args.flash_settings(),
+            Err(FlasherArgsError::InvalidValue { .. })
+        ));
+        args.flash_files
+            .insert("10k".to_string(), "app.bin".to_string());
+        assert!(matches!(
+            args.files(Path::new("build")),
+            Err(FlasherArgsError::InvalidOffset(_))
+        ));
+    }
+
+    #[test]
+    fn test_new_flasher_args() {
+        let settings = FlashSettings {
+            mode: Some(FlashMode::Qio),
+            size: Some(FlashSize::Flash8Mb),
+            ..FlashSettings::default()
+        };
+        let files = [
+            (0x1000, "bootloader.bin".to_string()),
+            (0x10000, "app.bin".to_string()),
+        ];
+        let args = FlasherArgs::new(Chip::Esp32, &settings, &files);
+
+        let json = serde_json::to_string(&args).unwrap();
+        let args = FlasherArgs::parse(&json).unwrap();
+        assert_eq!(Some(Chip::Esp32), args.chip().unwrap());
+        assert_eq!(settings, args.flash_settings().unwrap());
+        assert_eq!("keep", args.flash_settings.flash_freq);
+        assert_eq!(
+            vec![
+                (0x1000, PathBuf::from("out/bootloader.bin")),
+                (0x10000, PathBuf::from("out/app.bin")),
+            ],
+            args.files(Path::new("out")).unwrap()
+        );
+    }
+}
@@ -0,0 +1,288 @@
+//! FAT images, optionally behind the wear levelling layer
+//!
+//! ESP-IDF mounts read-write FAT partitions through its wear levelling
+//! component, which keeps a spare sector moving through the partition and its
+//! state in the last sectors of it. A freshly generated image has the spare
+//! sector in front of the filesystem and two copies of the initial state
+//! followed by the configuration at the end, as `wl_fatfsgen.py` lays it out.
+//! Without wear levelling the partition can only be mounted read-only.
+
+use std::io::{self, Cursor, Write};
+
+use fatfs::{FileSystem, FormatVolumeOptions, FsOptions};
+
+use crate::error::FsImageError;
+
+use super::Entry;
+
+/// Size of the sectors of the filesystem and the wear levelling layer
+pub const SECTOR_SIZE: usize = 4096;
+
+/// Version of the wear levelling state and configuration
+const WL_VERSION: u32 = 2;
+/// Size of the state, without the records of moved sectors
+const STATE_HEADER_LEN: usize = 64;
+/// Size of the record of each moved sector
+const STATE_RECORD_LEN: usize = 16;
+/// Offset of the CRC in the state, which covers everything before it
+const STATE_CRC_OFFSET: usize = 60;
+/// Size of the configuration, including the CRC and reserved bytes
+const CONFIG_LEN: usize = 48;
+/// Number of copies of the state
+const STATE_COPIES: usize = 2;
+/// How many writes the spare sector stays in place for
+const UPDATE_RATE: u32 = 16;
+/// Size of the writes of the wear levelling layer
+const WRITE_SIZE: u32 = 16;
+/// Size of the buffer used to move sectors
+const TEMP_BUFF_SIZE: u32 = 32;
+
+/// Parameters of the filesystem, which have to match how the firmware mounts
+/// the partition
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct FatConfig {
+    /// Whether the partition is mounted through the wear levelling layer, as
+    /// `esp_vfs_fat_spiflash_mount_rw_wl` does
+    pub wear_levelling: bool,
+    /// Identifies the image to the wear levelling layer, `wl_fatfsgen.py`
+    /// picks a random one
+    pub device_id: u32,
+    /// Label of the volume, up to 11 characters
+    pub volume_label: [u8; 11],
+}
+
+impl Default for FatConfig {
+    fn default() -> Self {
+        FatConfig {
+            wear_levelling: true,
+            device_id: 0,
+            volume_label: *b"Espressif  ",
+        }
+    }
+}
+
+/// Number of sectors each copy of the wear levelling state takes up, for a
+/// partition of `sectors` sectors
+fn state_sectors(sectors: usize) -> usize {
+    (STATE_HEADER_LEN + STATE_RECORD_LEN * sectors).div_ceil(SECTOR_SIZE)
+}
+
+/// Generate an image of `size` bytes holding `entries`
+pub fn generate(entries: &[Entry], size: u32, config: &FatConfig) -> Result<Vec<u8>, FsImageError> {
+    let size = size as usize;
+    if size == 0 || !size.is_multiple_of(SECTOR_SIZE) {
+        return Err(FsImageError::InvalidSize {
+            size: size as u32,
+            block_size: SECTOR_SIZE as u32,
+        });
+    }
+
+    let sectors = size / SECTOR_SIZE;
+    let fat_sectors = if config.wear_levelling {
+        // the spare sector, the copies of the state and the configuration
+        let reserved = 1 + STATE_COPIES * state_sectors(sectors) + 1;
+        sectors
+            .checked_sub(reserved)
+            .ok_or(FsImageError::TooLarge)?
+    } else {
+        sectors
+    };
+
+    let fat = fat_image(entries, fat_sectors, config)?;
+    if !config.wear_levelling {
+        return Ok(fat);
+    }
+
+    let mut image = vec![0xff; SECTOR_SIZE];
+    image.extend_from_slice(&fat);
+    let state = state(fat_sectors, config.device_id);
+    for _ in 0..STATE_COPIES {
+        let end = image.len() + state_sectors(sectors) * SECTOR_SIZE;
+        image.extend_from_slice(&state);
+        image.resize(end, 0xff);
+    }
+    image.extend_from_slice(&wl_config(size));
+    image.resize(size, 0xff);
+
+    Ok(image)
+}
+
+/// Format a FAT filesystem of `sectors` sectors and copy the entries to it
+fn fat_image(
+    entries: &[Entry],
+    sectors: usize,
+    config: &FatConfig,
+) -> Result<Vec<u8>, FsImageError> {
+    let mut image = vec![0; sectors * SECTOR_SIZE];
+    let options = FormatVolumeOptions::new()
+        .bytes_per_sector(SECTOR_SIZE as u16)
+        .bytes_per_cluster(SECTOR_SIZE as u32)
+        .total_sectors(sectors as u32)
+        .fats(1)
+        .max_root_dir_entries(512)
+        .media(0xf8)
+        .volume_label(config.volume_label);
+    // the smallest filesystems fatfs can format are still larger than a few
+    // sectors
+    fatfs::format_volume(Cursor::new(image.as_mut_slice()), options)
+        .map_err(|_| FsImageError::TooLarge)?;
+
+    let fs = FileSystem::new(Cursor::new(image.as_mut_slice()), FsOptions::new())
+        .map_err(|_| FsImageError::TooLarge)?;
+    for entry in entries {
+        let result = match &entry.data {
+            None => fs.root_dir().create_dir(&entry.path).map(drop),
+            Some(data) => fs.root_dir().create_file(&entry.path).and_then(|mut file| {
+                file.write_all(data)?;
+                file.flush()
+            }),
+        };
+        result.map_err(|err| match err.kind() {
+            io::ErrorKind::InvalidInput => FsImageError::NameTooLong(entry.path.clone()),
+            _ => FsImageError::TooLarge,
+        })?;
+    }
+    fs.unmount().map_err(|_| FsImageError::TooLarge)?;
+
+    Ok(image)
+}
+
+/// The initial state of the wear levelling layer, with the spare sector in
+/// front of the `fat_sectors` sectors of the filesystem
+fn state(fat_sectors: usize, device_id: u32) -> Vec<u8> {
+    let fields = [
+        0,                      // pos
+        fat_sectors as u32 + 1, // max_pos
+        0,                      // move_count
+        0,                      // access_count
+        UPDATE_RATE,            // max_count
+        SECTOR_SIZE as u32,     // block_size
+        WL_VERSION,
+        device_id,
+    ];
+    let mut state = fields
+        .iter()
+        .flat_map(|field| field.to_le_bytes())
+        .collect::<Vec<_>>();
+    state.resize(STATE_CRC_OFFSET, 0);
+    state.extend_from_slice(&crc(&state).to_le_bytes());
+    state
+}
+
+/// The configuration of the wear levelling layer for a partition of `size`
+/// bytes
+fn wl_config(size: usize) -> Vec<u8> {
+    let fields = [
+        0, // start_addr
+        size as u32,
+        SECTOR_SIZE as u32, // page_size
+        SECTOR_SIZE as u32, // sector_size
+        UPDATE_RATE,
+        WRITE_SIZE,
+        WL_VERSION,
+        TEMP_BUFF_SIZE,
+    ];
+    let mut config = fields
+        .iter()
+        .flat_map(|field| field.to_le_bytes())
+        .collect::<Vec<_>>();
+    config.extend_from_slice(&crc(&config).to_le_bytes());
+    config.resize(CONFIG_LEN, 0);
+    config
+}
+
+/// `crc32_le(UINT32_MAX, ...)` of the ROM
+fn crc(data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new_with_initial(u32::MAX);
+    hasher.update(data);
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::convert::TryInto;
+    use std::io::Read;
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..][..4].try_into().unwrap())
+    }
+
+    fn entries() -> Vec<Entry> {
+        vec![
+            Entry::file("index.html", b"<html></html>".to_vec()),
+            Entry::dir("static"),
+            Entry::file(
+                "static/large-file-name.bin",
+                (0..20000u32).map(|i| i as u8).collect::<Vec<_>>(),
+            ),
+        ]
+    }
+
+    fn read_file(fat: &[u8], path: &str) -> Vec<u8> {
+        let mut fat = fat.to_vec();
+        let fs = FileSystem::new(Cursor::new(fat.as_mut_slice()), FsOptions::new()).unwrap();
+        let mut data = Vec::new();
+        fs.root_dir()
+            .open_file(path)
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn test_wear_levelled_layout() {
+        let size = 0x10_0000;
+        let config = FatConfig {
+            device_id: 0x1234_5678,
+            ..FatConfig::default()
+        };
+        let image = generate(&entries(), size, &config).unwrap();
+        assert_eq!(size as usize, image.len());
+
+        // the state with a record for each of the 256 sectors takes up two
+        let fat_sectors = 256 - 6;
+        assert!(image[..SECTOR_SIZE].iter().all(|b| *b == 0xff));
+        let fat = &image[SECTOR_SIZE..][..fat_sectors * SECTOR_SIZE];
+        assert_eq!(b"<html></html>".to_vec(), read_file(fat, "index.html"));
+        assert_eq!(20000, read_file(fat, "static/large-file-name.bin").len());
+
+        for copy in 0..STATE_COPIES {
+            let state = &image[(1 + fat_sectors + copy * 2) * SECTOR_SIZE..][..2 * SECTOR_SIZE];
+            assert_eq!(fat_sectors as u32 + 1, read_u32(state, 4));
+            assert_eq!(0x1234_5678, read_u32(state, 28));
+            assert_eq!(crc(&state[..60]), read_u32(state, 60));
+            assert!(state[STATE_HEADER_LEN..].iter().all(|b| *b == 0xff));
+        }
+
+        let config = &image[size as usize - SECTOR_SIZE..];
+        assert_eq!(size, read_u32(config, 4));
+        assert_eq!(crc(&config[..32]), read_u32(config, 32));
+        assert!(config[CONFIG_LEN..].iter().all(|b| *b == 0xff));
+    }
+
+    #[test]
+    fn test_without_wear_levelling() {
+        let config = FatConfig {
+            wear_levelling: false,
+            ..FatConfig::default()
+        };
+        let image = generate(&entries(), 0x8_0000, &config).unwrap();
+        assert_eq!(b"<html></html>".to_vec(), read_file(&image, "index.html"));
+    }
+
+    #[test]
+    fn test_too_large() {
+        let entries = [Entry::file("big.bin", vec![0; 0x10_0000])];
+        assert!(matches!(
+            generate(&entries, 0x8_0000, &FatConfig::default()),
+            Err(FsImageError::TooLarge)
+        ));
+        assert!(matches!(
+            generate(&entries, 0x8_0001, &FatConfig::default()),
+            Err(FsImageError::InvalidSize { .. })
+        ));
+    }
+}
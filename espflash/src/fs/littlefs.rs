@@ -0,0 +1,520 @@
+//! LittleFS images
+//!
+//! Images use version 2.0 of the on-disk format, which every release of
+//! littlefs 2 mounts. Each directory is stored in one or more metadata pairs,
+//! of which only the first block is written, and all metadata pairs are
+//! linked through their tails starting at the root in blocks 0 and 1. Small
+//! files are stored inline in their directory, larger ones in a CTZ skip-list
+//! of data blocks.
+
+use crate::error::FsImageError;
+
+use super::Entry;
+
+const DISK_VERSION: u32 = 0x0002_0000;
+const FILE_MAX: u32 = 0x7fff_ffff;
+const ATTR_MAX: u32 = 1022;
+
+const TYPE_REG: u32 = 0x001;
+const TYPE_DIR: u32 = 0x002;
+const TYPE_SUPERBLOCK: u32 = 0x0ff;
+const TYPE_DIRSTRUCT: u32 = 0x200;
+const TYPE_INLINESTRUCT: u32 = 0x201;
+const TYPE_CTZSTRUCT: u32 = 0x202;
+const TYPE_CRC: u32 = 0x500;
+const TYPE_SOFTTAIL: u32 = 0x600;
+const TYPE_HARDTAIL: u32 = 0x601;
+
+/// Id of tags which don't belong to an entry
+const NO_ID: u32 = 0x3ff;
+/// Size of a tag
+const TAG_LEN: usize = 4;
+/// Revision count, tail and CRC of a metadata block
+const COMMIT_OVERHEAD: usize = 4 + (TAG_LEN + 8) + (TAG_LEN + 4);
+/// Size of the superblock entry, its name and the superblock itself
+const SUPERBLOCK_LEN: usize = TAG_LEN + 8 + TAG_LEN + 24;
+
+/// Parameters of the filesystem, which have to match the configuration of the
+/// `esp_littlefs` component of the firmware
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct LittleFsConfig {
+    /// Size of the blocks, the flash sector size
+    pub block_size: usize,
+    /// `CONFIG_LITTLEFS_WRITE_SIZE`, commits are padded to it
+    pub prog_size: usize,
+    /// `CONFIG_LITTLEFS_CACHE_SIZE`, which limits the size of inline files
+    pub cache_size: usize,
+    /// `CONFIG_LITTLEFS_OBJ_NAME_LEN`
+    pub name_max: usize,
+}
+
+impl Default for LittleFsConfig {
+    fn default() -> Self {
+        LittleFsConfig {
+            block_size: 4096,
+            prog_size: 128,
+            cache_size: 512,
+            name_max: 64,
+        }
+    }
+}
+
+impl LittleFsConfig {
+    /// Files up to this size are stored in their directory
+    fn inline_max(&self) -> usize {
+        0x3fe_usize.min(self.cache_size).min(self.block_size / 8)
+    }
+
+    /// Metadata pairs are filled up to the size littlefs splits them at when
+    /// compacting
+    fn metadata_max(&self) -> usize {
+        (self.block_size - 36).min(align_up(self.block_size / 2, self.prog_size))
+    }
+
+    /// Number of blocks of a CTZ skip-list holding `size` bytes
+    fn ctz_blocks(&self, size: usize) -> usize {
+        let mut blocks = 0;
+        let mut stored = 0;
+        while stored < size {
+            stored += self.block_size - ctz_pointers(blocks) * 4;
+            blocks += 1;
+        }
+        blocks
+    }
+}
+
+/// Number of pointers at the start of block `index` of a CTZ skip-list
+fn ctz_pointers(index: usize) -> usize {
+    match index {
+        0 => 0,
+        index => index.trailing_zeros() as usize + 1,
+    }
+}
+
+fn align_up(value: usize, alignment: usize) -> usize {
+    value.div_ceil(alignment) * alignment
+}
+
+enum Content<'a> {
+    Inline(&'a [u8]),
+    Ctz(&'a [u8]),
+    /// Index of the directory
+    Dir(usize),
+}
+
+struct DirEntry<'a> {
+    name: &'a str,
+    content: Content<'a>,
+}
+
+impl DirEntry<'_> {
+    fn len(&self) -> usize {
+        let data = match self.content {
+            Content::Inline(data) => data.len(),
+            Content::Ctz(_) | Content::Dir(_) => 8,
+        };
+        TAG_LEN + self.name.len() + TAG_LEN + data
+    }
+}
+
+/// A metadata pair, holding a range of the entries of a directory
+struct Pair {
+    dir: usize,
+    start: usize,
+    end: usize,
+}
+
+/// A metadata block being written, tags are XORed with the previous one
+struct Commit {
+    data: Vec<u8>,
+    ptag: u32,
+}
+
+impl Commit {
+    fn new(revision: u32) -> Self {
+        Commit {
+            data: revision.to_le_bytes().to_vec(),
+            ptag: 0xffff_ffff,
+        }
+    }
+
+    fn tag(&mut self, ty: u32, id: u32, data: &[u8]) {
+        let tag = (ty << 20) | (id << 10) | data.len() as u32;
+        self.data
+            .extend_from_slice(&(tag ^ self.ptag).to_be_bytes());
+        self.data.extend_from_slice(data);
+        self.ptag = tag;
+    }
+
+    /// Finish the commit with its CRC, padded to the program size
+    fn finish(mut self, prog_size: usize) -> Vec<u8> {
+        let off = self.data.len();
+        let end = align_up(off + TAG_LEN + 4, prog_size);
+        let tag = (TYPE_CRC << 20) | (NO_ID << 10) | (end - off - TAG_LEN) as u32;
+        self.data
+            .extend_from_slice(&(tag ^ self.ptag).to_be_bytes());
+        // littlefs doesn't invert the CRC at the end
+        let crc = !crc32fast::hash(&self.data);
+        self.data.extend_from_slice(&crc.to_le_bytes());
+        self.data.resize(end, 0xff);
+        self.data
+    }
+}
+
+/// Generate a LittleFS image of `size` bytes containing `entries`
+pub fn generate(
+    entries: &[Entry],
+    size: u32,
+    config: &LittleFsConfig,
+) -> Result<Vec<u8>, FsImageError> {
+    let size = size as usize;
+    if size == 0 || !size.is_multiple_of(config.block_size) {
+        return Err(FsImageError::InvalidSize {
+            size: size as u32,
+            block_size: config.block_size as u32,
+        });
+    }
+    let block_count = size / config.block_size;
+
+    // the directories with their entries, the root first
+    let mut dirs: Vec<(&str, Vec<DirEntry>)> = vec![("", Vec::new())];
+    for entry in entries {
+        let (parent, name) = entry.path.rsplit_once('/').unwrap_or(("", &entry.path));
+        if name.len() > config.name_max {
+            return Err(FsImageError::NameTooLong(entry.path.clone()));
+        }
+        let parent = dirs
+            .iter()
+            .position(|(path, _)| *path == parent)
+            .expect("directories precede their contents");
+        let content = match &entry.data {
+            Some(data) if data.len() <= config.inline_max() => Content::Inline(data),
+            Some(data) => Content::Ctz(data),
+            None => {
+                dirs.push((&entry.path, Vec::new()));
+                Content::Dir(dirs.len() - 1)
+            }
+        };
+        dirs[parent].1.push(DirEntry { name, content });
+    }
+    // littlefs keeps the entries of a directory sorted by name
+    for (_, entries) in &mut dirs {
+        entries.sort_by(|a, b| a.name.as_bytes().cmp(b.name.as_bytes()));
+    }
+
+    // split the directories into metadata pairs, in the order they're linked
+    let mut pairs = Vec::new();
+    for (dir, (_, entries)) in dirs.iter().enumerate() {
+        let mut pair = Pair {
+            dir,
+            start: 0,
+            end: 0,
+        };
+        let mut len = COMMIT_OVERHEAD + if dir == 0 { SUPERBLOCK_LEN } else { 0 };
+        for entry in entries {
+            if len + entry.len() > config.metadata_max() && pair.end > pair.start {
+                let start = pair.end;
+                pairs.push(pair);
+                pair = Pair {
+                    dir,
+                    start,
+                    end: start,
+                };
+                len = COMMIT_OVERHEAD;
+            }
+            len += entry.len();
+            pair.end += 1;
+        }
+        pairs.push(pair);
+    }
+    let first_pair: Vec<usize> = (0..dirs.len())
+        .map(|dir| pairs.iter().position(|pair| pair.dir == dir).unwrap_or(0))
+        .collect();
+
+    if pairs.len() * 2 > block_count {
+        return Err(FsImageError::TooLarge);
+    }
+
+    // the data blocks of files follow the metadata pairs, in the order of the
+    // entries
+    let mut image = vec![0xff; size];
+    let mut ctz_heads = Vec::new();
+    let mut next_block = pairs.len() * 2;
+    for (_, entries) in &dirs {
+        for entry in entries {
+            if let Content::Ctz(data) = entry.content {
+                let blocks = config.ctz_blocks(data.len());
+                if next_block + blocks > block_count {
+                    return Err(FsImageError::TooLarge);
+                }
+                write_ctz(&mut image, next_block, data, config);
+                next_block += blocks;
+                ctz_heads.push(next_block as u32 - 1);
+            }
+        }
+    }
+
+    let mut ctz_heads = ctz_heads.into_iter();
+    for (index, pair) in pairs.iter().enumerate() {
+        let mut commit = Commit::new(1);
+        let mut id = 0;
+        if index == 0 {
+            let superblock = [
+                DISK_VERSION,
+                config.block_size as u32,
+                block_count as u32,
+                config.name_max as u32,
+                FILE_MAX,
+                ATTR_MAX,
+            ];
+            let superblock: Vec<u8> = superblock.iter().flat_map(|v| v.to_le_bytes()).collect();
+            commit.tag(TYPE_SUPERBLOCK, 0, b"littlefs");
+            commit.tag(TYPE_INLINESTRUCT, 0, &superblock);
+            id += 1;
+        }
+
+        for entry in &dirs[pair.dir].1[pair.start..pair.end] {
+            let ty = match entry.content {
+                Content::Dir(_) => TYPE_DIR,
+                _ => TYPE_REG,
+            };
+            commit.tag(ty, id, entry.name.as_bytes());
+            match entry.content {
+                Content::Inline(data) => commit.tag(TYPE_INLINESTRUCT, id, data),
+                Content::Ctz(data) => {
+                    let head = ctz_heads.next().unwrap_or_default();
+                    commit.tag(TYPE_CTZSTRUCT, id, &pair_bytes(head, data.len() as u32));
+                }
+                Content::Dir(dir) => {
+                    let block = first_pair[dir] as u32 * 2;
+                    commit.tag(TYPE_DIRSTRUCT, id, &pair_bytes(block, block + 1));
+                }
+            }
+            id += 1;
+        }
+
+        // splits of a directory are hard tails, the next directory is a soft
+        // one, and the last pair has none
+        if let Some(next) = pairs.get(index + 1) {
+            let ty = match next.dir == pair.dir {
+                true => TYPE_HARDTAIL,
+                false => TYPE_SOFTTAIL,
+            };
+            let block = (index as u32 + 1) * 2;
+            commit.tag(ty, NO_ID, &pair_bytes(block, block + 1));
+        }
+
+        let data = commit.finish(config.prog_size);
+        image[index * 2 * config.block_size..][..data.len()].copy_from_slice(&data);
+    }
+
+    Ok(image)
+}
+
+fn pair_bytes(a: u32, b: u32) -> [u8; 8] {
+    let mut data = [0; 8];
+    data[..4].copy_from_slice(&a.to_le_bytes());
+    data[4..].copy_from_slice(&b.to_le_bytes());
+    data
+}
+
+/// Write `data` as a CTZ skip-list starting at block `first`, each block `n`
+/// starts with pointers to the blocks `n - 2^i`
+fn write_ctz(image: &mut [u8], first: usize, data: &[u8], config: &LittleFsConfig) {
+    let blocks = config.ctz_blocks(data.len());
+    let mut data = data;
+    for index in 0..blocks {
+        let block = &mut image[(first + index) * config.block_size..][..config.block_size];
+        let pointers = ctz_pointers(index);
+        for i in 0..pointers {
+            let pointer = (first + index - (1 << i)) as u32;
+            block[i * 4..][..4].copy_from_slice(&pointer.to_le_bytes());
+        }
+        let len = (config.block_size - pointers * 4).min(data.len());
+        block[pointers * 4..][..len].copy_from_slice(&data[..len]);
+        data = &data[len..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::convert::TryInto;
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..][..4].try_into().unwrap())
+    }
+
+    /// The tags of the first block of a metadata pair, checking the CRC like
+    /// `lfs_dir_fetch`
+    fn fetch(image: &[u8], block: u32, config: &LittleFsConfig) -> Vec<(u32, u32, Vec<u8>)> {
+        let data = &image[block as usize * config.block_size..][..config.block_size];
+        let mut tags = Vec::new();
+        let mut ptag = 0xffff_ffff;
+        let mut off = 4;
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(&data[..4]);
+        loop {
+            let raw = &data[off..][..4];
+            let tag = u32::from_be_bytes(raw.try_into().unwrap()) ^ ptag;
+            if tag & 0x8000_0000 != 0 {
+                break;
+            }
+            crc.update(raw);
+            let (ty, id, size) = (tag >> 20, (tag >> 10) & 0x3ff, (tag & 0x3ff) as usize);
+            if ty == TYPE_CRC {
+                assert_eq!(!crc.clone().finalize(), read_u32(data, off + 4));
+                assert_eq!(0, (off + 4 + size) % config.prog_size);
+                crc = crc32fast::Hasher::new();
+            } else {
+                crc.update(&data[off + 4..][..size]);
+                tags.push((ty, id, data[off + 4..][..size].to_vec()));
+            }
+            off += 4 + size;
+            ptag = tag;
+        }
+        assert!(!tags.is_empty() || off > 4, "no commit in block {}", block);
+        tags
+    }
+
+    fn read_ctz(image: &[u8], head: u32, size: usize, config: &LittleFsConfig) -> Vec<u8> {
+        // follow the first pointer of each block back to the start
+        let blocks = config.ctz_blocks(size);
+        let mut chain = vec![head];
+        for index in (1..blocks).rev() {
+            let block = *chain.last().unwrap() as usize * config.block_size;
+            let previous = read_u32(image, block);
+            if index & 1 == 0 {
+                // the second pointer skips a block
+                assert_eq!(read_u32(image, block + 4) + 1, previous);
+            }
+            chain.push(previous);
+        }
+        chain.reverse();
+
+        let mut data = Vec::new();
+        for (index, block) in chain.into_iter().enumerate() {
+            let start = block as usize * config.block_size + ctz_pointers(index) * 4;
+            let len = (config.block_size - ctz_pointers(index) * 4).min(size - data.len());
+            data.extend_from_slice(&image[start..][..len]);
+        }
+        data
+    }
+
+    /// Read back the files and directories below the pair at `block`
+    fn read_dir(
+        image: &[u8],
+        block: u32,
+        prefix: &str,
+        config: &LittleFsConfig,
+        files: &mut BTreeMap<String, Option<Vec<u8>>>,
+        pairs: &mut Vec<u32>,
+    ) {
+        let mut block = Some(block);
+        while let Some(current) = block.take() {
+            pairs.push(current);
+            let tags = fetch(image, current, config);
+            let mut names = BTreeMap::new();
+            for (ty, id, data) in &tags {
+                match *ty {
+                    TYPE_REG | TYPE_DIR => {
+                        names.insert(*id, format!("{}{}", prefix, String::from_utf8_lossy(data)));
+                    }
+                    TYPE_INLINESTRUCT if names.contains_key(id) => {
+                        files.insert(names[id].clone(), Some(data.clone()));
+                    }
+                    TYPE_CTZSTRUCT => {
+                        let head = read_u32(data, 0);
+                        let size = read_u32(data, 4) as usize;
+                        files.insert(names[id].clone(), Some(read_ctz(image, head, size, config)));
+                    }
+                    TYPE_DIRSTRUCT => {
+                        files.insert(names[id].clone(), None);
+                        let prefix = format!("{}/", names[id]);
+                        read_dir(image, read_u32(data, 0), &prefix, config, files, pairs);
+                    }
+                    TYPE_HARDTAIL => block = Some(read_u32(data, 0)),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_littlefs_image() {
+        let config = LittleFsConfig::default();
+        let large: Vec<u8> = (0..50_000u32).map(|i| (i * 13) as u8).collect();
+        let mut entries = vec![
+            Entry::file("config.json", "{}"),
+            Entry::dir("www"),
+            Entry::file("www/empty.txt", ""),
+            Entry::file("www/index.html", large.clone()),
+            Entry::dir("www/img"),
+        ];
+        // enough entries to split the directory into several pairs
+        for i in 0..100 {
+            entries.push(Entry::file(
+                format!("www/img/{:03}.png", i),
+                vec![i as u8; 100],
+            ));
+        }
+
+        let image = generate(&entries, 0x40000, &config).unwrap();
+        assert_eq!(0x40000, image.len());
+
+        let superblock = fetch(&image, 0, &config);
+        assert_eq!((TYPE_SUPERBLOCK, 0, b"littlefs".to_vec()), superblock[0]);
+        assert_eq!(TYPE_INLINESTRUCT, superblock[1].0);
+        assert_eq!(DISK_VERSION, read_u32(&superblock[1].2, 0));
+        assert_eq!(4096, read_u32(&superblock[1].2, 4));
+        assert_eq!(64, read_u32(&superblock[1].2, 8));
+        // the second block of each pair is left erased
+        assert!(image[0x1000..0x2000].iter().all(|b| *b == 0xff));
+
+        let mut files = BTreeMap::new();
+        let mut pairs = Vec::new();
+        read_dir(&image, 0, "", &config, &mut files, &mut pairs);
+        let expected: BTreeMap<String, Option<Vec<u8>>> = entries
+            .into_iter()
+            .map(|entry| (entry.path, entry.data))
+            .collect();
+        assert_eq!(expected, files);
+        assert!(pairs.len() > 3);
+
+        // all metadata pairs are linked through their tails from the root
+        let mut linked = BTreeSet::new();
+        let mut block = Some(0);
+        while let Some(current) = block.take() {
+            assert!(linked.insert(current));
+            for (ty, _, data) in fetch(&image, current, &config) {
+                if ty == TYPE_SOFTTAIL || ty == TYPE_HARDTAIL {
+                    block = Some(read_u32(&data, 0));
+                }
+            }
+        }
+        assert_eq!(pairs.into_iter().collect::<BTreeSet<_>>(), linked);
+    }
+
+    #[test]
+    fn test_littlefs_errors() {
+        let config = LittleFsConfig::default();
+        assert!(matches!(
+            generate(&[], 0x1800, &config),
+            Err(FsImageError::InvalidSize { .. })
+        ));
+        assert!(matches!(
+            generate(&[Entry::file("a".repeat(65), "")], 0x2000, &config),
+            Err(FsImageError::NameTooLong(_))
+        ));
+        assert!(matches!(
+            generate(
+                &[Entry::file("large.bin", vec![0; 0x4000])],
+                0x4000,
+                &config
+            ),
+            Err(FsImageError::TooLarge)
+        ));
+    }
+}
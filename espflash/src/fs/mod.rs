@@ -0,0 +1,73 @@
+//! Generating filesystem images for data partitions
+//!
+//! The images are built from a directory on the host, like `mkspiffs`,
+//! `mklittlefs` and `wl_fatfsgen.py` do, and can be written to the data
+//! partition the firmware mounts them from.
+
+use std::fs;
+use std::path::Path;
+
+use crate::error::FsImageError;
+
+pub mod fat;
+pub mod littlefs;
+pub mod spiffs;
+
+/// A file or directory of the host directory, with its path in the image
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Entry {
+    /// Path relative to the root of the image, separated by `/`
+    pub path: String,
+    /// Contents of a file, `None` for a directory
+    pub data: Option<Vec<u8>>,
+}
+
+impl Entry {
+    pub fn file(path: impl Into<String>, data: impl Into<Vec<u8>>) -> Self {
+        Entry {
+            path: path.into(),
+            data: Some(data.into()),
+        }
+    }
+
+    pub fn dir(path: impl Into<String>) -> Self {
+        Entry {
+            path: path.into(),
+            data: None,
+        }
+    }
+}
+
+/// Read the files and directories below `dir`, sorted by name with each
+/// directory preceding its contents
+pub fn read_dir(dir: &Path) -> Result<Vec<Entry>, FsImageError> {
+    let mut entries = Vec::new();
+    read_dir_into(dir, "", &mut entries)?;
+    Ok(entries)
+}
+
+fn read_dir_into(dir: &Path, prefix: &str, entries: &mut Vec<Entry>) -> Result<(), FsImageError> {
+    let io_error = |path: &Path| {
+        let path = path.display().to_string();
+        move |source| FsImageError::Io { path, source }
+    };
+
+    let mut children = fs::read_dir(dir)
+        .and_then(|children| children.collect::<Result<Vec<_>, _>>())
+        .map_err(io_error(dir))?;
+    children.sort_by_key(|child| child.file_name());
+
+    for child in children {
+        let path = child.path();
+        let name = format!("{}{}", prefix, child.file_name().to_string_lossy());
+        if path.is_dir() {
+            entries.push(Entry::dir(name.clone()));
+            read_dir_into(&path, &format!("{}/", name), entries)?;
+        } else {
+            let data = fs::read(&path).map_err(io_error(&path))?;
+            entries.push(Entry::file(name, data));
+        }
+    }
+
+    Ok(())
+}
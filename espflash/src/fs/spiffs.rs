@@ -0,0 +1,363 @@
+//! SPIFFS images
+//!
+//! The layout matches the one of ESP-IDF's `spiffsgen.py` with the default
+//! configuration of the `spiffs` component. Each block starts with a lookup
+//! page listing the object of every page in the block, followed by the pages
+//! themselves. A file is stored as index pages listing its data pages, the
+//! first of which also holds its name and size.
+
+use std::convert::TryFrom;
+
+use crate::error::FsImageError;
+
+use super::Entry;
+
+const OBJ_ID_LEN: usize = 2;
+const SPAN_IX_LEN: usize = 2;
+const PAGE_IX_LEN: usize = 2;
+/// Object id, span index and flags
+const PAGE_HEADER_LEN: usize = OBJ_ID_LEN + SPAN_IX_LEN + 1;
+/// The page header padded to 4 bytes, as in the index pages
+const PAGE_HEADER_LEN_ALIGNED: usize = (PAGE_HEADER_LEN + 3) & !3;
+/// Size, type, name and metadata follow the header of the first index page
+const INDEX_HEADER_LEN: usize = 4 + 1;
+
+const FLAG_USED_FINAL: u8 = 0xfc;
+const FLAG_USED_FINAL_INDEX: u8 = 0xf8;
+const TYPE_FILE: u8 = 1;
+/// Set in the object id of index pages
+const OBJ_ID_INDEX: u16 = 0x8000;
+const MAGIC: u32 = 0x2014_0529;
+
+/// Parameters of the filesystem, which have to match the configuration of the
+/// `spiffs` component of the firmware
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct SpiffsConfig {
+    /// `CONFIG_SPIFFS_PAGE_SIZE`
+    pub page_size: usize,
+    /// Size of the blocks, the flash sector size in ESP-IDF
+    pub block_size: usize,
+    /// `CONFIG_SPIFFS_OBJ_NAME_LEN`, including the terminating NUL
+    pub obj_name_len: usize,
+    /// `CONFIG_SPIFFS_META_LENGTH`
+    pub meta_len: usize,
+}
+
+impl Default for SpiffsConfig {
+    fn default() -> Self {
+        SpiffsConfig {
+            page_size: 256,
+            block_size: 4096,
+            obj_name_len: 32,
+            meta_len: 4,
+        }
+    }
+}
+
+impl SpiffsConfig {
+    fn pages_per_block(&self) -> usize {
+        self.block_size / self.page_size
+    }
+
+    fn lookup_pages(&self) -> usize {
+        (self.pages_per_block() * OBJ_ID_LEN).div_ceil(self.page_size)
+    }
+
+    fn data_len(&self) -> usize {
+        self.page_size - PAGE_HEADER_LEN
+    }
+
+    /// Number of data pages listed by an index page
+    fn index_entries(&self, span_ix: u16) -> usize {
+        let header = match span_ix {
+            0 => PAGE_HEADER_LEN_ALIGNED + INDEX_HEADER_LEN + self.obj_name_len + self.meta_len,
+            _ => PAGE_HEADER_LEN_ALIGNED,
+        };
+        (self.page_size - header) / PAGE_IX_LEN
+    }
+}
+
+/// An index page of a file, written once all its data pages are known
+struct IndexPage {
+    addr: usize,
+    obj_id: u16,
+    span_ix: u16,
+    pages: Vec<u16>,
+}
+
+struct Builder<'a> {
+    config: &'a SpiffsConfig,
+    image: Vec<u8>,
+    block_count: usize,
+    /// The block pages are allocated in, `None` before the first one
+    block: Option<usize>,
+    /// Index of the next free page in the block, not counting lookup pages
+    next_page: usize,
+    index_pages: Vec<IndexPage>,
+}
+
+impl<'a> Builder<'a> {
+    fn new(size: usize, config: &'a SpiffsConfig) -> Self {
+        let block_count = size / config.block_size;
+        let mut image = vec![0xff; size];
+
+        // every block is marked as formatted by a magic in its last lookup
+        // page, the last entry is left for the erase count
+        let magic_offset = config.lookup_pages() * config.page_size - 2 * OBJ_ID_LEN;
+        for bix in 0..block_count {
+            let magic = (MAGIC ^ config.page_size as u32 ^ (block_count - bix) as u32) as u16;
+            let offset = bix * config.block_size + magic_offset;
+            image[offset..][..2].copy_from_slice(&magic.to_le_bytes());
+        }
+
+        Builder {
+            config,
+            image,
+            block_count,
+            block: None,
+            next_page: 0,
+            index_pages: Vec::new(),
+        }
+    }
+
+    fn block_full(&self) -> bool {
+        self.block.is_none()
+            || self.next_page == self.config.pages_per_block() - self.config.lookup_pages()
+    }
+
+    fn next_block(&mut self) -> Result<(), FsImageError> {
+        let block = self.block.map_or(0, |block| block + 1);
+        if block >= self.block_count {
+            return Err(FsImageError::TooLarge);
+        }
+        self.block = Some(block);
+        self.next_page = 0;
+        Ok(())
+    }
+
+    /// Take the next free page for `obj_id`, returning its address
+    fn allocate(&mut self, obj_id: u16) -> Result<usize, FsImageError> {
+        if self.block_full() {
+            self.next_block()?;
+        }
+        let block = self.block.unwrap_or_default() * self.config.block_size;
+        let lookup = block + self.next_page * OBJ_ID_LEN;
+        self.image[lookup..][..2].copy_from_slice(&obj_id.to_le_bytes());
+
+        let addr = block + (self.config.lookup_pages() + self.next_page) * self.config.page_size;
+        self.next_page += 1;
+        Ok(addr)
+    }
+
+    fn begin_index(&mut self, obj_id: u16, span_ix: u16) -> Result<(), FsImageError> {
+        let addr = self.allocate(obj_id | OBJ_ID_INDEX)?;
+        self.index_pages.push(IndexPage {
+            addr,
+            obj_id,
+            span_ix,
+            pages: Vec::new(),
+        });
+        Ok(())
+    }
+
+    fn add_file(&mut self, obj_id: u16, name: &str, data: &[u8]) -> Result<(), FsImageError> {
+        let first_index = self.index_pages.len();
+        self.begin_index(obj_id, 0)?;
+
+        for (span_ix, chunk) in data.chunks(self.config.data_len()).enumerate() {
+            // a new block continues the current index page, a new index page is
+            // only started once it's full
+            if self.block_full() {
+                self.next_block()?;
+            }
+            let index = self.index_pages.last().expect("an index page was started");
+            if index.pages.len() == self.config.index_entries(index.span_ix) {
+                self.begin_index(obj_id, index.span_ix + 1)?;
+            }
+
+            let addr = self.allocate(obj_id)?;
+            let page = &mut self.image[addr..][..self.config.page_size];
+            page[..2].copy_from_slice(&obj_id.to_le_bytes());
+            page[2..4].copy_from_slice(&(span_ix as u16).to_le_bytes());
+            page[4] = FLAG_USED_FINAL;
+            page[PAGE_HEADER_LEN..][..chunk.len()].copy_from_slice(chunk);
+
+            let page_ix = (addr / self.config.page_size) as u16;
+            if let Some(index) = self.index_pages.last_mut() {
+                index.pages.push(page_ix);
+            }
+        }
+
+        for index in self.index_pages.drain(first_index..) {
+            let page = &mut self.image[index.addr..][..self.config.page_size];
+            page[..2].copy_from_slice(&(index.obj_id | OBJ_ID_INDEX).to_le_bytes());
+            page[2..4].copy_from_slice(&index.span_ix.to_le_bytes());
+            page[4] = FLAG_USED_FINAL_INDEX;
+
+            let mut offset = PAGE_HEADER_LEN_ALIGNED;
+            if index.span_ix == 0 {
+                page[offset..][..4].copy_from_slice(&(data.len() as u32).to_le_bytes());
+                page[offset + 4] = TYPE_FILE;
+                offset += INDEX_HEADER_LEN;
+                // the name is NUL padded, the metadata is zeroed
+                let len = self.config.obj_name_len + self.config.meta_len;
+                page[offset..][..len].fill(0);
+                page[offset..][..name.len()].copy_from_slice(name.as_bytes());
+                offset += len;
+            }
+            for page_ix in index.pages {
+                page[offset..][..PAGE_IX_LEN].copy_from_slice(&page_ix.to_le_bytes());
+                offset += PAGE_IX_LEN;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Generate a SPIFFS image of `size` bytes containing the files of `entries`
+///
+/// SPIFFS has no directories, files are stored with their full path starting
+/// with `/` as their name.
+pub fn generate(
+    entries: &[Entry],
+    size: u32,
+    config: &SpiffsConfig,
+) -> Result<Vec<u8>, FsImageError> {
+    let size = size as usize;
+    if size == 0 || !size.is_multiple_of(config.block_size) {
+        return Err(FsImageError::InvalidSize {
+            size: size as u32,
+            block_size: config.block_size as u32,
+        });
+    }
+
+    let mut builder = Builder::new(size, config);
+    let files = entries
+        .iter()
+        .filter_map(|entry| Some((format!("/{}", entry.path), entry.data.as_ref()?)));
+    for (index, (name, data)) in files.enumerate() {
+        if name.len() >= config.obj_name_len {
+            return Err(FsImageError::NameTooLong(name));
+        }
+        // object ids start at 1, the index page flag limits them to 15 bits
+        let obj_id = u16::try_from(index + 1)
+            .ok()
+            .filter(|obj_id| obj_id & OBJ_ID_INDEX == 0)
+            .ok_or(FsImageError::TooLarge)?;
+        builder.add_file(obj_id, &name, data)?;
+    }
+
+    Ok(builder.image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+    use std::convert::TryInto;
+
+    fn read_u16(data: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([data[offset], data[offset + 1]])
+    }
+
+    /// Read the files back by following the lookup and index pages
+    fn read_files(image: &[u8], config: &SpiffsConfig) -> BTreeMap<String, Vec<u8>> {
+        let pages = config.pages_per_block() - config.lookup_pages();
+        let mut index_pages = BTreeMap::new();
+        for block in (0..image.len()).step_by(config.block_size) {
+            for page in 0..pages {
+                let obj_id = read_u16(image, block + page * OBJ_ID_LEN);
+                if obj_id != 0xffff && obj_id & OBJ_ID_INDEX != 0 {
+                    let addr = block + (config.lookup_pages() + page) * config.page_size;
+                    let span_ix = read_u16(image, addr + 2);
+                    index_pages.insert((obj_id & !OBJ_ID_INDEX, span_ix), addr);
+                }
+            }
+        }
+
+        let mut files = BTreeMap::new();
+        for (&(obj_id, span_ix), &addr) in &index_pages {
+            if span_ix != 0 {
+                continue;
+            }
+            let size = u32::from_le_bytes(image[addr + 8..][..4].try_into().unwrap()) as usize;
+            let name = &image[addr + 13..][..config.obj_name_len];
+            let name = String::from_utf8(name.split(|b| *b == 0).next().unwrap().to_vec());
+
+            let mut data = Vec::new();
+            let mut span_ix = 0;
+            while data.len() < size {
+                let addr = index_pages[&(obj_id, span_ix)];
+                let start = match span_ix {
+                    0 => 13 + config.obj_name_len + config.meta_len,
+                    _ => PAGE_HEADER_LEN_ALIGNED,
+                };
+                for entry in 0..config.index_entries(span_ix) {
+                    let page_ix = read_u16(image, addr + start + entry * 2);
+                    if page_ix == 0xffff || data.len() == size {
+                        break;
+                    }
+                    let page = page_ix as usize * config.page_size;
+                    assert_eq!(obj_id, read_u16(image, page));
+                    assert_eq!(FLAG_USED_FINAL, image[page + 4]);
+                    let len = config.data_len().min(size - data.len());
+                    data.extend_from_slice(&image[page + PAGE_HEADER_LEN..][..len]);
+                }
+                span_ix += 1;
+            }
+            files.insert(name.unwrap(), data);
+        }
+        files
+    }
+
+    #[test]
+    fn test_spiffs_image() {
+        let config = SpiffsConfig::default();
+        let large: Vec<u8> = (0..100_000u32).map(|i| (i * 7) as u8).collect();
+        let entries = vec![
+            Entry::file("config.json", "{}"),
+            Entry::dir("www"),
+            Entry::file("www/empty.txt", ""),
+            Entry::file("www/index.html", large.clone()),
+        ];
+
+        let image = generate(&entries, 0x40000, &config).unwrap();
+        assert_eq!(0x40000, image.len());
+
+        // the magic of the first block, with 64 blocks in the image
+        assert_eq!(
+            (0x2014_0529u32 ^ 256 ^ 64) as u16,
+            read_u16(&image, config.page_size - 4)
+        );
+        assert_eq!(0xffff, read_u16(&image, config.page_size - 2));
+
+        let files = read_files(&image, &config);
+        assert_eq!(3, files.len());
+        assert_eq!(b"{}".to_vec(), files["/config.json"]);
+        assert!(files["/www/empty.txt"].is_empty());
+        assert_eq!(large, files["/www/index.html"]);
+    }
+
+    #[test]
+    fn test_spiffs_errors() {
+        let config = SpiffsConfig::default();
+        assert!(matches!(
+            generate(&[], 0x1800, &config),
+            Err(FsImageError::InvalidSize { .. })
+        ));
+        assert!(matches!(
+            generate(&[Entry::file("a".repeat(31), "")], 0x2000, &config),
+            Err(FsImageError::NameTooLong(_))
+        ));
+        assert!(matches!(
+            generate(
+                &[Entry::file("large.bin", vec![0; 0x4000])],
+                0x4000,
+                &config
+            ),
+            Err(FsImageError::TooLarge)
+        ));
+    }
+}
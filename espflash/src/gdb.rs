@@ -0,0 +1,61 @@
+//! Bridge between GDB and the GDB stub of the device
+//!
+//! With `CONFIG_ESP_SYSTEM_PANIC_GDBSTUB`, the ESP-IDF panic handler speaks
+//! the GDB remote serial protocol on the UART after a crash, as do the GDB
+//! stubs of the ESP8266 SDKs. The protocol is the same over TCP, so GDB can
+//! attach through a socket bridged to the serial port.
+
+use crate::Transport;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpListener;
+use std::time::Duration;
+
+/// Show the output of the device on `output` until GDB connects to
+/// `listener`, then pass the data between both until it disconnects
+pub fn serve(
+    serial: &mut dyn Transport,
+    listener: &TcpListener,
+    output: &mut impl Write,
+) -> io::Result<()> {
+    let mut buf = [0; 1024];
+    serial.set_timeout(Duration::from_millis(5))?;
+    listener.set_nonblocking(true)?;
+
+    let mut client = loop {
+        match listener.accept() {
+            Ok((client, _)) => break client,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err),
+        }
+
+        let count = read_serial(serial, &mut buf)?;
+        output.write_all(&buf[..count])?;
+        output.flush()?;
+    };
+    client.set_nonblocking(false)?;
+    client.set_nodelay(true)?;
+    client.set_read_timeout(Some(Duration::from_millis(5)))?;
+    log::info!("GDB connected from {}", client.peer_addr()?);
+
+    loop {
+        let count = read_serial(serial, &mut buf)?;
+        client.write_all(&buf[..count])?;
+
+        match client.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(count) => {
+                serial.write_all(&buf[..count])?;
+                serial.flush()?;
+            }
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn read_serial(serial: &mut dyn Transport, buf: &mut [u8]) -> io::Result<usize> {
+    match serial.read(buf) {
+        Err(err) if err.kind() == ErrorKind::TimedOut => Ok(0),
+        result => result,
+    }
+}
@@ -0,0 +1,221 @@
+//! Journal of the commands exchanged with the device
+//!
+//! When enabled, every command sent to the ROM bootloader is recorded as a
+//! line of JSON together with its timing and outcome. The journal is flushed
+//! after every entry, so it survives the process being interrupted and can be
+//! analyzed after the fact to find out where time was spent and which commands
+//! failed.
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::io::BufRead;
+#[cfg(feature = "serial")]
+use std::{
+    fs::File,
+    io::{self, LineWriter, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::command::Command;
+
+/// Number of failures listed individually in a summary
+const MAX_LISTED_FAILURES: usize = 10;
+
+/// A single command in the journal
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// Time since the journal was started at which the command was sent, in
+    /// milliseconds
+    pub elapsed_ms: f64,
+    /// The command that was sent
+    pub command: Command,
+    /// Size of the command payload in bytes
+    pub size: u32,
+    /// Time until the response was received or the command failed, in
+    /// milliseconds
+    pub duration_ms: f64,
+    /// The error the command failed with, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Writer appending entries to a journal file
+#[cfg(feature = "serial")]
+pub struct Journal {
+    writer: LineWriter<File>,
+    started: Instant,
+}
+
+#[cfg(feature = "serial")]
+impl Journal {
+    /// Create a new journal at `path`, replacing any existing file
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Journal {
+            writer: LineWriter::new(File::create(path)?),
+            started: Instant::now(),
+        })
+    }
+
+    /// Record a command which was sent at `sent` and has just completed
+    ///
+    /// Failing to write the journal should never interrupt flashing, so
+    /// errors are ignored.
+    pub(crate) fn record(
+        &mut self,
+        command: Command,
+        size: u32,
+        sent: Instant,
+        error: Option<String>,
+    ) {
+        let entry = JournalEntry {
+            elapsed_ms: as_ms(sent.duration_since(self.started)),
+            command,
+            size,
+            duration_ms: as_ms(sent.elapsed()),
+            error,
+        };
+
+        if let Ok(mut line) = serde_json::to_vec(&entry) {
+            line.push(b'\n');
+            let _ = self.writer.write_all(&line);
+        }
+    }
+}
+
+/// Statistics of a single command in a journal
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct CommandSummary {
+    /// Number of times the command was sent
+    pub count: u32,
+    /// Number of times the command failed
+    pub failures: u32,
+    /// Total number of payload bytes sent
+    pub bytes: u64,
+    /// Total time spent on the command, in milliseconds
+    pub duration_ms: f64,
+}
+
+/// Summary of a journal, showing where time was spent and where failures
+/// occurred
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct JournalSummary {
+    /// Time from the start of the journal until the last command completed,
+    /// in milliseconds
+    pub total_ms: f64,
+    /// Statistics per command, keyed by the command name
+    pub commands: BTreeMap<String, CommandSummary>,
+    /// The commands that failed, in the order they were sent
+    pub failures: Vec<JournalEntry>,
+}
+
+impl JournalSummary {
+    /// Analyze the journal read from `reader`
+    pub fn analyze(reader: impl BufRead) -> Result<Self, serde_json::Error> {
+        let mut summary = JournalSummary::default();
+
+        for line in reader.lines() {
+            let line = line.map_err(serde_json::Error::io)?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: JournalEntry = serde_json::from_str(&line)?;
+
+            summary.total_ms = summary.total_ms.max(entry.elapsed_ms + entry.duration_ms);
+
+            let command = summary
+                .commands
+                .entry(entry.command.to_string())
+                .or_default();
+            command.count += 1;
+            command.bytes += entry.size as u64;
+            command.duration_ms += entry.duration_ms;
+            if entry.error.is_some() {
+                command.failures += 1;
+                summary.failures.push(entry);
+            }
+        }
+
+        Ok(summary)
+    }
+}
+
+impl Display for JournalSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Total time: {:.1} ms", self.total_ms)?;
+        writeln!(
+            f,
+            "{:<20} {:>8} {:>8} {:>12} {:>12} {:>6}",
+            "Command", "Count", "Failed", "Bytes", "Time (ms)", "Time %"
+        )?;
+
+        let mut commands: Vec<_> = self.commands.iter().collect();
+        commands.sort_by(|(_, a), (_, b)| b.duration_ms.total_cmp(&a.duration_ms));
+        for (name, command) in commands {
+            writeln!(
+                f,
+                "{:<20} {:>8} {:>8} {:>12} {:>12.1} {:>5.1}%",
+                name,
+                command.count,
+                command.failures,
+                command.bytes,
+                command.duration_ms,
+                command.duration_ms * 100.0 / self.total_ms.max(f64::EPSILON)
+            )?;
+        }
+
+        if !self.failures.is_empty() {
+            writeln!(f, "\nFailures:")?;
+            for failure in self.failures.iter().take(MAX_LISTED_FAILURES) {
+                writeln!(
+                    f,
+                    "  at {:.1} ms: {} failed after {:.1} ms: {}",
+                    failure.elapsed_ms,
+                    failure.command,
+                    failure.duration_ms,
+                    failure.error.as_deref().unwrap_or_default()
+                )?;
+            }
+            if self.failures.len() > MAX_LISTED_FAILURES {
+                writeln!(
+                    f,
+                    "  and {} more",
+                    self.failures.len() - MAX_LISTED_FAILURES
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serial")]
+fn as_ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[test]
+fn test_analyze() {
+    let journal = r#"
+{"elapsed_ms":0.0,"command":"sync","size":36,"duration_ms":100.0,"error":"Timeout while running Sync command"}
+{"elapsed_ms":100.0,"command":"sync","size":36,"duration_ms":5.0}
+{"elapsed_ms":105.0,"command":"flash-deflate-data","size":1040,"duration_ms":40.0}
+{"elapsed_ms":145.0,"command":"flash-deflate-data","size":1040,"duration_ms":55.0}
+"#;
+
+    let summary = JournalSummary::analyze(journal.as_bytes()).unwrap();
+    assert_eq!(200.0, summary.total_ms);
+    assert_eq!(
+        Some(&CommandSummary {
+            count: 2,
+            failures: 1,
+            bytes: 72,
+            duration_ms: 105.0
+        }),
+        summary.commands.get("sync")
+    );
+    assert_eq!(2080, summary.commands["flash-deflate-data"].bytes);
+    assert_eq!(1, summary.failures.len());
+}
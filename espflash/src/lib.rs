@@ -1,15 +1,69 @@
+pub mod app_desc;
+#[cfg(feature = "async")]
+mod async_flasher;
+#[cfg(feature = "serial")]
+pub mod attestation;
+pub mod batch;
+pub mod board_info;
 mod chip;
+pub mod command;
 mod config;
+#[cfg(feature = "serial")]
 mod connection;
+pub mod coredump;
 mod elf;
+#[cfg(feature = "serial")]
 mod encoder;
 mod error;
+pub mod espota;
+#[cfg(feature = "serial")]
+pub mod esptool;
+pub mod flash_encryption;
+#[cfg(feature = "serial")]
 mod flash_target;
+#[cfg(feature = "serial")]
 mod flasher;
+pub mod flasher_args;
+pub mod fs;
+#[cfg(feature = "serial")]
+pub mod gdb;
+pub mod journal;
+pub mod logging;
+#[cfg(feature = "serial")]
+pub mod manifest;
+#[cfg(feature = "cli")]
+pub mod monitor;
+pub mod notify;
+pub mod nvs;
+#[cfg(feature = "serial")]
+pub mod ota;
+pub mod ota_server;
+pub mod output;
 mod partition_table;
+pub mod plugin;
+#[cfg(feature = "serial")]
+pub mod port;
+pub mod progress;
+pub mod report;
+pub mod reset;
+// the server side is only used by the monitor
+#[cfg(feature = "serial")]
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
+mod rfc2217;
+pub mod sdkconfig;
+pub mod secure_boot;
 
-pub use chip::Chip;
+#[cfg(feature = "async")]
+pub use async_flasher::AsyncFlasher;
+pub use chip::{efuse, Chip, Efuses, Feature, FlashOffsets, ImageInfo, ImageSegment};
 pub use config::Config;
-pub use error::Error;
-pub use flasher::Flasher;
-pub use partition_table::PartitionTable;
+#[cfg(feature = "serial")]
+pub use connection::{CancelToken, CommandResponse, Connection, Transport};
+pub use elf::{FlashFrequency, FlashMode, FlashSettings, FlashSize, SegmentLayout};
+pub use error::{Error, ProbeFailure};
+#[cfg(feature = "serial")]
+pub use flasher::{
+    FlashTiming, Flasher, FlasherBuilder, ResetAfterOperation, ResetBeforeOperation,
+    FLASH_STATUS_PROTECTION,
+};
+pub use partition_table::{DataType, PartitionTable};
@@ -0,0 +1,46 @@
+//! Logging to stderr, for the `-v`/`-vv` flags of the command line tools
+//!
+//! Informational messages are shown by default. `-v` adds debug messages about
+//! the connection and the regions written, `-vv` traces every command sent to
+//! the device with its payload size and response status.
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        match record.level() {
+            Level::Info => eprintln!("{}", record.args()),
+            level => eprintln!("[{}] {}", level, record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+/// The level of messages shown for the number of `-v` flags given
+fn level_for(verbosity: u64) -> LevelFilter {
+    match verbosity {
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Log to stderr, at the level for the number of `-v` flags given
+pub fn init(verbosity: u64) {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level_for(verbosity));
+    }
+}
@@ -1,18 +1,425 @@
-use std::fs::{read, read_to_string};
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::fs::{read, read_to_string, write, File};
+use std::io::{stdin, stdout, BufReader, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-use espflash::{Config, Error, Flasher, PartitionTable};
+use ed25519_dalek::SigningKey;
+use espflash::{
+    app_desc::{AppDescriptor, AppDescriptorOverrides},
+    attestation::AttestationRecord,
+    batch::{BatchFile, BatchSummary, Job, JobResult},
+    coredump,
+    efuse::{self, EfuseBlocks, EfuseValue},
+    espota::{self, EspotaOptions, EspotaTarget},
+    esptool::{EsptoolArgs, EsptoolCommand},
+    flash_encryption::{self, FlashEncryptionKey},
+    flasher_args::{FlasherArgs, FLASHER_ARGS_FILE},
+    fs::{self, fat::FatConfig, littlefs::LittleFsConfig, spiffs::SpiffsConfig},
+    gdb,
+    journal::{Journal, JournalSummary},
+    manifest::FlashManifest,
+    monitor::{self, MonitorOptions},
+    notify::Notifier,
+    nvs::{self, NvsPartition, NvsValue},
+    ota,
+    ota_server::{OtaArtifact, FIRMWARE_PATH, VERSION_PATH},
+    output::{app_image, merged_image, split_image, OutputFormat},
+    plugin,
+    port::{GpioPins, GpioResetPort},
+    progress::{JsonProgress, ProgressBars},
+    reset::ResetSequence,
+    sdkconfig::SdkConfig,
+    secure_boot::{digest_bootloader, SECURE_BOOT_IV_LEN},
+    Chip, Config, DataType, Error, FlashFrequency, FlashMode, FlashSettings, FlashSize,
+    FlashTiming, Flasher, FlasherBuilder, ImageInfo, PartitionTable, ResetAfterOperation,
+    ResetBeforeOperation, SegmentLayout, Transport, FLASH_STATUS_PROTECTION,
+};
 use miette::{IntoDiagnostic, Result, WrapErr};
 use pico_args::Arguments;
-use serial::{BaudRate, FlowControl, SerialPort};
+use serial::BaudRate;
+use sha2::{Digest, Sha256};
+
+mod repl;
+
+/// Default time to retry opening the serial port for, in seconds, retrying is
+/// opt-in so that a wrong port name fails right away
+const DEFAULT_RETRY_OPEN: f64 = 0.0;
+
+/// Set by `--non-interactive`, which disables all prompts
+static NON_INTERACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Maximum size of a partition table in flash
+const PARTITION_TABLE_SIZE: u32 = 0xc00;
 
 #[allow(clippy::unnecessary_wraps)]
 fn help() -> Result<()> {
-    println!("Usage: espflash [--board-info] [--ram] [--partition-table partition.csv] [--bootloader boot.bin] <serial> <elf image>");
+    println!("Usage: espflash [--board-info] [--format text|json] [--ram [--entry <address>] [--no-run]] [--safe-mode] [--pipeline] [--force] [--force-unprotect] [--partition-table partition.csv] [--bootloader boot.bin] [--sdkconfig sdkconfig] [--ledger ledger.jsonl --ledger-key key.bin] [--journal journal.jsonl] [--manifest manifest.json] [--sha256 <hex>] [--bootloader-sha256 <hex>] [--partition-table-sha256 <hex>] [--require-sha256] [--encrypt-key key.bin] [--before default_reset|usb_reset|no_reset] [--reset-sequence <steps>] [--after hard_reset|soft_reset|no_reset|stay_in_bootloader] [--speed <baud>] [--spi-clock-div <divider>] [--dummy-cycles <cycles>] [--notify] [--notify-webhook <url>] [--retry-open <seconds>] [--gpio-en <line> --gpio-io0 <line>] [--monitor [--monitor-baud <baud>] [--exit-key <key>] [--output-format text|jsonl] [--log-format serial|defmt] [--log-filter <tag=level>...] [--timestamps] [--log-file <path> [--log-file-size <size>]] [--input-mode raw|line] [--eol cr|lf|crlf] [--boot-baud <baud>] [--serve <address>]] <serial> <elf image>");
+    println!("       espflash flash-idf [--speed <baud>] [--before <reset>] [--after <reset>] [--encrypt-key key.bin] [--retry-open <seconds>] [<serial>] <build dir>");
+    println!("       espflash repl [--journal journal.jsonl] [--retry-open <seconds>] <serial>");
+    println!("       espflash board-info [--format text|json] [--retry-open <seconds>] <serial>");
+    println!("       espflash list-ports [--format text|json]");
+    println!("       espflash probe [--json] [--retry-open <seconds>] <serial>");
+    println!("       espflash reset [--usb-jtag] [--retry-open <seconds>] <serial>");
+    println!("       espflash monitor [--baud <baud>] [--exit-key <key>] [--output-format text|jsonl] [--log-format serial|defmt] [--log-filter <tag=level>...] [--timestamps] [--log-file <path> [--log-file-size <size>]] [--input-mode raw|line] [--eol cr|lf|crlf] [--boot-baud <baud>] [--serve <address>] [--no-reset] [--elf <elf image>] [--retry-open <seconds>] <serial>");
+    println!("       espflash audit --manifest manifest.json [--spi-clock-div <divider>] [--dummy-cycles <cycles>] [--retry-open <seconds>] <serial>");
+    println!("       espflash batch [--parallel] <jobs.toml>");
+    println!("       espflash image-info [--chip <chip>] <image>");
+    println!("       espflash journal-analyze <journal>");
+    println!("       espflash nvs-gen [--size 0x6000] [--keys nvs_keys.bin|--keygen nvs_keys.bin] [--flash <serial> [--partition-table partition.csv]] <input.csv> <output>");
+    println!("       espflash nvs-dump [--partition <name>] [--keys nvs_keys.bin] [--format text|json] [--partition-table partition.csv] [--partition-table-offset <offset>] [--retry-open <seconds>] <serial>");
+    println!("       espflash spiffs [--size <size>] [--page-size 256] [--partition <name>] [--partition-table partition.csv] [--flash <serial>] <dir> <output>");
+    println!("       espflash littlefs [--size <size>] [--partition <name>] [--partition-table partition.csv] [--flash <serial>] <dir> <output>");
+    println!("       espflash fat [--size <size>] [--no-wear-levelling] [--partition <name>] [--partition-table partition.csv] [--flash <serial>] <dir> <output>");
+    println!(
+        "       espflash efuse summary [--format text|json] [--retry-open <seconds>] [--virtual <file> [--chip <chip>]] <serial>"
+    );
+    println!(
+        "       espflash efuse burn [--retry-open <seconds>] [--virtual <file> [--chip <chip>]] <serial> <FIELD>=<value>|@<file>..."
+    );
+    println!("       espflash read-mac [--format text|json] [--retry-open <seconds>] <serial>");
+    println!(
+        "       espflash write-custom-mac [--retry-open <seconds>] [--virtual <file> [--chip <chip>]] <serial> <mac>"
+    );
+    println!("       espflash provision-flash-encryption --key <key.bin> [--dev-no-protect-key] [--bootloader boot.bin] [--partition-table partition.csv] [--retry-open <seconds>] [<serial>] <elf image>");
+    println!("       espflash dump-mem [-o <output>] [--retry-open <seconds>] <serial> <address> <length>");
+    println!("       espflash read-reg [--retry-open <seconds>] <serial> <address>");
+    println!("       espflash write-reg [--mask <mask>] [--delay <microseconds>] [--retry-open <seconds>] <serial> <address> <value>");
+    println!("       espflash flash-status [--unprotect] [--retry-open <seconds>] <serial>");
+    println!("       espflash gdb-server [--listen <address>] [--baud <baud>] [--retry-open <seconds>] <serial>");
+    println!("       espflash coredump [--partition-table partition.csv] [--partition-table-offset <offset>] [-o <output>] [--retry-open <seconds>] <serial>");
+    println!("       espflash set-boot-partition [--partition-table partition.csv] [--partition-table-offset <offset>] [--retry-open <seconds>] <serial> <partition>");
+    println!(
+        "       espflash secure-boot-digest --key <key> [--iv <iv>] [-o <output>] <bootloader>"
+    );
+    println!("       espflash partition-table [--to-binary|--to-csv|--format json] [-o <output>] <input>");
+    println!("       espflash partition-table --from-device <serial> [--partition-table-offset <offset>] [--to-binary|--to-csv|--format json|--visualize] [-o <output>]");
+    println!("       espflash partition-table --visualize [--svg] [--size 4MB] [--chip <chip> --elf <elf image>] [-o <output>] <input>");
+    println!("       espflash merge-bin --chip <chip> [--partition-table partition.csv] [--bootloader boot.bin] [--sdkconfig sdkconfig] [--fill 0xff] [--size 4MB] [--format bin|uf2|hex|srec] <elf image> <output>");
+    println!("       espflash [--port <serial>] [--baud <baud>] [--chip <chip>] [--before <reset>] [--after <reset>] write_flash|read_flash|read_mac|chip_id|flash_id|run|image_info [<args>...]");
+    println!("       espflash save-image --chip <chip> [--partition-table partition.csv] [--bootloader boot.bin] [--sdkconfig sdkconfig] <elf image> <output dir>");
+    println!("       espflash ota --ip <address> [--port <port>] [--host-port <port>] [--password <password>] [--filesystem] [--chip <chip>] [--timeout <seconds>] <image>");
+    println!("       espflash serve-ota [--chip <chip>] [--listen <address>] [--version-endpoint] [--cert <cert.pem> --key <key.pem>] <image>");
+    println!("       espflash --list");
+    println!("       espflash <command> [<args>...]");
+    println!();
+    println!("Image options, when flashing and for merge-bin, save-image and serve-ota:");
+    println!("    [--app-version <version>] [--project-name <name>] [--build-time <time>] [--build-date <date>] [--reproducible]");
+    println!("    [--segment-alignment 4] [--mmu-page-size 0x10000] [--no-page-tail-padding]");
+    println!("    [--flash-mode <mode>] [--flash-freq <frequency>] [--flash-size <size>]");
+    println!("    [--bootloader-offset <offset>] [--partition-table-offset <offset>] [--app-offset <offset>]");
+    println!();
+    println!("All commands accept -v to show debug messages, and -vv to trace every command sent to the device.");
+    println!("With --non-interactive nothing is prompted for, and failures exit with the codes of probe.");
     Ok(())
 }
 
 fn main() -> Result<()> {
-    let mut args = Arguments::from_env();
+    let mut args: Vec<OsString> = std::env::args_os().skip(1).collect();
+    if !args
+        .first()
+        .and_then(|arg| arg.to_str())
+        .is_some_and(is_plugin)
+    {
+        espflash::logging::init(take_verbosity(&mut args));
+        NON_INTERACTIVE.store(take_non_interactive(&mut args), Ordering::Relaxed);
+    }
+
+    let result = match args.first().and_then(|arg| arg.to_str()) {
+        _ if EsptoolArgs::is_esptool(&lossy_args(&args)) => esptool(&lossy_args(&args)),
+        Some("merge-bin") => merge_bin(Arguments::from_vec(args.split_off(1))),
+        Some("save-image") => save_image(Arguments::from_vec(args.split_off(1))),
+        Some("repl") => repl(Arguments::from_vec(args.split_off(1))),
+        Some("flash-idf") => flash_idf(Arguments::from_vec(args.split_off(1))),
+        Some("ota") => espota_upload(Arguments::from_vec(args.split_off(1))),
+        Some("serve-ota") => serve_ota(Arguments::from_vec(args.split_off(1))),
+        Some("board-info") => board_info(Arguments::from_vec(args.split_off(1))),
+        Some("list-ports") => list_ports(Arguments::from_vec(args.split_off(1))),
+        Some("probe") => probe(Arguments::from_vec(args.split_off(1))),
+        Some("reset") => reset(Arguments::from_vec(args.split_off(1))),
+        Some("monitor") => monitor(Arguments::from_vec(args.split_off(1))),
+        Some("audit") => audit(Arguments::from_vec(args.split_off(1))),
+        Some("batch") => batch(Arguments::from_vec(args.split_off(1))),
+        Some("image-info") => image_info(Arguments::from_vec(args.split_off(1))),
+        Some("partition-table") => partition_table(Arguments::from_vec(args.split_off(1))),
+        Some("journal-analyze") => journal_analyze(Arguments::from_vec(args.split_off(1))),
+        Some("nvs-gen") => nvs_gen(Arguments::from_vec(args.split_off(1))),
+        Some("nvs-dump") => nvs_dump(Arguments::from_vec(args.split_off(1))),
+        Some("spiffs") => fs_image(Arguments::from_vec(args.split_off(1)), Filesystem::Spiffs),
+        Some("littlefs") => fs_image(Arguments::from_vec(args.split_off(1)), Filesystem::LittleFs),
+        Some("fat") => fs_image(Arguments::from_vec(args.split_off(1)), Filesystem::Fat),
+        Some("set-boot-partition") => set_boot_partition(Arguments::from_vec(args.split_off(1))),
+        Some("coredump") => read_coredump(Arguments::from_vec(args.split_off(1))),
+        Some("gdb-server") => gdb_server(Arguments::from_vec(args.split_off(1))),
+        Some("efuse") => efuse(args.split_off(1)),
+        Some("read-mac") => read_mac(Arguments::from_vec(args.split_off(1))),
+        Some("write-custom-mac") => write_custom_mac(Arguments::from_vec(args.split_off(1))),
+        Some("provision-flash-encryption") => {
+            provision_flash_encryption(Arguments::from_vec(args.split_off(1)))
+        }
+        Some("dump-mem") => dump_mem(Arguments::from_vec(args.split_off(1))),
+        Some("read-reg") => read_reg(Arguments::from_vec(args.split_off(1))),
+        Some("write-reg") => write_reg(Arguments::from_vec(args.split_off(1))),
+        Some("flash-status") => flash_status(Arguments::from_vec(args.split_off(1))),
+        Some("secure-boot-digest") => secure_boot_digest(Arguments::from_vec(args.split_off(1))),
+        Some("--list") => list_subcommands(),
+        name => match name.and_then(plugin::find) {
+            Some(path) => run_plugin(&path, args.split_off(1)),
+            None => flash(Arguments::from_vec(args)),
+        },
+    };
+
+    match result {
+        // fail with an exit code telling what went wrong, as probe does
+        Err(err) if !interactive() => {
+            let code = err
+                .downcast_ref::<Error>()
+                .map_or(1, |err| err.probe_failure().exit_code());
+            eprintln!("{:?}", err);
+            exit(code)
+        }
+        result => result,
+    }
+}
+
+fn lossy_args(args: &[OsString]) -> Vec<String> {
+    args.iter()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Run an esptool command line, e.g. `espflash write_flash 0x1000 boot.bin`
+fn esptool(args: &[String]) -> Result<()> {
+    let config = Config::load();
+    let args = EsptoolArgs::parse(args)?;
+
+    // image_info doesn't talk to the device, all chips but the ESP8266 share
+    // the same image format
+    if let EsptoolCommand::ImageInfo { path } = &args.command {
+        let data = read(path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to open image \"{}\"", path.display()))?;
+        print!(
+            "{}",
+            ImageInfo::parse(&data, args.chip.unwrap_or(Chip::Esp32))?
+        );
+        return Ok(());
+    }
+
+    let open = open_options(&mut Arguments::from_vec(Vec::new()), &config)?;
+    let serial = serial_or_default(args.port.clone(), &config)?;
+    let mut flasher = FlasherBuilder::new()
+        .transport(open_serial(&serial, &open)?)
+        .usb_jtag_serial(espflash::port::is_usb_jtag_serial(&serial))
+        .reopen(serial.clone())
+        .chip(args.chip)
+        .speed(
+            args.baud
+                .or(config.connection.baud)
+                .map(BaudRate::from_speed),
+        )
+        .before(args.before)
+        .after(args.after)
+        .connect()?;
+
+    match args.command {
+        EsptoolCommand::WriteFlash { settings, files } => {
+            write_bins(&mut flasher, &files, &settings)?;
+            flasher.reset_after()?;
+        }
+        EsptoolCommand::ReadFlash { addr, size, output } => {
+            let data = flasher.read_flash(addr, size)?;
+            write(&output, data)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to write \"{}\"", output.display()))?;
+            println!("Read {} bytes at {:#x} to {}", size, addr, output.display());
+        }
+        EsptoolCommand::ReadMac => {
+            let mac: Vec<String> = flasher
+                .mac_address()?
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            println!("MAC: {}", mac.join(":"));
+        }
+        EsptoolCommand::ChipId => print!("{}", flasher.board_info()?),
+        EsptoolCommand::FlashId => {
+            println!("Flash: {}", flasher.flash_id()?);
+            println!("Detected flash size: {}", flasher.flash_size());
+        }
+        EsptoolCommand::Run => {
+            flasher.set_reset_after(ResetAfterOperation::SoftReset);
+            flasher.reset_after()?;
+        }
+        EsptoolCommand::ImageInfo { .. } => unreachable!(),
+    }
+
+    Ok(())
+}
+
+/// Write binaries to flash at their offsets, setting the flash mode, frequency
+/// and size in the header of the bootloader as esptool does
+fn write_bins(
+    flasher: &mut Flasher,
+    files: &[(u32, PathBuf)],
+    settings: &FlashSettings,
+) -> Result<()> {
+    let bootloader_offset = flasher
+        .chip()
+        .default_offsets()
+        .map_or(0, |offsets| offsets.bootloader);
+
+    for (addr, path) in files {
+        let mut data = read(path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to open \"{}\"", path.display()))?;
+        if *addr == bootloader_offset {
+            flasher.chip().update_image_header(&mut data, settings)?;
+        }
+        flasher.write_bin_to_flash(*addr, &data)?;
+        println!(
+            "Wrote {} bytes at {:#x} from {}",
+            data.len(),
+            addr,
+            path.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Remove the `--non-interactive` flag from `args`, returning whether it was
+/// given
+fn take_non_interactive(args: &mut Vec<OsString>) -> bool {
+    let len = args.len();
+    args.retain(|arg| arg != "--non-interactive");
+    args.len() != len
+}
+
+/// Whether the user can be prompted
+fn interactive() -> bool {
+    !NON_INTERACTIVE.load(Ordering::Relaxed)
+}
+
+/// Remove the `-v`, `-vv` and `--verbose` flags from `args`, returning how
+/// verbose to be
+fn take_verbosity(args: &mut Vec<OsString>) -> u64 {
+    let mut verbosity = 0;
+    args.retain(|arg| match arg.to_str() {
+        Some("-v") | Some("--verbose") => {
+            verbosity += 1;
+            false
+        }
+        Some("-vv") => {
+            verbosity += 2;
+            false
+        }
+        _ => true,
+    });
+
+    verbosity
+}
+
+/// Whether `name` runs an external subcommand, which gets all of its arguments
+fn is_plugin(name: &str) -> bool {
+    !SUBCOMMANDS.contains(&name) && plugin::find(name).is_some()
+}
+
+/// Built-in subcommands, listed by `--list`
+const SUBCOMMANDS: &[&str] = &[
+    "audit",
+    "batch",
+    "board-info",
+    "coredump",
+    "dump-mem",
+    "efuse",
+    "fat",
+    "flash-idf",
+    "flash-status",
+    "gdb-server",
+    "image-info",
+    "journal-analyze",
+    "list-ports",
+    "littlefs",
+    "merge-bin",
+    "monitor",
+    "nvs-dump",
+    "nvs-gen",
+    "ota",
+    "partition-table",
+    "probe",
+    "provision-flash-encryption",
+    "read-mac",
+    "read-reg",
+    "repl",
+    "reset",
+    "save-image",
+    "secure-boot-digest",
+    "serve-ota",
+    "set-boot-partition",
+    "spiffs",
+    "write-custom-mac",
+    "write-reg",
+];
+
+#[allow(clippy::unnecessary_wraps)]
+fn list_subcommands() -> Result<()> {
+    println!("Installed commands:");
+    for name in SUBCOMMANDS {
+        println!("    {}", name);
+    }
+    for name in plugin::list() {
+        println!("    {} (external)", name);
+    }
+
+    Ok(())
+}
+
+/// Run an external subcommand, exiting with its exit code
+fn run_plugin(path: &Path, args: Vec<OsString>) -> Result<()> {
+    let mut command = std::process::Command::new(path);
+    command.args(args);
+    if let Ok(exe) = std::env::current_exe() {
+        command.env(plugin::ESPFLASH_ENV, exe);
+    }
+
+    let status = command
+        .status()
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to run \"{}\"", path.display()))?;
+    exit(status.code().unwrap_or(1));
+}
+
+fn flash(mut args: Arguments) -> Result<()> {
+    let notifier = notifier(&mut args)?;
+    let json = json_format(&mut args)?;
+    let result = flash_elf(args, json);
+
+    if let (true, Err(err)) = (json, &result) {
+        println!(
+            "{}",
+            serde_json::json!({ "event": "failed", "error": err.to_string() })
+        );
+    }
+
+    if let Some(notifier) = notifier {
+        let sent = match &result {
+            Ok(()) => notifier.success("Flashing completed"),
+            Err(err) => notifier.failure(&err.to_string()),
+        };
+        if let Err(err) = sent {
+            eprintln!("Failed to send notification: {}", err);
+        }
+    }
+
+    result
+}
+
+fn flash_elf(mut args: Arguments, json: bool) -> Result<()> {
     let config = Config::load();
 
     if args.contains(["-h", "--help"]) {
@@ -20,44 +427,117 @@ fn main() -> Result<()> {
     }
 
     let ram = args.contains("--ram");
+    let entry = args
+        .opt_value_from_fn("--entry", parse_u32)
+        .into_diagnostic()?;
+    let no_run = args.contains("--no-run");
     let board_info = args.contains("--board-info");
-    let bootloader_path = args
-        .opt_value_from_str::<_, String>("--bootloader")
+    let safe_mode = args.contains("--safe-mode");
+    let pipeline = args.contains("--pipeline") && !safe_mode;
+    let force = args.contains("--force");
+    let force_unprotect = args.contains("--force-unprotect");
+    let app_desc = app_descriptor_overrides(&mut args)?;
+    let segment_layout = segment_layout(&mut args)?;
+    let flash_timing = flash_timing(&mut args, &config)?;
+    let speed = args
+        .opt_value_from_str::<_, usize>("--speed")
+        .into_diagnostic()?
+        .or(config.connection.baud)
+        // safe mode keeps the baud rate the ROM loader starts with
+        .filter(|_| !safe_mode)
+        .map(BaudRate::from_speed);
+    let (bootloader_path, partition_table_path, flash_settings) =
+        read_flash_settings(&mut args, &config)?;
+    let ledger_path = args
+        .opt_value_from_str::<_, String>("--ledger")
         .into_diagnostic()?;
-    let partition_table_path = args
-        .opt_value_from_str::<_, String>("--partition-table")
+    let ledger_key = args
+        .opt_value_from_str::<_, String>("--ledger-key")
+        .into_diagnostic()?
+        .map(|path| read_ledger_key(&path))
+        .transpose()?;
+    match (&ledger_path, &ledger_key) {
+        (Some(_), None) => return Err(miette::miette!("`--ledger` requires a `--ledger-key`")),
+        (None, Some(_)) => return Err(miette::miette!("`--ledger-key` requires a `--ledger`")),
+        _ => {}
+    }
+    let manifest_path = args
+        .opt_value_from_str::<_, String>("--manifest")
         .into_diagnostic()?;
+    let encrypt_key = read_encrypt_key(&mut args)?;
+    let checksums = Checksums::from_args(&mut args)?;
+    let before: ResetBeforeOperation = args
+        .opt_value_from_str("--before")
+        .into_diagnostic()?
+        .unwrap_or_default();
+    let reset_sequence = args
+        .opt_value_from_str::<_, ResetSequence>("--reset-sequence")
+        .into_diagnostic()?
+        .or_else(|| config.connection.reset_sequence.clone());
+    let after: ResetAfterOperation = args
+        .opt_value_from_str("--after")
+        .into_diagnostic()?
+        .unwrap_or_default();
+    let monitor_options = match args.contains("--monitor") {
+        true => Some(monitor_options(&mut args, "--monitor-baud")?),
+        false => None,
+    };
+    let journal = open_journal(&mut args)?;
+    let open = open_options(&mut args, &config)?;
 
     let mut serial: Option<String> = args.opt_free_from_str().into_diagnostic()?;
     let mut elf: Option<String> = args.opt_free_from_str().into_diagnostic()?;
 
-    if elf.is_none() && config.connection.serial.is_some() {
+    if elf.is_none() && !board_info {
         elf = serial.take();
-        serial = config.connection.serial;
     }
+    if elf.is_none() && !board_info {
+        return help();
+    }
+    let serial = serial_or_default(serial, &config)?;
 
-    let serial: String = match serial {
-        Some(serial) => serial,
-        _ => return help(),
-    };
-
-    let mut serial = serial::open(&serial)
-        .map_err(Error::from)
-        .wrap_err_with(|| format!("Failed to open serial port {}", serial))?;
-    serial
-        .reconfigure(&|settings| {
-            settings.set_flow_control(FlowControl::FlowNone);
-            settings.set_baud_rate(BaudRate::Baud115200)?;
-
-            Ok(())
-        })
-        .into_diagnostic()?;
+    // check the inputs before touching the device
+    if !board_info {
+        if let Some(elf) = &elf {
+            checksums.verify("elf image", elf, &checksums.elf)?;
+        }
+        if !ram {
+            if let Some(path) = &bootloader_path {
+                checksums.verify("bootloader", path, &checksums.bootloader)?;
+            }
+            if let Some(path) = &partition_table_path {
+                checksums.verify("partition table", path, &checksums.partition_table)?;
+            }
+        }
+    }
 
-    let mut flasher = Flasher::connect(serial, None)?;
+    let mut flasher = FlasherBuilder::new()
+        .transport(open_serial(&serial, &open)?)
+        .usb_jtag_serial(espflash::port::is_usb_jtag_serial(&serial))
+        .reopen(serial.clone())
+        .speed(speed)
+        .journal(journal)
+        .before(before)
+        .reset_sequence(reset_sequence)
+        .after(after)
+        .connect()?;
+    flasher.set_safe_mode(safe_mode);
+    flasher.set_pipelined(pipeline);
+    flasher.set_app_descriptor_overrides(app_desc);
+    flasher.set_flash_settings(flash_settings);
+    flasher.set_segment_layout(segment_layout);
+    flasher.set_flash_timing(flash_timing)?;
+    if json {
+        flasher.set_progress_callbacks(Box::new(JsonProgress::default()));
+    }
 
     if board_info {
-        println!("Chip type: {}", flasher.chip());
-        println!("Flash size: {}", flasher.flash_size());
+        let info = flasher.board_info()?;
+        if json {
+            println!("{}", serde_json::to_string(&info).into_diagnostic()?);
+        } else {
+            print!("{}", info);
+        }
 
         return Ok(());
     }
@@ -71,35 +551,2401 @@ fn main() -> Result<()> {
         .wrap_err_with(|| format!("Failed to open elf image \"{}\"", input))?;
 
     if ram {
-        flasher.load_elf_to_ram(&input_bytes)?;
+        flasher.load_elf_to_ram_with(&input_bytes, entry, !no_run)?;
+        if json {
+            println!("{}", serde_json::json!({ "event": "finished" }));
+        }
     } else {
-        let bootloader = bootloader_path
-            .as_deref()
-            .map(read)
-            .transpose()
-            .into_diagnostic()
-            .wrap_err_with(|| {
-                format!(
-                    "Failed to open bootloader image \"{}\"",
-                    bootloader_path.unwrap()
-                )
-            })?;
-        let partition_table = partition_table_path
-            .as_deref()
-            .map(|path| {
-                let table = read_to_string(path)?;
-                PartitionTable::try_from_str(&table).map_err(Error::from)
+        let bootloader = read_bootloader(bootloader_path)?;
+        let partition_table = read_partition_table(partition_table_path)?;
+
+        set_encrypt_key(&mut flasher, encrypt_key)?;
+        if !force {
+            flasher.check_downgrade(&input_bytes, partition_table.as_ref(), interactive())?;
+        }
+        if force_unprotect && flasher.unprotect_flash()? {
+            println!("Cleared the write protection of the flash");
+        }
+
+        // The device identity has to be collected before flashing, as the device
+        // is reset afterwards
+        let record = match ledger_path {
+            Some(_) => Some(AttestationRecord::collect(&mut flasher)?),
+            None => None,
+        };
+        let mac = match manifest_path {
+            Some(_) => Some(flasher.mac_address()?),
+            None => None,
+        };
+
+        let report = flasher.load_elf_to_flash(&input_bytes, bootloader, partition_table)?;
+        if json {
+            println!(
+                "{}",
+                serde_json::json!({ "event": "finished", "report": report })
+            );
+        } else {
+            print!("{}", report);
+        }
+
+        if let (Some(path), Some(mac)) = (manifest_path, mac) {
+            FlashManifest::new(flasher.chip(), mac, &report)
+                .save(&path)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to write manifest \"{}\"", path))?;
+        }
+
+        if let (Some(path), Some(mut record), Some(key)) = (ledger_path, record, ledger_key) {
+            record.set_firmware(&report);
+            record.sign(&key);
+            record
+                .append_to(&path)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to write to ledger \"{}\"", path))?;
+        }
+    }
+
+    if let Some(mut options) = monitor_options {
+        options.reopen = reopenable(&serial);
+        monitor::monitor(flasher.into_serial(), &options, Some(&input_bytes)).into_diagnostic()?;
+    }
+
+    Ok(())
+}
+
+/// Flash the binaries listed in the `flasher_args.json` of an ESP-IDF build
+/// directory
+fn flash_idf(mut args: Arguments) -> Result<()> {
+    let config = Config::load();
+
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let speed = args
+        .opt_value_from_str::<_, usize>("--speed")
+        .into_diagnostic()?
+        .or(config.connection.baud)
+        .map(BaudRate::from_speed);
+    let before = args
+        .opt_value_from_str::<_, ResetBeforeOperation>("--before")
+        .into_diagnostic()?;
+    let after = args
+        .opt_value_from_str::<_, ResetAfterOperation>("--after")
+        .into_diagnostic()?;
+    let encrypt_key = read_encrypt_key(&mut args)?;
+    let open = open_options(&mut args, &config)?;
+
+    let mut serial: Option<String> = args.opt_free_from_str().into_diagnostic()?;
+    let mut build_dir: Option<PathBuf> = args.opt_free_from_str().into_diagnostic()?;
+
+    if build_dir.is_none() {
+        build_dir = serial.take().map(PathBuf::from);
+    }
+    let build_dir = match build_dir {
+        Some(build_dir) => build_dir,
+        None => return help(),
+    };
+
+    let path = build_dir.join(FLASHER_ARGS_FILE);
+    let data = read_to_string(&path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open \"{}\"", path.display()))?;
+    let flasher_args = FlasherArgs::parse(&data).map_err(Error::from)?;
+    let settings = flasher_args.flash_settings().map_err(Error::from)?;
+    let files = flasher_args.files(&build_dir).map_err(Error::from)?;
+    let esptool_args = &flasher_args.extra_esptool_args;
+    let before = match before {
+        Some(before) => before,
+        None => esptool_args.before.parse().into_diagnostic()?,
+    };
+    let after = match after {
+        Some(after) => after,
+        None => esptool_args.after.parse().into_diagnostic()?,
+    };
+
+    let serial = serial_or_default(serial, &config)?;
+    let mut flasher = FlasherBuilder::new()
+        .transport(open_serial(&serial, &open)?)
+        .usb_jtag_serial(espflash::port::is_usb_jtag_serial(&serial))
+        .reopen(serial.clone())
+        .chip(flasher_args.chip().map_err(Error::from)?)
+        .speed(speed)
+        .before(before)
+        .after(after)
+        .connect()?;
+    set_encrypt_key(&mut flasher, encrypt_key)?;
+
+    write_bins(&mut flasher, &files, &settings)?;
+    flasher.reset_after()?;
+
+    Ok(())
+}
+
+fn repl(mut args: Arguments) -> Result<()> {
+    let config = Config::load();
+
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let journal = open_journal(&mut args)?;
+    let open = open_options(&mut args, &config)?;
+    let serial = serial_or_default(args.opt_free_from_str().into_diagnostic()?, &config)?;
+
+    let flasher = FlasherBuilder::new()
+        .transport(open_serial(&serial, &open)?)
+        .usb_jtag_serial(espflash::port::is_usb_jtag_serial(&serial))
+        .journal(journal)
+        .connect()?;
+    repl::repl(flasher)
+}
+
+fn audit(mut args: Arguments) -> Result<()> {
+    let config = Config::load();
+
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let manifest_path: String = match args.opt_value_from_str("--manifest").into_diagnostic()? {
+        Some(path) => path,
+        None => return help(),
+    };
+    let manifest = FlashManifest::load(&manifest_path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to read manifest \"{}\"", manifest_path))?;
+    let flash_timing = flash_timing(&mut args, &config)?;
+    let open = open_options(&mut args, &config)?;
+    let serial = serial_or_default(args.opt_free_from_str().into_diagnostic()?, &config)?;
+
+    let mut flasher = Flasher::connect(open_serial(&serial, &open)?, None)?;
+    flasher.set_flash_timing(flash_timing)?;
+    let report = manifest.audit(&mut flasher)?;
+    print!("{}", report);
+
+    if !report.is_ok() {
+        exit(1);
+    }
+
+    Ok(())
+}
+
+fn batch(mut args: Arguments) -> Result<()> {
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let parallel = args.contains("--parallel");
+    let path: String = args.free_from_str().into_diagnostic()?;
+    let batch = BatchFile::load(&path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to load job file \"{}\"", path))?;
+    let exe = std::env::current_exe().into_diagnostic()?;
+
+    let results = if parallel || batch.parallel {
+        // collect the output of each job, so it isn't interleaved
+        let handles: Vec<_> = batch
+            .jobs
+            .into_iter()
+            .map(|job| {
+                let exe = exe.clone();
+                std::thread::spawn(move || run_job(&exe, &job, true))
             })
-            .transpose()
-            .into_diagnostic()
-            .wrap_err_with(|| {
-                format!(
-                    "Failed to load partition table \"{}\"",
-                    partition_table_path.unwrap()
-                )
-            })?;
-        flasher.load_elf_to_flash(&input_bytes, bootloader, partition_table)?;
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("job thread panicked"))
+            .collect()
+    } else {
+        batch
+            .jobs
+            .iter()
+            .map(|job| run_job(&exe, job, false))
+            .collect()
+    };
+
+    let summary = BatchSummary { results };
+    println!();
+    print!("{}", summary);
+
+    if !summary.is_ok() {
+        exit(1);
+    }
+
+    Ok(())
+}
+
+/// Flash a single job of a batch with a separate process
+fn run_job(exe: &Path, job: &Job, capture_output: bool) -> JobResult {
+    let name = job.name();
+    let started = Instant::now();
+
+    let result = job.port().and_then(|port| {
+        let mut command = std::process::Command::new(exe);
+        command.args(job.args(port));
+
+        if capture_output {
+            let output = command.output()?;
+            print!("==> {}\n{}", name, String::from_utf8_lossy(&output.stdout));
+            eprint!("{}", String::from_utf8_lossy(&output.stderr));
+            Ok(output.status)
+        } else {
+            println!("==> {}", name);
+            command.status()
+        }
+    });
+
+    let error = match result {
+        Ok(status) if status.success() => None,
+        Ok(status) => Some(status.to_string()),
+        Err(err) => Some(err.to_string()),
+    };
+
+    JobResult {
+        name,
+        error,
+        duration: started.elapsed(),
+    }
+}
+
+fn board_info(mut args: Arguments) -> Result<()> {
+    let config = Config::load();
+
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let json = json_format(&mut args)?;
+    let open = open_options(&mut args, &config)?;
+    let serial = serial_or_default(args.opt_free_from_str().into_diagnostic()?, &config)?;
+
+    let mut flasher = Flasher::connect(open_serial(&serial, &open)?, None)?;
+    let info = flasher.board_info()?;
+
+    if json {
+        println!("{}", serde_json::to_string(&info).into_diagnostic()?);
+    } else {
+        print!("{}", info);
+    }
+
+    Ok(())
+}
+
+fn list_ports(mut args: Arguments) -> Result<()> {
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let json = json_format(&mut args)?;
+    let ports = espflash::port::list()?;
+
+    if json {
+        let ports: Vec<_> = ports
+            .iter()
+            .map(|port| {
+                let usb = port.usb.as_ref().map(|usb| {
+                    serde_json::json!({
+                        "vid": format!("{:04x}", usb.vid),
+                        "pid": format!("{:04x}", usb.pid),
+                        "manufacturer": usb.manufacturer,
+                        "product": usb.product,
+                        "serial_number": usb.serial_number,
+                        "known_bridge": usb.known_bridge(),
+                    })
+                });
+                serde_json::json!({ "port": port.name, "usb": usb })
+            })
+            .collect();
+        println!("{}", serde_json::Value::from(ports));
+        return Ok(());
+    }
+
+    let rows: Vec<[String; 6]> = ports
+        .iter()
+        .map(|port| match &port.usb {
+            Some(usb) => [
+                port.name.clone(),
+                format!("{:04x}:{:04x}", usb.vid, usb.pid),
+                usb.manufacturer.clone().unwrap_or_default(),
+                usb.product.clone().unwrap_or_default(),
+                usb.serial_number.clone().unwrap_or_default(),
+                usb.known_bridge()
+                    .map(|bridge| format!("* {}", bridge))
+                    .unwrap_or_default(),
+            ],
+            None => [
+                port.name.clone(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+            ],
+        })
+        .collect();
+
+    if rows.is_empty() {
+        println!("No serial ports found");
+        return Ok(());
+    }
+
+    let mut widths = [0; 6];
+    for row in &rows {
+        for (width, column) in widths.iter_mut().zip(row) {
+            *width = (*width).max(column.len());
+        }
+    }
+    for row in &rows {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(column, width)| format!("{:width$}", column, width = width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+
+    Ok(())
+}
+
+fn reset(mut args: Arguments) -> Result<()> {
+    let config = Config::load();
+
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let usb_jtag = args.contains("--usb-jtag");
+    let open = open_options(&mut args, &config)?;
+    let serial = serial_or_default(args.opt_free_from_str().into_diagnostic()?, &config)?;
+
+    let usb_jtag = usb_jtag || espflash::port::is_usb_jtag_serial(&serial);
+    let mut port = open_serial(&serial, &open)?;
+    espflash::port::hard_reset(port.as_mut(), usb_jtag)?;
+    println!("Reset the device on {}", serial);
+
+    Ok(())
+}
+
+fn monitor(mut args: Arguments) -> Result<()> {
+    let config = Config::load();
+
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let mut options = monitor_options(&mut args, "--baud")?;
+    options.reset = !args.contains("--no-reset");
+    let elf = args
+        .opt_value_from_str::<_, String>("--elf")
+        .into_diagnostic()?
+        .map(|path| {
+            read(&path)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to open elf image \"{}\"", path))
+        })
+        .transpose()?;
+    if options.log_format == monitor::LogFormat::Defmt && elf.is_none() {
+        return Err(miette::miette!("`--log-format defmt` requires an `--elf`"));
+    }
+    let open = open_options(&mut args, &config)?;
+    let serial = serial_or_default(args.opt_free_from_str().into_diagnostic()?, &config)?;
+    options.reopen = reopenable(&serial);
+
+    monitor::monitor(open_serial(&serial, &open)?, &options, elf.as_deref()).into_diagnostic()?;
+
+    Ok(())
+}
+
+fn probe(mut args: Arguments) -> Result<()> {
+    let config = Config::load();
+
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let json = args.contains("--json");
+    let open = open_options(&mut args, &config)?;
+    let serial = serial_or_default(args.opt_free_from_str().into_diagnostic()?, &config)?;
+
+    let started = Instant::now();
+    let result = espflash::port::open(&serial, open.retry_window).and_then(Flasher::probe);
+    let elapsed_ms = started.elapsed().as_millis();
+
+    let err = match result {
+        Ok(chip) => {
+            if json {
+                let status = serde_json::json!({
+                    "status": "ok",
+                    "chip": chip.to_string(),
+                    "elapsed_ms": elapsed_ms,
+                });
+                println!("{}", status);
+            } else {
+                println!("{} ({} ms)", chip, elapsed_ms);
+            }
+            return Ok(());
+        }
+        Err(err) => err,
+    };
+
+    let failure = err.probe_failure();
+    let mut message = err.to_string();
+    let mut source = std::error::Error::source(&err);
+    while let Some(err) = source {
+        message = format!("{}: {}", message, err);
+        source = err.source();
+    }
+
+    if json {
+        let status = serde_json::json!({
+            "status": failure.to_string(),
+            "error": message,
+            "elapsed_ms": elapsed_ms,
+        });
+        println!("{}", status);
+    } else {
+        println!("{}: {} ({} ms)", failure, message, elapsed_ms);
+    }
+
+    exit(failure.exit_code())
+}
+
+fn image_info(mut args: Arguments) -> Result<()> {
+    if args.contains(["-h", "--help"]) {
+        return help();
     }
 
+    // All chips but the ESP8266 share the same image format
+    let chip: Chip = args
+        .opt_value_from_str("--chip")
+        .into_diagnostic()?
+        .unwrap_or(Chip::Esp32);
+    let input: String = args.free_from_str().into_diagnostic()?;
+
+    let data = read(&input)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open image \"{}\"", input))?;
+    let info = ImageInfo::parse(&data, chip)?;
+
+    print!("{}", info);
     Ok(())
 }
+
+fn journal_analyze(mut args: Arguments) -> Result<()> {
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let path: String = args.free_from_str().into_diagnostic()?;
+    let file = File::open(&path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open journal \"{}\"", path))?;
+    let summary = JournalSummary::analyze(BufReader::new(file))
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to parse journal \"{}\"", path))?;
+
+    print!("{}", summary);
+    Ok(())
+}
+
+fn partition_table(mut args: Arguments) -> Result<()> {
+    let config = Config::load();
+
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let to_binary = args.contains("--to-binary");
+    let to_csv = args.contains("--to-csv");
+    let json = json_format(&mut args)?;
+    let visualize = args.contains("--visualize");
+    let svg = args.contains("--svg");
+    let size: Option<FlashSize> = args.opt_value_from_str("--size").into_diagnostic()?;
+    let chip: Option<Chip> = args.opt_value_from_str("--chip").into_diagnostic()?;
+    let elf = args
+        .opt_value_from_str::<_, String>("--elf")
+        .into_diagnostic()?;
+    let output = args
+        .opt_value_from_str::<_, String>(["-o", "--output"])
+        .into_diagnostic()?;
+    let from_device = args
+        .opt_value_from_str::<_, String>("--from-device")
+        .into_diagnostic()?;
+
+    let is_device = from_device.is_some();
+
+    let (table, is_binary, size) = match from_device {
+        Some(serial) => {
+            let offset = args
+                .opt_value_from_fn("--partition-table-offset", parse_u32)
+                .into_diagnostic()?;
+            let open = open_options(&mut args, &config)?;
+            let mut flasher = Flasher::connect(open_serial(&serial, &open)?, None)?;
+            let table = read_device_partition_table(&mut flasher, offset)?;
+            (table, false, size.or(Some(flasher.flash_size())))
+        }
+        None => {
+            let input: String = args.free_from_str().into_diagnostic()?;
+            let data = read(&input)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to open partition table \"{}\"", input))?;
+            let is_binary = data.starts_with(&[0xAA, 0x50]);
+            (PartitionTable::try_from_data(data)?, is_binary, size)
+        }
+    };
+
+    let data = if visualize {
+        let app_size = match (chip, elf) {
+            (Some(chip), Some(elf)) => {
+                let elf_data = read(&elf)
+                    .into_diagnostic()
+                    .wrap_err_with(|| format!("Failed to open elf image \"{}\"", elf))?;
+                Some(app_image(chip, &elf_data)?.len() as u32)
+            }
+            (None, Some(_)) => return Err(miette::miette!("`--elf` requires a `--chip`")),
+            _ => None,
+        };
+        let flash_size = size.map(FlashSize::size).unwrap_or_else(|| table.end());
+
+        if svg {
+            table.visualize_svg(flash_size, app_size).into_bytes()
+        } else {
+            table.visualize(flash_size, app_size).into_bytes()
+        }
+    } else if json {
+        format!("{}\n", table.to_json()).into_bytes()
+    } else if is_device && !(to_binary || to_csv) {
+        table.to_table().into_bytes()
+    } else if to_binary || !(to_csv || is_binary) {
+        // Without an explicit target format, convert to the other format
+        let mut data = Vec::new();
+        table.save(&mut data).into_diagnostic()?;
+        data
+    } else {
+        table.to_csv().into_bytes()
+    };
+
+    match output {
+        Some(output) => write(&output, data)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to write partition table \"{}\"", output))?,
+        None => stdout().write_all(&data).into_diagnostic()?,
+    }
+
+    Ok(())
+}
+
+/// Read the partition table from the flash of a device
+fn read_device_partition_table(
+    flasher: &mut Flasher,
+    offset: Option<u32>,
+) -> Result<PartitionTable> {
+    let offset = match (offset, flasher.chip().default_offsets()) {
+        (Some(offset), _) => offset,
+        (None, Some(defaults)) => defaults.partition_table,
+        (None, None) => {
+            return Err(miette::miette!(
+                "The {} doesn't use a partition table",
+                flasher.chip()
+            ))
+        }
+    };
+
+    let data = flasher.read_flash(offset, PARTITION_TABLE_SIZE)?;
+    if data.iter().all(|byte| *byte == 0xff) {
+        return Err(miette::miette!("No partition table found at {:#x}", offset));
+    }
+    let table = PartitionTable::try_from_bytes(&data)
+        .map_err(Error::from)
+        .wrap_err_with(|| format!("Failed to parse the partition table at {:#x}", offset))?;
+
+    Ok(table)
+}
+
+fn set_boot_partition(mut args: Arguments) -> Result<()> {
+    let config = Config::load();
+
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let partition_table_path = args
+        .opt_value_from_str::<_, String>("--partition-table")
+        .into_diagnostic()?;
+    let offset = args
+        .opt_value_from_fn("--partition-table-offset", parse_u32)
+        .into_diagnostic()?;
+    let open = open_options(&mut args, &config)?;
+
+    let mut serial: Option<String> = args.opt_free_from_str().into_diagnostic()?;
+    let mut partition: Option<String> = args.opt_free_from_str().into_diagnostic()?;
+
+    if partition.is_none() {
+        partition = serial.take();
+    }
+    let partition = match partition {
+        Some(partition) => partition,
+        None => return help(),
+    };
+    let serial = serial_or_default(serial, &config)?;
+
+    let mut flasher = Flasher::connect(open_serial(&serial, &open)?, None)?;
+    let table = match read_partition_table(partition_table_path)? {
+        Some(table) => table,
+        None => read_device_partition_table(&mut flasher, offset)?,
+    };
+
+    ota::set_boot_partition(&mut flasher, &table, &partition)?;
+    println!("The {} partition will be booted next", partition);
+
+    Ok(())
+}
+
+fn gdb_server(mut args: Arguments) -> Result<()> {
+    let config = Config::load();
+
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let listen = args
+        .opt_value_from_str::<_, String>("--listen")
+        .into_diagnostic()?
+        .unwrap_or_else(|| "127.0.0.1:3333".to_string());
+    let baud = args
+        .opt_value_from_str::<_, usize>("--baud")
+        .into_diagnostic()?;
+    let open = open_options(&mut args, &config)?;
+    let serial = serial_or_default(args.opt_free_from_str().into_diagnostic()?, &config)?;
+
+    // ports are opened at 115200 baud
+    let mut port = open_serial(&serial, &open)?;
+    if let Some(baud) = baud {
+        port.set_baud(baud).map_err(Error::from)?;
+    }
+    let listener = std::net::TcpListener::bind(&listen)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to listen on {}", listen))?;
+    eprintln!(
+        "Waiting for GDB on {}, attach with `target remote {}`",
+        listen, listen
+    );
+
+    gdb::serve(port.as_mut(), &listener, &mut stdout()).into_diagnostic()?;
+    eprintln!("GDB disconnected");
+
+    Ok(())
+}
+
+fn read_coredump(mut args: Arguments) -> Result<()> {
+    let config = Config::load();
+
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let partition_table_path = args
+        .opt_value_from_str::<_, String>("--partition-table")
+        .into_diagnostic()?;
+    let offset = args
+        .opt_value_from_fn("--partition-table-offset", parse_u32)
+        .into_diagnostic()?;
+    let output: Option<PathBuf> = args
+        .opt_value_from_str(["-o", "--output"])
+        .into_diagnostic()?;
+    let open = open_options(&mut args, &config)?;
+    let serial = serial_or_default(args.opt_free_from_str().into_diagnostic()?, &config)?;
+
+    let mut flasher = Flasher::connect(open_serial(&serial, &open)?, None)?;
+    let table = match read_partition_table(partition_table_path)? {
+        Some(table) => table,
+        None => read_device_partition_table(&mut flasher, offset)?,
+    };
+
+    let dump = coredump::read_from_flash(&mut flasher, &table)?;
+    let output = output.unwrap_or_else(|| format!("coredump.{}", dump.extension()).into());
+    dump.save(&output)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write core dump \"{}\"", output.display()))?;
+
+    if dump.is_elf() {
+        println!("Core dump saved to {}", output.display());
+    } else {
+        println!(
+            "Core dump saved to {}, decode it with `espcoredump.py info_corefile --core-format raw`",
+            output.display()
+        );
+    }
+
+    Ok(())
+}
+
+fn efuse(mut args: Vec<OsString>) -> Result<()> {
+    match args.first().and_then(|arg| arg.to_str()) {
+        Some("summary") => efuse_summary(Arguments::from_vec(args.split_off(1))),
+        Some("burn") => efuse_burn(Arguments::from_vec(args.split_off(1))),
+        _ => help(),
+    }
+}
+
+/// Print the named efuse fields of a device
+fn efuse_summary(mut args: Arguments) -> Result<()> {
+    let config = Config::load();
+
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let json = json_format(&mut args)?;
+    let (chip, blocks, _) = efuse_target(&mut args, &config)?;
+
+    if json {
+        let fields = chip
+            .efuse_fields()
+            .iter()
+            .map(|field| {
+                let value = match blocks.value(field) {
+                    EfuseValue::Bool(value) => serde_json::json!(value),
+                    EfuseValue::Uint(value) => serde_json::json!(value),
+                    value => serde_json::json!(value.to_string()),
+                };
+                serde_json::json!({
+                    "name": field.name,
+                    "block": chip.efuse_blocks()[field.block].name,
+                    "category": field.category.to_string(),
+                    "description": field.description,
+                    "value": value,
+                })
+            })
+            .collect::<Vec<_>>();
+        println!("{}", serde_json::Value::from(fields));
+        return Ok(());
+    }
+
+    println!("Efuses of the {}:", chip);
+    for category in efuse::Category::ALL {
+        println!();
+        println!("{}", category);
+        for field in chip
+            .efuse_fields()
+            .iter()
+            .filter(|field| field.category == category)
+        {
+            println!(
+                "  {:<34} {:<50} = {}",
+                field.name,
+                field.description,
+                blocks.value(field)
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Where efuses are read from and burned to
+enum EfuseTarget {
+    Device(Box<Flasher>),
+    /// Efuses simulated in a file by `--virtual`, for dry runs
+    Virtual(PathBuf),
+}
+
+/// Read the efuses of the device or of the file given by `--virtual`, which
+/// starts out blank for the chip given by `--chip` if it doesn't exist yet
+fn efuse_target(args: &mut Arguments, config: &Config) -> Result<(Chip, EfuseBlocks, EfuseTarget)> {
+    let virtual_file: Option<PathBuf> = args.opt_value_from_str("--virtual").into_diagnostic()?;
+    let chip: Option<Chip> = args.opt_value_from_str("--chip").into_diagnostic()?;
+
+    let (chip, blocks, target) = match virtual_file {
+        Some(path) if path.exists() => {
+            let data = read_to_string(&path)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to read \"{}\"", path.display()))?;
+            let file: serde_json::Value = serde_json::from_str(&data)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to parse \"{}\"", path.display()))?;
+            let chip: Chip = file["chip"]
+                .as_str()
+                .unwrap_or_default()
+                .parse()
+                .map_err(|_| miette::miette!("Unknown chip in \"{}\"", path.display()))?;
+            let blocks = serde_json::from_value(file["blocks"].clone())
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to parse \"{}\"", path.display()))?;
+            (chip, blocks, EfuseTarget::Virtual(path))
+        }
+        Some(path) => {
+            let chip = chip.ok_or_else(|| {
+                miette::miette!(
+                    "\"{}\" doesn't exist, use `--chip` to start from blank efuses",
+                    path.display()
+                )
+            })?;
+            let blocks = EfuseBlocks::blank(chip.efuse_blocks());
+            (chip, blocks, EfuseTarget::Virtual(path))
+        }
+        None => {
+            let open = open_options(args, config)?;
+            let serial = serial_or_default(args.opt_free_from_str().into_diagnostic()?, config)?;
+            let mut flasher = Flasher::connect(open_serial(&serial, &open)?, None)?;
+            let blocks = flasher.read_efuse_blocks()?;
+            (
+                flasher.chip(),
+                blocks,
+                EfuseTarget::Device(Box::new(flasher)),
+            )
+        }
+    };
+
+    if chip.efuse_fields().is_empty() {
+        return Err(miette::miette!(
+            "The efuse fields of the {} aren't known",
+            chip
+        ));
+    }
+
+    Ok((chip, blocks, target))
+}
+
+/// Burn efuse fields of a device or of a virtual efuse file
+fn efuse_burn(mut args: Arguments) -> Result<()> {
+    let config = Config::load();
+
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let (chip, current, target) = efuse_target(&mut args, &config)?;
+
+    let assignments = args.finish();
+    if assignments.is_empty() {
+        return Err(miette::miette!("No fields to burn, use `<FIELD>=<value>`"));
+    }
+    let mut blocks = current.clone();
+    let mut changes = Vec::new();
+    for assignment in &assignments {
+        let assignment = assignment.to_string_lossy();
+        let (name, value) = assignment
+            .split_once('=')
+            .ok_or_else(|| miette::miette!("Expected `<FIELD>=<value>`, found `{}`", assignment))?;
+        let field = chip
+            .efuse_field(name)
+            .ok_or_else(|| miette::miette!("No efuse field of the {} named `{}`", chip, name))?;
+        let value = match value.strip_prefix('@') {
+            Some(path) => EfuseValue::Bytes(
+                read(path)
+                    .into_diagnostic()
+                    .wrap_err_with(|| format!("Failed to read \"{}\"", path))?,
+            ),
+            None => field.parse_value(value).map_err(Error::from)?,
+        };
+        blocks.burn(field, &value).map_err(Error::from)?;
+        changes.push(field);
+    }
+
+    if blocks == current {
+        println!("All fields are already burned to these values");
+        return Ok(());
+    }
+
+    println!("Burning the efuses of the {}:", chip);
+    for field in &changes {
+        // keep keys off the screen
+        let value = match field.kind {
+            efuse::FieldKind::Bytes => format!("<{} bytes>", field.bits / 8),
+            _ => blocks.value(field).to_string(),
+        };
+        println!("  {:<34} {} -> {}", field.name, current.value(field), value);
+    }
+
+    match target {
+        EfuseTarget::Virtual(path) => write_virtual_efuses(&path, chip, &blocks)?,
+        EfuseTarget::Device(mut flasher) => {
+            confirm_burn()?;
+            flasher.burn_efuses(&blocks)?;
+            println!("Burned the efuses");
+        }
+    }
+
+    Ok(())
+}
+
+/// Write the efuses simulated by `--virtual` back to their file
+fn write_virtual_efuses(path: &Path, chip: Chip, blocks: &EfuseBlocks) -> Result<()> {
+    let file = serde_json::json!({
+        "chip": format!("{:?}", chip).to_lowercase(),
+        "blocks": blocks,
+    });
+    write(path, format!("{}\n", file))
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write \"{}\"", path.display()))?;
+    println!("Burned the virtual efuses in \"{}\"", path.display());
+    Ok(())
+}
+
+/// Print the base MAC address of a device, the addresses of its network
+/// interfaces derived from it and the custom MAC address burned to its efuses
+fn read_mac(mut args: Arguments) -> Result<()> {
+    let config = Config::load();
+
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let json = json_format(&mut args)?;
+    let open = open_options(&mut args, &config)?;
+    let serial = serial_or_default(args.opt_free_from_str().into_diagnostic()?, &config)?;
+
+    let mut flasher = Flasher::connect(open_serial(&serial, &open)?, None)?;
+    let chip = flasher.chip();
+    let base = flasher.mac_address()?;
+    let custom = match chip.efuse_fields().is_empty() {
+        true => None,
+        false => efuse::custom_mac(chip, &flasher.read_efuse_blocks()?),
+    };
+    let format = |mac: [u8; 6]| EfuseValue::Mac(mac).to_string();
+
+    if json {
+        let derived = chip
+            .derived_macs(base)
+            .into_iter()
+            .map(|(interface, mac)| serde_json::json!({ "interface": interface, "mac": format(mac) }))
+            .collect::<Vec<_>>();
+        let info = serde_json::json!({
+            "chip": chip.to_string(),
+            "base": format(base),
+            "derived": derived,
+            "custom": custom.map(format),
+        });
+        println!("{}", info);
+        return Ok(());
+    }
+
+    println!("Base MAC address: {}", format(base));
+    for (interface, mac) in chip.derived_macs(base) {
+        println!("  {:<20} {}", interface, format(mac));
+    }
+    match custom {
+        Some(mac) => println!("Custom MAC address: {}", format(mac)),
+        None => println!("Custom MAC address: none"),
+    }
+
+    Ok(())
+}
+
+/// Burn a custom MAC address to the efuses of a device or of a virtual efuse
+/// file
+///
+/// The firmware has to read it with `esp_efuse_mac_get_custom` and set it as
+/// base MAC address, the factory one stays in use otherwise.
+fn write_custom_mac(mut args: Arguments) -> Result<()> {
+    let config = Config::load();
+
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let (chip, current, target) = efuse_target(&mut args, &config)?;
+    let mac: String = args.free_from_str().into_diagnostic()?;
+    let mac = efuse::parse_mac(&mac).ok_or_else(|| {
+        miette::miette!(
+            "Invalid MAC address `{}`, expected six hex bytes separated by colons",
+            mac
+        )
+    })?;
+
+    if let Some(custom) = efuse::custom_mac(chip, &current) {
+        if custom == mac {
+            println!("The custom MAC address is already burned");
+            return Ok(());
+        }
+        return Err(miette::miette!(
+            "A custom MAC address is already burned: {}",
+            EfuseValue::Mac(custom)
+        ));
+    }
+    let mut blocks = current.clone();
+    efuse::burn_custom_mac(chip, &mut blocks, mac).map_err(Error::from)?;
+    println!(
+        "Burning the custom MAC address {} to the efuses of the {}",
+        EfuseValue::Mac(mac),
+        chip
+    );
+
+    match target {
+        EfuseTarget::Virtual(path) => write_virtual_efuses(&path, chip, &blocks)?,
+        EfuseTarget::Device(mut flasher) => {
+            confirm_burn()?;
+            flasher.burn_efuses(&blocks)?;
+            println!("Burned the efuses");
+        }
+    }
+
+    Ok(())
+}
+
+/// Have the user type `BURN` before burning efuses
+fn confirm_burn() -> Result<()> {
+    if !interactive() {
+        return Err(miette::miette!(
+            "Burning efuses needs a typed confirmation, which isn't possible in non-interactive mode"
+        ));
+    }
+    println!("Burning efuses is irreversible and can permanently disable the device");
+    print!("Type BURN to continue: ");
+    stdout().flush().into_diagnostic()?;
+
+    let mut answer = String::new();
+    stdin().read_line(&mut answer).into_diagnostic()?;
+    if answer.trim() != "BURN" {
+        return Err(miette::miette!("Burning aborted"));
+    }
+
+    Ok(())
+}
+
+/// Enable flash encryption in development mode: burn the key, write the
+/// bootloader, partition table and app encrypted with it and then enable
+/// encryption
+fn provision_flash_encryption(mut args: Arguments) -> Result<()> {
+    let config = Config::load();
+
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let key_path: PathBuf = args.value_from_str("--key").into_diagnostic()?;
+    let protect_key = !args.contains("--dev-no-protect-key");
+    let (bootloader_path, partition_table_path, flash_settings) =
+        read_flash_settings(&mut args, &config)?;
+    let open = open_options(&mut args, &config)?;
+
+    let mut serial: Option<String> = args.opt_free_from_str().into_diagnostic()?;
+    let mut elf: Option<String> = args.opt_free_from_str().into_diagnostic()?;
+    if elf.is_none() {
+        elf = serial.take();
+    }
+    let elf = match elf {
+        Some(elf) => elf,
+        None => return help(),
+    };
+    let serial = serial_or_default(serial, &config)?;
+
+    let elf_data = read(&elf)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open elf image \"{}\"", elf))?;
+    let bootloader = read_bootloader(bootloader_path)?;
+    let partition_table = read_partition_table(partition_table_path)?;
+
+    let mut flasher = Flasher::connect(open_serial(&serial, &open)?, None)?;
+    flasher.set_flash_settings(flash_settings);
+    let chip = flasher.chip();
+
+    let current = flasher.read_efuse_blocks()?;
+    if chip.efuse_controller().is_none() {
+        return Err(miette::miette!(
+            "Burning efuses isn't supported for the {}",
+            chip
+        ));
+    }
+    if flash_encryption::is_enabled(chip, &current) {
+        return Err(miette::miette!("Flash encryption is already enabled"));
+    }
+
+    let key = match key_path.exists() {
+        true => read(&key_path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to read \"{}\"", key_path.display()))?,
+        false => {
+            let mut key = vec![0; 32];
+            getrandom::getrandom(&mut key).into_diagnostic()?;
+            write(&key_path, &key)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to write \"{}\"", key_path.display()))?;
+            println!(
+                "Generated a new flash encryption key in \"{}\", keep it to update the device",
+                key_path.display()
+            );
+            key
+        }
+    };
+    let key = FlashEncryptionKey::new(chip, &key).map_err(Error::from)?;
+
+    let mut blocks = current.clone();
+    let used = flash_encryption::enable(chip, &mut blocks, &key, protect_key)?;
+    println!("Provisioning flash encryption of the {}:", chip);
+    for (i, (purpose, _)) in used.iter().zip(key.key_blocks()) {
+        println!(
+            "  Burn the key to BLOCK_KEY{} with purpose {}",
+            i,
+            efuse::key_purpose(purpose)
+        );
+    }
+    if protect_key {
+        println!("  Read and write protect the key blocks and write protect their purposes");
+    } else {
+        println!("  Leave the key blocks readable and writable, for development only");
+    }
+    println!("  Write the bootloader, partition table and app encrypted");
+    println!("  Enable flash encryption by burning SPI_BOOT_CRYPT_CNT");
+    confirm_burn()?;
+
+    // write the encrypted images first, so a failure doesn't leave the device
+    // decrypting plaintext
+    flasher.set_reset_after(ResetAfterOperation::NoReset);
+    flasher.set_flash_encryption_key(Some(key));
+    let report = flasher.load_elf_to_flash(&elf_data, bootloader, partition_table)?;
+    print!("{}", report);
+
+    flasher.burn_efuses(&blocks)?;
+    println!("Flash encryption is enabled in development mode");
+
+    flasher.set_reset_after(ResetAfterOperation::HardReset);
+    flasher.reset_after()?;
+
+    Ok(())
+}
+
+fn dump_mem(mut args: Arguments) -> Result<()> {
+    let config = Config::load();
+
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let output: Option<PathBuf> = args
+        .opt_value_from_str(["-o", "--output"])
+        .into_diagnostic()?;
+    let open = open_options(&mut args, &config)?;
+    let serial: String = args.free_from_str().into_diagnostic()?;
+    let addr = args.free_from_fn(parse_u32).into_diagnostic()?;
+    let length = args.free_from_fn(parse_u32).into_diagnostic()?;
+
+    let mut flasher = Flasher::connect(open_serial(&serial, &open)?, None)?;
+    let data = flasher.read_mem(addr, length)?;
+
+    match output {
+        Some(output) => write(&output, &data)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to write \"{}\"", output.display()))?,
+        None => print_hex_dump(addr, &data),
+    }
+
+    Ok(())
+}
+
+fn read_reg(mut args: Arguments) -> Result<()> {
+    let config = Config::load();
+
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let open = open_options(&mut args, &config)?;
+    let serial: String = args.free_from_str().into_diagnostic()?;
+    let addr = args.free_from_fn(parse_u32).into_diagnostic()?;
+
+    let mut flasher = Flasher::connect(open_serial(&serial, &open)?, None)?;
+    println!("{:#010x}", flasher.read_reg(addr)?);
+
+    Ok(())
+}
+
+fn flash_status(mut args: Arguments) -> Result<()> {
+    let config = Config::load();
+
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let unprotect = args.contains("--unprotect");
+    let open = open_options(&mut args, &config)?;
+    let serial: String = args.free_from_str().into_diagnostic()?;
+
+    let mut flasher = Flasher::connect(open_serial(&serial, &open)?, None)?;
+    let status = flasher.flash_status()?;
+    println!("Status register: {:#06x}", status);
+    match status & FLASH_STATUS_PROTECTION {
+        0 => println!("Write protection: none"),
+        bits => println!("Write protection: {:#06x}", bits),
+    }
+
+    if unprotect && flasher.unprotect_flash()? {
+        println!("Cleared the write protection");
+    }
+
+    Ok(())
+}
+
+fn write_reg(mut args: Arguments) -> Result<()> {
+    let config = Config::load();
+
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let mask = args
+        .opt_value_from_fn("--mask", parse_u32)
+        .into_diagnostic()?;
+    let delay = args
+        .opt_value_from_fn("--delay", parse_u32)
+        .into_diagnostic()?
+        .unwrap_or(0);
+    let open = open_options(&mut args, &config)?;
+    let serial: String = args.free_from_str().into_diagnostic()?;
+    let addr = args.free_from_fn(parse_u32).into_diagnostic()?;
+    let value = args.free_from_fn(parse_u32).into_diagnostic()?;
+
+    let mut flasher = Flasher::connect(open_serial(&serial, &open)?, None)?;
+    flasher.write_reg_delayed(addr, value, mask, delay)?;
+
+    Ok(())
+}
+
+/// Print `data` as lines of 16 bytes in hex, followed by the printable ones
+fn print_hex_dump(addr: u32, data: &[u8]) {
+    for (i, line) in data.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let text: String = line
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        println!(
+            "{:08x}  {:<47}  |{}|",
+            addr as usize + i * 16,
+            hex.join(" "),
+            text
+        );
+    }
+}
+
+fn secure_boot_digest(mut args: Arguments) -> Result<()> {
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let key_path: String = args.value_from_str("--key").into_diagnostic()?;
+    let iv_path = args
+        .opt_value_from_str::<_, String>("--iv")
+        .into_diagnostic()?;
+    let output = args
+        .opt_value_from_str::<_, String>(["-o", "--output"])
+        .into_diagnostic()?;
+    let input: String = args.free_from_str().into_diagnostic()?;
+
+    let key = read(&key_path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open secure boot key \"{}\"", key_path))?;
+    let bootloader = read(&input)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open bootloader \"{}\"", input))?;
+
+    let mut iv = [0u8; SECURE_BOOT_IV_LEN];
+    match iv_path {
+        Some(path) => {
+            let data = read(&path)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to open IV \"{}\"", path))?;
+            if data.len() != SECURE_BOOT_IV_LEN {
+                return Err(miette::miette!(
+                    "IV must be {} bytes long, found {} bytes",
+                    SECURE_BOOT_IV_LEN,
+                    data.len()
+                ));
+            }
+            iv.copy_from_slice(&data);
+        }
+        None => getrandom::getrandom(&mut iv).into_diagnostic()?,
+    }
+
+    let data = digest_bootloader(&key, &iv, &bootloader)?;
+
+    // Match the file name used by `espsecure.py digest_secure_bootloader`
+    let output = output.unwrap_or_else(|| {
+        let stem = input.strip_suffix(".bin").unwrap_or(&input);
+        format!("{}-digest-0x0000.bin", stem)
+    });
+    write(&output, data)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write digest \"{}\"", output))?;
+    println!("Digest and bootloader written to {}", output);
+
+    Ok(())
+}
+
+fn nvs_gen(mut args: Arguments) -> Result<()> {
+    let config = Config::load();
+
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let size = args
+        .opt_value_from_fn("--size", parse_u32)
+        .into_diagnostic()?;
+    let keys_path = args
+        .opt_value_from_str::<_, String>("--keys")
+        .into_diagnostic()?;
+    let keygen_path = args
+        .opt_value_from_str::<_, String>("--keygen")
+        .into_diagnostic()?;
+    let serial = args
+        .opt_value_from_str::<_, String>("--flash")
+        .into_diagnostic()?;
+    let partition_table_path = args
+        .opt_value_from_str::<_, String>("--partition-table")
+        .into_diagnostic()?;
+    let open = open_options(&mut args, &config)?;
+    let input: String = args.free_from_str().into_diagnostic()?;
+    let output: String = args.free_from_str().into_diagnostic()?;
+
+    let keys = match (keys_path, keygen_path) {
+        (Some(_), Some(_)) => {
+            return Err(miette::miette!(
+                "`--keys` and `--keygen` can't be used together"
+            ))
+        }
+        (Some(path), None) => {
+            let data = read(&path)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to open NVS keys \"{}\"", path))?;
+            Some(nvs::read_keys(&data).map_err(Error::from)?)
+        }
+        (None, Some(path)) => {
+            let mut keys = [0; nvs::NVS_KEYS_SIZE];
+            getrandom::getrandom(&mut keys).into_diagnostic()?;
+            write(&path, nvs::keys_partition(&keys))
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to write NVS keys \"{}\"", path))?;
+            println!("NVS keys written to {}", path);
+            Some(keys)
+        }
+        (None, None) => None,
+    };
+
+    // without a partition table, the nvs partition of the default one is used
+    let (offset, partition_size) = match read_partition_table(partition_table_path)? {
+        Some(table) => table
+            .find("nvs")
+            .ok_or_else(|| miette::miette!("The partition table has no `nvs` partition"))?,
+        None => (0x9000, 0x6000),
+    };
+
+    let data = read_to_string(&input)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open NVS CSV \"{}\"", input))?;
+    let base = Path::new(&input).parent().unwrap_or_else(|| Path::new(""));
+    let partition = NvsPartition::from_csv(&data, base).map_err(Error::from)?;
+    let image = partition
+        .generate(size.unwrap_or(partition_size), keys.as_ref())
+        .map_err(Error::from)?;
+
+    write(&output, &image)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write NVS partition \"{}\"", output))?;
+    println!("NVS partition written to {}", output);
+
+    if let Some(serial) = serial {
+        if image.len() as u32 > partition_size {
+            return Err(miette::miette!(
+                "The NVS partition is larger than the `nvs` partition of {:#x} bytes",
+                partition_size
+            ));
+        }
+
+        let mut flasher = Flasher::connect(open_serial(&serial, &open)?, None)?;
+        flasher.write_bin_to_flash(offset, &image)?;
+        println!("NVS partition flashed at {:#x}", offset);
+    }
+
+    Ok(())
+}
+
+/// Read the NVS partition of a device and print its entries
+fn nvs_dump(mut args: Arguments) -> Result<()> {
+    let config = Config::load();
+
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let name = args
+        .opt_value_from_str::<_, String>("--partition")
+        .into_diagnostic()?;
+    let keys_path = args
+        .opt_value_from_str::<_, String>("--keys")
+        .into_diagnostic()?;
+    let json = json_format(&mut args)?;
+    let partition_table_path = args
+        .opt_value_from_str::<_, String>("--partition-table")
+        .into_diagnostic()?;
+    let offset = args
+        .opt_value_from_fn("--partition-table-offset", parse_u32)
+        .into_diagnostic()?;
+    let open = open_options(&mut args, &config)?;
+    let serial = serial_or_default(args.opt_free_from_str().into_diagnostic()?, &config)?;
+
+    let keys = match keys_path {
+        Some(path) => {
+            let data = read(&path)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to open NVS keys \"{}\"", path))?;
+            Some(nvs::read_keys(&data).map_err(Error::from)?)
+        }
+        None => None,
+    };
+
+    let mut flasher = Flasher::connect(open_serial(&serial, &open)?, None)?;
+    let table = match read_partition_table(partition_table_path)? {
+        Some(table) => table,
+        None => read_device_partition_table(&mut flasher, offset)?,
+    };
+    let (name, offset, size) =
+        table
+            .find_data(name.as_deref(), DataType::Nvs)
+            .ok_or_else(|| {
+                miette::miette!(
+                    "The partition table has no `{}` partition",
+                    name.as_deref().unwrap_or("nvs")
+                )
+            })?;
+    let name = name.to_string();
+
+    let data = flasher.read_flash(offset, size)?;
+    let partition = NvsPartition::parse(&data, keys.as_ref())
+        .map_err(Error::from)
+        .wrap_err_with(|| format!("Failed to decode the `{}` partition", name))?;
+
+    if json {
+        let entries = partition
+            .entries
+            .iter()
+            .map(|entry| {
+                let value = match &entry.value {
+                    NvsValue::U8(value) => serde_json::json!(value),
+                    NvsValue::I8(value) => serde_json::json!(value),
+                    NvsValue::U16(value) => serde_json::json!(value),
+                    NvsValue::I16(value) => serde_json::json!(value),
+                    NvsValue::U32(value) => serde_json::json!(value),
+                    NvsValue::I32(value) => serde_json::json!(value),
+                    NvsValue::U64(value) => serde_json::json!(value),
+                    NvsValue::I64(value) => serde_json::json!(value),
+                    NvsValue::String(value) => serde_json::json!(value),
+                    // in hex, like the blobs of the CSV files
+                    NvsValue::Blob(_) => serde_json::json!(entry.value.to_string()),
+                };
+                serde_json::json!({
+                    "namespace": entry.namespace,
+                    "key": entry.key,
+                    "type": entry.value.type_name(),
+                    "value": value,
+                })
+            })
+            .collect::<Vec<_>>();
+        println!("{}", serde_json::Value::from(entries));
+        return Ok(());
+    }
+
+    let mut namespaces: Vec<&str> = Vec::new();
+    for entry in &partition.entries {
+        if !namespaces.contains(&entry.namespace.as_str()) {
+            namespaces.push(&entry.namespace);
+        }
+    }
+    println!("{} at {:#x}:", name, offset);
+    for namespace in namespaces {
+        println!("  {}", namespace);
+        for entry in partition
+            .entries
+            .iter()
+            .filter(|entry| entry.namespace == namespace)
+        {
+            let value = match &entry.value {
+                NvsValue::String(value) => format!("{:?}", value),
+                value => value.to_string(),
+            };
+            println!(
+                "    {:<15} {:<6} {}",
+                entry.key,
+                entry.value.type_name(),
+                value
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Filesystems images of a host directory are generated for
+#[derive(Copy, Clone)]
+enum Filesystem {
+    Spiffs,
+    LittleFs,
+    Fat,
+}
+
+impl Filesystem {
+    fn name(self) -> &'static str {
+        match self {
+            Filesystem::Spiffs => "SPIFFS",
+            Filesystem::LittleFs => "LittleFS",
+            Filesystem::Fat => "FAT",
+        }
+    }
+
+    /// The partition written without `--partition`, if the table has one of
+    /// that name
+    fn partition(self) -> &'static str {
+        match self {
+            Filesystem::Spiffs => "spiffs",
+            Filesystem::LittleFs => "littlefs",
+            Filesystem::Fat => "ffat",
+        }
+    }
+
+    /// The subtype of the data partitions holding the filesystem
+    fn data_type(self) -> DataType {
+        match self {
+            Filesystem::Spiffs | Filesystem::LittleFs => DataType::Spiffs,
+            Filesystem::Fat => DataType::Fat,
+        }
+    }
+}
+
+/// Pack a directory into a filesystem image for a data partition
+fn fs_image(mut args: Arguments, filesystem: Filesystem) -> Result<()> {
+    let config = Config::load();
+
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let size = args
+        .opt_value_from_fn("--size", parse_u32)
+        .into_diagnostic()?;
+    let page_size = match filesystem {
+        Filesystem::Spiffs => args
+            .opt_value_from_fn("--page-size", parse_u32)
+            .into_diagnostic()?,
+        Filesystem::LittleFs | Filesystem::Fat => None,
+    };
+    let wear_levelling = match filesystem {
+        Filesystem::Fat => !args.contains("--no-wear-levelling"),
+        Filesystem::Spiffs | Filesystem::LittleFs => false,
+    };
+    let name = args
+        .opt_value_from_str::<_, String>("--partition")
+        .into_diagnostic()?;
+    let serial = args
+        .opt_value_from_str::<_, String>("--flash")
+        .into_diagnostic()?;
+    let partition_table_path = args
+        .opt_value_from_str::<_, String>("--partition-table")
+        .into_diagnostic()?;
+    let open = open_options(&mut args, &config)?;
+    let input: String = args.free_from_str().into_diagnostic()?;
+    let output: String = args.free_from_str().into_diagnostic()?;
+
+    let partition = match read_partition_table(partition_table_path)? {
+        Some(table) => {
+            let default = table
+                .find(filesystem.partition())
+                .map(|_| filesystem.partition());
+            let name = name.as_deref().or(default);
+            let (_, offset, size) =
+                table
+                    .find_data(name, filesystem.data_type())
+                    .ok_or_else(|| {
+                        miette::miette!(
+                            "The partition table has no `{}` partition",
+                            name.unwrap_or(filesystem.partition())
+                        )
+                    })?;
+            Some((offset, size))
+        }
+        None => None,
+    };
+    let size = size
+        .or(partition.map(|(_, size)| size))
+        .ok_or_else(|| miette::miette!("Either `--size` or `--partition-table` is required"))?;
+
+    let entries = fs::read_dir(Path::new(&input)).map_err(Error::from)?;
+    let image = match filesystem {
+        Filesystem::Spiffs => {
+            let mut spiffs_config = SpiffsConfig::default();
+            if let Some(page_size) = page_size {
+                spiffs_config.page_size = page_size as usize;
+            }
+            fs::spiffs::generate(&entries, size, &spiffs_config)
+        }
+        Filesystem::LittleFs => fs::littlefs::generate(&entries, size, &LittleFsConfig::default()),
+        Filesystem::Fat => {
+            let mut device_id = [0; 4];
+            getrandom::getrandom(&mut device_id).into_diagnostic()?;
+            let fat_config = FatConfig {
+                wear_levelling,
+                device_id: u32::from_le_bytes(device_id),
+                ..FatConfig::default()
+            };
+            fs::fat::generate(&entries, size, &fat_config)
+        }
+    }
+    .map_err(Error::from)?;
+
+    write(&output, &image)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write {} image \"{}\"", filesystem.name(), output))?;
+    println!("{} image written to {}", filesystem.name(), output);
+
+    if let Some(serial) = serial {
+        let (offset, partition_size) =
+            partition.ok_or_else(|| miette::miette!("`--flash` requires a `--partition-table`"))?;
+        if image.len() as u32 > partition_size {
+            return Err(miette::miette!(
+                "The {} image is larger than the partition of {:#x} bytes",
+                filesystem.name(),
+                partition_size
+            ));
+        }
+
+        let mut flasher = Flasher::connect(open_serial(&serial, &open)?, None)?;
+        flasher.write_bin_to_flash(offset, &image)?;
+        println!("{} image flashed at {:#x}", filesystem.name(), offset);
+    }
+
+    Ok(())
+}
+
+fn merge_bin(mut args: Arguments) -> Result<()> {
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let chip: Chip = args.value_from_str("--chip").into_diagnostic()?;
+    let fill = args
+        .opt_value_from_fn("--fill", parse_u8)
+        .into_diagnostic()?
+        .unwrap_or(0xff);
+    let size: Option<FlashSize> = args.opt_value_from_str("--size").into_diagnostic()?;
+    let format: OutputFormat = args
+        .opt_value_from_str("--format")
+        .into_diagnostic()?
+        .unwrap_or_default();
+    let app_desc = app_descriptor_overrides(&mut args)?;
+    let segment_layout = segment_layout(&mut args)?;
+    let (bootloader_path, partition_table_path, mut flash_settings) =
+        read_flash_settings(&mut args, &Config::load())?;
+    flash_settings.size = size.or(flash_settings.size);
+
+    let input: String = args.free_from_str().into_diagnostic()?;
+    let output: String = args.free_from_str().into_diagnostic()?;
+
+    let input_bytes = read(&input)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open elf image \"{}\"", input))?;
+    let bootloader = read_bootloader(bootloader_path)?;
+    let partition_table = read_partition_table(partition_table_path)?;
+
+    let image = merged_image(
+        chip,
+        &input_bytes,
+        bootloader,
+        partition_table,
+        &app_desc,
+        &flash_settings,
+        &segment_layout,
+        fill,
+        format,
+    )?;
+    write(&output, image)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write image \"{}\"", output))?;
+
+    Ok(())
+}
+
+/// Save the bootloader, partition table and application as separate binaries,
+/// along with a `flasher_args.json` listing their offsets
+fn save_image(mut args: Arguments) -> Result<()> {
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let chip: Chip = args.value_from_str("--chip").into_diagnostic()?;
+    let app_desc = app_descriptor_overrides(&mut args)?;
+    let segment_layout = segment_layout(&mut args)?;
+    let (bootloader_path, partition_table_path, flash_settings) =
+        read_flash_settings(&mut args, &Config::load())?;
+
+    let input: String = args.free_from_str().into_diagnostic()?;
+    let output: PathBuf = args.free_from_str().into_diagnostic()?;
+
+    let input_bytes = read(&input)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open elf image \"{}\"", input))?;
+    let bootloader = read_bootloader(bootloader_path)?;
+    let partition_table = read_partition_table(partition_table_path)?;
+
+    let parts = split_image(
+        chip,
+        &input_bytes,
+        bootloader,
+        partition_table,
+        &app_desc,
+        &flash_settings,
+        &segment_layout,
+    )?;
+
+    std::fs::create_dir_all(&output)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to create \"{}\"", output.display()))?;
+
+    // The application is generated last, the ESP8266 has neither a bootloader
+    // nor a partition table
+    let stem = Path::new(&input)
+        .file_stem()
+        .map_or_else(|| "app".into(), |stem| stem.to_string_lossy());
+    let count = parts.len();
+    let mut files = Vec::with_capacity(count);
+    for (index, (offset, data)) in parts.into_iter().enumerate() {
+        let name = match (chip, index) {
+            (Chip::Esp8266, _) => format!("{}-{:#07x}.bin", stem, offset),
+            (_, index) if index + 1 == count => format!("{}.bin", stem),
+            (_, 0) => "bootloader.bin".to_string(),
+            _ => "partition-table.bin".to_string(),
+        };
+        let path = output.join(&name);
+        write(&path, data)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to write image \"{}\"", path.display()))?;
+        println!("Saved {} for {:#x}", path.display(), offset);
+        files.push((offset, name));
+    }
+
+    let path = output.join(FLASHER_ARGS_FILE);
+    FlasherArgs::new(chip, &flash_settings, &files)
+        .save(&path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write \"{}\"", path.display()))?;
+    println!("Saved {}", path.display());
+
+    Ok(())
+}
+
+/// Send an image to a device running ArduinoOTA, e.g.
+/// `espflash ota --ip 192.168.1.42 app.elf`
+///
+/// ELF files are converted to an application image first, anything else is
+/// sent as it is.
+fn espota_upload(mut args: Arguments) -> Result<()> {
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let ip: IpAddr = args.value_from_str("--ip").into_diagnostic()?;
+    let chip: Chip = args
+        .opt_value_from_str("--chip")
+        .into_diagnostic()?
+        .unwrap_or(Chip::Esp32);
+    let default_port = match chip {
+        Chip::Esp8266 => espota::ESP8266_PORT,
+        _ => espota::ESP32_PORT,
+    };
+    let port: u16 = args
+        .opt_value_from_str("--port")
+        .into_diagnostic()?
+        .unwrap_or(default_port);
+    let host_port: u16 = args
+        .opt_value_from_str("--host-port")
+        .into_diagnostic()?
+        .unwrap_or(0);
+    let password: Option<String> = args.opt_value_from_str("--password").into_diagnostic()?;
+    let target = match args.contains("--filesystem") {
+        true => EspotaTarget::Filesystem,
+        false => EspotaTarget::Flash,
+    };
+    let timeout: f64 = args
+        .opt_value_from_str("--timeout")
+        .into_diagnostic()?
+        .unwrap_or(10.0);
+    let timeout = Duration::try_from_secs_f64(timeout)
+        .into_diagnostic()
+        .wrap_err("Invalid `--timeout` duration")?;
+    let input: String = args.free_from_str().into_diagnostic()?;
+
+    let data = read(&input)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open image \"{}\"", input))?;
+    let image = match data.starts_with(b"\x7fELF") {
+        true => app_image(chip, &data)?,
+        false => data,
+    };
+    let name = Path::new(&input)
+        .file_name()
+        .map_or_else(|| input.clone(), |name| name.to_string_lossy().into_owned());
+
+    let options = EspotaOptions {
+        device: SocketAddr::new(ip, port),
+        host_port,
+        password,
+        target,
+        timeout,
+    };
+    println!("Sending {} bytes to {}", image.len(), options.device);
+    espota::upload(&image, &name, &options, &mut ProgressBars::default()).map_err(Error::from)?;
+    println!("The device accepted the update");
+
+    Ok(())
+}
+
+/// Serve the application image to firmware updating itself with
+/// `esp_https_ota`
+fn serve_ota(mut args: Arguments) -> Result<()> {
+    if args.contains(["-h", "--help"]) {
+        return help();
+    }
+
+    let chip: Chip = args
+        .opt_value_from_str("--chip")
+        .into_diagnostic()?
+        .unwrap_or(Chip::Esp32);
+    let listen = args
+        .opt_value_from_str::<_, String>("--listen")
+        .into_diagnostic()?
+        .unwrap_or_else(|| "0.0.0.0:8070".to_string());
+    let version_endpoint = args.contains("--version-endpoint");
+    let cert: Option<PathBuf> = args.opt_value_from_str("--cert").into_diagnostic()?;
+    let key: Option<PathBuf> = args.opt_value_from_str("--key").into_diagnostic()?;
+    let app_desc = app_descriptor_overrides(&mut args)?;
+    let segment_layout = segment_layout(&mut args)?;
+    let (_, _, flash_settings) = read_flash_settings(&mut args, &Config::load())?;
+    let input: String = args.free_from_str().into_diagnostic()?;
+
+    let data = read(&input)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open image \"{}\"", input))?;
+    // the application is generated last
+    let image = match data.starts_with(b"\x7fELF") {
+        true => split_image(
+            chip,
+            &data,
+            None,
+            None,
+            &app_desc,
+            &flash_settings,
+            &segment_layout,
+        )?
+        .pop()
+        .map(|(_, image)| image)
+        .unwrap_or_default(),
+        false => data,
+    };
+
+    let version = match version_endpoint {
+        true => Some(
+            AppDescriptor::from_image(&image)
+                .map(|desc| desc.version)
+                .ok_or_else(|| {
+                    miette::miette!(
+                        "The image has no application descriptor to take the version from"
+                    )
+                })?,
+        ),
+        false => None,
+    };
+    let artifact = OtaArtifact::new(image, version);
+
+    let scheme = match (&cert, &key) {
+        (Some(_), Some(_)) => "https",
+        (None, None) => "http",
+        _ => {
+            return Err(miette::miette!(
+                "`--cert` and `--key` must be given together"
+            ))
+        }
+    };
+    eprintln!(
+        "Serving {} at {}://{}{}",
+        input, scheme, listen, FIRMWARE_PATH
+    );
+    if version_endpoint {
+        eprintln!(
+            "Serving its version at {}://{}{}",
+            scheme, listen, VERSION_PATH
+        );
+    }
+
+    match (cert, key) {
+        (Some(cert), Some(key)) => artifact
+            .serve_https(&listen, &cert, &key)
+            .into_diagnostic()
+            .wrap_err("Failed to run `openssl s_server`"),
+        _ => {
+            let listener = std::net::TcpListener::bind(&listen)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to listen on {}", listen))?;
+            artifact.serve(&listener).into_diagnostic()
+        }
+    }
+}
+
+fn app_descriptor_overrides(args: &mut Arguments) -> Result<AppDescriptorOverrides> {
+    Ok(AppDescriptorOverrides {
+        version: args.opt_value_from_str("--app-version").into_diagnostic()?,
+        project_name: args
+            .opt_value_from_str("--project-name")
+            .into_diagnostic()?,
+        time: args.opt_value_from_str("--build-time").into_diagnostic()?,
+        date: args.opt_value_from_str("--build-date").into_diagnostic()?,
+        reproducible: args.contains("--reproducible"),
+    })
+}
+
+/// Read the flash settings and the path of the partition table from an ESP-IDF
+/// sdkconfig, if one is given
+fn read_sdkconfig(args: &mut Arguments) -> Result<(FlashSettings, Option<String>)> {
+    let path = match args
+        .opt_value_from_str::<_, String>("--sdkconfig")
+        .into_diagnostic()?
+    {
+        Some(path) => path,
+        None => return Ok((FlashSettings::default(), None)),
+    };
+
+    let data = read_to_string(&path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open sdkconfig \"{}\"", path))?;
+    let config = SdkConfig::parse(&data).map_err(Error::from)?;
+    let settings = config.flash_settings().map_err(Error::from)?;
+
+    let partition_table = config
+        .partition_table(Path::new(&path))
+        .map(|table| table.to_string_lossy().into_owned());
+
+    Ok((settings, partition_table))
+}
+
+/// Read the Ed25519 secret key signing attestation records, stored as its
+/// 32 raw bytes
+fn read_ledger_key(path: &str) -> Result<SigningKey> {
+    let data = read(path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open ledger key \"{}\"", path))?;
+    SigningKey::try_from(data.as_slice()).map_err(|_| {
+        miette::miette!(
+            "The ledger key \"{}\" is not a 32 byte Ed25519 secret key",
+            path
+        )
+    })
+}
+
+/// Read the paths of the bootloader and partition table and the flash settings
+///
+/// The ones given on the command line take precedence over the sdkconfig,
+/// which takes precedence over the config file.
+fn read_flash_settings(
+    args: &mut Arguments,
+    config: &Config,
+) -> Result<(Option<String>, Option<String>, FlashSettings)> {
+    let bootloader_path = args
+        .opt_value_from_str::<_, String>("--bootloader")
+        .into_diagnostic()?
+        .or_else(|| config_path(&config.flash.bootloader));
+    let partition_table_path = args
+        .opt_value_from_str::<_, String>("--partition-table")
+        .into_diagnostic()?;
+    let (mut settings, sdkconfig_partition_table) = read_sdkconfig(args)?;
+    let partition_table_path = partition_table_path
+        .or(sdkconfig_partition_table)
+        .or_else(|| config_path(&config.flash.partition_table));
+
+    settings.mode = args
+        .opt_value_from_str::<_, FlashMode>("--flash-mode")
+        .into_diagnostic()?
+        .or(settings.mode)
+        .or(config.flash.mode);
+    settings.frequency = args
+        .opt_value_from_str::<_, FlashFrequency>("--flash-freq")
+        .into_diagnostic()?
+        .or(settings.frequency)
+        .or(config.flash.frequency);
+    settings.size = args
+        .opt_value_from_str::<_, FlashSize>("--flash-size")
+        .into_diagnostic()?
+        .or(settings.size)
+        .or(config.flash.size);
+    settings.bootloader_offset = args
+        .opt_value_from_fn("--bootloader-offset", parse_u32)
+        .into_diagnostic()?
+        .or(settings.bootloader_offset)
+        .or(config.flash.bootloader_offset);
+    settings.partition_table_offset = args
+        .opt_value_from_fn("--partition-table-offset", parse_u32)
+        .into_diagnostic()?
+        .or(settings.partition_table_offset)
+        .or(config.flash.partition_table_offset);
+    settings.app_offset = args
+        .opt_value_from_fn("--app-offset", parse_u32)
+        .into_diagnostic()?
+        .or(settings.app_offset)
+        .or(config.flash.app_offset);
+
+    Ok((bootloader_path, partition_table_path, settings))
+}
+
+fn config_path(path: &Option<PathBuf>) -> Option<String> {
+    path.as_ref()
+        .map(|path| path.to_string_lossy().into_owned())
+}
+
+/// Read the key given by `--encrypt-key`
+fn read_encrypt_key(args: &mut Arguments) -> Result<Option<Vec<u8>>> {
+    args.opt_value_from_str::<_, String>("--encrypt-key")
+        .into_diagnostic()?
+        .map(|path| {
+            read(&path)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to open flash encryption key \"{}\"", path))
+        })
+        .transpose()
+}
+
+/// Encrypt everything written to flash with `key` on the host, which needs
+/// flash encryption to be enabled on the device
+fn set_encrypt_key(flasher: &mut Flasher, key: Option<Vec<u8>>) -> Result<()> {
+    let key = match key {
+        Some(key) => key,
+        None => return Ok(()),
+    };
+
+    let chip = flasher.chip();
+    let key = FlashEncryptionKey::new(chip, &key).map_err(Error::from)?;
+    if !flash_encryption::is_enabled(chip, &flasher.read_efuse_blocks()?) {
+        return Err(miette::miette!(
+            "Flash encryption isn't enabled on the device, which wouldn't boot an encrypted image"
+        ));
+    }
+    flasher.set_flash_encryption_key(Some(key));
+
+    Ok(())
+}
+
+fn segment_layout(args: &mut Arguments) -> Result<SegmentLayout> {
+    let default = SegmentLayout::default();
+
+    Ok(SegmentLayout {
+        segment_alignment: args
+            .opt_value_from_fn("--segment-alignment", parse_u32)
+            .into_diagnostic()?
+            .unwrap_or(default.segment_alignment),
+        mmu_page_size: args
+            .opt_value_from_fn("--mmu-page-size", parse_u32)
+            .into_diagnostic()?
+            .unwrap_or(default.mmu_page_size),
+        pad_page_tail: !args.contains("--no-page-tail-padding"),
+    })
+}
+
+fn flash_timing(args: &mut Arguments, config: &Config) -> Result<FlashTiming> {
+    Ok(FlashTiming {
+        clock_divider: args
+            .opt_value_from_fn("--spi-clock-div", parse_u8)
+            .into_diagnostic()?
+            .or(config.flash.spi_clock_div),
+        dummy_cycles: args
+            .opt_value_from_fn("--dummy-cycles", parse_u8)
+            .into_diagnostic()?
+            .or(config.flash.dummy_cycles),
+    })
+}
+
+/// Expected SHA-256 digests of the files to flash
+struct Checksums {
+    elf: Option<String>,
+    bootloader: Option<String>,
+    partition_table: Option<String>,
+    /// Refuse to flash files without a digest
+    required: bool,
+}
+
+impl Checksums {
+    fn from_args(args: &mut Arguments) -> Result<Self> {
+        Ok(Checksums {
+            elf: args.opt_value_from_str("--sha256").into_diagnostic()?,
+            bootloader: args
+                .opt_value_from_str("--bootloader-sha256")
+                .into_diagnostic()?,
+            partition_table: args
+                .opt_value_from_str("--partition-table-sha256")
+                .into_diagnostic()?,
+            required: args.contains("--require-sha256"),
+        })
+    }
+
+    /// Check that the file at `path` has the `expected` digest
+    fn verify(&self, name: &str, path: &str, expected: &Option<String>) -> Result<()> {
+        let expected = match expected {
+            Some(expected) => expected,
+            None if self.required => {
+                return Err(miette::miette!(
+                    "No SHA-256 digest given for the {} \"{}\"",
+                    name,
+                    path
+                ))
+            }
+            None => return Ok(()),
+        };
+
+        let data = read(path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to open {} \"{}\"", name, path))?;
+        let actual: String = Sha256::digest(&data)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        if !actual.eq_ignore_ascii_case(expected.trim()) {
+            return Err(miette::miette!(
+                "SHA-256 of the {} \"{}\" is {}, expected {}",
+                name,
+                path,
+                actual,
+                expected
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+fn notifier(args: &mut Arguments) -> Result<Option<Notifier>> {
+    let desktop = args.contains("--notify");
+    let webhook = args
+        .opt_value_from_str::<_, String>("--notify-webhook")
+        .into_diagnostic()?;
+
+    Ok((desktop || webhook.is_some()).then(|| Notifier::new(desktop, webhook)))
+}
+
+/// Whether `--format json` was given, as opposed to `--format text`
+fn json_format(args: &mut Arguments) -> Result<bool> {
+    let format = args
+        .opt_value_from_str::<_, String>("--format")
+        .into_diagnostic()?;
+
+    match format.as_deref() {
+        None | Some("text") => Ok(false),
+        Some("json") => Ok(true),
+        Some(format) => Err(miette::miette!(
+            "Unknown format `{}`, expected `text` or `json`",
+            format
+        )),
+    }
+}
+
+/// Settings of the serial monitor, with the baud rate taken from `baud`
+fn monitor_options(args: &mut Arguments, baud: &'static str) -> Result<MonitorOptions> {
+    let defaults = MonitorOptions::default();
+    let format = match args
+        .opt_value_from_str::<_, String>("--output-format")
+        .into_diagnostic()?
+        .as_deref()
+    {
+        None | Some("text") => monitor::OutputFormat::Text,
+        Some("jsonl") => monitor::OutputFormat::JsonLines,
+        Some(format) => {
+            return Err(miette::miette!(
+                "Unknown output format `{}`, expected `text` or `jsonl`",
+                format
+            ))
+        }
+    };
+    let log_format = match args
+        .opt_value_from_str::<_, String>("--log-format")
+        .into_diagnostic()?
+        .as_deref()
+    {
+        None | Some("serial") => monitor::LogFormat::Serial,
+        Some("defmt") => monitor::LogFormat::Defmt,
+        Some(format) => {
+            return Err(miette::miette!(
+                "Unknown log format `{}`, expected `serial` or `defmt`",
+                format
+            ))
+        }
+    };
+    let exit_key = match args
+        .opt_value_from_str::<_, String>("--exit-key")
+        .into_diagnostic()?
+    {
+        Some(key) => MonitorOptions::parse_exit_key(&key)?,
+        None => defaults.exit_key,
+    };
+
+    Ok(MonitorOptions {
+        format,
+        log_format,
+        baud: args
+            .opt_value_from_str(baud)
+            .into_diagnostic()?
+            .unwrap_or(defaults.baud),
+        boot_baud: args.opt_value_from_str("--boot-baud").into_diagnostic()?,
+        exit_key,
+        reset: false,
+        timestamps: args.contains("--timestamps"),
+        log_file: args.opt_value_from_str("--log-file").into_diagnostic()?,
+        log_file_size: args
+            .opt_value_from_str::<_, String>("--log-file-size")
+            .into_diagnostic()?
+            .map(|size| {
+                monitor::parse_size(&size)
+                    .ok_or_else(|| miette::miette!("Invalid log file size `{}`", size))
+            })
+            .transpose()?,
+        log_filter: log_filter(args.values_from_str("--log-filter").into_diagnostic()?)?,
+        input_mode: args
+            .opt_value_from_str("--input-mode")
+            .into_diagnostic()?
+            .unwrap_or_default(),
+        line_ending: args
+            .opt_value_from_str("--eol")
+            .into_diagnostic()?
+            .unwrap_or_default(),
+        serve: args
+            .opt_value_from_str::<_, String>("--serve")
+            .into_diagnostic()?
+            .map(|address| address.parse())
+            .transpose()?,
+        reopen: None,
+    })
+}
+
+/// The log filters given, which are combined with later ones taking precedence
+fn log_filter(filters: Vec<String>) -> Result<Option<monitor::LogFilter>> {
+    if filters.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(filters.join(",").parse()?))
+}
+
+fn open_journal(args: &mut Arguments) -> Result<Option<Journal>> {
+    args.opt_value_from_str::<_, String>("--journal")
+        .into_diagnostic()?
+        .map(|path| {
+            Journal::create(&path)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to create journal \"{}\"", path))
+        })
+        .transpose()
+}
+
+/// The given serial port, the one from the config file or the detected one
+fn serial_or_default(serial: Option<String>, config: &Config) -> Result<String> {
+    match serial.or_else(|| config.connection.serial.clone()) {
+        Some(serial) => Ok(serial),
+        None => Ok(espflash::port::detect(config, interactive())?),
+    }
+}
+
+/// The port to reopen if the device drops off the bus, as local ports do
+/// while the USB CDC port of an application is reset
+fn reopenable(port: &str) -> Option<String> {
+    (!espflash::port::NetworkPort::is_url(port)).then(|| port.to_string())
+}
+
+/// How to open the serial port
+struct OpenOptions {
+    retry_window: Duration,
+    /// GPIO lines resetting the chip instead of `DTR` and `RTS`
+    gpio: Option<GpioPins>,
+}
+
+fn open_options(args: &mut Arguments, config: &Config) -> Result<OpenOptions> {
+    let retry_window = retry_open(args, config)?;
+    let en = args
+        .opt_value_from_str::<_, u32>("--gpio-en")
+        .into_diagnostic()?
+        .or(config.connection.gpio_en);
+    let io0 = args
+        .opt_value_from_str::<_, u32>("--gpio-io0")
+        .into_diagnostic()?
+        .or(config.connection.gpio_io0);
+    let gpio = match (en, io0) {
+        (Some(en), Some(io0)) => Some(GpioPins { en, io0 }),
+        (None, None) => None,
+        _ => {
+            return Err(miette::miette!(
+                "Resetting through GPIOs needs both `--gpio-en` and `--gpio-io0`"
+            ))
+        }
+    };
+
+    Ok(OpenOptions { retry_window, gpio })
+}
+
+fn open_serial(port: &str, options: &OpenOptions) -> Result<Box<dyn Transport>> {
+    let serial = espflash::port::open(port, options.retry_window)
+        .wrap_err_with(|| format!("Failed to open serial port {}", port))?;
+    match options.gpio {
+        Some(pins) => Ok(Box::new(
+            GpioResetPort::new(serial, pins)
+                .into_diagnostic()
+                .wrap_err("Failed to set up the GPIOs resetting the chip")?,
+        )),
+        None => Ok(serial),
+    }
+}
+
+/// How long to retry opening the serial port for, while the device might still
+/// be enumerated
+fn retry_open(args: &mut Arguments, config: &Config) -> Result<Duration> {
+    let seconds = args
+        .opt_value_from_str::<_, f64>("--retry-open")
+        .into_diagnostic()?
+        .or(config.connection.retry_open)
+        .unwrap_or(DEFAULT_RETRY_OPEN);
+
+    Duration::try_from_secs_f64(seconds)
+        .into_diagnostic()
+        .wrap_err("Invalid `--retry-open` duration")
+}
+
+fn read_bootloader(path: Option<String>) -> Result<Option<Vec<u8>>> {
+    path.as_deref()
+        .map(read)
+        .transpose()
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open bootloader image \"{}\"", path.unwrap()))
+}
+
+fn read_partition_table(path: Option<String>) -> Result<Option<PartitionTable>> {
+    path.as_deref()
+        .map(|path| {
+            let table = read(path)?;
+            PartitionTable::try_from_data(table)
+        })
+        .transpose()
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to load partition table \"{}\"", path.unwrap()))
+}
+
+fn parse_u8(value: &str) -> Result<u8, std::num::ParseIntError> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+}
+
+fn parse_u32(value: &str) -> Result<u32, std::num::ParseIntError> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+}
@@ -0,0 +1,199 @@
+//! Records of what was flashed where
+//!
+//! A manifest lists the regions written to a device along with the MD5 digest
+//! of their contents. It is saved on the host when flashing, and can later be
+//! compared against the flash of a device to detect corruption or tampering,
+//! e.g. on units returned from the field.
+
+use serde::{Deserialize, Serialize};
+
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::report::FlashReport;
+use crate::{Error, Flasher};
+
+/// The regions flashed onto a device
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct FlashManifest {
+    /// Seconds since the unix epoch at which the device was flashed
+    pub timestamp: u64,
+    /// The chip type of the device
+    pub chip: String,
+    /// Factory MAC address, formatted as colon separated hex
+    pub mac: String,
+    /// The regions that were written
+    pub regions: Vec<ManifestRegion>,
+}
+
+/// A single region written to flash
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ManifestRegion {
+    /// Offset of the region in flash
+    pub offset: u32,
+    /// Size of the region in bytes
+    pub size: u32,
+    /// Hex encoded MD5 digest of the region contents
+    pub md5: String,
+}
+
+/// The result of comparing the flash of a device against a manifest
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AuditReport {
+    /// MAC address of the audited device
+    pub mac: String,
+    /// Whether the device is the one the manifest was recorded for
+    pub mac_matches: bool,
+    /// The regions of the manifest, in the same order
+    pub regions: Vec<RegionAudit>,
+}
+
+/// The result of comparing a single region
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RegionAudit {
+    /// The region as recorded in the manifest
+    pub expected: ManifestRegion,
+    /// Hex encoded MD5 digest of the region as currently in flash
+    pub md5: String,
+}
+
+impl FlashManifest {
+    /// Create a manifest for the regions written to the device with the given
+    /// MAC address
+    pub fn new(chip: impl ToString, mac: [u8; 6], report: &FlashReport) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+
+        FlashManifest {
+            timestamp,
+            chip: chip.to_string(),
+            mac: format_mac(mac),
+            regions: report
+                .regions
+                .iter()
+                .map(|region| ManifestRegion {
+                    offset: region.offset,
+                    size: region.size,
+                    md5: region.md5.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Load a manifest saved with [`save`]
+    ///
+    /// [`save`]: FlashManifest::save
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    /// Save the manifest as JSON
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut data = serde_json::to_vec_pretty(self)?;
+        data.push(b'\n');
+        fs::write(path, data)
+    }
+
+    /// Compare the flash of the connected device against the manifest
+    pub fn audit(&self, flasher: &mut Flasher) -> Result<AuditReport, Error> {
+        let mac = format_mac(flasher.mac_address()?);
+
+        let mut regions = Vec::with_capacity(self.regions.len());
+        for region in &self.regions {
+            let md5 = flasher.flash_md5(region.offset, region.size)?;
+            regions.push(RegionAudit {
+                expected: region.clone(),
+                md5: md5.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            });
+        }
+
+        Ok(AuditReport {
+            mac_matches: mac == self.mac,
+            mac,
+            regions,
+        })
+    }
+}
+
+impl AuditReport {
+    /// Whether the device and all regions match the manifest
+    pub fn is_ok(&self) -> bool {
+        self.mac_matches && self.regions.iter().all(RegionAudit::is_ok)
+    }
+}
+
+impl RegionAudit {
+    /// Whether the region is unchanged
+    pub fn is_ok(&self) -> bool {
+        self.md5 == self.expected.md5
+    }
+}
+
+impl Display for AuditReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if !self.mac_matches {
+            writeln!(
+                f,
+                "Device {} is not the device the manifest was recorded for",
+                self.mac
+            )?;
+        }
+
+        for region in &self.regions {
+            let expected = &region.expected;
+            if region.is_ok() {
+                writeln!(
+                    f,
+                    "{:#010x} {:>8} bytes  ok        {}",
+                    expected.offset, expected.size, region.md5
+                )?;
+            } else {
+                writeln!(
+                    f,
+                    "{:#010x} {:>8} bytes  MODIFIED  {} (expected {})",
+                    expected.offset, expected.size, region.md5, expected.md5
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn format_mac(mac: [u8; 6]) -> String {
+    mac.iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[test]
+fn test_audit_report() {
+    let expected = ManifestRegion {
+        offset: 0x10000,
+        size: 4,
+        md5: format!("{:x}", md5::compute(b"app!")),
+    };
+    let mut report = AuditReport {
+        mac: "24:0a:c4:00:00:01".into(),
+        mac_matches: true,
+        regions: vec![RegionAudit {
+            expected: expected.clone(),
+            md5: expected.md5.clone(),
+        }],
+    };
+    assert!(report.is_ok());
+
+    report.regions[0].md5 = format!("{:x}", md5::compute(b"app?"));
+    assert!(!report.is_ok());
+    assert!(report.to_string().contains("MODIFIED"));
+
+    report.regions[0].md5 = expected.md5;
+    report.mac_matches = false;
+    assert!(!report.is_ok());
+}
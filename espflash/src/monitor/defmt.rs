@@ -0,0 +1,451 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use xmas_elf::sections::SectionData;
+use xmas_elf::symbol_table::Entry;
+use xmas_elf::ElfFile;
+
+/// Bytes announcing a defmt frame in the serial output, as written by
+/// esp-println
+const FRAME_START: &[u8] = &[0xff, 0x00];
+/// Byte ending a defmt frame, rzCOBS encoded frames contain no zeros
+const FRAME_END: u8 = 0x00;
+
+/// Name of a symbol in the `.defmt` section, which holds its format string
+#[derive(Deserialize)]
+struct SymbolName {
+    tag: String,
+    data: String,
+}
+
+/// Format strings of the log statements, from the `.defmt` section of the ELF
+#[derive(Debug, Default)]
+pub struct Table {
+    entries: HashMap<u16, (String, String)>,
+    timestamp: Option<String>,
+}
+
+impl Table {
+    /// Read the table of the ELF, if it was built with defmt
+    pub fn new(data: &[u8]) -> Option<Self> {
+        let elf = ElfFile::new(data).ok()?;
+        let section = elf.find_section_by_name(".symtab")?;
+        let symbols = match section.get_data(&elf) {
+            Ok(SectionData::SymbolTable32(symbols)) => symbols,
+            _ => return None,
+        };
+
+        let mut table = Table::default();
+        for symbol in symbols {
+            let name = match symbol.get_name(&elf) {
+                Ok(name) if name.starts_with('{') => name,
+                _ => continue,
+            };
+            let name: SymbolName = match serde_json::from_str(name) {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            if name.tag == "defmt_timestamp" {
+                table.timestamp = Some(name.data);
+            } else if name.tag.starts_with("defmt_") {
+                table
+                    .entries
+                    .insert(symbol.value() as u16, (name.tag, name.data));
+            }
+        }
+
+        (!table.entries.is_empty()).then_some(table)
+    }
+
+    /// Render a decoded frame as a log line, `None` if it doesn't match the
+    /// table
+    fn render(&self, frame: &[u8]) -> Option<String> {
+        let mut reader = Reader { data: frame };
+        let (tag, format) = self.entries.get(&reader.u16()?)?;
+        let timestamp = match &self.timestamp {
+            Some(timestamp) => Some(self.format(timestamp, &mut reader)?),
+            None => None,
+        };
+        let message = self.format(format, &mut reader)?;
+
+        let level = match tag.as_str() {
+            "defmt_trace" => Some("TRACE"),
+            "defmt_debug" => Some("DEBUG"),
+            "defmt_info" => Some("INFO "),
+            "defmt_warn" => Some("WARN "),
+            "defmt_error" => Some("ERROR"),
+            _ => None,
+        };
+        Some(match (timestamp, level) {
+            (Some(timestamp), Some(level)) => format!("{} [{}] {}", timestamp, level, message),
+            (None, Some(level)) => format!("[{}] {}", level, message),
+            (Some(timestamp), None) => format!("{} {}", timestamp, message),
+            (None, None) => message,
+        })
+    }
+
+    /// Fill in the parameters of `format` with the values read from `reader`
+    fn format(&self, format: &str, reader: &mut Reader) -> Option<String> {
+        let segments = parse_format(format)?;
+
+        // values are encoded in the order of their parameter index, once each
+        let mut params: Vec<&Param> = segments
+            .iter()
+            .filter_map(|segment| match segment {
+                Segment::Param(param) => Some(param),
+                Segment::Literal(_) => None,
+            })
+            .collect();
+        params.sort_by_key(|param| param.index);
+        params.dedup_by_key(|param| param.index);
+
+        let mut values = HashMap::new();
+        for param in params {
+            values.insert(param.index, self.value(&param.ty, reader)?);
+        }
+
+        let mut text = String::new();
+        for segment in &segments {
+            match segment {
+                Segment::Literal(literal) => text.push_str(literal),
+                Segment::Param(param) => text.push_str(&values[&param.index].render(&param.hint)),
+            }
+        }
+
+        Some(text)
+    }
+
+    fn value(&self, ty: &str, reader: &mut Reader) -> Option<Value> {
+        Some(match ty {
+            "u8" => Value::UInt(reader.bytes(1)?[0] as u128),
+            "u16" => Value::UInt(reader.u16()? as u128),
+            "u32" => Value::UInt(reader.uint(4)?),
+            "u64" => Value::UInt(reader.uint(8)?),
+            "u128" => Value::UInt(reader.uint(16)?),
+            "i8" => Value::Int(reader.bytes(1)?[0] as i8 as i128),
+            "i16" => Value::Int(reader.uint(2)? as i16 as i128),
+            "i32" => Value::Int(reader.uint(4)? as i32 as i128),
+            "i64" => Value::Int(reader.uint(8)? as i64 as i128),
+            "i128" => Value::Int(reader.uint(16)? as i128),
+            "usize" => Value::UInt(reader.leb128()? as u128),
+            "isize" => {
+                let zigzag = reader.leb128()?;
+                Value::Int(((zigzag >> 1) as i64 ^ -((zigzag & 1) as i64)) as i128)
+            }
+            "f32" => Value::Float(f32::from_bits(reader.uint(4)? as u32) as f64),
+            "f64" => Value::Float(f64::from_bits(reader.uint(8)? as u64)),
+            "bool" => Value::Bool(reader.bytes(1)?[0] != 0),
+            "char" => Value::Char(char::from_u32(reader.uint(4)? as u32)?),
+            "str" | "__internal_Display" | "__internal_Debug" => {
+                let len = reader.leb128()? as usize;
+                Value::Str(String::from_utf8_lossy(reader.bytes(len)?).into_owned())
+            }
+            "istr" => Value::Str(self.entries.get(&reader.u16()?)?.1.clone()),
+            "[u8]" => {
+                let len = reader.leb128()? as usize;
+                Value::Bytes(reader.bytes(len)?.to_vec())
+            }
+            "?" => Value::Formatted(self.nested(reader)?),
+            "[?]" => {
+                let len = reader.leb128()?;
+                let items = (0..len)
+                    .map(|_| self.nested(reader))
+                    .collect::<Option<Vec<_>>>()?;
+                Value::Formatted(format!("[{}]", items.join(", ")))
+            }
+            ty => {
+                // byte arrays, `[u8; N]`
+                let len = ty.strip_prefix("[u8;")?.strip_suffix(']')?.trim();
+                Value::Bytes(reader.bytes(len.parse().ok()?)?.to_vec())
+            }
+        })
+    }
+
+    /// A value implementing `Format`, encoded as the index of its format
+    /// string followed by its fields
+    fn nested(&self, reader: &mut Reader) -> Option<String> {
+        let (_, format) = self.entries.get(&reader.u16()?)?;
+        self.format(format, reader)
+    }
+}
+
+/// Extracts defmt frames from the serial output, passing other output, like
+/// the bootloader messages, through unchanged
+pub struct Decoder {
+    table: Table,
+    frame: Option<Vec<u8>>,
+    pending: Vec<u8>,
+}
+
+impl Decoder {
+    pub fn new(table: Table) -> Self {
+        Decoder {
+            table,
+            frame: None,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Decode the received `data` into text
+    pub fn push(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for &byte in data {
+            match &mut self.frame {
+                Some(frame) if byte == FRAME_END => {
+                    let line = rzcobs_decode(frame)
+                        .and_then(|frame| self.table.render(&frame))
+                        .unwrap_or_else(|| "<malformed defmt frame>".to_string());
+                    out.extend_from_slice(line.as_bytes());
+                    out.push(b'\n');
+                    self.frame = None;
+                }
+                Some(frame) => frame.push(byte),
+                None => {
+                    self.pending.push(byte);
+                    if self.pending.ends_with(FRAME_START) {
+                        self.pending
+                            .truncate(self.pending.len() - FRAME_START.len());
+                        out.append(&mut self.pending);
+                        self.frame = Some(Vec::new());
+                    } else if !FRAME_START.starts_with(&self.pending[self.pending.len() - 1..]) {
+                        out.append(&mut self.pending);
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.data.len() {
+            return None;
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(bytes)
+    }
+
+    fn uint(&mut self, len: usize) -> Option<u128> {
+        let bytes = self.bytes(len)?;
+        Some(
+            bytes
+                .iter()
+                .rev()
+                .fold(0, |value, &byte| (value << 8) | byte as u128),
+        )
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.uint(2).map(|value| value as u16)
+    }
+
+    fn leb128(&mut self) -> Option<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.bytes(1)?[0];
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+}
+
+enum Value {
+    UInt(u128),
+    Int(i128),
+    Float(f64),
+    Bool(bool),
+    Char(char),
+    Str(String),
+    Bytes(Vec<u8>),
+    Formatted(String),
+}
+
+impl Value {
+    fn render(&self, hint: &str) -> String {
+        match (self, hint) {
+            (Value::UInt(value), "x") => format!("{:x}", value),
+            (Value::UInt(value), "#x") => format!("{:#x}", value),
+            (Value::UInt(value), "X") => format!("{:X}", value),
+            (Value::UInt(value), "#X") => format!("{:#X}", value),
+            (Value::UInt(value), "b") => format!("{:b}", value),
+            (Value::UInt(value), "#b") => format!("{:#b}", value),
+            (Value::UInt(value), "us") => format!("{}.{:06}", value / 1_000_000, value % 1_000_000),
+            (Value::UInt(value), "ms") => format!("{}.{:03}", value / 1_000, value % 1_000),
+            (Value::UInt(value), _) => value.to_string(),
+            (Value::Int(value), _) => value.to_string(),
+            (Value::Float(value), _) => value.to_string(),
+            (Value::Bool(value), _) => value.to_string(),
+            (Value::Char(value), "?") => format!("{:?}", value),
+            (Value::Char(value), _) => value.to_string(),
+            (Value::Str(value), "?") => format!("{:?}", value),
+            (Value::Str(value), _) => value.clone(),
+            (Value::Bytes(value), "x" | "#x") => format!("{:#04x?}", value),
+            (Value::Bytes(value), "a") => format!("b\"{}\"", value.escape_ascii()),
+            (Value::Bytes(value), _) => format!("{:?}", value),
+            (Value::Formatted(value), _) => value.clone(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Param(Param),
+}
+
+#[derive(Debug, PartialEq)]
+struct Param {
+    index: usize,
+    ty: String,
+    hint: String,
+}
+
+/// Split a defmt format string like `x={=u8:#x}` into literals and parameters
+fn parse_format(format: &str) -> Option<Vec<Segment>> {
+    let mut segments = Vec::new();
+    let mut literal = String::new();
+    let mut next_index = 0;
+    let mut chars = format.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '{' => {
+                let body: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                let (position, rest) = match body.find(['=', ':']) {
+                    Some(at) => body.split_at(at),
+                    None => (body.as_str(), ""),
+                };
+                let (ty, hint) = match rest.strip_prefix('=') {
+                    Some(rest) => rest.split_once(':').unwrap_or((rest, "")),
+                    None => ("?", rest.strip_prefix(':').unwrap_or("")),
+                };
+                let index = match position {
+                    "" => {
+                        next_index += 1;
+                        next_index - 1
+                    }
+                    position => position.parse().ok()?,
+                };
+
+                segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                segments.push(Segment::Param(Param {
+                    index,
+                    ty: ty.to_string(),
+                    hint: hint.to_string(),
+                }));
+            }
+            c => literal.push(c),
+        }
+    }
+    segments.push(Segment::Literal(literal));
+
+    Some(segments)
+}
+
+/// Decode a reverse zero-compressing COBS frame, as used by defmt
+///
+/// The encoding is read back to front: a byte below 0x80 is followed by seven
+/// bytes, with each set bit marking one of them as zero instead, `0x80 + n`
+/// by `n + 7` nonzero bytes and a zero, and `0xff` by 134 nonzero bytes. The
+/// last group may be padded with zeros.
+fn rzcobs_decode(data: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let mut data = data.iter().rev().copied();
+
+    while let Some(byte) = data.next() {
+        match byte {
+            0x00 => return None,
+            0x01..=0x7f => {
+                for bit in (0..7).rev() {
+                    if byte & (1 << bit) == 0 {
+                        decoded.push(data.next()?);
+                    } else {
+                        decoded.push(0);
+                    }
+                }
+            }
+            0x80..=0xfe => {
+                decoded.push(0);
+                for _ in 0..(byte & 0x7f) + 7 {
+                    decoded.push(data.next()?);
+                }
+            }
+            0xff => {
+                for _ in 0..134 {
+                    decoded.push(data.next()?);
+                }
+            }
+        }
+    }
+
+    decoded.reverse();
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rzcobs() {
+        assert_eq!(
+            rzcobs_decode(&[0x01, 0x02, 0x7a]).unwrap(),
+            vec![0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(rzcobs_decode(&[0x01, 0x00]), None);
+    }
+
+    #[test]
+    fn format() {
+        let mut table = Table::default();
+        table
+            .entries
+            .insert(1, ("defmt_info".into(), "x={=u8:#x}, {=str}{{}}".into()));
+        table
+            .entries
+            .insert(2, ("defmt_error".into(), "{=i16} {}".into()));
+        table
+            .entries
+            .insert(3, ("defmt_derived".into(), "Point {{ x: {=u8} }}".into()));
+
+        assert_eq!(
+            table.render(&[1, 0, 0x2a, 2, b'h', b'i', 0, 0]).unwrap(),
+            "[INFO ] x=0x2a, hi{}"
+        );
+        assert_eq!(
+            table.render(&[2, 0, 0xfe, 0xff, 3, 0, 7]).unwrap(),
+            "[ERROR] -2 Point { x: 7 }"
+        );
+        assert_eq!(table.render(&[4, 0]), None);
+    }
+
+    #[test]
+    fn decoder() {
+        let mut table = Table::default();
+        table
+            .entries
+            .insert(1, ("defmt_info".into(), "booted".into()));
+        let mut decoder = Decoder::new(table);
+
+        // a frame of index 1, encoded as [0x01, 0x7e]
+        let mut out = decoder.push(b"rst:0x1\n\xff\x00\x01");
+        out.extend(decoder.push(b"\x7e\x00"));
+        assert_eq!(String::from_utf8(out).unwrap(), "rst:0x1\n[INFO ] booted\n");
+    }
+}
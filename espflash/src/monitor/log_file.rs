@@ -0,0 +1,141 @@
+use std::fs::{rename, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Number of rotated files kept next to the log file
+const ROTATED_FILES: usize = 5;
+
+/// File the monitor output is appended to
+///
+/// With a maximum size, the file is renamed to `<path>.1` once it would grow
+/// past it, shifting older files up to `<path>.5`, and a new one is started.
+pub struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: Option<u64>,
+}
+
+impl LogFile {
+    pub fn open(path: &Path, max_size: Option<u64>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(LogFile {
+            path: path.to_path_buf(),
+            size: file.metadata()?.len(),
+            file,
+            max_size,
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..ROTATED_FILES).rev() {
+            match rename(self.rotated(n), self.rotated(n + 1)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        rename(&self.path, self.rotated(1))?;
+
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(max_size) = self.max_size {
+            if self.size > 0 && self.size + buf.len() as u64 > max_size {
+                self.rotate()?;
+            }
+        }
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Writes the monitor output to stdout and, if given, the log file
+pub struct Tee<W> {
+    pub out: W,
+    pub log_file: Option<LogFile>,
+}
+
+impl<W: Write> Write for Tee<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.out.write_all(buf)?;
+        if let Some(log_file) = &mut self.log_file {
+            log_file.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()?;
+        if let Some(log_file) = &mut self.log_file {
+            log_file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse a file size in bytes, with an optional `K`, `M` or `G` suffix
+pub fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let (digits, multiplier) = match size.char_indices().last()? {
+        (at, 'k' | 'K') => (&size[..at], 1 << 10),
+        (at, 'm' | 'M') => (&size[..at], 1 << 20),
+        (at, 'g' | 'G') => (&size[..at], 1 << 30),
+        _ => (size, 1),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("64K"), Some(64 * 1024));
+        assert_eq!(parse_size("10M"), Some(10 * 1024 * 1024));
+        assert_eq!(parse_size("1g"), Some(1 << 30));
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("ten"), None);
+    }
+
+    #[test]
+    fn rotation() {
+        let dir = std::env::temp_dir().join(format!("espflash-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("monitor.log");
+
+        let mut log_file = LogFile::open(&path, Some(8)).unwrap();
+        log_file.write_all(b"line 1\n").unwrap();
+        log_file.write_all(b"line 2\n").unwrap();
+        log_file.write_all(b"line 3\n").unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"line 3\n");
+        assert_eq!(
+            std::fs::read(dir.join("monitor.log.1")).unwrap(),
+            b"line 2\n"
+        );
+        assert_eq!(
+            std::fs::read(dir.join("monitor.log.2")).unwrap(),
+            b"line 1\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
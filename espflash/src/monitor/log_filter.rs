@@ -0,0 +1,103 @@
+use crate::error::Error;
+use std::str::FromStr;
+use strum_macros::EnumString;
+
+/// Level of an ESP-IDF log message, from the most to the least severe
+///
+/// `none` only appears in filters, silencing a tag completely.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, EnumString)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum LogLevel {
+    #[strum(serialize = "none", serialize = "n")]
+    None,
+    #[strum(serialize = "error", serialize = "e")]
+    Error,
+    #[strum(serialize = "warn", serialize = "w")]
+    Warn,
+    #[strum(serialize = "info", serialize = "i")]
+    Info,
+    #[strum(serialize = "debug", serialize = "d")]
+    Debug,
+    #[strum(serialize = "verbose", serialize = "v")]
+    Verbose,
+}
+
+impl LogLevel {
+    /// Color ESP-IDF uses for messages of this level
+    pub(super) fn color(self) -> Option<&'static str> {
+        match self {
+            LogLevel::Error => Some("\x1b[0;31m"),
+            LogLevel::Warn => Some("\x1b[0;33m"),
+            LogLevel::Info => Some("\x1b[0;32m"),
+            _ => None,
+        }
+    }
+}
+
+/// Which ESP-IDF log messages the monitor shows, by tag
+///
+/// Written as `tag=level` expressions separated by commas or spaces, like
+/// `wifi=warn,*=info`, where `*` sets the level of all other tags. Messages
+/// up to the level of their tag are shown, output not in the ESP-IDF log
+/// format always is.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogFilter {
+    default: LogLevel,
+    tags: Vec<(String, LogLevel)>,
+}
+
+impl LogFilter {
+    /// Whether a message of `level` logged under `tag` is shown
+    pub fn allows(&self, level: LogLevel, tag: &str) -> bool {
+        let max = self
+            .tags
+            .iter()
+            .rev()
+            .find(|(name, _)| name == tag)
+            .map_or(self.default, |(_, level)| *level);
+        level <= max
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = Error;
+
+    fn from_str(filter: &str) -> Result<Self, Self::Err> {
+        let mut parsed = LogFilter {
+            default: LogLevel::Verbose,
+            tags: Vec::new(),
+        };
+
+        for expression in filter.split([',', ' ']).filter(|e| !e.is_empty()) {
+            let invalid = || Error::InvalidLogFilter(expression.to_string());
+            let (tag, level) = expression.split_once('=').ok_or_else(invalid)?;
+            let level = level.parse().map_err(|_| invalid())?;
+            match tag {
+                "" => return Err(invalid()),
+                "*" => parsed.default = level,
+                tag => parsed.tags.push((tag.to_string(), level)),
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter() {
+        let filter: LogFilter = "wifi=warn, *=info,nvs=none".parse().unwrap();
+
+        assert!(filter.allows(LogLevel::Warn, "wifi"));
+        assert!(!filter.allows(LogLevel::Info, "wifi"));
+        assert!(filter.allows(LogLevel::Info, "main"));
+        assert!(!filter.allows(LogLevel::Debug, "main"));
+        assert!(!filter.allows(LogLevel::Error, "nvs"));
+
+        assert!("wifi".parse::<LogFilter>().is_err());
+        assert!("wifi=loud".parse::<LogFilter>().is_err());
+    }
+}
@@ -0,0 +1,180 @@
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A line of device output, decoded for structured output formats
+#[derive(Debug, PartialEq, Serialize)]
+pub struct LogLine {
+    /// Time the line started to arrive, in RFC 3339 format
+    pub timestamp: String,
+    /// The line as received, without line ending
+    pub raw: String,
+    /// Level of an ESP-IDF log message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub level: Option<&'static str>,
+    /// Tag of an ESP-IDF log message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    /// Text of an ESP-IDF log message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// Frames of a backtrace printed by the panic handler
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub backtrace: Vec<Frame>,
+}
+
+/// A single frame of a backtrace
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Frame {
+    pub pc: String,
+    pub sp: String,
+}
+
+impl LogLine {
+    pub fn parse(raw: String, received: SystemTime) -> Self {
+        let text = strip_ansi(&raw);
+
+        let mut line = LogLine {
+            timestamp: rfc3339(received),
+            raw,
+            level: None,
+            tag: None,
+            message: None,
+            backtrace: Vec::new(),
+        };
+
+        if let Some((level, tag, message)) = parse_log(&text) {
+            line.level = Some(level);
+            line.tag = Some(tag.to_string());
+            line.message = Some(message.to_string());
+        } else if let Some(frames) = text.trim().strip_prefix("Backtrace:") {
+            line.backtrace = frames
+                .split_whitespace()
+                .filter_map(|frame| {
+                    let (pc, sp) = frame.split_once(':')?;
+                    Some(Frame {
+                        pc: pc.to_string(),
+                        sp: sp.to_string(),
+                    })
+                })
+                .collect();
+        }
+
+        line
+    }
+}
+
+/// Parse an ESP-IDF log line like `I (320) wifi: connected` or
+/// `W (12:04:01.337) main: low memory`
+pub(super) fn parse_log(text: &str) -> Option<(&'static str, &str, &str)> {
+    let level = match text.get(..2)? {
+        "E " => "error",
+        "W " => "warn",
+        "I " => "info",
+        "D " => "debug",
+        "V " => "verbose",
+        _ => return None,
+    };
+    let rest = text[2..].strip_prefix('(')?;
+    let (_time, rest) = rest.split_once(") ")?;
+    let (tag, message) = rest.split_once(": ")?;
+
+    Some((level, tag, message))
+}
+
+/// Remove the color codes ESP-IDF wraps log messages in
+pub(super) fn strip_ansi(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // skip the parameters up to and including the final byte
+            if chars.next() == Some('[') {
+                for c in chars.by_ref() {
+                    if ('\x40'..='\x7e').contains(&c) {
+                        break;
+                    }
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+
+    stripped
+}
+
+pub(super) fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs_of_day = secs % 86400;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Convert days since the unix epoch to a date in the proleptic gregorian
+/// calendar, see http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn parse_log_line() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_634_000_000_123);
+        let line = LogLine::parse("\x1b[0;32mI (320) wifi: connected: yes\x1b[0m".into(), time);
+
+        assert_eq!("2021-10-12T00:53:20.123Z", line.timestamp);
+        assert_eq!(Some("info"), line.level);
+        assert_eq!(Some("wifi"), line.tag.as_deref());
+        assert_eq!(Some("connected: yes"), line.message.as_deref());
+    }
+
+    #[test]
+    fn parse_backtrace() {
+        let line = LogLine::parse(
+            "Backtrace:0x400d2f3e:0x3ffb5a70 0x400d1234:0x3ffb5a90 |<-CORRUPTED".into(),
+            UNIX_EPOCH,
+        );
+
+        assert_eq!("1970-01-01T00:00:00.000Z", line.timestamp);
+        assert_eq!(None, line.level);
+        assert_eq!(
+            vec![
+                Frame {
+                    pc: "0x400d2f3e".into(),
+                    sp: "0x3ffb5a70".into()
+                },
+                Frame {
+                    pc: "0x400d1234".into(),
+                    sp: "0x3ffb5a90".into()
+                }
+            ],
+            line.backtrace
+        );
+    }
+}
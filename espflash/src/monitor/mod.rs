@@ -0,0 +1,666 @@
+//! Serial monitor showing the output of the device
+//!
+//! Keys typed are sent to the device, `CTRL+R` resets the chip and `CTRL+C`,
+//! or the configured exit key, quits.
+
+mod defmt;
+mod line_endings;
+mod log_file;
+mod log_filter;
+mod log_line;
+mod server;
+mod symbols;
+
+use crate::coredump::{self, CoreDump};
+use crate::error::{ConnectionError, Error};
+use crate::port::hard_reset;
+use crate::Transport;
+use crossterm::event::{poll, read, Event, KeyCode, KeyEvent, KeyModifiers};
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
+use line_endings::normalized;
+pub use log_file::parse_size;
+use log_file::{LogFile, Tee};
+pub use log_filter::{LogFilter, LogLevel};
+use log_line::{parse_log, rfc3339, strip_ansi};
+pub use log_line::{Frame, LogLine};
+use server::Server;
+pub use server::{ServeAddress, ServeProtocol};
+use std::io::{stdout, ErrorKind, IsTerminal, Read, Write};
+use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::{Duration, SystemTime};
+use strum_macros::{Display, EnumString};
+pub use symbols::{Symbol, Symbols};
+
+/// Format in which the device output is written
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OutputFormat {
+    /// The output as received
+    Text,
+    /// One JSON object per line, see [`LogLine`]
+    JsonLines,
+}
+
+/// Encoding of the logs the device prints
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LogFormat {
+    /// Plain text, as printed by ESP-IDF and esp-println
+    Serial,
+    /// defmt frames, decoded with the table of the ELF running on the device
+    Defmt,
+}
+
+/// How typed keys are sent to the device
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Display, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum InputMode {
+    /// Each key as soon as it is typed, for consoles with line editing
+    #[default]
+    Raw,
+    /// Whole lines once `Enter` is pressed, edited and echoed locally
+    Line,
+}
+
+/// Line ending sent for the `Enter` key
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Display, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum LineEnding {
+    #[default]
+    Cr,
+    Lf,
+    #[strum(serialize = "crlf")]
+    CrLf,
+}
+
+impl LineEnding {
+    fn as_bytes(self) -> &'static [u8] {
+        match self {
+            LineEnding::Cr => b"\r",
+            LineEnding::Lf => b"\n",
+            LineEnding::CrLf => b"\r\n",
+        }
+    }
+}
+
+/// Settings of the serial monitor
+#[derive(Clone, Debug)]
+pub struct MonitorOptions {
+    pub format: OutputFormat,
+    pub log_format: LogFormat,
+    /// Baud rate the application prints at
+    pub baud: usize,
+    /// Baud rate to show the boot messages at, switching to `baud` once the
+    /// application starts printing
+    pub boot_baud: Option<usize>,
+    /// Key quitting the monitor together with `CTRL`
+    pub exit_key: char,
+    /// Reset the chip when the monitor starts, to see the output from boot
+    pub reset: bool,
+    /// Prefix each line with the time it was received
+    pub timestamps: bool,
+    /// File the output is appended to as well
+    pub log_file: Option<PathBuf>,
+    /// Size after which the log file is rotated
+    pub log_file_size: Option<u64>,
+    /// ESP-IDF log messages shown, all if not given
+    pub log_filter: Option<LogFilter>,
+    pub input_mode: InputMode,
+    pub line_ending: LineEnding,
+    /// Address to share the serial port on over TCP
+    pub serve: Option<ServeAddress>,
+    /// Serial port to reopen when the device drops off the bus, as the USB
+    /// CDC port of an application does while it resets
+    pub reopen: Option<String>,
+}
+
+impl Default for MonitorOptions {
+    fn default() -> Self {
+        MonitorOptions {
+            format: OutputFormat::Text,
+            log_format: LogFormat::Serial,
+            baud: 115_200,
+            boot_baud: None,
+            exit_key: 'c',
+            reset: false,
+            timestamps: false,
+            log_file: None,
+            log_file_size: None,
+            log_filter: None,
+            input_mode: InputMode::Raw,
+            line_ending: LineEnding::Cr,
+            serve: None,
+            reopen: None,
+        }
+    }
+}
+
+impl MonitorOptions {
+    /// Parse the key quitting the monitor, a single letter other than `r`
+    pub fn parse_exit_key(key: &str) -> Result<char, Error> {
+        let mut chars = key.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii_alphabetic() && !c.eq_ignore_ascii_case(&'r') => {
+                Ok(c.to_ascii_lowercase())
+            }
+            _ => Err(Error::InvalidExitKey(key.to_string())),
+        }
+    }
+}
+
+/// Collects device output into lines and writes complete lines
+///
+/// Writing whole lines keeps multi-byte characters split across reads intact.
+/// A partial line, like a prompt, is written once no more data arrives.
+/// Addresses in complete lines are resolved to the functions they are in.
+/// ESP-IDF log messages are filtered, and colored if ESP-IDF didn't already.
+/// Core dumps printed by ESP-IDF are saved instead of shown.
+#[derive(Default)]
+struct LineBuffer<'a> {
+    /// The base64 lines of the core dump being printed
+    coredump: Option<String>,
+    line: Vec<u8>,
+    received: Option<SystemTime>,
+    /// A partial line was written, so the rest gets no timestamp
+    continued: bool,
+    timestamps: bool,
+    colors: bool,
+    filter: Option<LogFilter>,
+    symbols: Option<Symbols<'a>>,
+}
+
+impl LineBuffer<'_> {
+    fn push(&mut self, data: &[u8], out: &mut impl Write) -> std::io::Result<()> {
+        for &byte in data {
+            self.received.get_or_insert_with(SystemTime::now);
+            self.line.push(byte);
+            if byte == b'\n' {
+                self.flush(out)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, out: &mut impl Write) -> std::io::Result<()> {
+        if !self.line.is_empty() {
+            let data: Vec<u8> = normalized(self.line.drain(..)).collect();
+            let mut text = String::from_utf8_lossy(&data);
+            let received = self.received.take().unwrap_or_else(SystemTime::now);
+            if let (Some(dump), true) = (&mut self.coredump, text.ends_with('\n')) {
+                if !text.contains(coredump::UART_END) {
+                    dump.push_str(text.trim());
+                    return Ok(());
+                }
+                let dump = CoreDump::from_base64(&self.coredump.take().unwrap());
+                return write!(out, "{}\r\n", save_coredump(dump));
+            }
+            if text.contains(coredump::UART_START) {
+                self.coredump = Some(String::new());
+            }
+            if let (Some((level, tag)), false) = (log_entry(&text), self.continued) {
+                if !self
+                    .filter
+                    .as_ref()
+                    .is_none_or(|filter| filter.allows(level, &tag))
+                {
+                    return Ok(());
+                }
+                if let (Some(color), true) = (level.color(), self.colors && !text.contains('\x1b'))
+                {
+                    text = format!("{}{}\x1b[0m\r\n", color, text.trim_end()).into();
+                }
+            }
+            if self.timestamps && !self.continued {
+                out.write_all(format!("[{}] {}", rfc3339(received), text).as_bytes())?;
+            } else {
+                out.write_all(text.as_bytes())?;
+            }
+            self.continued = !text.ends_with('\n');
+            if let (Some(symbols), true) = (&self.symbols, text.ends_with('\n')) {
+                for line in symbols.describe(&text) {
+                    write!(out, "{}\r\n", line)?;
+                }
+            }
+            out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Collects device output into lines and writes each as a JSON object
+#[derive(Default)]
+struct LineEncoder {
+    line: Vec<u8>,
+    received: Option<SystemTime>,
+    filter: Option<LogFilter>,
+}
+
+impl LineEncoder {
+    fn push(&mut self, data: &[u8], out: &mut impl Write) -> std::io::Result<()> {
+        for &byte in data {
+            match byte {
+                b'\n' => {
+                    let raw = String::from_utf8_lossy(&self.line).into_owned();
+                    let received = self.received.take().unwrap_or_else(SystemTime::now);
+                    let line = LogLine::parse(raw, received);
+                    self.line.clear();
+
+                    let level = line.level.and_then(|level| level.parse().ok());
+                    if let (Some(filter), Some(level), Some(tag)) = (&self.filter, level, &line.tag)
+                    {
+                        if !filter.allows(level, tag) {
+                            continue;
+                        }
+                    }
+                    serde_json::to_writer(&mut *out, &line)?;
+                    out.write_all(b"\n")?;
+                }
+                b'\r' => {}
+                _ => {
+                    self.received.get_or_insert_with(SystemTime::now);
+                    self.line.push(byte);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Save a core dump printed by the device to the current directory, returning
+/// the message to show
+fn save_coredump(dump: Result<CoreDump, crate::error::CoreDumpError>) -> String {
+    let dump = match dump {
+        Ok(dump) => dump,
+        Err(err) => return format!("Failed to read the core dump: {}", err),
+    };
+    let secs = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let path = PathBuf::from(format!("coredump-{}.{}", secs, dump.extension()));
+
+    match dump.save(&path) {
+        Ok(()) if dump.is_elf() => format!("Core dump saved to {}", path.display()),
+        Ok(()) => format!(
+            "Core dump saved to {}, decode it with `espcoredump.py info_corefile --core-format raw`",
+            path.display()
+        ),
+        Err(err) => format!("Failed to save the core dump: {}", err),
+    }
+}
+
+/// Whether `data` is plain ASCII text, as the boot messages are
+fn is_text(data: &[u8]) -> bool {
+    data.iter()
+        .all(|&byte| matches!(byte, b'\t' | b'\n' | b'\r' | 0x1b | 0x20..=0x7e))
+}
+
+/// Level and tag of an ESP-IDF log line
+fn log_entry(text: &str) -> Option<(LogLevel, String)> {
+    let text = strip_ansi(text);
+    let (level, tag, _) = parse_log(&text)?;
+    Some((level.parse().ok()?, tag.to_string()))
+}
+
+/// Collects typed keys into a line in [`InputMode::Line`], echoing them
+///
+/// Returns the bytes to send to the device: the line once `Enter` is pressed,
+/// or control characters right away.
+fn handle_line_input(
+    line: &mut String,
+    key: KeyEvent,
+    line_ending: LineEnding,
+    echo: &mut impl Write,
+) -> std::io::Result<Option<Vec<u8>>> {
+    match key.code {
+        KeyCode::Char(_) if key.modifiers.contains(KeyModifiers::CONTROL) => {
+            return Ok(handle_key_event(key))
+        }
+        KeyCode::Char(c) => {
+            line.push(c);
+            write!(echo, "{}", c)?;
+        }
+        KeyCode::Backspace if line.pop().is_some() => echo.write_all(b"\x08 \x08")?,
+        KeyCode::Enter => {
+            echo.write_all(b"\r\n")?;
+            echo.flush()?;
+            let mut bytes = std::mem::take(line).into_bytes();
+            bytes.extend_from_slice(line_ending.as_bytes());
+            return Ok(Some(bytes));
+        }
+        _ => {}
+    }
+    echo.flush()?;
+    Ok(None)
+}
+
+/// Converts key events from crossterm into appropriate character/escape sequences which are then
+/// sent over the serial connection.
+///
+/// Adapted from https://github.com/dhylands/serial-monitor
+fn handle_key_event(key_event: KeyEvent) -> Option<Vec<u8>> {
+    // The following escape sequences come from the MicroPython codebase.
+    //
+    //  Up      ESC [A
+    //  Down    ESC [B
+    //  Right   ESC [C
+    //  Left    ESC [D
+    //  Home    ESC [H  or ESC [1~
+    //  End     ESC [F  or ESC [4~
+    //  Del     ESC [3~
+    //  Insert  ESC [2~
+
+    let mut buf = [0; 4];
+
+    let key_str: Option<&[u8]> = match key_event.code {
+        KeyCode::Backspace => Some(b"\x08"),
+        KeyCode::Enter => Some(b"\r"),
+        KeyCode::Left => Some(b"\x1b[D"),
+        KeyCode::Right => Some(b"\x1b[C"),
+        KeyCode::Home => Some(b"\x1b[H"),
+        KeyCode::End => Some(b"\x1b[F"),
+        KeyCode::Up => Some(b"\x1b[A"),
+        KeyCode::Down => Some(b"\x1b[B"),
+        KeyCode::Tab => Some(b"\x09"),
+        KeyCode::Delete => Some(b"\x1b[3~"),
+        KeyCode::Insert => Some(b"\x1b[2~"),
+        KeyCode::Esc => Some(b"\x1b"),
+        KeyCode::Char(ch) => {
+            if key_event.modifiers & KeyModifiers::CONTROL == KeyModifiers::CONTROL {
+                buf[0] = ch as u8;
+                if ch.is_ascii_lowercase() || (ch == ' ') {
+                    buf[0] &= 0x1f;
+                    Some(&buf[0..1])
+                } else if ('4'..='7').contains(&ch) {
+                    // crossterm returns Control-4 thru 7 for \x1c thru \x1f
+                    buf[0] = (buf[0] + 8) & 0x1f;
+                    Some(&buf[0..1])
+                } else {
+                    Some(ch.encode_utf8(&mut buf).as_bytes())
+                }
+            } else {
+                Some(ch.encode_utf8(&mut buf).as_bytes())
+            }
+        }
+        _ => None,
+    };
+    key_str.map(|slice| slice.into())
+}
+
+struct RawModeGuard;
+
+impl RawModeGuard {
+    pub fn new() -> std::io::Result<Self> {
+        enable_raw_mode()?;
+        Ok(RawModeGuard)
+    }
+}
+
+impl Drop for RawModeGuard {
+    fn drop(&mut self) {
+        if let Err(e) = disable_raw_mode() {
+            eprintln!("{:#}", e)
+        }
+    }
+}
+
+/// Show the output of the device on `serial` until the exit key is pressed
+///
+/// With the ELF running on the device, code addresses in backtraces and panic
+/// dumps are resolved to the functions and source lines they point into.
+pub fn monitor(
+    mut serial: Box<dyn Transport>,
+    options: &MonitorOptions,
+    elf: Option<&[u8]>,
+) -> serial::Result<()> {
+    let exit_key = options.exit_key.to_ascii_lowercase();
+
+    // keep the output parseable in structured formats
+    if options.format == OutputFormat::Text {
+        println!("Commands:");
+        println!("    CTRL+R    Reset chip");
+        println!("    CTRL+{}    Exit", exit_key.to_ascii_uppercase());
+        println!();
+    }
+
+    let mut buff = [0; 128];
+    serial.set_timeout(Duration::from_millis(5))?;
+    let mut boot_baud = options.boot_baud;
+    let mut booted = false;
+    set_baud(serial.as_mut(), boot_baud.unwrap_or(options.baud))?;
+    if options.reset {
+        reset(serial.as_mut())?;
+    }
+
+    let mut server = match &options.serve {
+        Some(address) => Some(Server::bind(address, options.baud)?),
+        None => None,
+    };
+
+    let _raw_mode = RawModeGuard::new();
+    let stdout = stdout();
+    let mut stdout = Tee {
+        out: stdout.lock(),
+        log_file: match &options.log_file {
+            Some(path) => Some(LogFile::open(path, options.log_file_size)?),
+            None => None,
+        },
+    };
+    let mut lines = LineBuffer {
+        timestamps: options.timestamps,
+        colors: stdout.out.is_terminal(),
+        filter: options.log_filter.clone(),
+        symbols: elf.and_then(|elf| Symbols::new(elf).ok()),
+        ..LineBuffer::default()
+    };
+    let mut encoder = LineEncoder {
+        filter: options.log_filter.clone(),
+        ..LineEncoder::default()
+    };
+    let mut input = String::new();
+    let mut defmt = match options.log_format {
+        LogFormat::Defmt => match elf.and_then(defmt::Table::new) {
+            Some(table) => Some(defmt::Decoder::new(table)),
+            None => {
+                log::warn!("The ELF contains no defmt table, showing the output as text");
+                None
+            }
+        },
+        LogFormat::Serial => None,
+    };
+    loop {
+        let read_count = match serial.read(&mut buff) {
+            Ok(count) => count,
+            Err(e) if e.kind() == ErrorKind::TimedOut => 0,
+            Err(e) => match &options.reopen {
+                Some(port) => {
+                    log::debug!("Reading from {} failed: {}", port, e);
+                    match reopen(port, options.baud, exit_key)? {
+                        Some(reopened) => serial = reopened,
+                        None => break,
+                    }
+                    boot_baud = None;
+                    continue;
+                }
+                None => return Err(e.into()),
+            },
+        };
+        // output of the application at the wrong baud rate reads as garbage,
+        // once the boot messages started, as resetting can cause a stray byte
+        if boot_baud.is_some() && booted && !is_text(&buff[0..read_count]) {
+            log::debug!("Switching the monitor to {} baud", options.baud);
+            set_baud(serial.as_mut(), options.baud)?;
+            boot_baud = None;
+            continue;
+        }
+        booted |= buff[0..read_count].contains(&b'\n');
+        if let Some(server) = &mut server {
+            server.send(&buff[0..read_count]);
+            server.poll(serial.as_mut())?;
+        }
+
+        let decoded;
+        let data = match &mut defmt {
+            Some(decoder) => {
+                decoded = decoder.push(&buff[0..read_count]);
+                &decoded[..]
+            }
+            None => &buff[0..read_count],
+        };
+        match options.format {
+            OutputFormat::Text if read_count > 0 => lines.push(data, &mut stdout)?,
+            OutputFormat::Text => lines.flush(&mut stdout)?,
+            OutputFormat::JsonLines => {
+                encoder.push(data, &mut stdout)?;
+                stdout.flush()?;
+            }
+        }
+        if poll(Duration::from_secs(0))? {
+            if let Event::Key(key) = read()? {
+                if key.modifiers.contains(KeyModifiers::CONTROL) {
+                    match key.code {
+                        KeyCode::Char('c') => break,
+                        KeyCode::Char(key) if key == exit_key => break,
+                        KeyCode::Char('r') => {
+                            reset(serial.as_mut())?;
+                            continue;
+                        }
+                        _ => {}
+                    }
+                }
+                let bytes = match (options.input_mode, key.code) {
+                    (InputMode::Line, _) => {
+                        handle_line_input(&mut input, key, options.line_ending, &mut stdout.out)?
+                    }
+                    (InputMode::Raw, KeyCode::Enter) => {
+                        Some(options.line_ending.as_bytes().to_vec())
+                    }
+                    (InputMode::Raw, _) => handle_key_event(key),
+                };
+                if let Some(bytes) = bytes {
+                    serial.write_all(&bytes)?;
+                    serial.flush()?;
+                }
+            }
+        }
+    }
+    lines.flush(&mut stdout)?;
+    Ok(())
+}
+
+/// Set the baud rate, unless the port doesn't allow changing it, like those of
+/// raw TCP serial servers
+fn set_baud(serial: &mut dyn Transport, baud: usize) -> std::io::Result<()> {
+    match serial.set_baud(baud) {
+        Err(err) if err.kind() == ErrorKind::Unsupported => {
+            log::warn!("{}", err);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Wait for `port` to reappear after the device dropped off the bus, returning
+/// `None` if the monitor is quit meanwhile
+fn reopen(port: &str, baud: usize, exit_key: char) -> serial::Result<Option<Box<dyn Transport>>> {
+    log::warn!("{} disappeared, waiting for it to reappear", port);
+    loop {
+        if poll(Duration::from_secs(0))? {
+            if let Event::Key(key) = read()? {
+                if key.modifiers.contains(KeyModifiers::CONTROL)
+                    && matches!(key.code, KeyCode::Char(c) if c == 'c' || c == exit_key)
+                {
+                    return Ok(None);
+                }
+            }
+        }
+
+        match crate::port::open(port, Duration::from_millis(500)) {
+            Ok(mut serial) => {
+                serial.set_timeout(Duration::from_millis(5))?;
+                set_baud(serial.as_mut(), baud)?;
+                log::info!("Reopened {}", port);
+                return Ok(Some(serial));
+            }
+            Err(_) => sleep(Duration::from_millis(100)),
+        }
+    }
+}
+
+fn reset(serial: &mut dyn Transport) -> serial::Result<()> {
+    hard_reset(serial, false).map_err(|err| match err {
+        Error::Connection(ConnectionError::Serial(err)) => err.into(),
+        err => serial::Error::new(serial::ErrorKind::Io(ErrorKind::Other), err.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_buffer() {
+        let mut out = Vec::new();
+        let mut lines = LineBuffer::default();
+        lines.push(b"boot: \xe2\x9c", &mut out).unwrap();
+        assert!(out.is_empty());
+        lines.push(b"\x93\nprompt> ", &mut out).unwrap();
+        assert_eq!(out, "boot: \u{2713}\r\n".as_bytes());
+        lines.flush(&mut out).unwrap();
+        assert_eq!(out, "boot: \u{2713}\r\nprompt> ".as_bytes());
+    }
+
+    #[test]
+    fn filtered_lines() {
+        let mut out = Vec::new();
+        let mut lines = LineBuffer {
+            colors: true,
+            filter: Some("wifi=warn".parse().unwrap()),
+            ..LineBuffer::default()
+        };
+        lines
+            .push(
+                b"I (320) wifi: connected\nW (321) wifi: weak signal\nready\n",
+                &mut out,
+            )
+            .unwrap();
+        assert_eq!(
+            out,
+            b"\x1b[0;33mW (321) wifi: weak signal\x1b[0m\r\nready\r\n"
+        );
+    }
+
+    #[test]
+    fn line_input() {
+        let key = |code| KeyEvent::new(code, KeyModifiers::NONE);
+        let mut echo = Vec::new();
+        let mut line = String::new();
+
+        for code in [
+            KeyCode::Char('l'),
+            KeyCode::Char('x'),
+            KeyCode::Backspace,
+            KeyCode::Char('s'),
+        ] {
+            let sent = handle_line_input(&mut line, key(code), LineEnding::CrLf, &mut echo);
+            assert_eq!(sent.unwrap(), None);
+        }
+        let sent = handle_line_input(&mut line, key(KeyCode::Enter), LineEnding::CrLf, &mut echo);
+        assert_eq!(sent.unwrap(), Some(b"ls\r\n".to_vec()));
+        assert_eq!(echo, b"lx\x08 \x08s\r\n");
+    }
+
+    #[test]
+    fn boot_text() {
+        assert!(is_text(
+            b" ets Jan  8 2013,rst cause:2, boot mode:(3,6)\r\n"
+        ));
+        assert!(!is_text(b"\x00\xe0\x8c\x1c\xfc"));
+    }
+
+    #[test]
+    fn exit_key() {
+        assert_eq!(MonitorOptions::parse_exit_key("X").unwrap(), 'x');
+        assert!(MonitorOptions::parse_exit_key("r").is_err());
+        assert!(MonitorOptions::parse_exit_key("ab").is_err());
+    }
+}
@@ -0,0 +1,260 @@
+//! Sharing the serial port of the monitor over TCP
+//!
+//! Clients receive the output of the device and whatever they send is written
+//! to it. With RFC2217, the data is wrapped in telnet and clients can change
+//! the baud rate, framing and control lines of the port, as tools like
+//! pyserial's `rfc2217://` ports do.
+
+use crate::connection::Transport;
+use crate::error::Error;
+use crate::rfc2217::*;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::str::FromStr;
+
+/// Protocol the serial port is shared with
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ServeProtocol {
+    /// The bytes as they are, like `ser2net` in raw mode
+    Raw,
+    /// Telnet with the COM port control option of RFC2217
+    Rfc2217,
+}
+
+/// Address the serial port is shared on, written `host:port` or
+/// `tcp://host:port` for raw TCP and `rfc2217://host:port` for RFC2217
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServeAddress {
+    pub protocol: ServeProtocol,
+    pub address: String,
+}
+
+impl FromStr for ServeAddress {
+    type Err = Error;
+
+    fn from_str(address: &str) -> Result<Self, Self::Err> {
+        let (protocol, host) = match address.split_once("://") {
+            Some(("tcp", host)) => (ServeProtocol::Raw, host),
+            Some(("rfc2217", host)) => (ServeProtocol::Rfc2217, host),
+            Some(_) => return Err(Error::InvalidServeAddress(address.to_string())),
+            None => (ServeProtocol::Raw, address),
+        };
+        if !host.contains(':') {
+            return Err(Error::InvalidServeAddress(address.to_string()));
+        }
+
+        Ok(ServeAddress {
+            protocol,
+            address: host.to_string(),
+        })
+    }
+}
+
+pub(super) struct Server {
+    listener: TcpListener,
+    protocol: ServeProtocol,
+    clients: Vec<Client>,
+    baud: u32,
+    dtr: bool,
+    rts: bool,
+}
+
+struct Client {
+    stream: TcpStream,
+    telnet: Option<Telnet>,
+}
+
+impl Server {
+    pub(super) fn bind(address: &ServeAddress, baud: usize) -> io::Result<Self> {
+        let listener = TcpListener::bind(&address.address)?;
+        listener.set_nonblocking(true)?;
+        log::info!("Serving the serial port on {}", listener.local_addr()?);
+
+        Ok(Server {
+            listener,
+            protocol: address.protocol,
+            clients: Vec::new(),
+            baud: baud as u32,
+            dtr: false,
+            rts: false,
+        })
+    }
+
+    /// Accept new clients and write the data they sent to `serial`
+    pub(super) fn poll(&mut self, serial: &mut dyn Transport) -> io::Result<()> {
+        while let Ok((stream, peer)) = self.listener.accept() {
+            if let Some(client) = self.connect(stream) {
+                log::info!("{} connected to the serial port", peer);
+                self.clients.push(client);
+            }
+        }
+
+        let mut buf = [0; 256];
+        let mut i = 0;
+        while i < self.clients.len() {
+            let count = match self.clients[i].stream.read(&mut buf) {
+                Ok(0) => None,
+                Ok(count) => Some(count),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => Some(0),
+                Err(_) => None,
+            };
+            let count = match count {
+                Some(count) => count,
+                None => {
+                    self.disconnect(i);
+                    continue;
+                }
+            };
+            if count > 0 {
+                let (data, reply) = self.receive(i, serial, &buf[..count])?;
+                serial.write_all(&data)?;
+                serial.flush()?;
+                if self.clients[i].stream.write_all(&reply).is_err() {
+                    self.disconnect(i);
+                    continue;
+                }
+            }
+            i += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Send the output of the device to all clients
+    pub(super) fn send(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+
+        let mut escaped = Vec::new();
+        let mut i = 0;
+        while i < self.clients.len() {
+            let data = match self.clients[i].telnet {
+                Some(_) => {
+                    if escaped.is_empty() {
+                        escaped = escape(data);
+                    }
+                    &escaped[..]
+                }
+                None => data,
+            };
+            match self.clients[i].stream.write_all(data) {
+                Ok(()) => i += 1,
+                Err(_) => self.disconnect(i),
+            }
+        }
+    }
+
+    fn connect(&mut self, mut stream: TcpStream) -> Option<Client> {
+        stream.set_nonblocking(true).ok()?;
+        stream.set_nodelay(true).ok()?;
+        let telnet = match self.protocol {
+            ServeProtocol::Raw => None,
+            ServeProtocol::Rfc2217 => {
+                let (telnet, offer) = Telnet::new(&[SGA, COM_PORT_OPTION], &[]);
+                stream.write_all(&offer).ok()?;
+                Some(telnet)
+            }
+        };
+
+        Some(Client { stream, telnet })
+    }
+
+    fn disconnect(&mut self, i: usize) {
+        let client = self.clients.remove(i);
+        if let Ok(peer) = client.stream.peer_addr() {
+            log::info!("{} disconnected from the serial port", peer);
+        }
+    }
+
+    /// Split what client `i` sent into the data for the device and the reply
+    /// to the telnet commands in it, which are applied to `serial`
+    fn receive(
+        &mut self,
+        i: usize,
+        serial: &mut dyn Transport,
+        input: &[u8],
+    ) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let telnet = match &mut self.clients[i].telnet {
+            Some(telnet) => telnet,
+            None => return Ok((input.to_vec(), Vec::new())),
+        };
+
+        let mut data = Vec::new();
+        let mut reply = Vec::new();
+        let mut commands = Vec::new();
+        telnet.receive(input, &mut data, &mut reply, &mut commands);
+        for (command, value) in commands {
+            if let Some(answer) = self.com_port_option(serial, command, &value)? {
+                reply.extend(com_port_command(command + SERVER_OFFSET, &answer));
+            }
+        }
+
+        Ok((data, reply))
+    }
+
+    /// Apply a COM port control command, returning the value to answer with
+    ///
+    /// The port always uses 8 data bits, no parity, one stop bit and no flow
+    /// control, which is what requests to change them are answered with.
+    fn com_port_option(
+        &mut self,
+        serial: &mut dyn Transport,
+        command: u8,
+        value: &[u8],
+    ) -> io::Result<Option<Vec<u8>>> {
+        let answer = match (command, value) {
+            (SIGNATURE, _) => b"espflash".to_vec(),
+            (SET_BAUDRATE, &[a, b, c, d]) => {
+                let baud = u32::from_be_bytes([a, b, c, d]);
+                if baud != 0 {
+                    serial.set_baud(baud as usize)?;
+                    self.baud = baud;
+                }
+                self.baud.to_be_bytes().to_vec()
+            }
+            (SET_DATASIZE, &[_]) => vec![8],
+            (SET_PARITY | SET_STOPSIZE, &[_]) => vec![1],
+            (SET_CONTROL, &[control]) => {
+                let answer = match control {
+                    0 | 1 => 1,
+                    7 => 9 - self.dtr as u8,
+                    8 | 9 => {
+                        self.dtr = control == 8;
+                        serial.set_dtr(self.dtr)?;
+                        control
+                    }
+                    10 => 12 - self.rts as u8,
+                    11 | 12 => {
+                        self.rts = control == 11;
+                        serial.set_rts(self.rts)?;
+                        control
+                    }
+                    _ => return Ok(None),
+                };
+                vec![answer]
+            }
+            (SET_LINESTATE_MASK | SET_MODEMSTATE_MASK | PURGE_DATA, &[value]) => vec![value],
+            _ => return Ok(None),
+        };
+
+        Ok(Some(answer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses() {
+        let address: ServeAddress = "rfc2217://0.0.0.0:4000".parse().unwrap();
+        assert_eq!(address.protocol, ServeProtocol::Rfc2217);
+        assert_eq!(address.address, "0.0.0.0:4000");
+        let address: ServeAddress = "localhost:4000".parse().unwrap();
+        assert_eq!(address.protocol, ServeProtocol::Raw);
+
+        assert!("ftp://localhost:21".parse::<ServeAddress>().is_err());
+        assert!("4000".parse::<ServeAddress>().is_err());
+    }
+}
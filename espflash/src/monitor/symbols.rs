@@ -0,0 +1,157 @@
+use addr2line::gimli::{Dwarf, EndianSlice, LittleEndian, SectionId};
+use addr2line::Context;
+use xmas_elf::sections::SectionData;
+use xmas_elf::symbol_table::{Entry, Type};
+use xmas_elf::ElfFile;
+
+type Reader<'a> = EndianSlice<'a, LittleEndian>;
+
+/// A function an address resolved to
+#[derive(Debug, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    /// File and line, if the ELF has debug info for the address
+    pub location: Option<(String, u32)>,
+}
+
+/// Resolves code addresses printed by the device against the flashed ELF
+///
+/// The DWARF debug info is used when present, listing inlined functions as
+/// well, otherwise addresses are looked up in the symbol table.
+pub struct Symbols<'a> {
+    elf: ElfFile<'a>,
+    context: Option<Context<Reader<'a>>>,
+}
+
+impl<'a> Symbols<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, &'static str> {
+        let elf = ElfFile::new(data)?;
+        let dwarf = Dwarf::load(|id: SectionId| -> Result<_, ()> {
+            let data = elf
+                .find_section_by_name(id.name())
+                .map_or(&[][..], |section| section.raw_data(&elf));
+            Ok(EndianSlice::new(data, LittleEndian))
+        })
+        .map_err(|_| "failed to read debug info")?;
+        let context = Context::from_dwarf(dwarf).ok();
+
+        Ok(Symbols { elf, context })
+    }
+
+    /// The functions containing `addr`, innermost inlined function first
+    pub fn resolve(&self, addr: u32) -> Vec<Symbol> {
+        let symbols = self.resolve_dwarf(addr);
+        if !symbols.is_empty() {
+            return symbols;
+        }
+
+        self.symbol_table_name(addr)
+            .map(|name| Symbol {
+                name,
+                location: None,
+            })
+            .into_iter()
+            .collect()
+    }
+
+    fn resolve_dwarf(&self, addr: u32) -> Vec<Symbol> {
+        let mut symbols = Vec::new();
+        let context = match &self.context {
+            Some(context) => context,
+            None => return symbols,
+        };
+        let mut frames = match context.find_frames(addr as u64).skip_all_loads() {
+            Ok(frames) => frames,
+            Err(_) => return symbols,
+        };
+
+        while let Ok(Some(frame)) = frames.next() {
+            let name = match frame.function.as_ref().map(|name| name.demangle()) {
+                Some(Ok(name)) => name.into_owned(),
+                _ => continue,
+            };
+            let location = frame
+                .location
+                .and_then(|location| Some((location.file?.to_string(), location.line?)));
+            symbols.push(Symbol { name, location });
+        }
+
+        symbols
+    }
+
+    fn symbol_table_name(&self, addr: u32) -> Option<String> {
+        let section = self.elf.find_section_by_name(".symtab")?;
+        let entries = match section.get_data(&self.elf) {
+            Ok(SectionData::SymbolTable32(entries)) => entries,
+            _ => return None,
+        };
+
+        entries
+            .iter()
+            .filter(|entry| entry.get_type() == Ok(Type::Func))
+            .find(|entry| {
+                let start = entry.value() as u32;
+                addr >= start && addr < start + entry.size().max(1) as u32
+            })
+            .and_then(|entry| entry.get_name(&self.elf).ok())
+            .map(|name| addr2line::demangle_auto(name.into(), None).into_owned())
+    }
+
+    /// Lines describing the addresses in `line` that resolve to a function, as
+    /// found in backtraces and register dumps
+    pub fn describe(&self, line: &str) -> Vec<String> {
+        let mut lines = Vec::new();
+        for addr in addresses(line) {
+            for symbol in self.resolve(addr) {
+                lines.push(format!("0x{:08x} - {}", addr, symbol.name));
+                if let Some((file, line)) = symbol.location {
+                    lines.push(format!("    at {}:{}", file, line));
+                }
+            }
+        }
+
+        lines
+    }
+}
+
+/// The code addresses mentioned in a line of device output
+///
+/// Code lives at `0x4xxxxxxx` on all supported chips, other values like stack
+/// pointers are skipped.
+fn addresses(line: &str) -> Vec<u32> {
+    let mut addresses: Vec<u32> = Vec::new();
+    for (start, _) in line.match_indices("0x") {
+        let digits = &line[start + 2..];
+        let len = digits
+            .bytes()
+            .take_while(|byte| byte.is_ascii_hexdigit())
+            .count();
+        if len != 8 || !digits.starts_with('4') {
+            continue;
+        }
+        if let Ok(addr) = u32::from_str_radix(&digits[..len], 16) {
+            if !addresses.contains(&addr) {
+                addresses.push(addr);
+            }
+        }
+    }
+
+    addresses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::addresses;
+
+    #[test]
+    fn finds_addresses() {
+        assert_eq!(
+            addresses("Backtrace:0x400d2f3e:0x3ffb5a70 0x400d2f3e:0x3ffb5a90 0x42000a1c:0x3fc8"),
+            vec![0x400d2f3e, 0x42000a1c]
+        );
+        assert_eq!(
+            addresses("PC      : 0x400d1234  PS      : 0x00060030"),
+            vec![0x400d1234]
+        );
+    }
+}
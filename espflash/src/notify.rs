@@ -0,0 +1,194 @@
+//! Notifications when long running operations finish
+//!
+//! Desktop notifications are shown using the notification tool of the
+//! platform (`notify-send`, `osascript` or PowerShell), webhooks are sent as a
+//! JSON `POST` request using `curl`, so neither requires any extra
+//! dependencies.
+
+use serde::Serialize;
+
+use std::io::{self, Write};
+use std::process::{Child, Command, Stdio};
+use std::time::Instant;
+
+/// Environment variable the notification text is passed in, which avoids
+/// having to quote it for the scripting languages of the platform
+const MESSAGE_VAR: &str = "ESPFLASH_NOTIFICATION";
+
+/// Maximum time to wait for a webhook to be delivered, in seconds
+const WEBHOOK_TIMEOUT: &str = "10";
+
+/// The body of the request sent to a webhook
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WebhookPayload<'a> {
+    /// Whether the operation succeeded
+    pub success: bool,
+    /// Description of the outcome of the operation
+    pub message: &'a str,
+    /// Time since the notifier was created, in seconds
+    pub elapsed_secs: f64,
+}
+
+/// Sends notifications about the outcome of an operation
+pub struct Notifier {
+    desktop: bool,
+    webhook: Option<String>,
+    started: Instant,
+}
+
+impl Notifier {
+    /// Create a notifier, showing a desktop notification if `desktop` is set
+    /// and posting to `webhook` if given
+    pub fn new(desktop: bool, webhook: Option<String>) -> Self {
+        Notifier {
+            desktop,
+            webhook,
+            started: Instant::now(),
+        }
+    }
+
+    /// Notify that an operation completed successfully
+    pub fn success(&self, message: &str) -> io::Result<()> {
+        self.send(true, message)
+    }
+
+    /// Notify that an operation failed
+    pub fn failure(&self, message: &str) -> io::Result<()> {
+        self.send(false, message)
+    }
+
+    fn send(&self, success: bool, message: &str) -> io::Result<()> {
+        let payload = WebhookPayload {
+            success,
+            message,
+            elapsed_secs: self.started.elapsed().as_secs_f64(),
+        };
+
+        // try both, even if the first one fails
+        let desktop = if self.desktop {
+            desktop_notification(&payload)
+        } else {
+            Ok(())
+        };
+        let webhook = match &self.webhook {
+            Some(url) => post_webhook(url, &payload),
+            None => Ok(()),
+        };
+
+        // only one error is returned, so make sure the other one isn't lost
+        if let (Err(err), Err(_)) = (&desktop, &webhook) {
+            log::warn!("{}", err);
+        }
+
+        desktop.and(webhook)
+    }
+}
+
+fn desktop_notification(payload: &WebhookPayload) -> io::Result<()> {
+    let title = if payload.success {
+        "espflash finished"
+    } else {
+        "espflash failed"
+    };
+    let text = format!("{} ({:.0}s)", payload.message, payload.elapsed_secs);
+
+    let (program, mut command) = if cfg!(target_os = "macos") {
+        let mut command = Command::new("osascript");
+        command.arg("-e").arg(format!(
+            "display notification (system attribute \"{}\") with title \"{}\"",
+            MESSAGE_VAR, title
+        ));
+        ("osascript", command)
+    } else if cfg!(windows) {
+        let mut command = Command::new("powershell");
+        command.args(["-NoProfile", "-Command"]).arg(format!(
+            "Add-Type -AssemblyName System.Windows.Forms; \
+             $icon = New-Object System.Windows.Forms.NotifyIcon; \
+             $icon.Icon = [System.Drawing.SystemIcons]::Information; \
+             $icon.Visible = $true; \
+             $icon.ShowBalloonTip(10000, '{}', $env:{}, 'Info'); \
+             Start-Sleep -Seconds 1",
+            title, MESSAGE_VAR
+        ));
+        ("powershell", command)
+    } else {
+        let mut command = Command::new("notify-send");
+        command.args(["--app-name", "espflash", title, &text]);
+        ("notify-send", command)
+    };
+
+    command.env(MESSAGE_VAR, &text).stdout(Stdio::null());
+    let status = spawn(program, &mut command)?.wait()?;
+    check_status("notification", status)
+}
+
+fn post_webhook(url: &str, payload: &WebhookPayload) -> io::Result<()> {
+    let body = serde_json::to_vec(payload)?;
+
+    let mut command = Command::new("curl");
+    command
+        .args(["--fail", "--silent", "--show-error", "--max-time"])
+        .arg(WEBHOOK_TIMEOUT)
+        .args(["--header", "Content-Type: application/json"])
+        .args(["--data-binary", "@-"])
+        // the URL is user supplied, so it must not be taken for an option
+        .arg("--")
+        .arg(url)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null());
+    let mut child = spawn("curl", &mut command)?;
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(&body)?;
+
+    check_status("webhook", child.wait()?)
+}
+
+/// Start `command`, naming `program` in the error if it can't be run, which
+/// usually means it isn't installed
+fn spawn(program: &str, command: &mut Command) -> io::Result<Child> {
+    command.spawn().map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("failed to run `{}`, is it installed? ({})", program, err),
+        )
+    })
+}
+
+fn check_status(what: &str, status: std::process::ExitStatus) -> io::Result<()> {
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "sending the {} failed with {}",
+            what, status
+        )))
+    }
+}
+
+#[test]
+fn test_webhook_payload() {
+    let payload = WebhookPayload {
+        success: false,
+        message: "Flashing \"app\" failed",
+        elapsed_secs: 1.5,
+    };
+
+    assert_eq!(
+        r#"{"success":false,"message":"Flashing \"app\" failed","elapsed_secs":1.5}"#,
+        serde_json::to_string(&payload).unwrap()
+    );
+}
+
+#[test]
+fn test_spawn_error() {
+    let mut command = Command::new("espflash-missing-notifier");
+    let err = spawn("espflash-missing-notifier", &mut command).unwrap_err();
+
+    assert_eq!(io::ErrorKind::NotFound, err.kind());
+    assert!(err
+        .to_string()
+        .starts_with("failed to run `espflash-missing-notifier`, is it installed?"));
+}
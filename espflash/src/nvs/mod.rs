@@ -0,0 +1,870 @@
+//! Generating and reading NVS partitions
+//!
+//! Converts the key/value CSV files understood by ESP-IDF's
+//! `nvs_partition_gen.py` into a binary NVS partition, optionally encrypted
+//! with the keys of an `nvs_keys` partition. Partitions use version 2 of the
+//! format, where blobs are split into chunks which can span pages.
+//!
+//! Partitions read back from a device are decoded like the NVS library does
+//! when it mounts them, skipping erased and corrupted entries.
+
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use aes::cipher::KeyInit;
+use aes::Aes256;
+use xts_mode::Xts128;
+
+use crate::error::NvsError;
+
+const PAGE_SIZE: usize = 4096;
+const ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_PAGE: usize = 126;
+const FIRST_ENTRY_OFFSET: usize = 64;
+const BITMAP_OFFSET: usize = 32;
+
+const PAGE_ACTIVE: u32 = 0xffff_fffe;
+const PAGE_FULL: u32 = 0xffff_fffc;
+const PAGE_FREEING: u32 = 0xffff_fff8;
+const PAGE_VERSION: u8 = 0xfe;
+
+const MAX_KEY_LENGTH: usize = 15;
+const MAX_STRING_LENGTH: usize = 4000;
+const MAX_NAMESPACES: usize = 254;
+const NO_CHUNK: u8 = 0xff;
+
+/// Size of the keys in an `nvs_keys` partition
+pub const NVS_KEYS_SIZE: usize = 64;
+
+const TYPE_U8: u8 = 0x01;
+const TYPE_I8: u8 = 0x11;
+const TYPE_U16: u8 = 0x02;
+const TYPE_I16: u8 = 0x12;
+const TYPE_U32: u8 = 0x04;
+const TYPE_I32: u8 = 0x14;
+const TYPE_U64: u8 = 0x08;
+const TYPE_I64: u8 = 0x18;
+const TYPE_STR: u8 = 0x21;
+/// Blobs of version 1 of the format, which aren't split into chunks
+const TYPE_BLOB: u8 = 0x41;
+const TYPE_BLOB_DATA: u8 = 0x42;
+const TYPE_BLOB_INDEX: u8 = 0x48;
+
+/// A value stored in NVS
+#[derive(Debug, Clone, PartialEq)]
+pub enum NvsValue {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    /// A string, without the terminating null byte
+    String(String),
+    Blob(Vec<u8>),
+}
+
+impl NvsValue {
+    /// Name of the type, as used for the encoding in CSV files
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            NvsValue::U8(_) => "u8",
+            NvsValue::I8(_) => "i8",
+            NvsValue::U16(_) => "u16",
+            NvsValue::I16(_) => "i16",
+            NvsValue::U32(_) => "u32",
+            NvsValue::I32(_) => "i32",
+            NvsValue::U64(_) => "u64",
+            NvsValue::I64(_) => "i64",
+            NvsValue::String(_) => "string",
+            NvsValue::Blob(_) => "blob",
+        }
+    }
+}
+
+/// Blobs are shown in hex
+impl fmt::Display for NvsValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NvsValue::U8(value) => write!(f, "{}", value),
+            NvsValue::I8(value) => write!(f, "{}", value),
+            NvsValue::U16(value) => write!(f, "{}", value),
+            NvsValue::I16(value) => write!(f, "{}", value),
+            NvsValue::U32(value) => write!(f, "{}", value),
+            NvsValue::I32(value) => write!(f, "{}", value),
+            NvsValue::U64(value) => write!(f, "{}", value),
+            NvsValue::I64(value) => write!(f, "{}", value),
+            NvsValue::String(value) => write!(f, "{}", value),
+            NvsValue::Blob(data) => data.iter().try_for_each(|byte| write!(f, "{:02x}", byte)),
+        }
+    }
+}
+
+/// A key/value pair in a namespace
+#[derive(Debug, Clone, PartialEq)]
+pub struct NvsEntry {
+    pub namespace: String,
+    pub key: String,
+    pub value: NvsValue,
+}
+
+/// The contents of an NVS partition
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NvsPartition {
+    pub entries: Vec<NvsEntry>,
+}
+
+impl NvsPartition {
+    /// Parse a CSV file in the format of `nvs_partition_gen.py`
+    ///
+    /// Each line holds a `key,type,encoding,value`, where the type is either
+    /// `namespace`, starting a new namespace, `data` or `file`. Paths of
+    /// `file` entries are resolved against `base`.
+    pub fn from_csv(data: &str, base: &Path) -> Result<Self, NvsError> {
+        let mut reader = csv::ReaderBuilder::new()
+            .comment(Some(b'#'))
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .from_reader(data.as_bytes());
+
+        let mut partition = NvsPartition::default();
+        let mut namespace = None;
+
+        for record in reader.records() {
+            let record = record?;
+            let line = record.position().map_or(0, |pos| pos.line() as usize);
+            let invalid = |reason: String| NvsError::InvalidEntry { line, reason };
+
+            let field = |index| record.get(index).unwrap_or_default();
+            let (key, ty, encoding, value) = (field(0), field(1), field(2), field(3));
+            if key.len() > MAX_KEY_LENGTH {
+                return Err(invalid(format!(
+                    "key `{}` is longer than {} characters",
+                    key, MAX_KEY_LENGTH
+                )));
+            }
+
+            let value = match ty {
+                "namespace" => {
+                    namespace = Some(key.to_string());
+                    continue;
+                }
+                "data" => parse_value(encoding, value).map_err(invalid)?,
+                "file" => {
+                    let path = base.join(value);
+                    let data = fs::read(&path).map_err(|source| NvsError::Io {
+                        path: path.display().to_string(),
+                        source,
+                    })?;
+                    match encoding {
+                        "binary" => NvsValue::Blob(data),
+                        _ => parse_value(encoding, &String::from_utf8_lossy(&data))
+                            .map_err(invalid)?,
+                    }
+                }
+                _ => return Err(invalid(format!("unknown type `{}`", ty))),
+            };
+
+            let namespace = namespace
+                .clone()
+                .ok_or_else(|| invalid("the first entry needs to be a namespace".into()))?;
+            partition.entries.push(NvsEntry {
+                namespace,
+                key: key.to_string(),
+                value,
+            });
+        }
+
+        Ok(partition)
+    }
+
+    /// Generate a partition of `size` bytes, encrypting it if keys are given
+    ///
+    /// One page is kept free, as NVS needs it to garbage collect.
+    pub fn generate(
+        &self,
+        size: u32,
+        keys: Option<&[u8; NVS_KEYS_SIZE]>,
+    ) -> Result<Vec<u8>, NvsError> {
+        let size = size as usize;
+        if !size.is_multiple_of(PAGE_SIZE) || size < 3 * PAGE_SIZE {
+            return Err(NvsError::InvalidSize(size as u32));
+        }
+
+        let mut writer = PageWriter::new(size / PAGE_SIZE - 1);
+
+        let mut namespaces: Vec<&str> = Vec::new();
+        for entry in &self.entries {
+            let index = match namespaces.iter().position(|ns| *ns == entry.namespace) {
+                Some(index) => index + 1,
+                None => {
+                    if namespaces.len() == MAX_NAMESPACES {
+                        return Err(NvsError::TooManyNamespaces);
+                    }
+                    namespaces.push(&entry.namespace);
+                    writer.write_primitive(
+                        0,
+                        &entry.namespace,
+                        TYPE_U8,
+                        &[namespaces.len() as u8],
+                    )?;
+                    namespaces.len()
+                }
+            } as u8;
+
+            writer.write_value(index, &entry.key, &entry.value)?;
+        }
+
+        let xts = keys.map(xts);
+        let mut data = Vec::with_capacity(size);
+        for (index, page) in writer.pages.iter().enumerate() {
+            let last = index == writer.pages.len() - 1;
+            data.extend(page.finish(
+                if last { PAGE_ACTIVE } else { PAGE_FULL },
+                index,
+                xts.as_ref(),
+            ));
+        }
+        data.resize(size, 0xff);
+
+        Ok(data)
+    }
+
+    /// Decode the contents of a partition, decrypting it if keys are given
+    ///
+    /// Entries are listed in the order they were written in, blobs at the
+    /// position of their index.
+    pub fn parse(data: &[u8], keys: Option<&[u8; NVS_KEYS_SIZE]>) -> Result<Self, NvsError> {
+        if data.is_empty() || !data.len().is_multiple_of(PAGE_SIZE) {
+            return Err(NvsError::InvalidSize(data.len() as u32));
+        }
+
+        let xts = keys.map(xts);
+        let mut pages = data
+            .chunks(PAGE_SIZE)
+            .enumerate()
+            .filter_map(|(index, page)| {
+                let state = u32::from_le_bytes(page[0..4].try_into().unwrap());
+                let seq = u32::from_le_bytes(page[4..8].try_into().unwrap());
+                let crc = u32::from_le_bytes(page[28..32].try_into().unwrap());
+                match state {
+                    PAGE_ACTIVE | PAGE_FULL | PAGE_FREEING if crc == crc32(&page[4..28]) => {
+                        Some((seq, index))
+                    }
+                    PAGE_ACTIVE | PAGE_FULL | PAGE_FREEING => {
+                        log::warn!("Skipping page {} with an invalid header", index);
+                        None
+                    }
+                    _ => None,
+                }
+            })
+            .collect::<Vec<_>>();
+        pages.sort_unstable();
+
+        let mut reader = EntryReader {
+            data,
+            xts: xts.as_ref(),
+            namespaces: HashMap::new(),
+            items: Vec::new(),
+            chunks: HashMap::new(),
+        };
+        for (_, index) in pages {
+            reader.read_page(index);
+        }
+
+        Ok(reader.finish())
+    }
+}
+
+/// What an entry, along with the entries of its data, holds
+enum Item {
+    Value(NvsValue),
+    /// The size, number of chunks and index of the first chunk of a blob
+    BlobIndex(usize, u8, u8),
+}
+
+/// Collects the entries of the pages of a partition, in the order of their
+/// sequence numbers
+struct EntryReader<'a> {
+    data: &'a [u8],
+    xts: Option<&'a Xts128<Aes256>>,
+    namespaces: HashMap<u8, String>,
+    items: Vec<(u8, String, Item)>,
+    /// Data of the chunks of blobs, by namespace, key and chunk index
+    chunks: HashMap<(u8, String, u8), Vec<u8>>,
+}
+
+impl EntryReader<'_> {
+    /// The entry at `offset`, decrypted
+    fn entry(&self, offset: usize) -> [u8; ENTRY_SIZE] {
+        let mut entry: [u8; ENTRY_SIZE] = self.data[offset..][..ENTRY_SIZE].try_into().unwrap();
+        if let Some(xts) = self.xts {
+            let mut tweak = [0; 16];
+            tweak[..8].copy_from_slice(&(offset as u64).to_le_bytes());
+            xts.decrypt_sector(&mut entry, tweak);
+        }
+        entry
+    }
+
+    fn read_page(&mut self, index: usize) {
+        let page = index * PAGE_SIZE;
+        let data = self.data;
+        let written = |i: usize| {
+            let state = data[page + BITMAP_OFFSET + i / 4] >> ((i % 4) * 2);
+            state & 0b11 == 0b10
+        };
+
+        let mut i = 0;
+        while i < ENTRIES_PER_PAGE {
+            if !written(i) {
+                i += 1;
+                continue;
+            }
+
+            let offset = page + FIRST_ENTRY_OFFSET + i * ENTRY_SIZE;
+            let entry = self.entry(offset);
+            let crc = crc32(&[&entry[0..4], &entry[8..32]].concat());
+            if crc != u32::from_le_bytes(entry[4..8].try_into().unwrap()) {
+                log::warn!("Skipping entry {} of page {} with an invalid CRC", i, index);
+                i += 1;
+                continue;
+            }
+
+            let span = (entry[2] as usize).clamp(1, ENTRIES_PER_PAGE - i);
+            self.read_entry(&entry, offset, span);
+            i += span;
+        }
+    }
+
+    fn read_entry(&mut self, entry: &[u8; ENTRY_SIZE], offset: usize, span: usize) {
+        let (ns, ty, chunk_index) = (entry[0], entry[1], entry[3]);
+        let key = entry[8..24]
+            .split(|byte| *byte == 0)
+            .next()
+            .unwrap_or_default();
+        let key = String::from_utf8_lossy(key).into_owned();
+        let payload = &entry[24..32];
+
+        let item = match ty {
+            TYPE_U8 if ns == 0 => {
+                self.namespaces.insert(payload[0], key);
+                return;
+            }
+            TYPE_U8 => NvsValue::U8(payload[0]),
+            TYPE_I8 => NvsValue::I8(payload[0] as i8),
+            TYPE_U16 => NvsValue::U16(u16::from_le_bytes(payload[..2].try_into().unwrap())),
+            TYPE_I16 => NvsValue::I16(i16::from_le_bytes(payload[..2].try_into().unwrap())),
+            TYPE_U32 => NvsValue::U32(u32::from_le_bytes(payload[..4].try_into().unwrap())),
+            TYPE_I32 => NvsValue::I32(i32::from_le_bytes(payload[..4].try_into().unwrap())),
+            TYPE_U64 => NvsValue::U64(u64::from_le_bytes(payload.try_into().unwrap())),
+            TYPE_I64 => NvsValue::I64(i64::from_le_bytes(payload.try_into().unwrap())),
+            TYPE_STR | TYPE_BLOB | TYPE_BLOB_DATA => {
+                let size = u16::from_le_bytes(payload[..2].try_into().unwrap()) as usize;
+                let mut data = (1..span)
+                    .flat_map(|i| self.entry(offset + i * ENTRY_SIZE))
+                    .collect::<Vec<_>>();
+                if size > data.len()
+                    || crc32(&data[..size]) != u32::from_le_bytes(payload[4..8].try_into().unwrap())
+                {
+                    log::warn!("Skipping `{}` with invalid data", key);
+                    return;
+                }
+                data.truncate(size);
+
+                match ty {
+                    TYPE_STR => {
+                        // without the terminating null byte
+                        let string = data.split(|byte| *byte == 0).next().unwrap_or_default();
+                        NvsValue::String(String::from_utf8_lossy(string).into_owned())
+                    }
+                    TYPE_BLOB => NvsValue::Blob(data),
+                    _ => {
+                        self.chunks.insert((ns, key, chunk_index), data);
+                        return;
+                    }
+                }
+            }
+            TYPE_BLOB_INDEX => {
+                let size = u32::from_le_bytes(payload[..4].try_into().unwrap()) as usize;
+                self.items
+                    .push((ns, key, Item::BlobIndex(size, payload[4], payload[5])));
+                return;
+            }
+            _ => {
+                log::warn!("Skipping `{}` of unknown type {:#04x}", key, ty);
+                return;
+            }
+        };
+
+        self.items.push((ns, key, Item::Value(item)));
+    }
+
+    /// Resolve the namespaces and join the chunks of blobs
+    fn finish(mut self) -> NvsPartition {
+        let mut partition = NvsPartition::default();
+        for (ns, key, item) in std::mem::take(&mut self.items) {
+            let namespace = match self.namespaces.get(&ns) {
+                Some(namespace) => namespace.clone(),
+                None => {
+                    log::warn!("Skipping `{}` of unknown namespace {}", key, ns);
+                    continue;
+                }
+            };
+
+            let value = match item {
+                Item::Value(value) => value,
+                Item::BlobIndex(size, count, start) => {
+                    let mut data = Vec::with_capacity(size);
+                    for chunk_index in (0..count).map(|i| start.wrapping_add(i)) {
+                        match self.chunks.get(&(ns, key.clone(), chunk_index)) {
+                            Some(chunk) => data.extend_from_slice(chunk),
+                            None => break,
+                        }
+                    }
+                    if data.len() != size {
+                        log::warn!("Skipping `{}` with missing chunks", key);
+                        continue;
+                    }
+                    NvsValue::Blob(data)
+                }
+            };
+
+            // an entry is written again before the old one is erased
+            partition
+                .entries
+                .retain(|entry| entry.namespace != namespace || entry.key != key);
+            partition.entries.push(NvsEntry {
+                namespace,
+                key,
+                value,
+            });
+        }
+
+        partition
+    }
+}
+
+/// XTS-AES-256 with the encryption key followed by the tweak key of an
+/// `nvs_keys` partition
+fn xts(keys: &[u8; NVS_KEYS_SIZE]) -> Xts128<Aes256> {
+    let (key, tweak_key) = keys.split_at(NVS_KEYS_SIZE / 2);
+    Xts128::new(
+        Aes256::new_from_slice(key).unwrap(),
+        Aes256::new_from_slice(tweak_key).unwrap(),
+    )
+}
+
+/// Generate the contents of an `nvs_keys` partition holding the given keys
+pub fn keys_partition(keys: &[u8; NVS_KEYS_SIZE]) -> Vec<u8> {
+    let mut data = keys.to_vec();
+    data.extend(crc32(keys).to_le_bytes());
+    data.resize(PAGE_SIZE, 0xff);
+    data
+}
+
+/// Read the keys from an `nvs_keys` partition
+pub fn read_keys(data: &[u8]) -> Result<[u8; NVS_KEYS_SIZE], NvsError> {
+    data.get(..NVS_KEYS_SIZE)
+        .and_then(|keys| keys.try_into().ok())
+        .ok_or(NvsError::InvalidKeys(data.len()))
+}
+
+fn parse_value(encoding: &str, value: &str) -> Result<NvsValue, String> {
+    fn int<T: TryFrom<i128>>(value: &str) -> Result<T, String> {
+        let (negative, digits) = match value.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, value),
+        };
+        let parsed = match digits.strip_prefix("0x") {
+            Some(hex) => i128::from_str_radix(hex, 16),
+            None => digits.parse(),
+        };
+
+        parsed
+            .ok()
+            .and_then(|value| T::try_from(if negative { -value } else { value }).ok())
+            .ok_or_else(|| format!("`{}` is out of range or not a number", value))
+    }
+
+    Ok(match encoding {
+        "u8" => NvsValue::U8(int(value)?),
+        "i8" => NvsValue::I8(int(value)?),
+        "u16" => NvsValue::U16(int(value)?),
+        "i16" => NvsValue::I16(int(value)?),
+        "u32" => NvsValue::U32(int(value)?),
+        "i32" => NvsValue::I32(int(value)?),
+        "u64" => NvsValue::U64(int(value)?),
+        "i64" => NvsValue::I64(int(value)?),
+        "string" => {
+            // including the terminating null byte
+            if value.len() >= MAX_STRING_LENGTH {
+                return Err(format!(
+                    "strings must be shorter than {} bytes",
+                    MAX_STRING_LENGTH
+                ));
+            }
+            NvsValue::String(value.to_string())
+        }
+        "hex2bin" => NvsValue::Blob(decode_hex(value)?),
+        "base64" => NvsValue::Blob(decode_base64(value)?),
+        _ => return Err(format!("unsupported encoding `{}`", encoding)),
+    })
+}
+
+fn decode_hex(value: &str) -> Result<Vec<u8>, String> {
+    let invalid = || format!("`{}` is not a valid hex string", value);
+    if !value.len().is_multiple_of(2) {
+        return Err(invalid());
+    }
+
+    (0..value.len())
+        .step_by(2)
+        .map(|i| {
+            value
+                .get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(invalid)
+        })
+        .collect()
+}
+
+fn decode_base64(value: &str) -> Result<Vec<u8>, String> {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut data = Vec::with_capacity(value.len() * 3 / 4);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in value
+        .bytes()
+        .filter(|c| !c.is_ascii_whitespace() && *c != b'=')
+    {
+        let sextet = ALPHABET
+            .iter()
+            .position(|a| *a == c)
+            .ok_or_else(|| format!("`{}` is not valid base64", value))?;
+        bits = (bits << 6) | sextet as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            data.push((bits >> count) as u8);
+        }
+    }
+
+    Ok(data)
+}
+
+/// CRC-32 as calculated by the NVS library, which starts from 0 instead of
+/// the usual `0xffffffff`
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new_with_initial(0xffff_ffff);
+    hasher.update(data);
+    hasher.finalize()
+}
+
+struct Page {
+    seq: u32,
+    entries: Vec<[u8; ENTRY_SIZE]>,
+}
+
+impl Page {
+    fn free(&self) -> usize {
+        ENTRIES_PER_PAGE - self.entries.len()
+    }
+
+    /// Serialize the page, encrypting its entries if a cipher is given
+    fn finish(&self, state: u32, index: usize, xts: Option<&Xts128<Aes256>>) -> Vec<u8> {
+        let mut page = vec![0xff; PAGE_SIZE];
+
+        page[0..4].copy_from_slice(&state.to_le_bytes());
+        page[4..8].copy_from_slice(&self.seq.to_le_bytes());
+        page[8] = PAGE_VERSION;
+        let crc = crc32(&page[4..28]);
+        page[28..32].copy_from_slice(&crc.to_le_bytes());
+
+        for (i, entry) in self.entries.iter().enumerate() {
+            // mark the entry as written
+            page[BITMAP_OFFSET + i / 4] &= !(1 << ((i % 4) * 2));
+
+            let offset = FIRST_ENTRY_OFFSET + i * ENTRY_SIZE;
+            let mut entry = *entry;
+            if let Some(xts) = xts {
+                let mut tweak = [0; 16];
+                tweak[..8].copy_from_slice(&((index * PAGE_SIZE + offset) as u64).to_le_bytes());
+                xts.encrypt_sector(&mut entry, tweak);
+            }
+            page[offset..offset + ENTRY_SIZE].copy_from_slice(&entry);
+        }
+
+        page
+    }
+}
+
+struct PageWriter {
+    max_pages: usize,
+    pages: Vec<Page>,
+}
+
+impl PageWriter {
+    fn new(max_pages: usize) -> Self {
+        PageWriter {
+            max_pages,
+            pages: vec![Page {
+                seq: 0,
+                entries: Vec::new(),
+            }],
+        }
+    }
+
+    /// Make sure the current page has room for `entries` entries, starting a
+    /// new page if needed
+    fn reserve(&mut self, entries: usize) -> Result<&mut Page, NvsError> {
+        let current = self.pages.last().unwrap();
+        if current.free() < entries {
+            if self.pages.len() == self.max_pages {
+                return Err(NvsError::TooLarge);
+            }
+            let seq = current.seq + 1;
+            self.pages.push(Page {
+                seq,
+                entries: Vec::new(),
+            });
+        }
+
+        Ok(self.pages.last_mut().unwrap())
+    }
+
+    fn write_value(&mut self, ns: u8, key: &str, value: &NvsValue) -> Result<(), NvsError> {
+        match value {
+            NvsValue::U8(value) => self.write_primitive(ns, key, TYPE_U8, &value.to_le_bytes()),
+            NvsValue::I8(value) => self.write_primitive(ns, key, TYPE_I8, &value.to_le_bytes()),
+            NvsValue::U16(value) => self.write_primitive(ns, key, TYPE_U16, &value.to_le_bytes()),
+            NvsValue::I16(value) => self.write_primitive(ns, key, TYPE_I16, &value.to_le_bytes()),
+            NvsValue::U32(value) => self.write_primitive(ns, key, TYPE_U32, &value.to_le_bytes()),
+            NvsValue::I32(value) => self.write_primitive(ns, key, TYPE_I32, &value.to_le_bytes()),
+            NvsValue::U64(value) => self.write_primitive(ns, key, TYPE_U64, &value.to_le_bytes()),
+            NvsValue::I64(value) => self.write_primitive(ns, key, TYPE_I64, &value.to_le_bytes()),
+            NvsValue::String(value) => {
+                let mut data = value.as_bytes().to_vec();
+                data.push(0);
+                self.write_variable(ns, key, TYPE_STR, NO_CHUNK, &data)
+            }
+            NvsValue::Blob(data) => self.write_blob(ns, key, data),
+        }
+    }
+
+    fn write_primitive(&mut self, ns: u8, key: &str, ty: u8, value: &[u8]) -> Result<(), NvsError> {
+        let mut data = [0xff; 8];
+        data[..value.len()].copy_from_slice(value);
+
+        let page = self.reserve(1)?;
+        page.entries.push(entry(ns, ty, 1, NO_CHUNK, key, data));
+        Ok(())
+    }
+
+    /// Write a string or a chunk of a blob, which is stored in the entries
+    /// following its header
+    fn write_variable(
+        &mut self,
+        ns: u8,
+        key: &str,
+        ty: u8,
+        chunk_index: u8,
+        value: &[u8],
+    ) -> Result<(), NvsError> {
+        let span = 1 + value.len().div_ceil(ENTRY_SIZE);
+
+        let mut data = [0xff; 8];
+        data[0..2].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        data[4..8].copy_from_slice(&crc32(value).to_le_bytes());
+
+        let page = self.reserve(span)?;
+        page.entries
+            .push(entry(ns, ty, span as u8, chunk_index, key, data));
+        for chunk in value.chunks(ENTRY_SIZE) {
+            let mut entry = [0xff; ENTRY_SIZE];
+            entry[..chunk.len()].copy_from_slice(chunk);
+            page.entries.push(entry);
+        }
+
+        Ok(())
+    }
+
+    fn write_blob(&mut self, ns: u8, key: &str, value: &[u8]) -> Result<(), NvsError> {
+        // each chunk fills the rest of a page
+        let mut chunks = 0u8;
+        let mut remaining = value;
+        loop {
+            let free = self.reserve(2)?.free();
+            let (chunk, rest) = remaining.split_at(remaining.len().min((free - 1) * ENTRY_SIZE));
+            self.write_variable(ns, key, TYPE_BLOB_DATA, chunks, chunk)?;
+            chunks = chunks.checked_add(1).ok_or(NvsError::TooLarge)?;
+            remaining = rest;
+            if remaining.is_empty() {
+                break;
+            }
+        }
+
+        let mut data = [0xff; 8];
+        data[0..4].copy_from_slice(&(value.len() as u32).to_le_bytes());
+        data[4] = chunks;
+        // chunk start
+        data[5] = 0;
+
+        let page = self.reserve(1)?;
+        page.entries
+            .push(entry(ns, TYPE_BLOB_INDEX, 1, NO_CHUNK, key, data));
+        Ok(())
+    }
+}
+
+fn entry(ns: u8, ty: u8, span: u8, chunk_index: u8, key: &str, data: [u8; 8]) -> [u8; ENTRY_SIZE] {
+    let mut entry = [0; ENTRY_SIZE];
+    entry[0] = ns;
+    entry[1] = ty;
+    entry[2] = span;
+    entry[3] = chunk_index;
+    entry[8..8 + key.len()].copy_from_slice(key.as_bytes());
+    entry[24..32].copy_from_slice(&data);
+
+    let crc = crc32(&[&entry[0..4], &entry[8..32]].concat());
+    entry[4..8].copy_from_slice(&crc.to_le_bytes());
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(0xd202d277, crc32(b"123456789"));
+    }
+
+    #[test]
+    fn test_generate() {
+        let csv = "key,type,encoding,value\n\
+                   storage,namespace,,\n\
+                   count,data,u8,0x2a\n\
+                   name,data,string,espflash\n\
+                   cert,data,hex2bin,00112233\n\
+                   big,data,base64,QUJD\n";
+        let mut partition = NvsPartition::from_csv(csv, Path::new(".")).unwrap();
+        assert_eq!(NvsValue::U8(42), partition.entries[0].value);
+        assert_eq!(NvsValue::Blob(b"ABC".to_vec()), partition.entries[3].value);
+
+        // a blob larger than a page is split into chunks
+        partition.entries[3].value = NvsValue::Blob(vec![0x55; 5000]);
+        let data = partition.generate(0x6000, None).unwrap();
+        assert_eq!(0x6000, data.len());
+
+        let (first, second) = (&data[..PAGE_SIZE], &data[PAGE_SIZE..2 * PAGE_SIZE]);
+        assert_eq!(PAGE_FULL.to_le_bytes(), first[0..4]);
+        assert_eq!(PAGE_ACTIVE.to_le_bytes(), second[0..4]);
+        assert_eq!(1, u32::from_le_bytes(second[4..8].try_into().unwrap()));
+        assert_eq!(crc32(&first[4..28]).to_le_bytes(), first[28..32]);
+        assert!(data[2 * PAGE_SIZE..].iter().all(|byte| *byte == 0xff));
+
+        let entry = |page: &[u8], i: usize| {
+            page[FIRST_ENTRY_OFFSET + i * ENTRY_SIZE..][..ENTRY_SIZE].to_vec()
+        };
+        // the namespace
+        let namespace = entry(first, 0);
+        assert_eq!([0, TYPE_U8, 1, NO_CHUNK], namespace[0..4]);
+        assert_eq!(b"storage\0", &namespace[8..16]);
+        assert_eq!(1, namespace[24]);
+        let crc = crc32(&[&namespace[0..4], &namespace[8..32]].concat());
+        assert_eq!(crc.to_le_bytes(), namespace[4..8]);
+
+        // the string, spanning a header and a data entry
+        let string = entry(first, 2);
+        assert_eq!([1, TYPE_STR, 2, NO_CHUNK], string[0..4]);
+        assert_eq!(9u16.to_le_bytes(), string[24..26]);
+        assert_eq!(b"espflash\0", &entry(first, 3)[..9]);
+
+        // the first chunk of the large blob fills the rest of the first page,
+        // the second chunk and the index go to the second page
+        assert_eq!([1, TYPE_BLOB_DATA, 2, 0], entry(first, 4)[0..4]);
+        assert_eq!([1, TYPE_BLOB_INDEX, 1, NO_CHUNK], entry(first, 6)[0..4]);
+        assert_eq!([1, TYPE_BLOB_DATA, 119, 0], entry(first, 7)[0..4]);
+        assert_eq!([1, TYPE_BLOB_DATA], entry(second, 0)[0..2]);
+        assert_eq!(1, entry(second, 0)[3]);
+        let index = entry(second, 0)[2] as usize;
+        assert_eq!(
+            [1, TYPE_BLOB_INDEX, 1, NO_CHUNK],
+            entry(second, index)[0..4]
+        );
+        assert_eq!(5000u32.to_le_bytes(), entry(second, index)[24..28]);
+        assert_eq!(2, entry(second, index)[28]);
+
+        // all 126 entries of the first page are written
+        assert!(first[BITMAP_OFFSET..FIRST_ENTRY_OFFSET - 1]
+            .iter()
+            .all(|byte| *byte == 0xaa));
+        assert_eq!(0xfa, first[FIRST_ENTRY_OFFSET - 1]);
+
+        assert_eq!(partition, NvsPartition::parse(&data, None).unwrap());
+
+        // one page is kept free
+        partition.entries[3].value = NvsValue::Blob(vec![0x55; 10_000]);
+        assert!(matches!(
+            partition.generate(0x3000, None),
+            Err(NvsError::TooLarge)
+        ));
+    }
+
+    #[test]
+    fn test_xts() {
+        let keys: [u8; NVS_KEYS_SIZE] = (0..64).collect::<Vec<_>>().try_into().unwrap();
+        let mut tweak = [0; 16];
+        tweak[..2].copy_from_slice(&0x1040u16.to_le_bytes());
+        let mut entry: Vec<u8> = (0..32).collect();
+        xts(&keys).encrypt_sector(&mut entry, tweak);
+        let expected = "15be8ec8ab0cbba1f26f81a96465236874f8d0d97f578c16958e9e96c5042196";
+        let hex: String = entry.iter().map(|byte| format!("{:02x}", byte)).collect();
+        assert_eq!(expected, hex);
+
+        xts(&keys).decrypt_sector(&mut entry, tweak);
+        assert_eq!((0..32).collect::<Vec<u8>>(), entry);
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut partition = NvsPartition::default();
+        for (namespace, key, value) in [
+            ("wifi", "ssid", NvsValue::String("espflash".into())),
+            ("wifi", "channel", NvsValue::I8(-6)),
+            ("app", "counter", NvsValue::U64(1 << 40)),
+            (
+                "app",
+                "cert",
+                NvsValue::Blob((0..=255).cycle().take(6000).collect()),
+            ),
+        ] {
+            partition.entries.push(NvsEntry {
+                namespace: namespace.into(),
+                key: key.into(),
+                value,
+            });
+        }
+
+        let keys: [u8; NVS_KEYS_SIZE] = (0..64).collect::<Vec<_>>().try_into().unwrap();
+        let mut data = partition.generate(0x6000, Some(&keys)).unwrap();
+        assert_eq!(partition, NvsPartition::parse(&data, Some(&keys)).unwrap());
+
+        // erasing the entry of the counter, the sixth one, hides it
+        data[BITMAP_OFFSET + 1] &= !(0b11 << 2);
+        let parsed = NvsPartition::parse(&data, Some(&keys)).unwrap();
+        assert_eq!(3, parsed.entries.len());
+        assert!(parsed.entries.iter().all(|entry| entry.key != "counter"));
+
+        assert!(matches!(
+            NvsPartition::parse(&data[..100], None),
+            Err(NvsError::InvalidSize(100))
+        ));
+    }
+}
@@ -0,0 +1,189 @@
+//! Selecting the app partition the bootloader starts
+//!
+//! The `otadata` partition consists of two flash sectors, each starting with
+//! an `esp_ota_select_entry_t`. The bootloader starts the OTA slot
+//! `(seq - 1) % ota_app_count` of the valid entry with the highest sequence
+//! number, or the factory partition if neither entry is valid.
+
+use std::convert::TryInto;
+
+use crate::error::OtaError;
+use crate::nvs::crc32;
+use crate::partition_table::AppType;
+use crate::{Error, Flasher, PartitionTable};
+
+const SECTOR_SIZE: usize = 0x1000;
+const ENTRY_SIZE: usize = 32;
+
+/// Size of the `otadata` partition, one sector for each entry
+pub const OTADATA_SIZE: u32 = 2 * SECTOR_SIZE as u32;
+
+/// `ESP_OTA_IMG_UNDEFINED`, the state of an app which doesn't need to be
+/// confirmed when rollback is enabled
+const OTA_IMG_UNDEFINED: u32 = 0xffff_ffff;
+
+/// An entry of the `otadata` partition
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct OtaSelectEntry {
+    pub seq: u32,
+    pub state: u32,
+}
+
+impl OtaSelectEntry {
+    /// Parse an entry, `None` if it is erased or its checksum doesn't match
+    pub fn from_bytes(data: &[u8; ENTRY_SIZE]) -> Option<Self> {
+        let seq = u32::from_le_bytes(data[0..4].try_into().unwrap());
+        let state = u32::from_le_bytes(data[24..28].try_into().unwrap());
+        let crc = u32::from_le_bytes(data[28..32].try_into().unwrap());
+
+        if seq == 0xffff_ffff || crc != crc32(&data[0..4]) {
+            return None;
+        }
+        Some(OtaSelectEntry { seq, state })
+    }
+
+    /// Serialize the entry, leaving the label erased
+    pub fn to_bytes(&self) -> [u8; ENTRY_SIZE] {
+        let mut data = [0xff; ENTRY_SIZE];
+        data[0..4].copy_from_slice(&self.seq.to_le_bytes());
+        data[24..28].copy_from_slice(&self.state.to_le_bytes());
+        let crc = crc32(&data[0..4]);
+        data[28..32].copy_from_slice(&crc.to_le_bytes());
+        data
+    }
+}
+
+/// The contents of the `otadata` partition
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct OtaData {
+    pub entries: [Option<OtaSelectEntry>; 2],
+}
+
+impl OtaData {
+    /// Parse both sectors of the `otadata` partition
+    pub fn from_bytes(data: &[u8]) -> Self {
+        let entry = |sector: usize| {
+            data.get(sector * SECTOR_SIZE..sector * SECTOR_SIZE + ENTRY_SIZE)
+                .and_then(|entry| OtaSelectEntry::from_bytes(entry.try_into().unwrap()))
+        };
+
+        OtaData {
+            entries: [entry(0), entry(1)],
+        }
+    }
+
+    /// The sector and entry the bootloader uses
+    fn active(&self) -> Option<(usize, OtaSelectEntry)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter_map(|(sector, entry)| entry.map(|entry| (sector, entry)))
+            .max_by_key(|(_, entry)| entry.seq)
+    }
+
+    /// The OTA slot the bootloader starts, `None` for the factory partition
+    pub fn boot_slot(&self, ota_app_count: u32) -> Option<u32> {
+        match self.active() {
+            Some((_, entry)) if ota_app_count > 0 => Some((entry.seq - 1) % ota_app_count),
+            _ => None,
+        }
+    }
+
+    /// Select the OTA slot to start on the next boot, returning the offset
+    /// of the sector to write in the partition and its contents
+    ///
+    /// As the bootloader does, the sector which isn't active is written, so
+    /// the previous selection stays valid if writing is interrupted.
+    pub fn select(&mut self, slot: u32, ota_app_count: u32) -> (u32, Vec<u8>) {
+        let (sector, seq) = match self.active() {
+            Some((sector, entry)) => ((sector + 1) % 2, entry.seq + 1),
+            None => (0, 1),
+        };
+        let seq = (seq..)
+            .find(|seq| (seq - 1) % ota_app_count == slot)
+            .unwrap();
+
+        let entry = OtaSelectEntry {
+            seq,
+            state: OTA_IMG_UNDEFINED,
+        };
+        self.entries[sector] = Some(entry);
+
+        let mut data = vec![0xff; SECTOR_SIZE];
+        data[..ENTRY_SIZE].copy_from_slice(&entry.to_bytes());
+        ((sector * SECTOR_SIZE) as u32, data)
+    }
+}
+
+/// Make the bootloader start the app partition `name` on the next boot
+///
+/// Selecting the factory partition erases the `otadata` partition.
+pub fn set_boot_partition(
+    flasher: &mut Flasher,
+    table: &PartitionTable,
+    name: &str,
+) -> Result<(), Error> {
+    let (offset, size) = table.otadata().ok_or(OtaError::NoOtadata)?;
+    if size != OTADATA_SIZE {
+        return Err(OtaError::InvalidOtadataSize(size).into());
+    }
+    let app_type = table
+        .app_type(name)
+        .ok_or_else(|| OtaError::UnknownPartition(name.to_string()))?;
+
+    match app_type.ota_slot() {
+        Some(slot) if slot >= table.ota_app_count() => {
+            // the bootloader only counts the OTA slots, so gaps can't be selected
+            Err(OtaError::NotSelectable(name.to_string()).into())
+        }
+        Some(slot) => {
+            let mut otadata = OtaData::from_bytes(&flasher.read_flash(offset, size)?);
+            let (sector, data) = otadata.select(slot, table.ota_app_count());
+            flasher.write_bin_to_flash(offset + sector, &data)
+        }
+        None if matches!(app_type, AppType::Factory) => {
+            flasher.write_bin_to_flash(offset, &vec![0xff; size as usize])
+        }
+        None => Err(OtaError::NotSelectable(name.to_string()).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry() {
+        let entry = OtaSelectEntry {
+            seq: 1,
+            state: OTA_IMG_UNDEFINED,
+        };
+        let data = entry.to_bytes();
+
+        assert_eq!(
+            0x4743989a,
+            u32::from_le_bytes(data[28..32].try_into().unwrap())
+        );
+        assert_eq!(Some(entry), OtaSelectEntry::from_bytes(&data));
+        assert_eq!(None, OtaSelectEntry::from_bytes(&[0xff; ENTRY_SIZE]));
+    }
+
+    #[test]
+    fn test_select() {
+        let mut otadata = OtaData::from_bytes(&[0xff; OTADATA_SIZE as usize]);
+        assert_eq!(None, otadata.boot_slot(2));
+
+        let (sector, data) = otadata.select(1, 2);
+        assert_eq!(0, sector);
+        assert_eq!(Some(1), OtaData::from_bytes(&data).boot_slot(2));
+
+        let (sector, _) = otadata.select(0, 2);
+        assert_eq!(0x1000, sector);
+        assert_eq!(Some(0), otadata.boot_slot(2));
+        assert_eq!(3, otadata.entries[1].unwrap().seq);
+
+        otadata.select(0, 2);
+        assert_eq!(5, otadata.entries[0].unwrap().seq);
+        assert_eq!(Some(0), otadata.boot_slot(2));
+    }
+}
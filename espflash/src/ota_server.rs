@@ -0,0 +1,307 @@
+//! Serving application images to firmware updating itself over HTTP(S)
+//!
+//! Firmware using `esp_https_ota` downloads the new application image from a
+//! URL, optionally after fetching the version of the image to decide whether
+//! to update at all. The image is served at [`FIRMWARE_PATH`] and its version
+//! as plain text at [`VERSION_PATH`].
+//!
+//! HTTP is served directly, HTTPS is served by `openssl s_server` from the
+//! complete responses written to a directory, so that no TLS implementation
+//! has to be linked in.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+
+/// Path the application image is served at
+pub const FIRMWARE_PATH: &str = "/firmware.bin";
+/// Path the version of the application is served at
+pub const VERSION_PATH: &str = "/version";
+
+/// How long to wait for a client to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An application image to serve
+#[derive(Debug, Clone)]
+pub struct OtaArtifact {
+    image: Vec<u8>,
+    version: Option<String>,
+    etag: String,
+}
+
+/// A complete HTTP response
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Response {
+    pub status: &'static str,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status: &'static str, content_type: &str, body: Vec<u8>) -> Self {
+        Response {
+            status,
+            headers: vec![
+                ("Content-Type", content_type.to_string()),
+                ("Content-Length", body.len().to_string()),
+            ],
+            body,
+        }
+    }
+
+    fn error(status: &'static str) -> Self {
+        Response::new(status, "text/plain", format!("{}\n", status).into_bytes())
+    }
+
+    /// The response as sent, without the body for a `HEAD` request
+    pub fn to_bytes(&self, head: bool) -> Vec<u8> {
+        let mut data = format!("HTTP/1.1 {}\r\n", self.status).into_bytes();
+        for (name, value) in &self.headers {
+            data.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        data.extend_from_slice(b"Connection: close\r\n\r\n");
+        if !head {
+            data.extend_from_slice(&self.body);
+        }
+        data
+    }
+}
+
+impl OtaArtifact {
+    /// Serve `image`, and `version` at [`VERSION_PATH`] if given
+    pub fn new(image: Vec<u8>, version: Option<String>) -> Self {
+        let etag = format!("\"{:x}\"", Sha256::digest(&image));
+        OtaArtifact {
+            image,
+            version,
+            etag,
+        }
+    }
+
+    /// The response to a `GET` of `path`, `range` being the value of the
+    /// `Range` header
+    pub fn response(&self, path: &str, range: Option<&str>) -> Response {
+        // the query is commonly used to defeat caches, it doesn't matter here
+        let path = path.split('?').next().unwrap_or_default();
+        match (path, &self.version) {
+            (FIRMWARE_PATH, _) => self.firmware(range),
+            (VERSION_PATH, Some(version)) => Response::new(
+                "200 OK",
+                "text/plain",
+                format!("{}\n", version).into_bytes(),
+            ),
+            _ => Response::error("404 Not Found"),
+        }
+    }
+
+    fn firmware(&self, range: Option<&str>) -> Response {
+        let len = self.image.len();
+        let (status, start, end) = match range.map(|range| parse_range(range, len)) {
+            None => ("200 OK", 0, len),
+            Some(Some((start, end))) => ("206 Partial Content", start, end),
+            Some(None) => {
+                let mut response = Response::error("416 Range Not Satisfiable");
+                response
+                    .headers
+                    .push(("Content-Range", format!("bytes */{}", len)));
+                return response;
+            }
+        };
+
+        let mut response = Response::new(
+            status,
+            "application/octet-stream",
+            self.image[start..end].to_vec(),
+        );
+        response.headers.extend([
+            ("Accept-Ranges", "bytes".to_string()),
+            ("ETag", self.etag.clone()),
+            (
+                "Content-Disposition",
+                "attachment; filename=\"firmware.bin\"".to_string(),
+            ),
+        ]);
+        if start != 0 || end != len {
+            response.headers.push((
+                "Content-Range",
+                format!("bytes {}-{}/{}", start, end - 1, len),
+            ));
+        }
+        response
+    }
+
+    /// Serve requests on `listener` until an error occurs
+    pub fn serve(&self, listener: &TcpListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            // a client going away shouldn't stop the server
+            if let Err(err) = self.handle(stream) {
+                log::warn!("Failed to answer request: {}", err);
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let peer = stream.peer_addr()?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let mut fields = line.split_whitespace();
+        let method = fields.next().unwrap_or_default().to_string();
+        let path = fields.next().unwrap_or_default().to_string();
+
+        let mut range = None;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("range") {
+                    range = Some(value.trim().to_string());
+                }
+            }
+        }
+
+        let response = match method.as_str() {
+            "GET" | "HEAD" => self.response(&path, range.as_deref()),
+            _ => Response::error("405 Method Not Allowed"),
+        };
+        log::info!("{} {} {} {}", peer, method, path, response.status);
+        stream.write_all(&response.to_bytes(method == "HEAD"))
+    }
+
+    /// Write the responses `openssl s_server -HTTP` serves to `dir`, which
+    /// doesn't support range requests
+    pub fn write_responses(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        for path in [FIRMWARE_PATH, VERSION_PATH] {
+            let mut response = self.response(path, None);
+            response
+                .headers
+                .retain(|(name, _)| *name != "Accept-Ranges");
+            if response.status == "200 OK" {
+                fs::write(
+                    dir.join(path.trim_start_matches('/')),
+                    response.to_bytes(false),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Serve over HTTPS on `listen` using `openssl s_server`, with the
+    /// certificate and private key in PEM format
+    pub fn serve_https(&self, listen: &str, cert: &Path, key: &Path) -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("espflash-ota-{}", std::process::id()));
+        // openssl runs in the directory of the responses
+        let cert = fs::canonicalize(cert)?;
+        let key = fs::canonicalize(key)?;
+        self.write_responses(&dir)?;
+
+        let status = Command::new("openssl")
+            .args(["s_server", "-quiet", "-HTTP", "-http_server_binmode"])
+            .arg("-accept")
+            .arg(listen)
+            .arg("-cert")
+            .arg(cert)
+            .arg("-key")
+            .arg(key)
+            .current_dir(&dir)
+            .status();
+        fs::remove_dir_all(&dir)?;
+
+        match status? {
+            status if status.success() => Ok(()),
+            status => Err(io::Error::other(format!("openssl exited with {}", status))),
+        }
+    }
+}
+
+/// The half-open byte range of a `bytes=` range header, `None` if it can't be
+/// satisfied
+fn parse_range(range: &str, len: usize) -> Option<(usize, usize)> {
+    let (start, end) = range.strip_prefix("bytes=")?.trim().split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        // the last `end` bytes
+        ("", end) => (len.checked_sub(end.parse().ok()?)?, len),
+        (start, "") => (start.parse().ok()?, len),
+        (start, end) => (
+            start.parse().ok()?,
+            end.parse::<usize>().ok()?.checked_add(1)?,
+        ),
+    };
+    let end = end.min(len);
+    (start < end).then_some((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header<'a>(response: &'a Response, name: &str) -> Option<&'a str> {
+        response
+            .headers
+            .iter()
+            .find(|(header, _)| *header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn test_firmware_response() {
+        let image: Vec<u8> = (0..100).collect();
+        let artifact = OtaArtifact::new(image.clone(), None);
+
+        let response = artifact.response("/firmware.bin?t=1", None);
+        assert_eq!("200 OK", response.status);
+        assert_eq!(image, response.body);
+        assert_eq!(Some("100"), header(&response, "Content-Length"));
+        assert_eq!(
+            Some("application/octet-stream"),
+            header(&response, "Content-Type")
+        );
+
+        let response = artifact.response(FIRMWARE_PATH, Some("bytes=90-"));
+        assert_eq!("206 Partial Content", response.status);
+        assert_eq!(&image[90..], response.body.as_slice());
+        assert_eq!(Some("bytes 90-99/100"), header(&response, "Content-Range"));
+
+        let response = artifact.response(FIRMWARE_PATH, Some("bytes=200-300"));
+        assert_eq!("416 Range Not Satisfiable", response.status);
+
+        assert_eq!(
+            "404 Not Found",
+            artifact.response(VERSION_PATH, None).status
+        );
+    }
+
+    #[test]
+    fn test_version_response() {
+        let artifact = OtaArtifact::new(vec![0xe9; 16], Some("1.2.3".to_string()));
+        let response = artifact.response(VERSION_PATH, None);
+        assert_eq!(b"1.2.3\n".to_vec(), response.body);
+
+        let data = response.to_bytes(true);
+        let data = String::from_utf8(data).unwrap();
+        assert!(data.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(data.ends_with("Connection: close\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(Some((0, 10)), parse_range("bytes=0-9", 100));
+        assert_eq!(Some((50, 100)), parse_range("bytes=50-200", 100));
+        assert_eq!(Some((80, 100)), parse_range("bytes=-20", 100));
+        assert_eq!(None, parse_range("bytes=-200", 100));
+        assert_eq!(None, parse_range("bytes=100-", 100));
+        assert_eq!(None, parse_range("items=0-9", 100));
+    }
+}
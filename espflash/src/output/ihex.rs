@@ -0,0 +1,95 @@
+use std::fmt::Write;
+
+use crate::elf::RomSegment;
+
+const DATA_RECORD: u8 = 0x00;
+const END_OF_FILE_RECORD: u8 = 0x01;
+const EXTENDED_LINEAR_ADDRESS_RECORD: u8 = 0x04;
+
+const BYTES_PER_RECORD: usize = 16;
+
+/// Encode the segments as Intel HEX records
+pub fn to_ihex(segments: &[RomSegment]) -> String {
+    let mut hex = String::new();
+    let mut upper_addr = None;
+
+    for segment in segments {
+        for (i, chunk) in segment.data.chunks(BYTES_PER_RECORD).enumerate() {
+            let addr = segment.addr + (i * BYTES_PER_RECORD) as u32;
+
+            // records can only address 64KiB, emit an extended linear address
+            // record whenever we cross into a new region
+            let upper = (addr >> 16) as u16;
+            if upper_addr != Some(upper) {
+                write_record(
+                    &mut hex,
+                    0,
+                    EXTENDED_LINEAR_ADDRESS_RECORD,
+                    &upper.to_be_bytes(),
+                );
+                upper_addr = Some(upper);
+            }
+
+            // a record may not wrap around the 64KiB boundary
+            let split = usize::min(chunk.len(), 0x10000 - (addr & 0xffff) as usize);
+            write_record(&mut hex, addr as u16, DATA_RECORD, &chunk[..split]);
+            if split < chunk.len() {
+                let addr = addr + split as u32;
+                let upper = (addr >> 16) as u16;
+                write_record(
+                    &mut hex,
+                    0,
+                    EXTENDED_LINEAR_ADDRESS_RECORD,
+                    &upper.to_be_bytes(),
+                );
+                upper_addr = Some(upper);
+                write_record(&mut hex, addr as u16, DATA_RECORD, &chunk[split..]);
+            }
+        }
+    }
+
+    write_record(&mut hex, 0, END_OF_FILE_RECORD, &[]);
+    hex
+}
+
+fn write_record(hex: &mut String, addr: u16, ty: u8, data: &[u8]) {
+    let [addr_high, addr_low] = addr.to_be_bytes();
+    let mut checksum = (data.len() as u8)
+        .wrapping_add(addr_high)
+        .wrapping_add(addr_low)
+        .wrapping_add(ty);
+
+    write!(hex, ":{:02X}{:04X}{:02X}", data.len(), addr, ty).unwrap();
+    for byte in data {
+        write!(hex, "{:02X}", byte).unwrap();
+        checksum = checksum.wrapping_add(*byte);
+    }
+    writeln!(hex, "{:02X}", checksum.wrapping_neg()).unwrap();
+}
+
+#[test]
+fn test_to_ihex() {
+    use std::borrow::Cow;
+
+    let segments = [
+        RomSegment {
+            addr: 0x0,
+            data: Cow::Owned(vec![0x01, 0x02]),
+        },
+        RomSegment {
+            addr: 0x1fffe,
+            data: Cow::Owned(vec![0xaa, 0xbb, 0xcc]),
+        },
+    ];
+
+    assert_eq!(
+        to_ihex(&segments),
+        ":020000040000FA\n\
+         :020000000102FB\n\
+         :020000040001F9\n\
+         :02FFFE00AABB9C\n\
+         :020000040002F8\n\
+         :01000000CC33\n\
+         :00000001FF\n"
+    );
+}
@@ -0,0 +1,184 @@
+use strum_macros::{Display, EnumString};
+
+use crate::{
+    app_desc::AppDescriptorOverrides,
+    elf::{FirmwareImage, FlashSettings, FlashSize, RomSegment, SegmentLayout},
+    error::ElfError,
+    Chip, Error, PartitionTable,
+};
+
+use self::{ihex::to_ihex, srec::to_srec, uf2::to_uf2};
+
+mod ihex;
+mod srec;
+mod uf2;
+
+/// File format of a generated image
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Display, EnumString)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum OutputFormat {
+    /// Raw binary, to be written at offset 0
+    #[default]
+    Bin,
+    /// UF2 file for drag-and-drop onto a UF2 bootloader
+    Uf2,
+    /// Intel HEX file, as used by many production programmers
+    Hex,
+    /// Motorola S-record file
+    Srec,
+}
+
+/// Generate a single image containing the bootloader, partition table and
+/// application, each placed at its offset in flash.
+///
+/// For binary output gaps between the parts are filled with `fill`, if a
+/// flash size is given in `settings` the image is padded up to the full flash
+/// size. In all formats the image header is updated with the given settings.
+#[allow(clippy::too_many_arguments)]
+pub fn merged_image(
+    chip: Chip,
+    elf_data: &[u8],
+    bootloader: Option<Vec<u8>>,
+    partition_table: Option<PartitionTable>,
+    app_desc: &AppDescriptorOverrides,
+    settings: &FlashSettings,
+    layout: &SegmentLayout,
+    fill: u8,
+    format: OutputFormat,
+) -> Result<Vec<u8>, Error> {
+    let image = firmware_image(elf_data, app_desc, settings, layout)?;
+    let mut segments = chip
+        .get_flash_segments(&image, bootloader, partition_table)
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    match format {
+        OutputFormat::Bin => {
+            merge_segments(&mut segments, settings.size.map(FlashSize::size), fill)
+        }
+        OutputFormat::Uf2 => {
+            segments.sort_by_key(|segment| segment.addr);
+            Ok(to_uf2(chip.uf2_family_id(), &segments))
+        }
+        OutputFormat::Hex => {
+            segments.sort_by_key(|segment| segment.addr);
+            Ok(to_ihex(&segments).into_bytes())
+        }
+        OutputFormat::Srec => {
+            segments.sort_by_key(|segment| segment.addr);
+            Ok(to_srec(&segments).into_bytes())
+        }
+    }
+}
+
+/// Generate the bootloader, partition table and application as separate
+/// images, with their offsets in flash
+///
+/// The images are returned in the order they are generated in, with the
+/// application last. The image header is updated with the given settings.
+pub fn split_image(
+    chip: Chip,
+    elf_data: &[u8],
+    bootloader: Option<Vec<u8>>,
+    partition_table: Option<PartitionTable>,
+    app_desc: &AppDescriptorOverrides,
+    settings: &FlashSettings,
+    layout: &SegmentLayout,
+) -> Result<Vec<(u32, Vec<u8>)>, Error> {
+    let image = firmware_image(elf_data, app_desc, settings, layout)?;
+    chip.get_flash_segments(&image, bootloader, partition_table)
+        .map(|segment| segment.map(|segment| (segment.addr, segment.data.into_owned())))
+        .collect()
+}
+
+fn firmware_image<'a>(
+    elf_data: &'a [u8],
+    app_desc: &AppDescriptorOverrides,
+    settings: &FlashSettings,
+    layout: &SegmentLayout,
+) -> Result<FirmwareImage<'a>, Error> {
+    let mut image = FirmwareImage::from_data(elf_data).map_err(ElfError::from)?;
+    settings.apply(&mut image);
+    image.segment_layout = *layout;
+    image.app_desc = app_desc.clone();
+    Ok(image)
+}
+
+/// Generate only the application image, as written to its partition
+pub fn app_image(chip: Chip, elf_data: &[u8]) -> Result<Vec<u8>, Error> {
+    let image = FirmwareImage::from_data(elf_data).map_err(ElfError::from)?;
+
+    // The application is always the last segment generated for a chip
+    let app = chip
+        .get_flash_segments(&image, None, None)
+        .last()
+        .expect("an image always contains the application")?;
+
+    Ok(app.data.into_owned())
+}
+
+/// Lay out the segments in a single buffer starting at address 0
+fn merge_segments(
+    segments: &mut [RomSegment],
+    size: Option<u32>,
+    fill: u8,
+) -> Result<Vec<u8>, Error> {
+    segments.sort_by_key(|segment| segment.addr);
+
+    let mut data = Vec::new();
+    for segment in segments.iter() {
+        let addr = segment.addr as usize;
+        if addr < data.len() {
+            return Err(Error::OverlappingSegments(segment.addr));
+        }
+        data.resize(addr, fill);
+        data.extend_from_slice(&segment.data);
+    }
+
+    if let Some(size) = size {
+        if data.len() > size as usize {
+            return Err(Error::ImageTooLarge {
+                required: data.len() as u32,
+                size,
+            });
+        }
+        data.resize(size as usize, fill);
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+
+    fn segment(addr: u32, data: &[u8]) -> RomSegment<'static> {
+        RomSegment {
+            addr,
+            data: Cow::Owned(data.to_vec()),
+        }
+    }
+
+    #[test]
+    fn test_merge_segments() {
+        let mut segments = [segment(4, &[3, 4]), segment(1, &[1, 2])];
+
+        let merged = merge_segments(&mut segments, None, 0xff).unwrap();
+        assert_eq!(merged, [0xff, 1, 2, 0xff, 3, 4]);
+
+        let merged = merge_segments(&mut segments, Some(8), 0).unwrap();
+        assert_eq!(merged, [0, 1, 2, 0, 3, 4, 0, 0]);
+
+        assert!(merge_segments(&mut segments, Some(4), 0).is_err());
+    }
+
+    #[test]
+    fn test_merge_overlapping_segments() {
+        let mut segments = [segment(0, &[1, 2]), segment(1, &[3])];
+
+        assert!(matches!(
+            merge_segments(&mut segments, None, 0),
+            Err(Error::OverlappingSegments(1))
+        ));
+    }
+}
@@ -0,0 +1,50 @@
+use std::fmt::Write;
+
+use crate::elf::RomSegment;
+
+const BYTES_PER_RECORD: usize = 16;
+
+/// Encode the segments as Motorola S-records using 32 bit addresses
+pub fn to_srec(segments: &[RomSegment]) -> String {
+    let mut srec = String::new();
+
+    write_record(&mut srec, 0, &[0, 0], b"espflash");
+    for segment in segments {
+        for (i, chunk) in segment.data.chunks(BYTES_PER_RECORD).enumerate() {
+            let addr = segment.addr + (i * BYTES_PER_RECORD) as u32;
+            write_record(&mut srec, 3, &addr.to_be_bytes(), chunk);
+        }
+    }
+    write_record(&mut srec, 7, &0u32.to_be_bytes(), &[]);
+
+    srec
+}
+
+fn write_record(srec: &mut String, ty: u8, addr: &[u8], data: &[u8]) {
+    let count = (addr.len() + data.len() + 1) as u8;
+    let mut checksum = count;
+
+    write!(srec, "S{}{:02X}", ty, count).unwrap();
+    for byte in addr.iter().chain(data) {
+        write!(srec, "{:02X}", byte).unwrap();
+        checksum = checksum.wrapping_add(*byte);
+    }
+    writeln!(srec, "{:02X}", !checksum).unwrap();
+}
+
+#[test]
+fn test_to_srec() {
+    use std::borrow::Cow;
+
+    let segments = [RomSegment {
+        addr: 0x1000,
+        data: Cow::Owned(vec![0xaa]),
+    }];
+
+    let srec = to_srec(&segments);
+    let mut lines = srec.lines();
+    assert!(lines.next().unwrap().starts_with("S00B0000"));
+    assert_eq!(Some("S30600001000AA3F"), lines.next());
+    assert_eq!(Some("S70500000000FA"), lines.next());
+    assert_eq!(None, lines.next());
+}
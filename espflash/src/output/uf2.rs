@@ -0,0 +1,87 @@
+use crate::elf::RomSegment;
+
+const UF2_MAGIC_START0: u32 = 0x0A324655;
+const UF2_MAGIC_START1: u32 = 0x9E5D5157;
+const UF2_MAGIC_END: u32 = 0x0AB16F30;
+
+const UF2_FLAG_FAMILY_ID_PRESENT: u32 = 0x00002000;
+
+const UF2_BLOCK_SIZE: usize = 512;
+const UF2_PAYLOAD_SIZE: usize = 256;
+const UF2_DATA_SIZE: usize = 476;
+
+/// Encode the segments as UF2 blocks of 256 bytes each, tagged with the
+/// family id of the target chip
+pub fn to_uf2(family_id: u32, segments: &[RomSegment]) -> Vec<u8> {
+    let block_count: usize = segments
+        .iter()
+        .map(|segment| segment.data.len().div_ceil(UF2_PAYLOAD_SIZE))
+        .sum();
+
+    let mut data = Vec::with_capacity(block_count * UF2_BLOCK_SIZE);
+    let mut block_no = 0;
+
+    for segment in segments {
+        for (i, chunk) in segment.data.chunks(UF2_PAYLOAD_SIZE).enumerate() {
+            let addr = segment.addr + (i * UF2_PAYLOAD_SIZE) as u32;
+
+            for word in [
+                UF2_MAGIC_START0,
+                UF2_MAGIC_START1,
+                UF2_FLAG_FAMILY_ID_PRESENT,
+                addr,
+                UF2_PAYLOAD_SIZE as u32,
+                block_no,
+                block_count as u32,
+                family_id,
+            ] {
+                data.extend_from_slice(&word.to_le_bytes());
+            }
+
+            // pad partial chunks with the value of erased flash
+            let mut payload = [0u8; UF2_DATA_SIZE];
+            payload[..UF2_PAYLOAD_SIZE].fill(0xff);
+            payload[..chunk.len()].copy_from_slice(chunk);
+            data.extend_from_slice(&payload);
+
+            data.extend_from_slice(&UF2_MAGIC_END.to_le_bytes());
+            block_no += 1;
+        }
+    }
+
+    data
+}
+
+#[test]
+fn test_to_uf2() {
+    use std::borrow::Cow;
+
+    let segments = [RomSegment {
+        addr: 0x1000,
+        data: Cow::Owned(vec![0xaa; 300]),
+    }];
+
+    let uf2 = to_uf2(0x1c5f21b0, &segments);
+    assert_eq!(2 * UF2_BLOCK_SIZE, uf2.len());
+
+    let word = |block: usize, index: usize| {
+        let offset = block * UF2_BLOCK_SIZE + index * 4;
+        u32::from_le_bytes([
+            uf2[offset],
+            uf2[offset + 1],
+            uf2[offset + 2],
+            uf2[offset + 3],
+        ])
+    };
+    assert_eq!(UF2_MAGIC_START0, word(0, 0));
+    assert_eq!(0x1100, word(1, 3));
+    assert_eq!(1, word(1, 5));
+    assert_eq!(2, word(1, 6));
+    assert_eq!(0x1c5f21b0, word(1, 7));
+    assert_eq!(UF2_MAGIC_END, word(1, 127));
+
+    // the second block only carries 44 bytes of data
+    let payload = &uf2[UF2_BLOCK_SIZE + 32..UF2_BLOCK_SIZE + 32 + UF2_PAYLOAD_SIZE];
+    assert!(payload[..44].iter().all(|b| *b == 0xaa));
+    assert!(payload[44..].iter().all(|b| *b == 0xff));
+}
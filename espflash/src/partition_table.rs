@@ -1,15 +1,28 @@
 use md5::{Context, Digest};
+use miette::SourceSpan;
 use regex::Regex;
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::error::PartitionTableError;
+use crate::error::{
+    AppTooLargeError, BinaryPartitionTableError, InvalidPartitionTableError, PartitionTableError,
+    StructuredPartitionTableError,
+};
+use crate::Error;
+use std::convert::TryFrom;
 use std::io::Write;
 
-const MAX_PARTITION_LENGTH: usize = 0xC00;
+pub(crate) const MAX_PARTITION_LENGTH: usize = 0xC00;
 const PARTITION_TABLE_SIZE: usize = 0x1000;
 const MAX_PARTITION_TABLE_ENTRIES: usize = 95;
+const PARTITION_MAGIC: [u8; 2] = [0xAA, 0x50];
+const MD5_MAGIC: [u8; 2] = [0xEB, 0xEB];
+const APP_PARTITION_ALIGNMENT: u32 = 0x10000;
+const DATA_PARTITION_ALIGNMENT: u32 = 0x1000;
 
-#[derive(Copy, Clone, Debug, Deserialize)]
+/// Names of the partition flags and their bits
+const PARTITION_FLAGS: [(&str, u32); 2] = [("encrypted", 1 << 0), ("readonly", 1 << 1)];
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 #[repr(u8)]
 #[allow(dead_code)]
 #[serde(rename_all = "lowercase")]
@@ -18,7 +31,7 @@ pub enum Type {
     Data = 0x01,
 }
 
-#[derive(Copy, Clone, Debug, Deserialize)]
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 #[repr(u8)]
 #[allow(dead_code)]
 #[serde(rename_all = "lowercase")]
@@ -59,7 +72,18 @@ pub enum AppType {
     Test = 0x20,
 }
 
-#[derive(Copy, Clone, Debug, Deserialize)]
+impl AppType {
+    /// The index of the OTA slot, `None` for the factory and test partitions
+    #[cfg(feature = "serial")]
+    pub fn ota_slot(self) -> Option<u32> {
+        match self as u8 {
+            slot @ 0x10..=0x1f => Some(slot as u32 - 0x10),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 #[repr(u8)]
 #[allow(dead_code)]
 #[serde(rename_all = "lowercase")]
@@ -68,6 +92,7 @@ pub enum DataType {
     Phy = 0x01,
     Nvs = 0x02,
     CoreDump = 0x03,
+    #[serde(rename = "nvs_keys", alias = "nvskeys")]
     NvsKeys = 0x04,
     EFuse = 0x05,
     Undefined = 0x06,
@@ -76,7 +101,7 @@ pub enum DataType {
     Spiffs = 0x82,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[allow(dead_code)]
 #[serde(untagged)]
 pub enum SubType {
@@ -84,6 +109,54 @@ pub enum SubType {
     Data(DataType),
 }
 
+impl TryFrom<u8> for AppType {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(AppType::Factory),
+            0x10 => Ok(AppType::Ota0),
+            0x11 => Ok(AppType::Ota1),
+            0x12 => Ok(AppType::Ota2),
+            0x13 => Ok(AppType::Ota3),
+            0x14 => Ok(AppType::Ota4),
+            0x15 => Ok(AppType::Ota5),
+            0x16 => Ok(AppType::Ota6),
+            0x17 => Ok(AppType::Ota7),
+            0x18 => Ok(AppType::Ota8),
+            0x19 => Ok(AppType::Ota9),
+            0x1a => Ok(AppType::Ota10),
+            0x1b => Ok(AppType::Ota11),
+            0x1c => Ok(AppType::Ota12),
+            0x1d => Ok(AppType::Ota13),
+            0x1e => Ok(AppType::Ota14),
+            0x1f => Ok(AppType::Ota15),
+            0x20 => Ok(AppType::Test),
+            _ => Err(value),
+        }
+    }
+}
+
+impl TryFrom<u8> for DataType {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(DataType::Ota),
+            0x01 => Ok(DataType::Phy),
+            0x02 => Ok(DataType::Nvs),
+            0x03 => Ok(DataType::CoreDump),
+            0x04 => Ok(DataType::NvsKeys),
+            0x05 => Ok(DataType::EFuse),
+            0x06 => Ok(DataType::Undefined),
+            0x80 => Ok(DataType::EspHttpd),
+            0x81 => Ok(DataType::Fat),
+            0x82 => Ok(DataType::Spiffs),
+            _ => Err(value),
+        }
+    }
+}
+
 impl SubType {
     fn as_u8(&self) -> u8 {
         match self {
@@ -96,6 +169,9 @@ impl SubType {
 #[derive(Debug)]
 pub struct PartitionTable {
     partitions: Vec<Partition>,
+    /// The CSV the table was parsed from, used to point at partitions in
+    /// diagnostics
+    source: Option<String>,
 }
 
 impl PartitionTable {
@@ -133,6 +209,7 @@ impl PartitionTable {
                     None,
                 ),
             ],
+            source: None,
         }
     }
 
@@ -140,23 +217,429 @@ impl PartitionTable {
     /// information on the partition table CSV format see:
     /// https://docs.espressif.com/projects/esp-idf/en/latest/esp32/api-guides/partition-tables.html
     pub fn try_from_str<S: Into<String>>(data: S) -> Result<Self, PartitionTableError> {
-        let data = data.into();
+        // positions reported by the reader are relative to the trimmed data
+        let data = data.into().trim().to_string();
         let mut reader = csv::ReaderBuilder::new()
             .comment(Some(b'#'))
             .has_headers(false)
             .trim(csv::Trim::All)
-            .from_reader(data.trim().as_bytes());
+            .from_reader(data.as_bytes());
 
         let mut partitions = Vec::with_capacity(MAX_PARTITION_TABLE_ENTRIES);
-        for partition in reader.deserialize() {
-            let partition: Partition =
-                partition.map_err(|e| PartitionTableError::new(e, data.clone()))?;
+        for record in reader.records() {
+            let record = record.map_err(|e| PartitionTableError::new(e, data.clone()))?;
+            let mut partition: Partition = record
+                .deserialize(None)
+                .map_err(|e| PartitionTableError::new(e, data.clone()))?;
+            partition.span = record
+                .position()
+                .map(|pos| record_span(&data, pos.byte() as usize));
             partitions.push(partition);
         }
 
-        Ok(Self { partitions })
+        Ok(Self {
+            partitions,
+            source: Some(data),
+        })
+    }
+
+    /// Attempt to parse a partition table in the binary format written to
+    /// flash, as generated by `gen_esp32part.py`
+    pub fn try_from_bytes(data: &[u8]) -> Result<Self, BinaryPartitionTableError> {
+        let data = &data[..data.len().min(MAX_PARTITION_LENGTH)];
+
+        let mut partitions = Vec::with_capacity(MAX_PARTITION_TABLE_ENTRIES);
+        for (index, entry) in data.chunks_exact(PARTITION_SIZE).enumerate() {
+            match [entry[0], entry[1]] {
+                PARTITION_MAGIC if index >= MAX_PARTITION_TABLE_ENTRIES => {
+                    return Err(BinaryPartitionTableError::TooManyEntries(
+                        MAX_PARTITION_TABLE_ENTRIES,
+                    ))
+                }
+                PARTITION_MAGIC => partitions.push(Partition::from_bytes(entry, index)?),
+                MD5_MAGIC => {
+                    let table = &data[..index * PARTITION_SIZE];
+                    if md5::compute(table).0 != entry[16..] {
+                        return Err(BinaryPartitionTableError::ChecksumMismatch);
+                    }
+                }
+                [0xFF, 0xFF] => break,
+                magic => {
+                    return Err(BinaryPartitionTableError::InvalidMagic(
+                        u16::from_be_bytes(magic),
+                        index,
+                    ))
+                }
+            }
+        }
+
+        Ok(Self {
+            partitions,
+            source: None,
+        })
+    }
+
+    /// Attempt to parse a partition table from a JSON array of partitions
+    ///
+    /// Each partition is an object with the same fields as the CSV format,
+    /// `name`, `type`, `subtype`, `offset`, `size` and optionally `flags`.
+    /// Offsets and sizes can be given as numbers or in any format accepted in
+    /// the CSV format.
+    pub fn try_from_json(data: &str) -> Result<Self, StructuredPartitionTableError> {
+        let partitions = serde_json::from_str(data)?;
+        Ok(Self {
+            partitions,
+            source: None,
+        })
+    }
+
+    /// Attempt to parse a partition table from a YAML list of partitions,
+    /// with the same fields as [`try_from_json`]
+    ///
+    /// [`try_from_json`]: PartitionTable::try_from_json
+    pub fn try_from_yaml(data: &str) -> Result<Self, StructuredPartitionTableError> {
+        let partitions = serde_yaml::from_str(data)?;
+        Ok(Self {
+            partitions,
+            source: None,
+        })
+    }
+
+    /// Parse a partition table from the binary, CSV, JSON or YAML format,
+    /// detecting the format from the contents
+    ///
+    /// The table isn't validated, so fragments and tables without an app
+    /// partition can still be converted.
+    pub fn try_from_data(data: Vec<u8>) -> Result<Self, Error> {
+        if data.starts_with(&PARTITION_MAGIC) {
+            return Ok(Self::try_from_bytes(&data)?);
+        }
+
+        let data = String::from_utf8_lossy(&data).into_owned();
+        let first_line = data
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'));
+        match first_line {
+            // JSON is a subset of YAML, so flow style YAML lists are parsed as
+            // YAML if they aren't valid JSON
+            Some(line) if line.starts_with('[') => Ok(Self::try_from_json(&data)
+                .or_else(|err| Self::try_from_yaml(&data).map_err(|_| err))?),
+            Some(line) if line.starts_with('-') => Ok(Self::try_from_yaml(&data)?),
+            _ => Ok(Self::try_from_str(data)?),
+        }
+    }
+
+    /// Write the partition table in the CSV format used by ESP-IDF
+    pub fn to_csv(&self) -> String {
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(b"# Name, Type, SubType, Offset, Size, Flags\n".to_vec());
+        for partition in &self.partitions {
+            writer.serialize(partition).unwrap();
+        }
+
+        String::from_utf8(writer.into_inner().unwrap()).unwrap()
+    }
+
+    /// Write the partition table as a JSON array of partitions, as read by
+    /// [`try_from_json`]
+    ///
+    /// [`try_from_json`]: PartitionTable::try_from_json
+    pub fn to_json(&self) -> String {
+        let mut partitions = serde_json::to_value(&self.partitions).unwrap();
+        for partition in partitions.as_array_mut().unwrap() {
+            let partition = partition.as_object_mut().unwrap();
+            if partition["flags"].is_null() {
+                partition.remove("flags");
+            }
+        }
+
+        serde_json::to_string_pretty(&partitions).unwrap()
+    }
+
+    /// Format the partitions as a table with aligned columns
+    pub fn to_table(&self) -> String {
+        let mut rows = vec![vec![
+            "Name".to_string(),
+            "Type".into(),
+            "SubType".into(),
+            "Offset".into(),
+            "Size".into(),
+            "Flags".into(),
+        ]];
+        let csv = self.to_csv();
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .comment(Some(b'#'))
+            .from_reader(csv.as_bytes());
+        for (record, partition) in reader.records().zip(&self.partitions) {
+            let mut row: Vec<String> = record.unwrap().iter().map(String::from).collect();
+            row[4] = format!("{} ({} KiB)", row[4], partition.size / 1024);
+            rows.push(row);
+        }
+
+        let mut widths = [0; 6];
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.len());
+            }
+        }
+
+        let mut output = String::new();
+        for row in rows {
+            let line: Vec<String> = row
+                .iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect();
+            output += line.join("  ").trim_end();
+            output.push('\n');
+        }
+        output
+    }
+
+    /// The offset of the end of the last partition
+    pub fn end(&self) -> u32 {
+        self.partitions
+            .iter()
+            .map(|partition| partition.offset + partition.size)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// The partition the bootloader starts the application from, the factory
+    /// partition or the first OTA slot if there is none
+    fn boot_partition(&self) -> Option<(usize, &Partition)> {
+        let find = |app_type: AppType| {
+            self.partitions.iter().enumerate().find(|(_, partition)| {
+                matches!(partition.sub_type, SubType::App(ty) if ty as u8 == app_type as u8)
+            })
+        };
+        find(AppType::Factory).or_else(|| find(AppType::Ota0))
+    }
+
+    /// The offset of the partition the application is booted from
+    pub(crate) fn app_offset(&self) -> Option<u32> {
+        self.boot_partition().map(|(_, partition)| partition.offset)
+    }
+
+    /// The offset and size of the partition with the given name
+    pub fn find(&self, name: &str) -> Option<(u32, u32)> {
+        self.partitions
+            .iter()
+            .find(|partition| partition.name == name)
+            .map(|partition| (partition.offset, partition.size))
     }
 
+    /// The name, offset and size of the partition called `name`, or of the
+    /// first data partition of subtype `ty` if no name is given
+    pub fn find_data(&self, name: Option<&str>, ty: DataType) -> Option<(&str, u32, u32)> {
+        self.partitions
+            .iter()
+            .find(|partition| match name {
+                Some(name) => partition.name == name,
+                None => matches!(partition.sub_type, SubType::Data(data) if data as u8 == ty as u8),
+            })
+            .map(|partition| (partition.name.as_str(), partition.offset, partition.size))
+    }
+
+    /// The offset and size of the `otadata` partition
+    #[cfg(feature = "serial")]
+    pub(crate) fn otadata(&self) -> Option<(u32, u32)> {
+        self.partitions
+            .iter()
+            .find(|partition| matches!(partition.sub_type, SubType::Data(DataType::Ota)))
+            .map(|partition| (partition.offset, partition.size))
+    }
+
+    /// The offset and size of the `coredump` partition
+    #[cfg(feature = "serial")]
+    pub(crate) fn coredump(&self) -> Option<(u32, u32)> {
+        self.partitions
+            .iter()
+            .find(|partition| matches!(partition.sub_type, SubType::Data(DataType::CoreDump)))
+            .map(|partition| (partition.offset, partition.size))
+    }
+
+    /// The subtype of the app partition with the given name
+    #[cfg(feature = "serial")]
+    pub(crate) fn app_type(&self, name: &str) -> Option<AppType> {
+        self.partitions
+            .iter()
+            .find_map(|partition| match partition.sub_type {
+                SubType::App(ty) if partition.name == name => Some(ty),
+                _ => None,
+            })
+    }
+
+    /// The number of OTA app partitions
+    #[cfg(feature = "serial")]
+    pub(crate) fn ota_app_count(&self) -> u32 {
+        self.partitions
+            .iter()
+            .filter(|partition| matches!(partition.sub_type, SubType::App(ty) if ty.ota_slot().is_some()))
+            .count() as u32
+    }
+
+    /// Whether any partition overlaps the `size` bytes of flash at `offset`
+    pub(crate) fn overlaps(&self, offset: u32, size: u32) -> bool {
+        let end = offset as u64 + size as u64;
+        self.partitions.iter().any(|partition| {
+            (partition.offset as u64) < end
+                && (offset as u64) < partition.offset as u64 + partition.size as u64
+        })
+    }
+
+    /// Check that an application of `app_size` bytes fits into the partition
+    /// it is booted from
+    pub fn check_app_fits(&self, app_size: u32) -> Result<(), AppTooLargeError> {
+        let (index, partition) = match self.boot_partition() {
+            Some(boot_partition) => boot_partition,
+            None => return Ok(()),
+        };
+        if app_size <= partition.size {
+            return Ok(());
+        }
+
+        let (source_code, span) = self.source_span(index);
+        Err(AppTooLargeError {
+            source_code,
+            span,
+            name: partition.name.clone(),
+            size: partition.size,
+            app_size,
+            suggested_size: app_size.div_ceil(APP_PARTITION_ALIGNMENT) * APP_PARTITION_ALIGNMENT,
+        })
+    }
+
+    /// The source of the table and the span of the partition at `index` in it
+    fn source_span(&self, index: usize) -> (String, SourceSpan) {
+        let (source, mut spans) = self.source_spans();
+        (source, spans.swap_remove(index))
+    }
+
+    /// The source of the table and the spans of all partitions in it
+    ///
+    /// Tables which weren't parsed from CSV are rendered as CSV, so that
+    /// diagnostics can always point at the offending partition.
+    fn source_spans(&self) -> (String, Vec<SourceSpan>) {
+        if let Some(source) = &self.source {
+            let spans: Option<Vec<_>> = self
+                .partitions
+                .iter()
+                .map(|partition| partition.span.clone())
+                .collect();
+            if let Some(spans) = spans {
+                return (source.clone(), spans);
+            }
+        }
+
+        let csv = self.to_csv();
+        let mut start = 0;
+        let spans = csv
+            .split_inclusive('\n')
+            .filter_map(|line| {
+                let span = SourceSpan::new(start.into(), line.trim_end().len().into());
+                start += line.len();
+                Some(span).filter(|_| !line.starts_with('#'))
+            })
+            .collect();
+
+        (csv, spans)
+    }
+
+    /// Check the table for overlapping or misaligned partitions, duplicate
+    /// names and missing partitions
+    pub fn validate(&self) -> Result<(), InvalidPartitionTableError> {
+        let mut problems = Vec::new();
+        let mut problem = |index: usize, message: String| problems.push((index, message));
+
+        for (index, partition) in self.partitions.iter().enumerate() {
+            let alignment = match partition.ty {
+                Type::App => APP_PARTITION_ALIGNMENT,
+                Type::Data => DATA_PARTITION_ALIGNMENT,
+            };
+            if !partition.offset.is_multiple_of(alignment) {
+                problem(
+                    index,
+                    format!(
+                        "offset {:#x} is not aligned to {:#x}",
+                        partition.offset, alignment
+                    ),
+                );
+            }
+            if partition.size == 0 {
+                problem(index, String::from("partition is empty"));
+            }
+
+            if index == MAX_PARTITION_TABLE_ENTRIES {
+                problem(
+                    index,
+                    format!(
+                        "the partition table holds at most {} partitions",
+                        MAX_PARTITION_TABLE_ENTRIES
+                    ),
+                );
+            }
+
+            let previous = &self.partitions[..index];
+            if previous.iter().any(|other| other.name == partition.name) {
+                problem(index, format!("duplicate name `{}`", partition.name));
+            }
+            let range = |partition: &Partition| {
+                partition.offset as u64..partition.offset as u64 + partition.size as u64
+            };
+            let (start, end) = (range(partition).start, range(partition).end);
+            if let Some(other) = previous
+                .iter()
+                .find(|other| start < range(other).end && range(other).start < end)
+            {
+                problem(index, format!("overlaps with `{}`", other.name));
+            }
+        }
+
+        let is_ota_app = |partition: &Partition| matches!(partition.sub_type, SubType::App(ty) if (AppType::Ota0 as u8..=AppType::Ota15 as u8).contains(&(ty as u8)));
+        let has_otadata = self
+            .partitions
+            .iter()
+            .any(|partition| matches!(partition.sub_type, SubType::Data(DataType::Ota)));
+        if let Some(index) = self.partitions.iter().position(is_ota_app) {
+            if !has_otadata {
+                problem(
+                    index,
+                    String::from("OTA partitions require an `otadata` partition"),
+                );
+            }
+        }
+
+        let has_app = self
+            .partitions
+            .iter()
+            .any(|partition| matches!(partition.ty, Type::App));
+        if problems.is_empty() && has_app {
+            return Ok(());
+        }
+
+        let (source_code, spans) = self.source_spans();
+        Err(InvalidPartitionTableError {
+            source_code,
+            problems: problems
+                .into_iter()
+                .map(|(index, message)| (spans[index].clone(), message))
+                .collect(),
+            missing_app: !has_app,
+        })
+    }
+
+    /// The table in the binary format written to flash
+    ///
+    /// # Panics
+    ///
+    /// Panics if the table has more partitions than fit in the binary format,
+    /// which [`validate`] reports.
+    ///
+    /// [`validate`]: PartitionTable::validate
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut result = Vec::with_capacity(PARTITION_TABLE_SIZE);
         self.save(&mut result).unwrap();
@@ -171,14 +654,21 @@ impl PartitionTable {
 
         let (writer, hash) = hasher.compute();
 
-        writer.write_all(&[
-            0xEB, 0xEB, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
-            0xFF, 0xFF,
-        ])?;
+        writer.write_all(&MD5_MAGIC)?;
+        writer.write_all(&[0xFF; 14])?;
         writer.write_all(&hash.0)?;
 
         let written = self.partitions.len() * PARTITION_SIZE + 32;
-        for _ in 0..(MAX_PARTITION_LENGTH - written) {
+        let padding = MAX_PARTITION_LENGTH.checked_sub(written).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "the partition table holds at most {} partitions",
+                    MAX_PARTITION_TABLE_ENTRIES
+                ),
+            )
+        })?;
+        for _ in 0..padding {
             writer.write_all(&[0xFF])?;
         }
 
@@ -188,17 +678,215 @@ impl PartitionTable {
 
 const PARTITION_SIZE: usize = 32;
 
-#[derive(Debug, Deserialize)]
+const VISUALIZATION_WIDTH: u32 = 64;
+const SVG_WIDTH: u32 = 800;
+const SVG_BAR_HEIGHT: u32 = 40;
+
+/// Escape text for use in XML content and attribute values
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A region of flash in a visualization of the partition table
+struct Region<'a> {
+    partition: Option<&'a Partition>,
+    offset: u32,
+    size: u32,
+    used: Option<u32>,
+}
+
+impl PartitionTable {
+    /// Render the layout of the partitions in a flash of `flash_size` bytes
+    /// as a proportional text bar, followed by a legend listing each
+    /// partition and the free gaps between them.
+    ///
+    /// If `app_size` is given, the part of the first app partition occupied
+    /// by the application is highlighted.
+    pub fn visualize(&self, flash_size: u32, app_size: Option<u32>) -> String {
+        let regions = self.regions(flash_size, app_size);
+        let mut letters = (b'a'..=b'z').cycle();
+        let symbols: Vec<char> = regions
+            .iter()
+            .map(|region| match region.partition {
+                Some(_) => letters.next().unwrap() as char,
+                None if region.offset == 0 => '#',
+                None => '.',
+            })
+            .collect();
+        let symbol = |index: usize, region: &Region, addr: u32| match region.used {
+            Some(used) if addr < region.offset + used => symbols[index].to_ascii_uppercase(),
+            _ => symbols[index],
+        };
+
+        let mut bar = String::from("|");
+        for column in 0..VISUALIZATION_WIDTH {
+            let addr = (column as u64 * flash_size as u64 / VISUALIZATION_WIDTH as u64) as u32;
+            if let Some((index, region)) = regions
+                .iter()
+                .enumerate()
+                .find(|(_, region)| (region.offset..region.offset + region.size).contains(&addr))
+            {
+                bar.push(symbol(index, region, addr));
+            }
+        }
+        bar.push('|');
+
+        let mut output = format!("{}\n", bar);
+        for (index, region) in regions.iter().enumerate() {
+            let name = match region.partition {
+                Some(partition) => partition.name.as_str(),
+                None if region.offset == 0 => "<reserved>",
+                None => "<free>",
+            };
+            output += &format!(
+                "{} {:<16} {:#08x} {:#08x} {:>6} KiB",
+                symbol(index, region, region.offset + region.size),
+                name,
+                region.offset,
+                region.size,
+                region.size / 1024
+            );
+            if let Some(used) = region.used {
+                output += &format!(
+                    "  {} KiB used ({}%)",
+                    used.div_ceil(1024),
+                    used as u64 * 100 / region.size.max(1) as u64
+                );
+            }
+            output.push('\n');
+        }
+
+        output
+    }
+
+    /// Render the layout of the partitions in a flash of `flash_size` bytes
+    /// as an SVG image, see [`visualize`] for details.
+    ///
+    /// [`visualize`]: PartitionTable::visualize
+    pub fn visualize_svg(&self, flash_size: u32, app_size: Option<u32>) -> String {
+        let scale = |value: u32| value as u64 * SVG_WIDTH as u64 / flash_size.max(1) as u64;
+
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" font-family=\"monospace\" font-size=\"10\">\n",
+            SVG_WIDTH,
+            SVG_BAR_HEIGHT + 20
+        );
+        for region in self.regions(flash_size, app_size) {
+            let (name, color) = match region.partition {
+                Some(partition) => match partition.ty {
+                    Type::App => (partition.name.as_str(), "#4e79a7"),
+                    Type::Data => (partition.name.as_str(), "#f28e2b"),
+                },
+                None if region.offset == 0 => ("<reserved>", "#bab0ac"),
+                None => ("<free>", "#eeeeee"),
+            };
+            let (x, width) = (scale(region.offset), scale(region.size));
+
+            svg += &format!(
+                "  <rect x=\"{}\" y=\"0\" width=\"{}\" height=\"{}\" fill=\"{}\" stroke=\"white\"><title>{} {:#x} {:#x}</title></rect>\n",
+                x,
+                width,
+                SVG_BAR_HEIGHT,
+                color,
+                xml_escape(name),
+                region.offset,
+                region.size
+            );
+            if let Some(used) = region.used {
+                svg += &format!(
+                    "  <rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"#2c4a6b\"><title>{} bytes used</title></rect>\n",
+                    x,
+                    SVG_BAR_HEIGHT / 2,
+                    scale(used.min(region.size)),
+                    SVG_BAR_HEIGHT / 2,
+                    used
+                );
+            }
+            if region.partition.is_some() {
+                svg += &format!(
+                    "  <text x=\"{}\" y=\"{}\">{}</text>\n",
+                    x + 2,
+                    SVG_BAR_HEIGHT + 14,
+                    xml_escape(name)
+                );
+            }
+        }
+        svg += "</svg>\n";
+
+        svg
+    }
+
+    /// The partitions sorted by offset, with the gaps in between
+    fn regions(&self, flash_size: u32, app_size: Option<u32>) -> Vec<Region<'_>> {
+        let mut partitions: Vec<_> = self.partitions.iter().collect();
+        partitions.sort_by_key(|partition| partition.offset);
+
+        let app = partitions
+            .iter()
+            .find(|partition| matches!(partition.ty, Type::App))
+            .map(|partition| partition.offset);
+
+        let mut regions = Vec::new();
+        let mut end = 0;
+        for partition in partitions {
+            if partition.offset > end {
+                regions.push(Region {
+                    partition: None,
+                    offset: end,
+                    size: partition.offset - end,
+                    used: None,
+                });
+            }
+            regions.push(Region {
+                partition: Some(partition),
+                offset: partition.offset,
+                size: partition.size,
+                used: app_size.filter(|_| app == Some(partition.offset)),
+            });
+            end = end.max(partition.offset + partition.size);
+        }
+        if flash_size > end {
+            regions.push(Region {
+                partition: None,
+                offset: end,
+                size: flash_size - end,
+                used: None,
+            });
+        }
+
+        regions
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 struct Partition {
     #[serde(deserialize_with = "deserialize_partition_name")]
     name: String,
+    #[serde(rename = "type", alias = "ty")]
     ty: Type,
+    #[serde(rename = "subtype", alias = "sub_type")]
     sub_type: SubType,
-    #[serde(deserialize_with = "deserialize_partition_offset_or_size")]
+    #[serde(
+        deserialize_with = "deserialize_partition_offset_or_size",
+        serialize_with = "serialize_hex"
+    )]
     offset: u32,
-    #[serde(deserialize_with = "deserialize_partition_offset_or_size")]
+    #[serde(
+        deserialize_with = "deserialize_partition_offset_or_size",
+        serialize_with = "serialize_hex"
+    )]
     size: u32,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_partition_flags",
+        serialize_with = "serialize_partition_flags"
+    )]
     flags: Option<u32>,
+    #[serde(skip)]
+    span: Option<SourceSpan>,
 }
 
 impl Partition {
@@ -219,11 +907,37 @@ impl Partition {
             offset,
             size,
             flags,
+            span: None,
         }
     }
 
+    fn from_bytes(entry: &[u8], index: usize) -> Result<Self, BinaryPartitionTableError> {
+        let sub_type = match entry[2] {
+            0x00 => SubType::App(
+                AppType::try_from(entry[3])
+                    .map_err(|ty| BinaryPartitionTableError::UnknownSubType(ty, index))?,
+            ),
+            0x01 => SubType::Data(
+                DataType::try_from(entry[3])
+                    .map_err(|ty| BinaryPartitionTableError::UnknownSubType(ty, index))?,
+            ),
+            ty => return Err(BinaryPartitionTableError::UnknownType(ty, index)),
+        };
+        let offset = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]);
+        let size = u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]);
+
+        let name = &entry[12..28];
+        let name_len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+        let name = String::from_utf8_lossy(&name[..name_len]).into_owned();
+
+        let flags = u32::from_le_bytes([entry[28], entry[29], entry[30], entry[31]]);
+        let flags = if flags == 0 { None } else { Some(flags) };
+
+        Ok(Partition::new(name, sub_type, offset, size, flags))
+    }
+
     pub fn save<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
-        writer.write_all(&[0xAA, 0x50])?;
+        writer.write_all(&PARTITION_MAGIC)?;
         writer.write_all(&[self.ty as u8, self.sub_type.as_u8()])?;
         writer.write_all(&self.offset.to_le_bytes())?;
         writer.write_all(&self.size.to_le_bytes())?;
@@ -244,6 +958,22 @@ impl Partition {
     }
 }
 
+/// The span of the line of the record starting at `start`
+///
+/// The position of the first record is reported before any leading comments,
+/// so these are skipped.
+fn record_span(data: &str, mut start: usize) -> SourceSpan {
+    for line in data[start..].split_inclusive('\n') {
+        let trimmed = line.trim();
+        if !trimmed.is_empty() && !trimmed.starts_with('#') {
+            return SourceSpan::new(start.into(), line.trim_end().len().into());
+        }
+        start += line.len();
+    }
+
+    SourceSpan::new(start.into(), 0.into())
+}
+
 fn deserialize_partition_name<'de, D>(deserializer: D) -> Result<String, D::Error>
 where
     D: Deserializer<'de>,
@@ -267,7 +997,17 @@ where
 {
     use serde::de::Error;
 
-    let buf = String::deserialize(deserializer)?;
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum NumberOrString {
+        Number(u32),
+        String(String),
+    }
+
+    let buf = match NumberOrString::deserialize(deserializer)? {
+        NumberOrString::Number(value) => return Ok(value),
+        NumberOrString::String(buf) => buf,
+    };
     let re = Regex::new(r"(?i)^(\d+)([km]{1})$").unwrap();
 
     // NOTE: Partitions of type 'app' must be placed at offsets aligned to 0x10000
@@ -277,27 +1017,99 @@ where
     if buf.starts_with("0x") {
         // Hexadecimal format
         let src = buf.trim_start_matches("0x");
-        let size = u32::from_str_radix(src, 16).unwrap();
-
-        Ok(size)
+        u32::from_str_radix(src, 16)
+            .map_err(|_| Error::custom(format!("invalid hexadecimal value `{}`", buf)))
     } else if let Ok(size) = buf.parse::<u32>() {
         // Decimal format
         Ok(size)
     } else if let Some(captures) = re.captures(&buf) {
         // Size multiplier format (1k, 2M, etc.)
-        let digits = captures.get(1).unwrap().as_str().parse::<u32>().unwrap();
-        let multiplier = match captures.get(2).unwrap().as_str() {
+        let too_large = || Error::custom(format!("partition size/offset `{}` is too large", buf));
+        let digits = captures[1].parse::<u32>().map_err(|_| too_large())?;
+        let multiplier = match &captures[2] {
             "k" | "K" => 1024,
             "m" | "M" => 1024 * 1024,
             _ => unreachable!(),
         };
 
-        Ok(digits * multiplier)
+        digits.checked_mul(multiplier).ok_or_else(too_large)
     } else {
         Err(Error::custom("invalid partition size/offset format"))
     }
 }
 
+/// Deserialize the flags column, a list of flag names separated by `:` like
+/// `encrypted:readonly`
+fn deserialize_partition_flags<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    use serde::de::Error;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum NumberOrString {
+        Number(u32),
+        String(String),
+    }
+
+    let buf = match Option::<NumberOrString>::deserialize(deserializer)? {
+        None => return Ok(None),
+        Some(NumberOrString::Number(flags)) => return Ok(Some(flags).filter(|&flags| flags != 0)),
+        Some(NumberOrString::String(buf)) => buf,
+    };
+
+    let mut flags = 0;
+    for name in buf
+        .split(':')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        flags |= match PARTITION_FLAGS.iter().find(|(flag, _)| *flag == name) {
+            Some((_, bit)) => *bit,
+            // flags unknown to us are written as numbers
+            None => match name.strip_prefix("0x") {
+                Some(hex) => u32::from_str_radix(hex, 16)
+                    .map_err(|_| Error::custom(format!("unknown partition flag `{}`", name)))?,
+                None => return Err(Error::custom(format!("unknown partition flag `{}`", name))),
+            },
+        };
+    }
+
+    Ok(Some(flags).filter(|&flags| flags != 0))
+}
+
+fn serialize_partition_flags<S>(flags: &Option<u32>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let flags = match flags {
+        Some(flags) => *flags,
+        None => return serializer.serialize_none(),
+    };
+
+    let mut names: Vec<String> = PARTITION_FLAGS
+        .iter()
+        .filter(|(_, bit)| flags & bit != 0)
+        .map(|(name, _)| name.to_string())
+        .collect();
+    let unknown = PARTITION_FLAGS
+        .iter()
+        .fold(flags, |flags, (_, bit)| flags & !bit);
+    if unknown != 0 {
+        names.push(format!("{:#x}", unknown));
+    }
+
+    serializer.serialize_str(&names.join(":"))
+}
+
+fn serialize_hex<S>(value: &u32, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&format!("{:#x}", value))
+}
+
 struct HashWriter<W: Write> {
     inner: W,
     hasher: Context,
@@ -385,4 +1197,250 @@ ota_1,    app,  ota_1,   0x210000, 1M,
         let pt1 = PartitionTable::try_from_str(PTABLE_1);
         assert!(pt1.is_ok());
     }
+
+    #[test]
+    fn test_visualize() {
+        let table = PartitionTable::try_from_str(PTABLE_0).unwrap();
+        let output = table.visualize(0x200000, Some(0x40000));
+        let mut lines = output.lines();
+
+        assert_eq!(
+            Some("|##CCCCCCCCcccccccccccccccccccccccc..............................|"),
+            lines.next()
+        );
+        assert_eq!(5, lines.count());
+    }
+
+    #[test]
+    fn test_visualize_svg() {
+        let mut table = PartitionTable::try_from_str(PTABLE_0).unwrap();
+        table.partitions[0].name = "a&b<\"c\">".into();
+        let svg = table.visualize_svg(0x200000, Some(0x40000));
+        let document = roxmltree::Document::parse(&svg).unwrap();
+
+        let titles: Vec<_> = document
+            .descendants()
+            .filter(|node| node.has_tag_name("title"))
+            .filter_map(|node| node.text())
+            .collect();
+        assert!(titles[0].starts_with("<reserved>"));
+        assert!(titles.iter().any(|title| title.starts_with("a&b<\"c\">")));
+        assert!(titles.iter().any(|title| title.starts_with("<free>")));
+
+        // an unknown flash size doesn't divide by zero
+        roxmltree::Document::parse(&table.visualize_svg(0, None)).unwrap();
+    }
+
+    #[test]
+    fn test_to_table() {
+        let table = PartitionTable::try_from_str(PTABLE_0).unwrap();
+        let output = table.to_table();
+        let mut lines = output.lines();
+
+        assert_eq!(
+            Some("Name      Type  SubType  Offset   Size                 Flags"),
+            lines.next()
+        );
+        assert_eq!(table.partitions.len(), lines.count());
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let expected = PartitionTable::try_from_str(PTABLE_1).unwrap().to_bytes();
+
+        let table = PartitionTable::try_from_data(expected.clone()).unwrap();
+        assert_eq!(6, table.partitions.len());
+        assert_eq!("otadata", table.partitions[1].name);
+        assert_eq!(expected, table.to_bytes());
+
+        let csv = table.to_csv();
+        let table = PartitionTable::try_from_str(csv).unwrap();
+        assert_eq!(expected, table.to_bytes());
+
+        let mut corrupted = expected;
+        corrupted[4] ^= 0x01;
+        assert!(matches!(
+            PartitionTable::try_from_bytes(&corrupted),
+            Err(BinaryPartitionTableError::ChecksumMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_from_json_and_yaml() {
+        let expected = PartitionTable::try_from_str(PTABLE_0).unwrap().to_bytes();
+
+        let json = r#"[
+            { "name": "nvs", "type": "data", "subtype": "nvs", "offset": "0x9000", "size": 24576 },
+            { "name": "phy_init", "type": "data", "subtype": "phy", "offset": 61440, "size": "4K" },
+            { "name": "factory", "type": "app", "subtype": "factory", "offset": "0x10000", "size": "1M" }
+        ]"#;
+        let table = PartitionTable::try_from_data(json.as_bytes().to_vec()).unwrap();
+        assert_eq!(expected, table.to_bytes());
+
+        let table = PartitionTable::try_from_json(&table.to_json()).unwrap();
+        assert_eq!(expected, table.to_bytes());
+
+        let yaml = "
+# ESP-IDF Partition Table
+- name: nvs
+  type: data
+  subtype: nvs
+  offset: 0x9000
+  size: 24576
+- name: 'phy_init' # PHY calibration data
+  type: data
+  subtype: phy
+  offset: 61440
+  size: 4K
+-
+  name: factory
+  type: app
+  subtype: factory
+  offset: 0x10000
+  size: 1M
+";
+        let table = PartitionTable::try_from_data(yaml.as_bytes().to_vec()).unwrap();
+        assert_eq!(expected, table.to_bytes());
+
+        let flow = "[{name: nvs, type: data, subtype: nvs, offset: 0x9000, size: 24K}]";
+        let table = PartitionTable::try_from_data(flow.as_bytes().to_vec()).unwrap();
+        assert_eq!(0x6000, table.partitions[0].size);
+
+        let quoted = "- {name: 'a #1', type: data, subtype: nvs, offset: 0x9000, size: 24K}";
+        let table = PartitionTable::try_from_yaml(quoted).unwrap();
+        assert_eq!("a #1", table.partitions[0].name);
+
+        assert!(matches!(
+            PartitionTable::try_from_yaml("- name: nvs\ntype: data"),
+            Err(StructuredPartitionTableError::Yaml(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_app_fits() {
+        let table = PartitionTable::try_from_str(PTABLE_0).unwrap();
+        let err = table.check_app_fits(0x100001).unwrap_err();
+        assert!(err.source_code[err.span.offset()..].starts_with("factory,  app"));
+        assert_eq!(
+            Some(&SourceSpan::new(72.into(), 41.into())),
+            table.partitions[0].span.as_ref()
+        );
+
+        let table = PartitionTable::try_from_str(PTABLE_1).unwrap();
+        assert!(table.check_app_fits(0x100000).is_ok());
+
+        let err = table.check_app_fits(0x100001).unwrap_err();
+        assert_eq!("factory", err.name);
+        assert_eq!(0x110000, err.suggested_size);
+        assert!(err.source_code[err.span.offset()..].starts_with("factory,  app,  factory"));
+        assert_eq!(
+            "factory,  app,  factory, 0x10000,  1M,".len(),
+            err.span.len()
+        );
+
+        let table = PartitionTable::try_from_bytes(&table.to_bytes()).unwrap();
+        let err = table.check_app_fits(0x100001).unwrap_err();
+        assert!(err.source_code[err.span.offset()..].starts_with("factory,app,factory"));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(PartitionTable::try_from_str(PTABLE_0)
+            .unwrap()
+            .validate()
+            .is_ok());
+        assert!(PartitionTable::try_from_str(PTABLE_1)
+            .unwrap()
+            .validate()
+            .is_ok());
+
+        let table = PartitionTable::try_from_str(
+            "
+nvs,      data, nvs,     0x9000,  0x6000,
+nvs,      data, phy,     0xf000,  0x1000,
+factory,  app,  factory, 0x18000, 1M,
+ota_0,    app,  ota_0,   0x100000, 1M,
+",
+        )
+        .unwrap();
+        let err = table.validate().unwrap_err();
+        let problems: Vec<_> = err
+            .problems
+            .iter()
+            .map(|(span, message)| (&err.source_code[span.offset()..][..3], message.as_str()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("nvs", "duplicate name `nvs`"),
+                ("fac", "offset 0x18000 is not aligned to 0x10000"),
+                ("ota", "overlaps with `factory`"),
+                ("ota", "OTA partitions require an `otadata` partition"),
+            ],
+            problems
+        );
+        assert!(!err.missing_app);
+
+        let table = PartitionTable::try_from_str("nvs, data, nvs, 0x9000, 0x6000,").unwrap();
+        assert!(table.validate().unwrap_err().missing_app);
+    }
+
+    #[test]
+    fn test_flags() {
+        let table = PartitionTable::try_from_str(
+            "
+nvs,      data, nvs,     0x9000,  0x6000,
+nvs_keys, data, nvs_keys, 0xf000, 0x1000, encrypted
+factory,  app,  factory, 0x10000, 1M, encrypted:readonly
+",
+        )
+        .unwrap();
+        let flags: Vec<_> = table.partitions.iter().map(|p| p.flags).collect();
+        assert_eq!(vec![None, Some(0b01), Some(0b11)], flags);
+
+        let table = PartitionTable::try_from_bytes(&table.to_bytes()).unwrap();
+        let csv = table.to_csv();
+        assert!(csv.contains("nvs_keys,data,nvs_keys,0xf000,0x1000,encrypted\n"));
+        assert!(csv.contains("factory,app,factory,0x10000,0x100000,encrypted:readonly\n"));
+        assert!(csv.contains("nvs,data,nvs,0x9000,0x6000,\n"));
+
+        assert!(
+            PartitionTable::try_from_str("factory, app, factory, 0x10000, 1M, secret").is_err()
+        );
+    }
+
+    #[test]
+    fn test_too_many_partitions() {
+        let csv: String = (0..=MAX_PARTITION_TABLE_ENTRIES)
+            .map(|index| format!("nvs{}, data, nvs, {:#x}, 0x1000,\n", index, index * 0x1000))
+            .collect();
+        let table =
+            PartitionTable::try_from_str(format!("{}factory, app, factory, 0x100000, 1M,", csv))
+                .unwrap();
+        let err = table.validate().unwrap_err();
+        assert_eq!(1, err.problems.len());
+        assert!(table.save(&mut Vec::new()).is_err());
+
+        let mut data = vec![0xFF; MAX_PARTITION_LENGTH];
+        let entry = &PartitionTable::try_from_str(PTABLE_0).unwrap().to_bytes()[..PARTITION_SIZE];
+        for chunk in data.chunks_exact_mut(PARTITION_SIZE) {
+            chunk.copy_from_slice(entry);
+        }
+        assert!(matches!(
+            PartitionTable::try_from_bytes(&data),
+            Err(BinaryPartitionTableError::TooManyEntries(95))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_offset_or_size() {
+        for (offset, size) in [
+            ("0x10000", "0xfoo"),
+            ("0xzz", "1M"),
+            ("0x10000", "4096M"),
+            ("0x10000", "99999999999K"),
+        ] {
+            let csv = format!("factory, app, factory, {}, {},", offset, size);
+            assert!(PartitionTable::try_from_str(csv).is_err(), "{}", size);
+        }
+    }
 }
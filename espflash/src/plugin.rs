@@ -0,0 +1,92 @@
+//! Discovering external subcommands
+//!
+//! Like cargo, `espflash <name>` runs an `espflash-<name>` executable found on
+//! `PATH` if `<name>` isn't a built-in subcommand, passing it the remaining
+//! arguments. This allows shipping extensions without forking the CLI, see the
+//! `espflash-plugin` crate for the primitives to write them with.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Prefix of the executables of external subcommands
+pub const PREFIX: &str = "espflash-";
+
+/// Environment variable holding the path of the `espflash` executable that
+/// runs an external subcommand
+pub const ESPFLASH_ENV: &str = "ESPFLASH";
+
+/// Find the executable of the external subcommand `name` on `PATH`
+pub fn find(name: &str) -> Option<PathBuf> {
+    if !is_valid_name(name) {
+        return None;
+    }
+
+    let file_name = format!("{}{}{}", PREFIX, name, env::consts::EXE_SUFFIX);
+    search_path()
+        .map(|dir| dir.join(&file_name))
+        .find(|path| is_executable(path))
+}
+
+/// The names of all external subcommands on `PATH`, sorted
+pub fn list() -> Vec<String> {
+    let mut names: Vec<String> = search_path()
+        .flat_map(|dir| fs::read_dir(dir).into_iter().flatten())
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let name = path
+                .file_name()?
+                .to_str()?
+                .strip_prefix(PREFIX)?
+                .strip_suffix(env::consts::EXE_SUFFIX)?
+                .to_string();
+            (is_valid_name(&name) && is_executable(&path)).then_some(name)
+        })
+        .collect();
+
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Whether `name` can name an external subcommand, which keeps serial ports
+/// like `/dev/ttyUSB0` from being mistaken for one
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn search_path() -> impl Iterator<Item = PathBuf> {
+    env::var_os("PATH")
+        .map(|path| env::split_paths(&path).collect::<Vec<_>>())
+        .unwrap_or_default()
+        .into_iter()
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::metadata(path)
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    use std::ffi::OsStr;
+
+    path.extension() == Some(OsStr::new("exe")) && path.is_file()
+}
+
+#[test]
+fn test_is_valid_name() {
+    assert!(is_valid_name("provision"));
+    assert!(is_valid_name("set_keys-v2"));
+    assert!(!is_valid_name("/dev/ttyUSB0"));
+    assert!(!is_valid_name("--help"));
+    assert!(!is_valid_name("app.elf"));
+    assert!(!is_valid_name(""));
+}
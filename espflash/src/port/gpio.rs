@@ -0,0 +1,153 @@
+use crate::connection::Transport;
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+const SYSFS_GPIO: &str = "/sys/class/gpio";
+
+/// GPIO lines of the host wired to the `EN` and `IO0` pins of the chip
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct GpioPins {
+    pub en: u32,
+    pub io0: u32,
+}
+
+/// Serial port of a chip reset through GPIO lines of the host, like on a
+/// Raspberry Pi wired to the UART and the `EN` and `IO0` pins of the chip
+///
+/// `RTS` and `DTR` drive `EN` and `IO0` the way the transistors of the usual
+/// auto-reset circuit do, so all reset sequences work unchanged. The lines are
+/// driven through the sysfs GPIO interface of Linux.
+pub struct GpioResetPort {
+    serial: Box<dyn Transport>,
+    en: GpioLine,
+    io0: GpioLine,
+}
+
+impl GpioResetPort {
+    /// Take over the lines in `pins`, leaving the chip running
+    pub fn new(serial: Box<dyn Transport>, pins: GpioPins) -> io::Result<Self> {
+        Self::new_in(serial, pins, Path::new(SYSFS_GPIO))
+    }
+
+    fn new_in(serial: Box<dyn Transport>, pins: GpioPins, root: &Path) -> io::Result<Self> {
+        Ok(GpioResetPort {
+            serial,
+            en: GpioLine::output(root, pins.en)?,
+            io0: GpioLine::output(root, pins.io0)?,
+        })
+    }
+}
+
+struct GpioLine {
+    value: PathBuf,
+}
+
+impl GpioLine {
+    /// Export `line` if needed and make it an output driven high
+    fn output(root: &Path, line: u32) -> io::Result<Self> {
+        let dir = root.join(format!("gpio{}", line));
+        if !dir.exists() {
+            fs::write(root.join("export"), line.to_string())?;
+        }
+
+        // udev can take a moment to grant access to a line which was just
+        // exported
+        let deadline = Instant::now() + Duration::from_secs(1);
+        loop {
+            match fs::write(dir.join("direction"), "high") {
+                Ok(()) => {
+                    return Ok(GpioLine {
+                        value: dir.join("value"),
+                    })
+                }
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        ErrorKind::PermissionDenied | ErrorKind::NotFound
+                    ) && Instant::now() < deadline =>
+                {
+                    sleep(Duration::from_millis(50))
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn set(&self, level: bool) -> io::Result<()> {
+        fs::write(&self.value, if level { "1" } else { "0" })
+    }
+}
+
+impl Read for GpioResetPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.serial.read(buf)
+    }
+}
+
+impl Write for GpioResetPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.serial.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.serial.flush()
+    }
+}
+
+impl Transport for GpioResetPort {
+    fn timeout(&self) -> Duration {
+        self.serial.timeout()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.serial.set_timeout(timeout)
+    }
+
+    fn set_baud(&mut self, baud: usize) -> io::Result<()> {
+        self.serial.set_baud(baud)
+    }
+
+    /// Pulls `IO0` low while asserted
+    fn set_dtr(&mut self, level: bool) -> io::Result<()> {
+        self.io0.set(!level)
+    }
+
+    /// Holds the chip in reset through `EN` while asserted
+    fn set_rts(&mut self, level: bool) -> io::Result<()> {
+        self.en.set(!level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::port::NetworkPort;
+    use std::net::TcpListener;
+
+    #[test]
+    fn gpio_lines() {
+        let root = std::env::temp_dir().join(format!("espflash-gpio-{}", std::process::id()));
+        fs::create_dir_all(root.join("gpio17")).unwrap();
+        fs::create_dir_all(root.join("gpio27")).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("tcp://{}", listener.local_addr().unwrap());
+        let serial = Box::new(NetworkPort::connect(&url).unwrap());
+
+        let pins = GpioPins { en: 17, io0: 27 };
+        let mut port = GpioResetPort::new_in(serial, pins, &root).unwrap();
+        port.set_rts(true).unwrap();
+        port.set_dtr(false).unwrap();
+        let read = |line: &str| fs::read_to_string(root.join(line).join("value")).unwrap();
+        let (en, io0) = (read("gpio17"), read("gpio27"));
+        let direction = fs::read_to_string(root.join("gpio17/direction")).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(direction, "high");
+        assert_eq!(en, "0");
+        assert_eq!(io0, "1");
+    }
+}
@@ -0,0 +1,561 @@
+//! Opening and listing serial ports
+//!
+//! Right after a device is plugged in or reset, its serial port can be missing
+//! or busy for a moment while the USB device is enumerated, and flaky hubs can
+//! cause spurious I/O errors. Opening the port is retried with an increasing
+//! delay to smooth over these races.
+//!
+//! Ports of network serial servers are opened by URL, see [`NetworkPort`]. On
+//! boards without an auto-reset circuit, [`GpioResetPort`] resets the chip
+//! through GPIO lines of the host.
+
+mod gpio;
+mod network;
+
+pub use gpio::{GpioPins, GpioResetPort};
+pub use network::NetworkPort;
+use serial::{BaudRate, FlowControl, SerialPort, SystemPort};
+
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::{stdin, stdout, IsTerminal, Write};
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::{Config, Error, Transport};
+
+const INITIAL_RETRY_DELAY: Duration = Duration::from_millis(50);
+const MAX_RETRY_DELAY: Duration = Duration::from_millis(800);
+
+/// Open the serial port at `port` at 115200 baud
+///
+/// If opening fails with an error that can be caused by the device still being
+/// enumerated, it is retried until `retry_window` has passed. `tcp://` and
+/// `rfc2217://` URLs connect to a network serial server instead.
+pub fn open(port: &str, retry_window: Duration) -> Result<Box<dyn Transport>, Error> {
+    if NetworkPort::is_url(port) {
+        return Ok(Box::new(NetworkPort::connect(port)?));
+    }
+
+    let deadline = Instant::now() + retry_window;
+    let mut delay = INITIAL_RETRY_DELAY;
+
+    loop {
+        match try_open(port) {
+            Err(err) if is_transient(port, &err) && Instant::now() + delay < deadline => {
+                sleep(delay);
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+            }
+            result => {
+                return result
+                    .map(|serial| Box::new(serial) as Box<dyn Transport>)
+                    .map_err(Error::from)
+            }
+        }
+    }
+}
+
+fn try_open(port: &str) -> serial::Result<SystemPort> {
+    let mut serial = serial::open(port)?;
+    serial.reconfigure(&|settings| {
+        settings.set_flow_control(FlowControl::FlowNone);
+        settings.set_baud_rate(BaudRate::Baud115200)?;
+
+        Ok(())
+    })?;
+
+    Ok(serial)
+}
+
+/// Set the baud rate of the port
+///
+/// Rates without a predefined constant, like the 74880 baud the ESP8266 ROM
+/// prints its boot messages at, are set through `termios2` on Linux.
+pub fn set_baud(serial: &mut SystemPort, baud: usize) -> serial::Result<()> {
+    match serial.reconfigure(&|settings| settings.set_baud_rate(BaudRate::from_speed(baud))) {
+        #[cfg(target_os = "linux")]
+        Err(err) if err.kind() == serial::ErrorKind::InvalidInput => {
+            set_custom_baud(serial, baud as u32).map_err(serial::Error::from)
+        }
+        result => result,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_custom_baud(serial: &SystemPort, baud: u32) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = serial.as_raw_fd();
+    // SAFETY: `termios2` is plain data filled in by the kernel, and `fd` is
+    // the open descriptor of the port
+    unsafe {
+        let mut termios: libc::termios2 = std::mem::zeroed();
+        if libc::ioctl(fd, libc::TCGETS2 as _, &mut termios) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        termios.c_cflag &= !(libc::CBAUD | (libc::CBAUD << libc::IBSHIFT));
+        termios.c_cflag |= libc::BOTHER | (libc::BOTHER << libc::IBSHIFT);
+        termios.c_ispeed = baud;
+        termios.c_ospeed = baud;
+        if libc::ioctl(fd, libc::TCSETS2 as _, &termios) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the error can be caused by a device which is being enumerated
+///
+/// `serial` reports a missing device, `EBUSY` and permission errors all as
+/// `NoDevice`, and other errors like `EIO` as `Io(Other)`. Permission errors
+/// don't go away by waiting, so they are told apart by checking the access to
+/// the port.
+fn is_transient(port: &str, err: &serial::Error) -> bool {
+    match err.kind() {
+        serial::ErrorKind::NoDevice => !permission_denied(port),
+        serial::ErrorKind::Io(std::io::ErrorKind::Other) => true,
+        _ => false,
+    }
+}
+
+/// Whether the port exists, but the user isn't allowed to open it
+#[cfg(unix)]
+fn permission_denied(port: &str) -> bool {
+    let path = match std::ffi::CString::new(port) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+
+    // SAFETY: `path` is a valid NUL-terminated string
+    let result = unsafe { libc::access(path.as_ptr(), libc::R_OK | libc::W_OK) };
+    result != 0 && std::io::Error::last_os_error().raw_os_error() == Some(libc::EACCES)
+}
+
+#[cfg(not(unix))]
+fn permission_denied(_port: &str) -> bool {
+    false
+}
+
+/// USB-serial bridges and native USB interfaces found on ESP boards, by vendor
+/// id and product id, `None` matching any product of the vendor
+const KNOWN_BRIDGES: &[(u16, Option<u16>, &str)] = &[
+    (0x303a, None, "Espressif USB"),
+    (0x10c4, Some(0xea60), "CP210x"),
+    (0x10c4, Some(0xea70), "CP2105"),
+    (0x10c4, Some(0xea71), "CP2108"),
+    (0x1a86, Some(0x7523), "CH340"),
+    (0x1a86, Some(0x5523), "CH341"),
+    (0x1a86, Some(0x55d4), "CH9102"),
+    (0x0403, Some(0x6001), "FT232R"),
+    (0x0403, Some(0x6010), "FT2232"),
+    (0x0403, Some(0x6011), "FT4232"),
+    (0x0403, Some(0x6014), "FT232H"),
+    (0x0403, Some(0x6015), "FT231X"),
+];
+
+/// Vendor and product id of the USB-JTAG-serial peripheral of the ESP32-C3 and
+/// later chips
+const USB_JTAG_SERIAL: (u16, u16) = (0x303a, 0x1001);
+
+/// A serial port of the host
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PortInfo {
+    /// Path of the port, e.g. `/dev/ttyUSB0`
+    pub name: String,
+    /// Details of the USB device the port belongs to, if any
+    pub usb: Option<UsbInfo>,
+}
+
+/// The USB device a serial port belongs to
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct UsbInfo {
+    pub vid: u16,
+    pub pid: u16,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+}
+
+impl UsbInfo {
+    /// The name of the bridge if it is one commonly found on ESP boards
+    pub fn known_bridge(&self) -> Option<&'static str> {
+        KNOWN_BRIDGES
+            .iter()
+            .find(|(vid, pid, _)| *vid == self.vid && pid.is_none_or(|pid| pid == self.pid))
+            .map(|(_, _, name)| *name)
+    }
+
+    /// Whether this is the USB-JTAG-serial peripheral of the chip itself
+    pub fn is_usb_jtag_serial(&self) -> bool {
+        (self.vid, self.pid) == USB_JTAG_SERIAL
+    }
+}
+
+/// Whether the serial port `name` belongs to the USB-JTAG-serial peripheral of
+/// the chip, rather than to a USB-serial bridge
+///
+/// Ports which can't be found among the listed ones are recognized by their
+/// name, as the macOS `usbmodem` ports of native USB interfaces.
+pub fn is_usb_jtag_serial(name: &str) -> bool {
+    is_usb_jtag_serial_in(name, &list().unwrap_or_default())
+}
+
+fn is_usb_jtag_serial_in(name: &str, ports: &[PortInfo]) -> bool {
+    let path = fs::canonicalize(name).ok();
+    let port = ports.iter().find(|port| {
+        // names like `COM3` on Windows don't canonicalize
+        port.name == name
+            || path
+                .as_ref()
+                .is_some_and(|path| fs::canonicalize(&port.name).is_ok_and(|port| port == *path))
+    });
+
+    match port {
+        Some(port) => port.usb.as_ref().is_some_and(UsbInfo::is_usb_jtag_serial),
+        None => name.contains("usbmodem"),
+    }
+}
+
+/// Hard reset the chip by pulling its `EN` pin low through `RTS`
+///
+/// The USB-JTAG-serial peripheral maps `RTS` to the reset of the chip, but
+/// needs longer pulses, and time to reconnect afterwards.
+pub fn hard_reset(serial: &mut dyn Transport, usb_jtag_serial: bool) -> Result<(), Error> {
+    let pulse = if usb_jtag_serial {
+        Duration::from_millis(200)
+    } else {
+        Duration::from_millis(100)
+    };
+
+    serial.set_dtr(false)?;
+    serial.set_rts(true)?;
+    sleep(pulse);
+    serial.set_rts(false)?;
+    if usb_jtag_serial {
+        sleep(pulse);
+    }
+
+    Ok(())
+}
+
+/// List the serial ports of the host, sorted by name
+///
+/// On Linux the ports are found through sysfs, on other platforms through
+/// `serialport`.
+pub fn list() -> Result<Vec<PortInfo>, Error> {
+    #[cfg(target_os = "linux")]
+    let ports = list_in(Path::new("/sys/class/tty"));
+    #[cfg(not(target_os = "linux"))]
+    let ports = {
+        let mut ports: Vec<PortInfo> = serialport::available_ports()
+            .map_err(|err| Error::ListSerialPorts(err.to_string()))?
+            .into_iter()
+            .map(PortInfo::from)
+            .collect();
+        ports.sort_by(|a, b| a.name.cmp(&b.name));
+        ports
+    };
+
+    Ok(ports)
+}
+
+#[cfg(not(target_os = "linux"))]
+impl From<serialport::SerialPortInfo> for PortInfo {
+    fn from(info: serialport::SerialPortInfo) -> Self {
+        let usb = match info.port_type {
+            serialport::SerialPortType::UsbPort(usb) => Some(UsbInfo {
+                vid: usb.vid,
+                pid: usb.pid,
+                manufacturer: usb.manufacturer,
+                product: usb.product,
+                serial_number: usb.serial_number,
+            }),
+            _ => None,
+        };
+
+        PortInfo {
+            name: info.port_name,
+            usb,
+        }
+    }
+}
+
+/// Find the serial port of the connected ESP board, for when none is given
+///
+/// Only ports of known USB-serial bridges and native USB interfaces are
+/// considered. If there are several, the one of the device remembered in the
+/// config file is used, otherwise the user is asked to pick one when
+/// `interactive` and running in a terminal, and is offered to remember it.
+pub fn detect(config: &Config, interactive: bool) -> Result<String, Error> {
+    let ports = list()?;
+    let candidates: Vec<PortInfo> = ports
+        .iter()
+        .filter(|port| port.usb.as_ref().and_then(UsbInfo::known_bridge).is_some())
+        .cloned()
+        .collect();
+
+    let remembered = config
+        .connection
+        .usb_serial
+        .as_deref()
+        .and_then(|usb_serial| {
+            candidates
+                .iter()
+                .find(|port| port.usb_serial() == Some(usb_serial))
+        });
+
+    let port = match (candidates.as_slice(), remembered) {
+        ([], _) => return Err(no_known_bridge(&ports)),
+        ([port], _) | (_, Some(port)) => port,
+        _ if interactive && stdin().is_terminal() => {
+            let port = prompt(&candidates)?;
+            if let Some(usb_serial) = port.usb_serial() {
+                offer_to_remember(usb_serial)?;
+            }
+            return Ok(port.name.clone());
+        }
+        _ => return Err(ambiguous(&candidates)),
+    };
+
+    log::info!("Using serial port {}", port);
+    Ok(port.name.clone())
+}
+
+impl PortInfo {
+    fn usb_serial(&self) -> Option<&str> {
+        self.usb.as_ref()?.serial_number.as_deref()
+    }
+}
+
+/// Ask whether to use the device with `usb_serial` in future runs, and save it
+/// in the config file if so
+fn offer_to_remember(usb_serial: &str) -> Result<(), Error> {
+    print!("Remember this device for future runs? [y/N] ");
+    stdout().flush()?;
+
+    let mut answer = String::new();
+    stdin().read_line(&mut answer)?;
+    if answer.trim().eq_ignore_ascii_case("y") {
+        match Config::save_usb_serial(usb_serial) {
+            Ok(()) => println!("Saved USB serial number {} in the config file", usb_serial),
+            Err(err) => println!("Failed to save the config file: {}", err),
+        }
+    }
+
+    Ok(())
+}
+
+/// Ask the user to pick one of the `candidates`
+fn prompt(candidates: &[PortInfo]) -> Result<&PortInfo, Error> {
+    println!("Several serial ports found:");
+    for (i, port) in candidates.iter().enumerate() {
+        println!("  {}) {}", i + 1, port);
+    }
+
+    loop {
+        print!("Select a port [1-{}]: ", candidates.len());
+        stdout().flush()?;
+
+        let mut answer = String::new();
+        if stdin().read_line(&mut answer)? == 0 {
+            return Err(ambiguous(candidates));
+        }
+        if let Some(port) = answer
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .and_then(|i| candidates.get(i.checked_sub(1)?))
+        {
+            return Ok(port);
+        }
+    }
+}
+
+/// The error for when none of `ports` is of a known USB-serial bridge, naming
+/// the other ports so the user can pass one of them
+fn no_known_bridge(ports: &[PortInfo]) -> Error {
+    if ports.is_empty() {
+        return Error::NoSerialPort;
+    }
+
+    let names: Vec<&str> = ports.iter().map(|port| port.name.as_str()).collect();
+    Error::UnknownSerialPorts(names.join(", "))
+}
+
+fn ambiguous(candidates: &[PortInfo]) -> Error {
+    let names: Vec<&str> = candidates.iter().map(|port| port.name.as_str()).collect();
+    Error::AmbiguousSerialPort(names.join(", "))
+}
+
+impl Display for PortInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(usb) = &self.usb {
+            write!(f, " ({:04x}:{:04x}", usb.vid, usb.pid)?;
+            if let Some(description) = usb.product.as_deref().or_else(|| usb.known_bridge()) {
+                write!(f, " {}", description)?;
+            }
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+fn list_in(sys_class_tty: &Path) -> Vec<PortInfo> {
+    let mut ports: Vec<PortInfo> = fs::read_dir(sys_class_tty)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            // virtual terminals and ptys don't have a device
+            let device = fs::canonicalize(entry.path().join("device")).ok()?;
+            // the legacy serial driver registers ports that may not exist
+            let driver = fs::read_link(device.join("driver")).ok();
+            if driver.as_deref().and_then(Path::file_name) == Some("serial8250".as_ref()) {
+                return None;
+            }
+
+            Some(PortInfo {
+                name: format!("/dev/{}", entry.file_name().to_string_lossy()),
+                usb: device.ancestors().find_map(read_usb_info),
+            })
+        })
+        .collect();
+
+    ports.sort_by(|a, b| a.name.cmp(&b.name));
+    ports
+}
+
+/// Read the details of the USB device at `dir`, `None` if it isn't one
+fn read_usb_info(dir: &Path) -> Option<UsbInfo> {
+    let read = |name: &str| {
+        fs::read_to_string(dir.join(name))
+            .ok()
+            .map(|value| value.trim().to_string())
+    };
+    let id = |name: &str| u16::from_str_radix(&read(name)?, 16).ok();
+
+    Some(UsbInfo {
+        vid: id("idVendor")?,
+        pid: id("idProduct")?,
+        manufacturer: read("manufacturer"),
+        product: read("product"),
+        serial_number: read("serial"),
+    })
+}
+
+#[cfg(unix)]
+#[test]
+fn test_list_ports() {
+    use std::os::unix::fs::symlink;
+
+    let root = std::env::temp_dir().join(format!("espflash-ports-{}", std::process::id()));
+    let usb = root.join("devices/usb1/1-1");
+    let interface = usb.join("1-1:1.0/ttyUSB0");
+    fs::create_dir_all(&interface).unwrap();
+    fs::create_dir_all(root.join("class/tty/ttyUSB0")).unwrap();
+    fs::create_dir_all(root.join("class/tty/tty0")).unwrap();
+    fs::write(usb.join("idVendor"), "10c4\n").unwrap();
+    fs::write(usb.join("idProduct"), "ea60\n").unwrap();
+    fs::write(
+        usb.join("product"),
+        "CP2102 USB to UART Bridge Controller\n",
+    )
+    .unwrap();
+    fs::write(usb.join("serial"), "0001\n").unwrap();
+    symlink(&interface, root.join("class/tty/ttyUSB0/device")).unwrap();
+
+    let ports = list_in(&root.join("class/tty"));
+    fs::remove_dir_all(&root).unwrap();
+
+    let usb = UsbInfo {
+        vid: 0x10c4,
+        pid: 0xea60,
+        manufacturer: None,
+        product: Some("CP2102 USB to UART Bridge Controller".to_string()),
+        serial_number: Some("0001".to_string()),
+    };
+    assert_eq!(Some("CP210x"), usb.known_bridge());
+    assert_eq!(
+        vec![PortInfo {
+            name: "/dev/ttyUSB0".to_string(),
+            usb: Some(usb),
+        }],
+        ports
+    );
+}
+
+#[test]
+fn test_no_known_bridge() {
+    assert!(matches!(no_known_bridge(&[]), Error::NoSerialPort));
+
+    let ports = [
+        PortInfo {
+            name: "/dev/ttyACM0".to_string(),
+            usb: None,
+        },
+        PortInfo {
+            name: "/dev/ttyUSB0".to_string(),
+            usb: Some(UsbInfo {
+                vid: 0x1234,
+                pid: 0x5678,
+                manufacturer: None,
+                product: None,
+                serial_number: None,
+            }),
+        },
+    ];
+    assert!(matches!(
+        no_known_bridge(&ports),
+        Error::UnknownSerialPorts(names) if names == "/dev/ttyACM0, /dev/ttyUSB0"
+    ));
+}
+
+#[test]
+fn test_is_usb_jtag_serial() {
+    let usb = |(vid, pid)| {
+        Some(UsbInfo {
+            vid,
+            pid,
+            manufacturer: None,
+            product: None,
+            serial_number: None,
+        })
+    };
+    let ports = [
+        PortInfo {
+            name: "COM3".to_string(),
+            usb: usb(USB_JTAG_SERIAL),
+        },
+        PortInfo {
+            name: "COM4".to_string(),
+            usb: usb((0x10c4, 0xea60)),
+        },
+        PortInfo {
+            name: "/dev/cu.usbmodem1101".to_string(),
+            usb: usb((0x10c4, 0xea60)),
+        },
+    ];
+
+    assert!(is_usb_jtag_serial_in("COM3", &ports));
+    assert!(!is_usb_jtag_serial_in("COM4", &ports));
+    assert!(!is_usb_jtag_serial_in("/dev/cu.usbmodem1101", &ports));
+    assert!(is_usb_jtag_serial_in("/dev/tty.usbmodem1101", &ports));
+    assert!(!is_usb_jtag_serial_in("/dev/ttyUSB0", &ports));
+}
+
+#[test]
+fn test_retry_missing_port() {
+    use crate::error::ConnectionError;
+
+    let started = Instant::now();
+    let result = open("/dev/espflash-missing-port", Duration::from_millis(200));
+
+    assert!(matches!(
+        result,
+        Err(Error::Connection(ConnectionError::DeviceNotFound))
+    ));
+    assert!(started.elapsed() >= INITIAL_RETRY_DELAY);
+}
@@ -0,0 +1,203 @@
+use crate::connection::Transport;
+use crate::rfc2217::*;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+/// Serial port of a network serial server, like ser2net
+///
+/// With `tcp://host:port` the bytes are passed as they are, and the baud rate
+/// and control lines are whatever the server is configured with. With
+/// `rfc2217://host:port` they are set through RFC2217, so the chip can be
+/// reset into the bootloader and flashed at higher baud rates.
+pub struct NetworkPort {
+    stream: TcpStream,
+    telnet: Option<Telnet>,
+    timeout: Duration,
+    /// Data received but not read yet
+    received: Vec<u8>,
+}
+
+impl NetworkPort {
+    /// Connect to the serial server at `url`, configuring the port for
+    /// 115200 baud and 8N1 with RFC2217
+    pub fn connect(url: &str) -> io::Result<Self> {
+        let (address, rfc2217) = match url.split_once("://") {
+            Some(("tcp", address)) => (address, false),
+            Some(("rfc2217", address)) => (address, true),
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("`{}` is not a tcp:// or rfc2217:// URL", url),
+                ))
+            }
+        };
+
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        let mut port = NetworkPort {
+            stream,
+            telnet: None,
+            timeout: Duration::from_secs(1),
+            received: Vec::new(),
+        };
+        if rfc2217 {
+            let (telnet, offer) = Telnet::new(&[BINARY, COM_PORT_OPTION], &[BINARY, SGA]);
+            port.telnet = Some(telnet);
+            port.stream.write_all(&offer)?;
+            port.set_baud(115_200)?;
+            port.command(SET_DATASIZE, &[8])?;
+            port.command(SET_PARITY, &[1])?;
+            port.command(SET_STOPSIZE, &[1])?;
+            port.command(SET_CONTROL, &[1])?;
+        }
+
+        Ok(port)
+    }
+
+    /// Whether `port` names a port of a network serial server
+    pub fn is_url(port: &str) -> bool {
+        port.starts_with("tcp://") || port.starts_with("rfc2217://")
+    }
+
+    /// Send a COM port control command, if the server supports RFC2217
+    fn command(&mut self, command: u8, value: &[u8]) -> io::Result<bool> {
+        if self.telnet.is_none() {
+            return Ok(false);
+        }
+        self.stream.write_all(&com_port_command(command, value))?;
+        Ok(true)
+    }
+}
+
+impl Read for NetworkPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = Instant::now() + self.timeout;
+        let mut data = [0; 1024];
+
+        while self.received.is_empty() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::Error::new(ErrorKind::TimedOut, "Operation timed out"));
+            }
+            self.stream.set_read_timeout(Some(remaining))?;
+
+            let count = match self.stream.read(&mut data) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        ErrorKind::ConnectionAborted,
+                        "The serial server closed the connection",
+                    ))
+                }
+                Ok(count) => count,
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    continue
+                }
+                Err(err) => return Err(err),
+            };
+            match &mut self.telnet {
+                Some(telnet) => {
+                    let mut reply = Vec::new();
+                    // the answers to our commands only confirm them
+                    telnet.receive(
+                        &data[..count],
+                        &mut self.received,
+                        &mut reply,
+                        &mut Vec::new(),
+                    );
+                    self.stream.write_all(&reply)?;
+                }
+                None => self.received.extend_from_slice(&data[..count]),
+            }
+        }
+
+        let count = buf.len().min(self.received.len());
+        buf[..count].copy_from_slice(&self.received[..count]);
+        self.received.drain(..count);
+        Ok(count)
+    }
+}
+
+impl Write for NetworkPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.telnet {
+            Some(_) => self.stream.write_all(&escape(buf))?,
+            None => self.stream.write_all(buf)?,
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Transport for NetworkPort {
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    /// Fails for `tcp://` ports, as the chip would switch to a rate the server
+    /// doesn't use
+    fn set_baud(&mut self, baud: usize) -> io::Result<()> {
+        if self.command(SET_BAUDRATE, &(baud as u32).to_be_bytes())? {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "The baud rate of tcp:// ports is set on the server, use rfc2217:// to change it",
+            ))
+        }
+    }
+
+    /// Does nothing for `tcp://` ports, the chip has to be reset by hand
+    fn set_dtr(&mut self, level: bool) -> io::Result<()> {
+        self.command(SET_CONTROL, &[if level { 8 } else { 9 }])?;
+        Ok(())
+    }
+
+    /// Does nothing for `tcp://` ports, the chip has to be reset by hand
+    fn set_rts(&mut self, level: bool) -> io::Result<()> {
+        self.command(SET_CONTROL, &[if level { 11 } else { 12 }])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn raw_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("tcp://{}", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).unwrap();
+            stream.write_all(&buf).unwrap();
+            stream
+        });
+
+        let mut port = NetworkPort::connect(&url).unwrap();
+        port.write_all(&[1, 2, IAC, 3]).unwrap();
+        let mut buf = [0; 4];
+        port.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [1, 2, IAC, 3]);
+
+        let _stream = server.join().unwrap();
+        port.set_timeout(Duration::from_millis(10)).unwrap();
+        assert_eq!(port.read(&mut buf).unwrap_err().kind(), ErrorKind::TimedOut);
+        assert_eq!(
+            port.set_baud(921_600).unwrap_err().kind(),
+            ErrorKind::Unsupported
+        );
+        assert!(NetworkPort::connect("ftp://localhost:21").is_err());
+    }
+}
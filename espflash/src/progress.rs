@@ -0,0 +1,120 @@
+//! Reporting the progress of writing segments to flash
+
+#[cfg(feature = "cli")]
+use indicatif::{ProgressBar, ProgressStyle};
+use serde_json::json;
+
+#[cfg(feature = "cli")]
+use std::io::{stdout, IsTerminal};
+
+/// Callbacks invoked while writing a segment to flash
+pub trait ProgressCallbacks: Send {
+    /// Writing `total` blocks to `addr` started
+    fn init(&mut self, addr: u32, total: usize);
+    /// `current` of the blocks were written
+    fn update(&mut self, current: usize);
+    /// All blocks of the segment were written
+    fn finish(&mut self);
+}
+
+/// Progress bars on the terminal
+///
+/// When stdout isn't a terminal, like in CI logs, a line is printed for each
+/// segment written instead.
+#[cfg(feature = "cli")]
+#[derive(Default)]
+pub struct ProgressBars {
+    bar: Option<(u32, ProgressBar)>,
+}
+
+#[cfg(feature = "cli")]
+impl ProgressCallbacks for ProgressBars {
+    fn init(&mut self, addr: u32, total: usize) {
+        if !stdout().is_terminal() {
+            self.bar = Some((addr, ProgressBar::hidden()));
+            return;
+        }
+
+        let bar = ProgressBar::new(total as u64);
+        bar.set_style(
+            ProgressStyle::default_bar()
+                .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}")
+                .progress_chars("#>-"),
+        );
+        bar.set_message(format!("segment 0x{:X} writing chunks", addr));
+        self.bar = Some((addr, bar));
+    }
+
+    fn update(&mut self, current: usize) {
+        if let Some((_, bar)) = &self.bar {
+            bar.set_position(current as u64);
+        }
+    }
+
+    fn finish(&mut self) {
+        match self.bar.take() {
+            Some((addr, bar)) if bar.is_hidden() => println!("Wrote segment 0x{:X}", addr),
+            Some((addr, bar)) => bar.finish_with_message(format!("segment 0x{:X}", addr)),
+            None => {}
+        }
+    }
+}
+
+/// Ignores the progress, the default without the `cli` feature
+#[derive(Default)]
+pub struct NoProgress;
+
+impl ProgressCallbacks for NoProgress {
+    fn init(&mut self, _addr: u32, _total: usize) {}
+
+    fn update(&mut self, _current: usize) {}
+
+    fn finish(&mut self) {}
+}
+
+/// Progress bars with the `cli` feature, no progress reporting without
+#[cfg(feature = "serial")]
+pub(crate) fn default_progress() -> Box<dyn ProgressCallbacks> {
+    #[cfg(feature = "cli")]
+    return Box::new(ProgressBars::default());
+    #[cfg(not(feature = "cli"))]
+    return Box::new(NoProgress);
+}
+
+/// Progress events written to stdout as JSON objects, one per line
+///
+/// Writing a segment emits a `write_start` event with its `offset` and the
+/// number of `blocks`, `write_progress` events with the `current` number of
+/// blocks written and a `write_done` event.
+#[derive(Default)]
+pub struct JsonProgress {
+    addr: u32,
+    total: usize,
+}
+
+impl ProgressCallbacks for JsonProgress {
+    fn init(&mut self, addr: u32, total: usize) {
+        self.addr = addr;
+        self.total = total;
+        println!(
+            "{}",
+            json!({ "event": "write_start", "offset": addr, "blocks": total })
+        );
+    }
+
+    fn update(&mut self, current: usize) {
+        println!(
+            "{}",
+            json!({
+                "event": "write_progress",
+                "offset": self.addr,
+                "current": current,
+                "blocks": self.total,
+            })
+        );
+    }
+
+    fn finish(&mut self) {
+        println!("{}", json!({ "event": "write_done", "offset": self.addr }));
+    }
+}
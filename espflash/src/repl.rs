@@ -0,0 +1,155 @@
+use std::convert::TryFrom;
+use std::io::{stdin, stdout, BufRead, Write};
+use std::time::Instant;
+
+use espflash::{command::Command, Chip, Flasher};
+use miette::{miette, IntoDiagnostic, Result};
+
+const BLOCK_SIZE: u32 = 0x400;
+
+const HELP: &str = "\
+Commands:
+    sync                                  Synchronize with the bootloader
+    read-reg <addr>                       Read a 32 bit register
+    write-reg <addr> <value> [mask]       Write a 32 bit register
+    spi-attach                            Attach the SPI flash
+    flash-begin <size> <offset>           Begin a flash download
+    send <command> [hex payload]          Send a command with a raw payload
+    help                                  Show this message
+    quit                                  Exit the REPL
+
+Numbers can be given in decimal or hex with a 0x prefix, commands by name
+(e.g. `flash-md5`) or opcode.";
+
+/// Result of executing a single line of input
+enum Outcome {
+    Value(u32),
+    Done,
+    Quit,
+}
+
+/// Run an interactive prompt for sending individual commands to the loader
+pub fn repl(mut flasher: Flasher) -> Result<()> {
+    println!(
+        "Connected to {}, type `help` for a list of commands",
+        flasher.chip()
+    );
+
+    let stdin = stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("> ");
+        stdout().flush().into_diagnostic()?;
+
+        let line = match lines.next() {
+            Some(line) => line.into_diagnostic()?,
+            None => return Ok(()),
+        };
+        let args: Vec<&str> = line.split_whitespace().collect();
+        if args.is_empty() {
+            continue;
+        }
+
+        let started = Instant::now();
+        let result = execute(&mut flasher, &args);
+        let elapsed = started.elapsed();
+
+        match result {
+            Ok(Outcome::Value(value)) => println!("{:#010x} ({:?})", value, elapsed),
+            Ok(Outcome::Done) => println!("ok ({:?})", elapsed),
+            Ok(Outcome::Quit) => return Ok(()),
+            Err(err) => println!("error: {} ({:?})", err, elapsed),
+        }
+    }
+}
+
+fn execute(flasher: &mut Flasher, args: &[&str]) -> Result<Outcome> {
+    let outcome = match args {
+        ["help"] => {
+            println!("{}", HELP);
+            Outcome::Done
+        }
+        ["quit"] | ["exit"] => Outcome::Quit,
+        ["sync"] => {
+            flasher.sync()?;
+            Outcome::Done
+        }
+        ["read-reg", addr] => Outcome::Value(flasher.read_reg(parse_u32(addr)?)?),
+        ["write-reg", addr, value] => {
+            flasher.write_reg(parse_u32(addr)?, parse_u32(value)?, None)?;
+            Outcome::Done
+        }
+        ["write-reg", addr, value, mask] => {
+            let mask = Some(parse_u32(mask)?);
+            flasher.write_reg(parse_u32(addr)?, parse_u32(value)?, mask)?;
+            Outcome::Done
+        }
+        ["spi-attach"] => {
+            flasher.spi_attach()?;
+            Outcome::Done
+        }
+        ["flash-begin", size, offset] => {
+            let payload = flash_begin_payload(flasher.chip(), parse_u32(size)?, parse_u32(offset)?);
+            Outcome::Value(flasher.command(Command::FlashBegin, &payload)?)
+        }
+        ["send", command] => Outcome::Value(flasher.command(parse_command(command)?, &[])?),
+        ["send", command, payload] => {
+            let command = parse_command(command)?;
+            Outcome::Value(flasher.command(command, &parse_hex(payload)?)?)
+        }
+        _ => {
+            return Err(miette!(
+                "Unknown command, type `help` for a list of commands"
+            ))
+        }
+    };
+
+    Ok(outcome)
+}
+
+fn flash_begin_payload(chip: Chip, size: u32, offset: u32) -> Vec<u8> {
+    let blocks = size.div_ceil(BLOCK_SIZE);
+    let mut payload = [size, blocks, BLOCK_SIZE, offset]
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect::<Vec<_>>();
+
+    // Everything but the ESP32 and ESP8266 also takes the `encrypted` field
+    if chip != Chip::Esp32 && chip != Chip::Esp8266 {
+        payload.extend_from_slice(&0u32.to_le_bytes());
+    }
+
+    payload
+}
+
+fn parse_command(command: &str) -> Result<Command> {
+    let parsed = match parse_u32(command) {
+        Ok(opcode) => u8::try_from(opcode)
+            .ok()
+            .and_then(|opcode| Command::try_from(opcode).ok()),
+        Err(_) => command.parse().ok(),
+    };
+
+    parsed.ok_or_else(|| miette!("Unknown loader command `{}`", command))
+}
+
+fn parse_hex(data: &str) -> Result<Vec<u8>> {
+    let bytes = if data.len().is_multiple_of(2) {
+        (0..data.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(data.get(i..i + 2)?, 16).ok())
+            .collect()
+    } else {
+        None
+    };
+
+    bytes.ok_or_else(|| miette!("Payload must be an even number of hex digits"))
+}
+
+fn parse_u32(value: &str) -> Result<u32> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|_| miette!("Invalid number `{}`", value))
+}
@@ -0,0 +1,83 @@
+use serde::Serialize;
+
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+/// Summary of a completed flash operation
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FlashReport {
+    /// The regions that were written, in the order they were written
+    pub regions: Vec<RegionReport>,
+    /// Total time spent writing, including finishing the operation
+    pub duration: Duration,
+    /// Hex encoded SHA-256 digest of the contents of all regions, in the order
+    /// they were written
+    pub sha256: String,
+}
+
+/// Summary of a single region written to flash
+#[derive(Debug, Clone, Serialize)]
+pub struct RegionReport {
+    /// Offset of the region in flash
+    pub offset: u32,
+    /// Size of the region in bytes
+    pub size: u32,
+    /// Number of bytes sent to the device, which is smaller than the size of
+    /// the region if the data was compressed
+    pub transferred: u32,
+    /// Time spent writing the region
+    pub duration: Duration,
+    /// Hex encoded MD5 digest of the region contents
+    pub md5: String,
+}
+
+impl FlashReport {
+    /// Total number of bytes written to flash
+    pub fn size(&self) -> u32 {
+        self.regions.iter().map(|region| region.size).sum()
+    }
+
+    /// Total number of bytes sent to the device
+    pub fn transferred(&self) -> u32 {
+        self.regions.iter().map(|region| region.transferred).sum()
+    }
+}
+
+impl RegionReport {
+    /// Start a report for a region with the given contents, the transfer
+    /// statistics are filled in once it has been written
+    #[cfg(feature = "serial")]
+    pub(crate) fn new(offset: u32, data: &[u8]) -> Self {
+        RegionReport {
+            offset,
+            size: data.len() as u32,
+            transferred: 0,
+            duration: Duration::default(),
+            md5: format!("{:x}", md5::compute(data)),
+        }
+    }
+}
+
+impl Display for FlashReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Wrote {} bytes ({} transferred) in {:.2}s",
+            self.size(),
+            self.transferred(),
+            self.duration.as_secs_f32()
+        )?;
+        for region in &self.regions {
+            writeln!(
+                f,
+                "  {:#08x}: {:>8} bytes ({:>8} transferred) in {:>6.2}s, md5 {}",
+                region.offset,
+                region.size,
+                region.transferred,
+                region.duration.as_secs_f32(),
+                region.md5
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+//! Custom sequences resetting the chip into the bootloader
+//!
+//! Boards with inverted transistors, an external reset controller or no
+//! auto-reset circuit at all can need other `DTR` and `RTS` transitions than
+//! the default sequence. A sequence is written as comma separated steps, e.g.
+//! `dtr=0, rts=1, sleep 100ms, dtr=1, rts=0, sleep 50ms, dtr=0`.
+
+use crate::Error;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// A step of a [`ResetSequence`]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ResetStep {
+    /// Set `DTR`, `dtr=0` or `dtr=1`
+    Dtr(bool),
+    /// Set `RTS`, `rts=0` or `rts=1`
+    Rts(bool),
+    /// Wait, `sleep 100ms` or `sleep 1s`
+    Sleep(Duration),
+}
+
+/// Steps resetting the chip into the bootloader
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ResetSequence(pub Vec<ResetStep>);
+
+impl ResetSequence {
+    /// Run the steps on `serial`
+    #[cfg(feature = "serial")]
+    pub fn run(&self, serial: &mut dyn crate::Transport) -> std::io::Result<()> {
+        for step in &self.0 {
+            match *step {
+                ResetStep::Dtr(level) => serial.set_dtr(level)?,
+                ResetStep::Rts(level) => serial.set_rts(level)?,
+                ResetStep::Sleep(duration) => std::thread::sleep(duration),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ResetStep {
+    type Err = Error;
+
+    fn from_str(step: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidResetStep(step.to_string());
+        let level = |level: &str| match level.trim() {
+            "0" => Ok(false),
+            "1" => Ok(true),
+            _ => Err(invalid()),
+        };
+
+        match step.trim().split_once(['=', ' ']) {
+            Some(("dtr", value)) => Ok(ResetStep::Dtr(level(value)?)),
+            Some(("rts", value)) => Ok(ResetStep::Rts(level(value)?)),
+            Some(("sleep", value)) => {
+                let value = value.trim();
+                let duration = match (value.strip_suffix("ms"), value.strip_suffix('s')) {
+                    (Some(ms), _) => ms.parse().map(Duration::from_millis).ok(),
+                    (None, Some(s)) => s
+                        .parse()
+                        .ok()
+                        .and_then(|s| Duration::try_from_secs_f64(s).ok()),
+                    (None, None) => None,
+                };
+                duration.map(ResetStep::Sleep).ok_or_else(invalid)
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl FromStr for ResetSequence {
+    type Err = Error;
+
+    fn from_str(sequence: &str) -> Result<Self, Self::Err> {
+        sequence
+            .split(',')
+            .filter(|step| !step.trim().is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(ResetSequence)
+    }
+}
+
+impl Display for ResetStep {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResetStep::Dtr(level) => write!(f, "dtr={}", *level as u8),
+            ResetStep::Rts(level) => write!(f, "rts={}", *level as u8),
+            ResetStep::Sleep(duration) => write!(f, "sleep {}ms", duration.as_millis()),
+        }
+    }
+}
+
+impl Display for ResetSequence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, step) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", step)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequences() {
+        let sequence: ResetSequence = "dtr=0, rts=1, sleep 100ms,dtr=1,sleep 0.05s"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            sequence.0,
+            [
+                ResetStep::Dtr(false),
+                ResetStep::Rts(true),
+                ResetStep::Sleep(Duration::from_millis(100)),
+                ResetStep::Dtr(true),
+                ResetStep::Sleep(Duration::from_millis(50)),
+            ]
+        );
+        assert_eq!(
+            sequence.to_string(),
+            "dtr=0, rts=1, sleep 100ms, dtr=1, sleep 50ms"
+        );
+
+        assert!("dtr=2".parse::<ResetSequence>().is_err());
+        assert!("sleep 100".parse::<ResetSequence>().is_err());
+        assert!("cts=1".parse::<ResetSequence>().is_err());
+    }
+}
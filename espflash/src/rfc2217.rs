@@ -0,0 +1,213 @@
+//! Telnet with the COM port control option of RFC2217
+//!
+//! Network serial servers like ser2net use it to let clients change the baud
+//! rate and control lines of the serial port they share. The data is passed
+//! through telnet, with the `IAC` byte escaped.
+
+pub(crate) const IAC: u8 = 255;
+pub(crate) const DONT: u8 = 254;
+pub(crate) const DO: u8 = 253;
+pub(crate) const WONT: u8 = 252;
+pub(crate) const WILL: u8 = 251;
+pub(crate) const SB: u8 = 250;
+pub(crate) const SE: u8 = 240;
+
+pub(crate) const BINARY: u8 = 0;
+pub(crate) const SGA: u8 = 3;
+pub(crate) const COM_PORT_OPTION: u8 = 44;
+
+pub(crate) const SIGNATURE: u8 = 0;
+pub(crate) const SET_BAUDRATE: u8 = 1;
+pub(crate) const SET_DATASIZE: u8 = 2;
+pub(crate) const SET_PARITY: u8 = 3;
+pub(crate) const SET_STOPSIZE: u8 = 4;
+pub(crate) const SET_CONTROL: u8 = 5;
+pub(crate) const SET_LINESTATE_MASK: u8 = 10;
+pub(crate) const SET_MODEMSTATE_MASK: u8 = 11;
+pub(crate) const PURGE_DATA: u8 = 12;
+/// Offset of the server's answers to the commands of the client
+pub(crate) const SERVER_OFFSET: u8 = 100;
+
+/// Escape the bytes equal to `IAC` in data sent over telnet
+pub(crate) fn escape(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for &byte in data {
+        if byte == IAC {
+            escaped.push(IAC);
+        }
+        escaped.push(byte);
+    }
+    escaped
+}
+
+/// A COM port control command, or the answer to one
+pub(crate) fn com_port_command(command: u8, value: &[u8]) -> Vec<u8> {
+    let mut data = vec![IAC, SB, COM_PORT_OPTION, command];
+    data.extend(escape(value));
+    data.extend_from_slice(&[IAC, SE]);
+    data
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum State {
+    Data,
+    Iac,
+    Negotiation(u8),
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+/// One side of a telnet session
+pub(crate) struct Telnet {
+    state: State,
+    subnegotiation: Vec<u8>,
+    /// Options enabled on our side
+    will: Vec<u8>,
+    /// Options enabled on the other side
+    doing: Vec<u8>,
+}
+
+impl Telnet {
+    /// A new session and the request to enable the options in `will` on our
+    /// side and those in `doing` on the other one
+    pub(crate) fn new(will: &[u8], doing: &[u8]) -> (Self, Vec<u8>) {
+        let mut offer = Vec::new();
+        for &option in will {
+            offer.extend_from_slice(&[IAC, WILL, option]);
+        }
+        for &option in doing {
+            offer.extend_from_slice(&[IAC, DO, option]);
+        }
+
+        let telnet = Telnet {
+            state: State::Data,
+            subnegotiation: Vec::new(),
+            will: will.to_vec(),
+            doing: doing.to_vec(),
+        };
+        (telnet, offer)
+    }
+
+    /// Split `input` into data, the replies to option negotiations and the
+    /// COM port control commands with their values
+    pub(crate) fn receive(
+        &mut self,
+        input: &[u8],
+        data: &mut Vec<u8>,
+        reply: &mut Vec<u8>,
+        commands: &mut Vec<(u8, Vec<u8>)>,
+    ) {
+        for &byte in input {
+            self.state = match (self.state, byte) {
+                (State::Data, IAC) => State::Iac,
+                (State::Data, _) => {
+                    data.push(byte);
+                    State::Data
+                }
+                (State::Iac, IAC) => {
+                    data.push(IAC);
+                    State::Data
+                }
+                (State::Iac, WILL | WONT | DO | DONT) => State::Negotiation(byte),
+                (State::Iac, SB) => {
+                    self.subnegotiation.clear();
+                    State::Subnegotiation
+                }
+                (State::Iac, _) => State::Data,
+                (State::Negotiation(command), option) => {
+                    self.negotiate(command, option, reply);
+                    State::Data
+                }
+                (State::Subnegotiation, IAC) => State::SubnegotiationIac,
+                (State::Subnegotiation, _) => {
+                    self.subnegotiation.push(byte);
+                    State::Subnegotiation
+                }
+                (State::SubnegotiationIac, IAC) => {
+                    self.subnegotiation.push(IAC);
+                    State::Subnegotiation
+                }
+                (State::SubnegotiationIac, SE) => {
+                    if let [COM_PORT_OPTION, command, value @ ..] = &self.subnegotiation[..] {
+                        commands.push((*command, value.to_vec()));
+                    }
+                    State::Data
+                }
+                (State::SubnegotiationIac, _) => State::Data,
+            };
+        }
+    }
+
+    /// Answer a request to enable or disable an option, acknowledging only
+    /// changes so both sides don't keep answering each other
+    fn negotiate(&mut self, command: u8, option: u8, reply: &mut Vec<u8>) {
+        let supported = matches!(option, BINARY | SGA | COM_PORT_OPTION);
+        match command {
+            DO if !supported => reply.extend_from_slice(&[IAC, WONT, option]),
+            DO if !self.will.contains(&option) => {
+                self.will.push(option);
+                reply.extend_from_slice(&[IAC, WILL, option]);
+            }
+            WILL if !supported => reply.extend_from_slice(&[IAC, DONT, option]),
+            WILL if !self.doing.contains(&option) => {
+                self.doing.push(option);
+                reply.extend_from_slice(&[IAC, DO, option]);
+            }
+            DONT if self.will.contains(&option) => {
+                self.will.retain(|o| *o != option);
+                reply.extend_from_slice(&[IAC, WONT, option]);
+            }
+            WONT if self.doing.contains(&option) => {
+                self.doing.retain(|o| *o != option);
+                reply.extend_from_slice(&[IAC, DONT, option]);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn telnet() {
+        let (mut telnet, _) = Telnet::new(&[SGA, COM_PORT_OPTION], &[]);
+        let (mut data, mut reply, mut commands) = (Vec::new(), Vec::new(), Vec::new());
+        telnet.receive(
+            &[
+                b'a',
+                IAC,
+                IAC,
+                IAC,
+                DO,
+                COM_PORT_OPTION,
+                IAC,
+                WILL,
+                BINARY,
+                IAC,
+                DO,
+                1,
+                IAC,
+                SB,
+                COM_PORT_OPTION,
+                SET_BAUDRATE,
+                0,
+                1,
+                194,
+                0,
+                IAC,
+                SE,
+                b'b',
+            ],
+            &mut data,
+            &mut reply,
+            &mut commands,
+        );
+
+        assert_eq!(data, [b'a', IAC, b'b']);
+        assert_eq!(reply, [IAC, DO, BINARY, IAC, WONT, 1]);
+        assert_eq!(commands, [(SET_BAUDRATE, vec![0, 1, 194, 0])]);
+        assert_eq!(escape(&[1, IAC, 2]), [1, IAC, IAC, 2]);
+    }
+}
@@ -0,0 +1,195 @@
+//! Settings from the `sdkconfig` of an ESP-IDF project
+//!
+//! Both the `sdkconfig` file in the project directory and the
+//! `config/sdkconfig.json` file generated in the build directory are
+//! supported, so that the image is flashed with the same flash parameters and
+//! layout the firmware was configured for.
+
+use serde_json::{Map, Value};
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::error::SdkConfigError;
+use crate::{FlashFrequency, FlashMode, FlashSettings, FlashSize};
+
+/// The options set in an `sdkconfig`, keyed by their name without the
+/// `CONFIG_` prefix
+#[derive(Debug, Clone, Default)]
+pub struct SdkConfig {
+    values: HashMap<String, String>,
+}
+
+impl SdkConfig {
+    /// Parse either an `sdkconfig` or an `sdkconfig.json`, detecting the
+    /// format from the contents
+    pub fn parse(data: &str) -> Result<Self, SdkConfigError> {
+        if data.trim_start().starts_with('{') {
+            Self::parse_json(data)
+        } else {
+            Self::parse_kconfig(data)
+        }
+    }
+
+    fn parse_kconfig(data: &str) -> Result<Self, SdkConfigError> {
+        let mut values = HashMap::new();
+        for (index, line) in data.lines().enumerate() {
+            let line = line.trim();
+            // Options which are not set are written as comments
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or(SdkConfigError::InvalidLine(index + 1))?;
+            let key = key.strip_prefix("CONFIG_").unwrap_or(key);
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value);
+
+            values.insert(key.to_string(), value.to_string());
+        }
+
+        Ok(SdkConfig { values })
+    }
+
+    fn parse_json(data: &str) -> Result<Self, SdkConfigError> {
+        let options: Map<String, Value> = serde_json::from_str(data)?;
+        let values = options
+            .into_iter()
+            .filter_map(|(key, value)| {
+                let value = match value {
+                    Value::Bool(true) => String::from("y"),
+                    Value::Number(number) => number.to_string(),
+                    Value::String(string) => string,
+                    _ => return None,
+                };
+                Some((key, value))
+            })
+            .collect();
+
+        Ok(SdkConfig { values })
+    }
+
+    /// The value of an option, `y` for enabled boolean options
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    /// The flash parameters and offsets the firmware was configured for
+    pub fn flash_settings(&self) -> Result<FlashSettings, SdkConfigError> {
+        Ok(FlashSettings {
+            mode: self.parse_value("ESPTOOLPY_FLASHMODE", FlashMode::from_str)?,
+            frequency: self.parse_value("ESPTOOLPY_FLASHFREQ", FlashFrequency::from_str)?,
+            size: self.parse_value("ESPTOOLPY_FLASHSIZE", FlashSize::from_str)?,
+            bootloader_offset: self.parse_value("BOOTLOADER_OFFSET_IN_FLASH", parse_u32)?,
+            partition_table_offset: self.parse_value("PARTITION_TABLE_OFFSET", parse_u32)?,
+            // the application is written to its partition
+            app_offset: None,
+        })
+    }
+
+    /// Path of the custom partition table CSV, if any, given the path the
+    /// sdkconfig was loaded from
+    ///
+    /// The partition table is relative to the project directory, which
+    /// contains the `sdkconfig`, while `sdkconfig.json` is generated in
+    /// `build/config` of the project.
+    pub fn partition_table(&self, sdkconfig: &Path) -> Option<PathBuf> {
+        let table = match self.get("PARTITION_TABLE_CUSTOM") {
+            Some("y") => self.get("PARTITION_TABLE_CUSTOM_FILENAME")?,
+            _ => return None,
+        };
+
+        let project_dir = if sdkconfig.extension() == Some("json".as_ref()) {
+            sdkconfig.ancestors().nth(3)
+        } else {
+            sdkconfig.parent()
+        };
+        Some(project_dir.unwrap_or_else(|| Path::new("")).join(table))
+    }
+
+    fn parse_value<T, E>(
+        &self,
+        key: &str,
+        parse: impl Fn(&str) -> Result<T, E>,
+    ) -> Result<Option<T>, SdkConfigError> {
+        self.get(key)
+            .map(|value| {
+                parse(value).map_err(|_| SdkConfigError::InvalidValue {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                })
+            })
+            .transpose()
+    }
+}
+
+fn parse_u32(value: &str) -> Result<u32, std::num::ParseIntError> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flash_settings() {
+        let kconfig = r#"
+#
+# Serial flasher config
+#
+CONFIG_ESPTOOLPY_FLASHMODE_QIO=y
+CONFIG_ESPTOOLPY_FLASHMODE="qio"
+CONFIG_ESPTOOLPY_FLASHFREQ="80m"
+CONFIG_ESPTOOLPY_FLASHSIZE="8MB"
+CONFIG_BOOTLOADER_OFFSET_IN_FLASH=0x1000
+# CONFIG_PARTITION_TABLE_SINGLE_APP is not set
+CONFIG_PARTITION_TABLE_CUSTOM=y
+CONFIG_PARTITION_TABLE_CUSTOM_FILENAME="partitions.csv"
+CONFIG_PARTITION_TABLE_OFFSET=0x9000
+"#;
+        let json = r#"{
+    "ESPTOOLPY_FLASHMODE": "qio",
+    "ESPTOOLPY_FLASHFREQ": "80m",
+    "ESPTOOLPY_FLASHSIZE": "8MB",
+    "BOOTLOADER_OFFSET_IN_FLASH": 4096,
+    "PARTITION_TABLE_SINGLE_APP": false,
+    "PARTITION_TABLE_CUSTOM": true,
+    "PARTITION_TABLE_CUSTOM_FILENAME": "partitions.csv",
+    "PARTITION_TABLE_OFFSET": 36864
+}"#;
+
+        let expected = FlashSettings {
+            mode: Some(FlashMode::Qio),
+            frequency: Some(FlashFrequency::Flash80M),
+            size: Some(FlashSize::Flash8Mb),
+            bootloader_offset: Some(0x1000),
+            partition_table_offset: Some(0x9000),
+            app_offset: None,
+        };
+        for (data, path) in [
+            (kconfig, "project/sdkconfig"),
+            (json, "project/build/config/sdkconfig.json"),
+        ] {
+            let config = SdkConfig::parse(data).unwrap();
+            assert_eq!(expected, config.flash_settings().unwrap());
+            assert_eq!(
+                Some(PathBuf::from("project/partitions.csv")),
+                config.partition_table(Path::new(path))
+            );
+        }
+
+        let config = SdkConfig::parse("CONFIG_ESPTOOLPY_FLASHSIZE=\"256MB\"").unwrap();
+        assert!(matches!(
+            config.flash_settings(),
+            Err(SdkConfigError::InvalidValue { .. })
+        ));
+    }
+}
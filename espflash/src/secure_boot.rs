@@ -0,0 +1,123 @@
+//! Secure Boot V1 bootloader digests for the ESP32
+//!
+//! With reflashable secure boot V1 the ROM verifies the bootloader against a
+//! digest stored at offset 0x0 of the flash, which is derived from the
+//! bootloader image and the secure boot key burned into efuse block 2. This
+//! generates that digest the same way as `espsecure.py
+//! digest_secure_bootloader`.
+
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::Aes256;
+use sha2::{Digest, Sha512};
+
+use crate::chip::{appends_digest, validate_bootloader, ESP32_CHIP_ID};
+use crate::Error;
+
+/// Offset in flash at which the digest and bootloader are written
+pub const SECURE_BOOT_DIGEST_OFFSET: u32 = 0x0;
+/// Length of the random IV preceding the digest
+pub const SECURE_BOOT_IV_LEN: usize = 128;
+
+/// The secure boot engine reads the bootloader in blocks of this size
+const DIGEST_BLOCK_LEN: usize = 128;
+/// Size of the region holding the IV and digest, the bootloader follows it
+const DIGEST_REGION_LEN: usize = 0x1000;
+
+/// Generate the image to write at [`SECURE_BOOT_DIGEST_OFFSET`], containing
+/// the IV, the digest of `bootloader` and the bootloader itself
+///
+/// `key` is either the 256 bit secure boot key, or the 192 bit key used with
+/// the 3/4 coding scheme.
+pub fn digest_bootloader(
+    key: &[u8],
+    iv: &[u8; SECURE_BOOT_IV_LEN],
+    bootloader: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let key = hardware_key(key)?;
+    validate_bootloader(bootloader, ESP32_CHIP_ID)?;
+
+    // The secure boot engine doesn't know about an appended SHA-256 digest and
+    // only reads up to the end of the 128 byte block containing the image
+    let mut image = bootloader.to_vec();
+    if appends_digest(bootloader) && image.len() % DIGEST_BLOCK_LEN <= 32 {
+        image.truncate(image.len() - image.len() % DIGEST_BLOCK_LEN);
+    }
+    // Pad with unwritten flash, as that is what the secure boot engine sees
+    let padded_len = image.len().div_ceil(DIGEST_BLOCK_LEN) * DIGEST_BLOCK_LEN;
+    image.resize(padded_len, 0xff);
+
+    // The hardware feeds the AES-256 ECB ciphertext of the IV and image through
+    // SHA-512, with the bytes of each block and word reordered
+    let aes = Aes256::new(&key.into());
+    let mut hasher = Sha512::new();
+    for chunk in iv.chunks_exact(16).chain(image.chunks_exact(16)) {
+        let mut block = aes::Block::clone_from_slice(chunk);
+        block.reverse();
+        aes.encrypt_block(&mut block);
+        block.reverse();
+        for word in block.chunks_exact(4) {
+            hasher.update([word[3], word[2], word[1], word[0]]);
+        }
+    }
+
+    let mut data = Vec::with_capacity(DIGEST_REGION_LEN + image.len());
+    data.extend_from_slice(iv);
+    for word in hasher.finalize().chunks_exact(4) {
+        data.extend_from_slice(&[word[3], word[2], word[1], word[0]]);
+    }
+    data.resize(DIGEST_REGION_LEN, 0xff);
+    data.extend_from_slice(&image);
+
+    Ok(data)
+}
+
+/// Expand the key to 256 bits, the 192 bit keys used with the 3/4 coding
+/// scheme are extended by repeating their second 64 bits
+fn hardware_key(key: &[u8]) -> Result<[u8; 32], Error> {
+    let mut hardware_key = [0u8; 32];
+    match key.len() {
+        32 => hardware_key.copy_from_slice(key),
+        24 => {
+            hardware_key[..24].copy_from_slice(key);
+            hardware_key[24..].copy_from_slice(&key[8..16]);
+        }
+        len => return Err(Error::InvalidSecureBootKey(len)),
+    }
+
+    Ok(hardware_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_digest_bootloader() {
+        let mut bootloader = vec![0xe9, 0, 0, 0, 0, 0, 0, 0, 0xee, 0, 0, 0, 0, 0];
+        bootloader.resize(200, 0);
+        let iv = [0x5a; SECURE_BOOT_IV_LEN];
+
+        let data = digest_bootloader(&[0x01; 32], &iv, &bootloader).unwrap();
+        assert_eq!(DIGEST_REGION_LEN + 256, data.len());
+        assert_eq!(&iv[..], &data[..SECURE_BOOT_IV_LEN]);
+        assert_eq!(&bootloader[..], &data[DIGEST_REGION_LEN..][..200]);
+        assert!(data[DIGEST_REGION_LEN + 200..].iter().all(|&b| b == 0xff));
+        let digest: String = data[SECURE_BOOT_IV_LEN..][..64]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        assert_eq!(
+            "664b63a2cb6556767d02aff87e8aadac25228e0e1f36f7f23bbf03b2e0fa6a26\
+             656557e0c58419f2e79e1687c2d06867fae2e97865a48292bdada23109d67eeb",
+            digest
+        );
+
+        // 192 bit keys are extended, rather than used as is
+        let extended = digest_bootloader(&[0x01; 24], &iv, &bootloader).unwrap();
+        assert_eq!(data, extended);
+        assert!(matches!(
+            digest_bootloader(&[0x01; 16], &iv, &bootloader),
+            Err(Error::InvalidSecureBootKey(16))
+        ));
+    }
+}